@@ -0,0 +1,23 @@
+import openai
+
+openai.api_key = "EMPTY"
+openai.base_url = "http://localhost:1234/v1/"
+
+texts = [
+    "The cat sat on the mat.",
+    "A kitten was sitting on the rug.",
+    "Rust is a systems programming language.",
+]
+
+response = openai.embeddings.create(
+    model="mistral",
+    input=texts,
+    extra_body={"pooling": "mean", "normalize": True},
+)
+embeddings = [data.embedding for data in response.data]
+
+# The embeddings are normalized, so the dot product is the cosine similarity.
+for i, text in enumerate(texts[1:], start=1):
+    similarity = sum(a * b for a, b in zip(embeddings[0], embeddings[i]))
+    print(f"{texts[0]!r} vs {text!r}: {similarity:.3f}")
+print(f"Dimensions: {len(embeddings[0])}, prompt tokens: {response.usage.prompt_tokens}")
@@ -0,0 +1,56 @@
+import openai
+
+openai.api_key = "EMPTY"
+openai.base_url = "http://localhost:1234/v1/"
+
+# A grammar in the GBNF format of llama.cpp, which starts with the `root` rule.
+ARITHMETIC_GBNF = r"""
+root   ::= expr "\n"
+expr   ::= term (ws [-+*/] ws term)*
+term   ::= number | "(" ws expr ws ")"
+number ::= [0-9]+ ("." [0-9]+)?
+ws     ::= " "?
+"""
+
+completion = openai.chat.completions.create(
+    model="mistral",
+    messages=[
+        {
+            "role": "user",
+            "content": "Write an arithmetic expression which evaluates to 42.",
+        }
+    ],
+    max_tokens=64,
+    temperature=0,
+    extra_body={"grammar": {"type": "gbnf", "value": ARITHMETIC_GBNF}},
+)
+
+print(completion.choices[0].message.content)
+
+print("---")
+
+# The same with a Lark grammar, which starts with the `start` rule.
+ARITHMETIC_LARK = r"""
+start: expr "\n"
+?expr: term (OP term)*
+?term: NUMBER | "(" expr ")"
+OP: "+" | "-" | "*" | "/"
+
+%import common.NUMBER
+%ignore " "
+"""
+
+completion = openai.chat.completions.create(
+    model="mistral",
+    messages=[
+        {
+            "role": "user",
+            "content": "Write an arithmetic expression which evaluates to 42.",
+        }
+    ],
+    max_tokens=64,
+    temperature=0,
+    extra_body={"grammar": {"type": "lark", "value": ARITHMETIC_LARK}},
+)
+
+print(completion.choices[0].message.content)
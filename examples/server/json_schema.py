@@ -0,0 +1,34 @@
+import json
+
+import openai
+
+openai.api_key = "EMPTY"
+openai.base_url = "http://localhost:1234/v1/"
+
+schema = {
+    "type": "object",
+    "properties": {
+        "name": {"type": "string"},
+        "age": {"type": "integer"},
+        "languages": {"type": "array", "items": {"type": "string"}},
+    },
+    "required": ["name", "age"],
+}
+
+completion = openai.chat.completions.create(
+    model="mistral",
+    messages=[
+        {
+            "role": "user",
+            "content": "Describe a fictional programmer as JSON.",
+        }
+    ],
+    max_tokens=256,
+    temperature=0,
+    response_format={
+        "type": "json_schema",
+        "json_schema": {"name": "programmer", "schema": schema},
+    },
+)
+
+print(json.loads(completion.choices[0].message.content))
@@ -0,0 +1,59 @@
+import json
+
+import openai
+
+openai.api_key = "EMPTY"
+openai.base_url = "http://localhost:1234/v1/"
+
+tools = [
+    {
+        "type": "function",
+        "function": {
+            "name": "get_weather",
+            "description": "Get the current weather in a city.",
+            "parameters": {
+                "type": "object",
+                "properties": {"city": {"type": "string"}},
+                "required": ["city"],
+            },
+        },
+    }
+]
+
+messages = [
+    {
+        "role": "user",
+        "content": "What is the weather like in Paris?",
+    }
+]
+
+completion = openai.chat.completions.create(
+    model="mistral",
+    messages=messages,
+    max_tokens=256,
+    temperature=0,
+    tools=tools,
+    tool_choice="required",
+)
+
+tool_call = completion.choices[0].message.tool_calls[0]
+print(tool_call.function.name, tool_call.function.arguments)
+
+messages.append(completion.choices[0].message)
+messages.append(
+    {
+        "role": "tool",
+        "tool_call_id": tool_call.id,
+        "content": json.dumps({"temperature": "18C", "sky": "sunny"}),
+    }
+)
+
+completion = openai.chat.completions.create(
+    model="mistral",
+    messages=messages,
+    max_tokens=256,
+    temperature=0,
+    tools=tools,
+)
+
+print(completion.choices[0].message.content)
@@ -44,6 +44,9 @@ pub struct CfgParser {
     skip_patterns: Vob,
     friendly_pattern_names: Vec<String>,
     viable_vobidx_by_state: Vec<VobIdx>,
+    // Whether tokens are lexed with the tokens the parser can accept, see `from_yacc_with_viable_tokens`.
+    lex_viable_tokens: bool,
+    all_tokens: VobIdx,
 }
 
 fn is_rx(name: &str) -> bool {
@@ -58,6 +61,7 @@ fn quote_rx(name: &str) -> String {
                 || ch.is_ascii_uppercase()
                 || '<' == ch
                 || '>' == ch
+                || !ch.is_ascii()
             {
                 ch.to_string()
             } else {
@@ -117,6 +121,18 @@ pub(crate) fn parse_yacc(yacc: &str) -> Result<YaccGrammar> {
 
 impl CfgParser {
     pub fn from_yacc(yacc: &str) -> Result<Self> {
+        Self::new(yacc, false)
+    }
+
+    /// Like `from_yacc`, but a token only ends when none of the tokens which the parser can accept next can
+    /// continue it, and only such a token is lexed. The end of the sentence also ends the pending token in
+    /// grammars without `SKIP`. This is needed for grammars whose tokens overlap, like the ones compiled from GBNF
+    /// and Lark grammars.
+    pub fn from_yacc_with_viable_tokens(yacc: &str) -> Result<Self> {
+        Self::new(yacc, true)
+    }
+
+    fn new(yacc: &str, lex_viable_tokens: bool) -> Result<Self> {
         let grm = parse_yacc(yacc)?;
         // TIME: all these annotation are for native release x86 build for C grammar
         // TIME: 27ms
@@ -238,6 +254,8 @@ impl CfgParser {
             pat_idx_to_tidx,
             tidx_to_pat_idx,
             viable_vobidx_by_state,
+            lex_viable_tokens,
+            all_tokens: all1,
             skip_patterns,
             friendly_pattern_names,
             parse_stacks,
@@ -266,6 +284,15 @@ impl CfgParser {
         self.viable_vobidx_by_state[stidx.as_storaget() as usize]
     }
 
+    /// The tokens which the lexer may lex in this state.
+    fn lexable_vobidx(&self, state: &ByteState) -> VobIdx {
+        if self.lex_viable_tokens {
+            state.viable
+        } else {
+            self.all_tokens
+        }
+    }
+
     #[allow(dead_code)]
     fn friendly_token_name(&self, lexeme: TIdx<StorageT>) -> &str {
         if let Some(pidx) = self.tidx_to_pat_idx.get(&lexeme) {
@@ -337,7 +364,11 @@ impl CfgParser {
                 print!("<EOF>")
             }
         }
-        let (info, res) = match self.lexer.advance(top.lexer_state, byte) {
+        let lexable = self.lexable_vobidx(&top);
+        let (info, res) = match self
+            .lexer
+            .advance(top.lexer_state, byte, &self.vobset, lexable)
+        {
             // Error?
             None => ("lex-err", None),
             // Just new state, no token - the hot path
@@ -467,7 +498,7 @@ impl Recognizer for CfgParser {
 
     fn special_allowed(&mut self, tok: SpecialToken) -> bool {
         match tok {
-            SpecialToken::EndOfSentence => {
+            SpecialToken::EndOfSentence if !self.lex_viable_tokens => {
                 if let Some(st) = self.try_push(None) {
                     let tidx = self.grm.eof_token_idx();
                     let mut pstack = self.pstack_for(&st).clone();
@@ -476,6 +507,26 @@ impl Recognizer for CfgParser {
                     false
                 }
             }
+            SpecialToken::EndOfSentence => {
+                // The end of the sentence ends the pending token. This does not go through `try_push`, which
+                // would reject it if no more tokens are viable, as for grammars without `SKIP`.
+                let top = self.byte_states.last().unwrap().clone();
+                let Some((_, Some(pat_idx))) =
+                    self.lexer
+                        .advance(top.lexer_state, None, &self.vobset, top.viable)
+                else {
+                    return false;
+                };
+                let mut pstack = self.pstack_for(&top).clone();
+                if !self.skip_patterns[pat_idx] {
+                    let tidx = self.pat_idx_to_tidx[pat_idx];
+                    if !matches!(self.parse_lexeme(tidx, &mut pstack), ParseResult::Continue) {
+                        return false;
+                    }
+                }
+                let tidx = self.grm.eof_token_idx();
+                matches!(self.parse_lexeme(tidx, &mut pstack), ParseResult::Accept)
+            }
             _ => false,
         }
     }
@@ -494,3 +545,63 @@ impl Recognizer for CfgParser {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::CfgParser;
+    use crate::grammars::tests;
+
+    fn accepts(yacc: &str, text: &str) -> bool {
+        tests::accepts(CfgParser::from_yacc(yacc).unwrap(), text)
+    }
+
+    const STATEMENTS: &str = r#"
+%start prog
+%%
+
+SKIP: "/[ \t\n]*/" ;
+
+prog: stmt | prog stmt ;
+stmt: "while" ID "do" stmt | ID "=" expr ";" ;
+expr: NUM | ID | expr "+" expr ;
+ID: "/[a-z]+/" ;
+NUM: "/[0-9]+/" ;
+"#;
+
+    #[test]
+    fn tokens_are_the_longest_match() {
+        assert!(accepts(STATEMENTS, "x = 12 + 345;"));
+        assert!(accepts(STATEMENTS, "x=12+345;"));
+        assert!(accepts(STATEMENTS, "whiles = 1;"));
+        assert!(!accepts(STATEMENTS, "x = 12 + ;"));
+    }
+
+    #[test]
+    fn literals_take_precedence_over_regexes() {
+        assert!(accepts(STATEMENTS, "while x do y = 1;"));
+        assert!(!accepts(STATEMENTS, "while = 1;"));
+        assert!(!accepts(STATEMENTS, "x = while;"));
+    }
+
+    #[test]
+    fn viable_tokens_are_lexed_where_literals_cannot_be_parsed() {
+        let parser = CfgParser::from_yacc_with_viable_tokens(STATEMENTS).unwrap();
+        assert!(tests::accepts(parser, "x = while;"));
+        let parser = CfgParser::from_yacc_with_viable_tokens(STATEMENTS).unwrap();
+        assert!(!tests::accepts(parser, "while = 1;"));
+    }
+
+    #[test]
+    fn skipped_tokens_may_appear_between_tokens() {
+        assert!(accepts(STATEMENTS, " x =\n1 ;\n\ty = x; "));
+        assert!(!accepts(STATEMENTS, "x = 1 2;"));
+    }
+
+    #[test]
+    fn the_end_requires_a_complete_parse() {
+        assert!(accepts(STATEMENTS, "x = 1;"));
+        assert!(!accepts(STATEMENTS, "x = 1"));
+        assert!(!accepts(STATEMENTS, "while x do"));
+        assert!(!accepts(STATEMENTS, ""));
+    }
+}
@@ -265,18 +265,18 @@ impl Lexer {
         self.vobidx_by_state_off[state.as_usize() >> self.dfa.stride2()]
     }
 
-    fn get_token(&self, prev: StateID) -> Option<PatIdx> {
+    fn get_token(&self, prev: StateID, viable: &Vob) -> Option<PatIdx> {
         let state = self.dfa.next_eoi_state(prev);
         if !self.dfa.is_match_state(state) {
             return None;
         }
 
-        // we take the first token that matched
+        // we take the first viable token that matched
         // (eg., "while" will match both keyword and identifier, but keyword is first)
         let pat_idx = (0..self.dfa.match_len(state))
             .map(|idx| self.dfa.match_pattern(state, idx).as_usize())
-            .min()
-            .unwrap();
+            .filter(|pat_idx| viable[*pat_idx])
+            .min()?;
 
         if LOG_LEXER {
             debug!("token: {}", pat_idx);
@@ -285,8 +285,16 @@ impl Lexer {
         Some(pat_idx)
     }
 
+    /// Advance the lexer by `byte`, or by the end of the input. The token ends when no `viable` token can
+    /// continue it, and only a `viable` token is returned.
     #[inline(always)]
-    pub fn advance(&self, prev: StateID, byte: Option<u8>) -> Option<(LexerState, Option<PatIdx>)> {
+    pub fn advance(
+        &self,
+        prev: StateID,
+        byte: Option<u8>,
+        vobset: &VobSet,
+        viable: VobIdx,
+    ) -> Option<(LexerState, Option<PatIdx>)> {
         let dfa = &self.dfa;
         if let Some(byte) = byte {
             let state = dfa.next_state(prev, byte);
@@ -300,9 +308,9 @@ impl Lexer {
                 );
             }
             let v = self.reachable_tokens(state);
-            if v.is_zero() {
+            if vobset.and_is_zero(v, viable) {
                 // if final_state is a match state, find the token that matched
-                let tok = self.get_token(prev);
+                let tok = self.get_token(prev, vobset.resolve(viable));
                 if tok.is_none() {
                     None
                 } else {
@@ -322,7 +330,7 @@ impl Lexer {
                 ))
             }
         } else {
-            let tok = self.get_token(prev);
+            let tok = self.get_token(prev, vobset.resolve(viable));
             if tok.is_none() {
                 None
             } else {
@@ -0,0 +1,202 @@
+use std::collections::HashMap;
+
+/// Configuration of the paged KV cache. The total number of cached tokens is `block_size * num_blocks`.
+///
+/// The models' attention does not read the blocks directly: on each step, the blocks of the scheduled sequences
+/// are gathered into a contiguous KV cache, and the new keys and values are written back to the blocks after the
+/// forward pass. The paged KV cache is not used for X-LoRA and LoRA models or without a KV cache, and it
+/// disables prefix caching, speculative decoding and beam search.
+#[derive(Clone, Copy, Debug)]
+pub struct PagedCacheConfig {
+    /// Number of tokens stored in one block.
+    pub block_size: usize,
+    /// Number of blocks to preallocate on the device.
+    pub num_blocks: usize,
+}
+
+/// Hands out fixed-size physical blocks from a free list.
+struct BlockAllocator {
+    free_blocks: Vec<usize>,
+    num_blocks: usize,
+}
+
+impl BlockAllocator {
+    fn new(num_blocks: usize) -> Self {
+        Self {
+            // Reverse so that `pop` hands out the lowest block ids first
+            free_blocks: (0..num_blocks).rev().collect(),
+            num_blocks,
+        }
+    }
+
+    fn allocate(&mut self) -> Option<usize> {
+        self.free_blocks.pop()
+    }
+
+    fn free(&mut self, block: usize) {
+        debug_assert!(block < self.num_blocks);
+        debug_assert!(!self.free_blocks.contains(&block));
+        self.free_blocks.push(block);
+    }
+
+    fn num_free(&self) -> usize {
+        self.free_blocks.len()
+    }
+}
+
+/// Bookkeeping for the paged KV cache: which physical blocks belong to which sequence.
+/// The tensors themselves live in the `PagedKvCache` of the model `Cache`.
+pub struct BlockEngine {
+    block_size: usize,
+    allocator: BlockAllocator,
+    // Sequence id -> physical block ids, in logical order
+    block_tables: HashMap<usize, Vec<usize>>,
+}
+
+impl BlockEngine {
+    pub fn new(config: PagedCacheConfig) -> Self {
+        Self {
+            block_size: config.block_size,
+            allocator: BlockAllocator::new(config.num_blocks),
+            block_tables: HashMap::new(),
+        }
+    }
+
+    pub fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    pub fn num_total_blocks(&self) -> usize {
+        self.allocator.num_blocks
+    }
+
+    fn blocks_for(&self, num_tokens: usize) -> usize {
+        num_tokens.div_ceil(self.block_size)
+    }
+
+    /// Number of new blocks needed so that the sequence can hold `num_tokens` tokens.
+    fn blocks_to_grow(&self, id: usize, num_tokens: usize) -> usize {
+        let have = self.block_tables.get(&id).map(|t| t.len()).unwrap_or(0);
+        self.blocks_for(num_tokens).saturating_sub(have)
+    }
+
+    /// Can the sequence `id` store KV for `num_tokens` tokens?
+    pub fn can_allocate(&self, id: usize, num_tokens: usize) -> bool {
+        self.blocks_to_grow(id, num_tokens) <= self.allocator.num_free()
+    }
+
+    /// Grow the block table of the sequence `id` so that it can store KV for `num_tokens` tokens.
+    /// Returns `false` and leaves the table untouched if there are not enough free blocks.
+    pub fn allocate(&mut self, id: usize, num_tokens: usize) -> bool {
+        if !self.can_allocate(id, num_tokens) {
+            return false;
+        }
+        let n = self.blocks_to_grow(id, num_tokens);
+        let mut new_blocks = Vec::with_capacity(n);
+        for _ in 0..n {
+            // NOTE(EricLBuehler): Unwrap reasoning: We checked that there are enough free blocks.
+            new_blocks.push(self.allocator.allocate().unwrap());
+        }
+        self.block_tables.entry(id).or_default().extend(new_blocks);
+        true
+    }
+
+    /// Release all blocks held by the sequence. This is a no-op for unknown ids.
+    pub fn free_sequence(&mut self, id: usize) {
+        if let Some(table) = self.block_tables.remove(&id) {
+            for block in table {
+                self.allocator.free(block);
+            }
+        }
+    }
+
+    /// Flat slot indices (`block * block_size + offset`) for the logical positions `start..end` of a sequence.
+    ///
+    /// # Panics
+    /// If the sequence does not hold enough blocks for `end` tokens.
+    #[allow(clippy::cast_possible_truncation)]
+    pub fn slot_mapping(&self, id: usize, start: usize, end: usize) -> Vec<u32> {
+        let table = &self.block_tables[&id];
+        (start..end)
+            .map(|pos| {
+                let block = table[pos / self.block_size];
+                (block * self.block_size + pos % self.block_size) as u32
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BlockEngine, PagedCacheConfig};
+
+    fn block_engine(block_size: usize, num_blocks: usize) -> BlockEngine {
+        BlockEngine::new(PagedCacheConfig {
+            block_size,
+            num_blocks,
+        })
+    }
+
+    #[test]
+    fn allocate_grows_by_whole_blocks() {
+        let mut engine = block_engine(4, 3);
+        assert!(engine.allocate(0, 1));
+        assert_eq!(engine.allocator.num_free(), 2);
+        // The first block has room for 4 tokens.
+        assert!(engine.allocate(0, 4));
+        assert_eq!(engine.allocator.num_free(), 2);
+        assert!(engine.allocate(0, 5));
+        assert_eq!(engine.allocator.num_free(), 1);
+        assert_eq!(engine.block_tables[&0], vec![0, 1]);
+    }
+
+    #[test]
+    fn allocate_fails_without_enough_blocks() {
+        let mut engine = block_engine(4, 3);
+        assert!(engine.allocate(0, 8));
+        assert!(engine.can_allocate(1, 4));
+        assert!(!engine.can_allocate(1, 5));
+        assert!(!engine.allocate(1, 5));
+        // A failed allocation leaves everything untouched.
+        assert!(!engine.block_tables.contains_key(&1));
+        assert_eq!(engine.allocator.num_free(), 1);
+        // Growing a sequence only needs the blocks it does not have yet.
+        assert!(engine.can_allocate(0, 12));
+        assert!(!engine.can_allocate(0, 13));
+    }
+
+    #[test]
+    fn free_sequence_returns_blocks() {
+        let mut engine = block_engine(2, 4);
+        assert!(engine.allocate(0, 4));
+        assert!(engine.allocate(1, 4));
+        assert!(!engine.can_allocate(2, 1));
+        engine.free_sequence(0);
+        assert_eq!(engine.allocator.num_free(), 2);
+        assert!(!engine.block_tables.contains_key(&0));
+        // The freed blocks are handed out again.
+        assert!(engine.allocate(2, 3));
+        let mut table = engine.block_tables[&2].clone();
+        table.sort_unstable();
+        assert_eq!(table, vec![0, 1]);
+        // Freeing an unknown sequence is a no-op.
+        engine.free_sequence(7);
+        assert_eq!(engine.allocator.num_free(), 0);
+    }
+
+    #[test]
+    fn slot_mapping_crosses_block_boundaries() {
+        let mut engine = block_engine(4, 4);
+        assert!(engine.allocate(0, 4));
+        assert!(engine.allocate(1, 4));
+        assert!(engine.allocate(0, 10));
+        // Sequence 0 holds blocks 0, 2 and 3, and sequence 1 holds block 1.
+        assert_eq!(engine.block_tables[&0], vec![0, 2, 3]);
+        assert_eq!(
+            engine.slot_mapping(0, 2, 10),
+            vec![2, 3, 8, 9, 10, 11, 12, 13]
+        );
+        assert_eq!(engine.slot_mapping(1, 0, 4), vec![4, 5, 6, 7]);
+        assert_eq!(engine.slot_mapping(0, 5, 5), Vec::<u32>::new());
+    }
+}
@@ -0,0 +1,143 @@
+use std::collections::HashMap;
+
+use candle_core::Tensor;
+
+use crate::{
+    get_mut_arcmutex, handle_seq_error_stateaware,
+    pipeline::Pipeline,
+    sequence::{Sequence, SequenceState, StopReason},
+};
+
+use super::Engine;
+
+impl Engine {
+    /// Instead of sampling a token, a beam keeps the `2 * beam_width` most likely next tokens as candidates,
+    /// and waits until all beams of its group have theirs.
+    pub(super) fn add_beam_candidates(pipeline: &dyn Pipeline, seq: &mut Sequence, logits: Tensor) {
+        // NOTE(EricLBuehler): Unwrap reasoning: The seq is a beam.
+        let beam_width = seq
+            .get_mut_group()
+            .beam_search
+            .as_ref()
+            .unwrap()
+            .params
+            .beam_width;
+        let logits = handle_seq_error_stateaware!(logits.flatten_all(), seq);
+        let toks = seq.get_toks();
+        let ctxt = toks[toks.len().saturating_sub(pipeline.get_repeat_last_n())..].to_vec();
+        let return_logprobs = seq.return_logprobs();
+        let candidates =
+            seq.sampler()
+                .beam_candidates(&logits, Some(&ctxt), 2 * beam_width, return_logprobs);
+        let candidates = handle_seq_error_stateaware!(candidates, seq);
+        seq.set_beam_candidates(candidates);
+    }
+
+    /// Advance the beam searches whose live beams all have their candidates: the best extensions of the beams
+    /// are forked into the next beams, and the beams they replace are dropped. Once a search is done, its best
+    /// finished beams are the choices of the response.
+    pub(super) fn step_beams(&mut self) {
+        let mut groups: HashMap<usize, Vec<&mut Sequence>> = HashMap::new();
+        for seq in self.scheduler.seqs_mut() {
+            if seq.is_beam() && (seq.is_running() || seq.is_waiting()) {
+                let request_id = seq.get_mut_group().request_id;
+                groups.entry(request_id).or_default().push(seq);
+            }
+        }
+        groups.retain(|_, beams| beams.iter().all(|seq| seq.has_beam_candidates()));
+        if groups.is_empty() {
+            return;
+        }
+        let mut pipeline = get_mut_arcmutex!(self.pipeline);
+        let mut forks = Vec::new();
+        for beams in groups.into_values() {
+            forks.extend(Self::step_beam_group(&mut *pipeline, beams, &mut self.id));
+        }
+        drop(pipeline);
+        for seq in forks {
+            self.scheduler.add_seq(seq);
+        }
+    }
+
+    /// One step of the beam search of a group. Returns the next beams.
+    fn step_beam_group(
+        pipeline: &mut dyn Pipeline,
+        mut beams: Vec<&mut Sequence>,
+        next_id: &mut usize,
+    ) -> Vec<Sequence> {
+        // NOTE(EricLBuehler): Unwrap reasoning: The seqs are beams.
+        let params = beams[0]
+            .get_mut_group()
+            .beam_search
+            .as_ref()
+            .unwrap()
+            .params;
+        let eos_tok = pipeline.eos_tok();
+        let max_model_len = pipeline.get_max_seq_len();
+
+        // The candidates of all beams, by the cumulative logprob of the beam they extend.
+        let mut candidates = Vec::new();
+        for (i, beam) in beams.iter_mut().enumerate() {
+            let cumulative_logprob = beam.cumulative_logprob();
+            for candidate in beam.take_beam_candidates() {
+                candidates.push((i, cumulative_logprob + candidate.logprob, candidate));
+            }
+        }
+        candidates.sort_by(|a, b| b.1.total_cmp(&a.1));
+
+        // A candidate which finishes its beam is kept if it is among the `beam_width` best candidates, and the
+        // best other ones are the next beams.
+        let mut next_beams: Vec<Sequence> = Vec::new();
+        for (rank, (i, _, candidate)) in candidates.into_iter().enumerate() {
+            if next_beams.len() == params.beam_width {
+                break;
+            }
+            let mut beam = beams[i].fork(*next_id);
+            *next_id += 1;
+            let token = candidate.token;
+            beam.add_token(candidate, pipeline.tok_trie().decode(&[token]));
+            match beam.is_done(token, eos_tok, max_model_len) {
+                Some(reason) if rank < params.beam_width => {
+                    let hypothesis = beam.beam_hypothesis(reason, params.length_penalty);
+                    let mut group = beam.get_mut_group();
+                    // NOTE(EricLBuehler): Unwrap reasoning: The seq is a beam.
+                    group
+                        .beam_search
+                        .as_mut()
+                        .unwrap()
+                        .add_hypothesis(hypothesis);
+                }
+                Some(_) => {}
+                None => next_beams.push(beam),
+            }
+        }
+
+        // The replaced beams are dropped by the scheduler without responding.
+        for beam in beams.iter() {
+            beam.set_state(SequenceState::Done(StopReason::Canceled));
+        }
+        let best_live_score = next_beams
+            .iter()
+            .map(|beam| beam.beam_score(params.length_penalty))
+            .max_by(|a, b| a.total_cmp(b));
+        let is_done = beams[0]
+            .get_mut_group()
+            .beam_search
+            .as_ref()
+            .unwrap()
+            .is_done(best_live_score);
+        if !is_done {
+            return next_beams;
+        }
+
+        let hypotheses = beams[0].get_mut_group().take_beam_hypotheses();
+        for (response_index, hypothesis) in hypotheses.into_iter().enumerate() {
+            let reason = hypothesis.reason;
+            let seq = beams[0].fork_hypothesis(*next_id, response_index, hypothesis);
+            *next_id += 1;
+            Self::finish_seq(pipeline, &seq, reason);
+        }
+        pipeline.reset_non_granular_state();
+        Vec::new()
+    }
+}
@@ -1,36 +1,53 @@
 use std::{
     cell::RefCell,
     collections::VecDeque,
-    iter::zip,
+    iter::{repeat, zip},
     rc::Rc,
-    sync::{mpsc::Receiver, Mutex},
-    time::{Instant, SystemTime, UNIX_EPOCH},
+    sync::{
+        mpsc::{Receiver, TryRecvError},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
 use crate::{
     aici::{cfg::CfgParser, recognizer::StackRecognizer, rx::RecRx},
-    response::CompletionChoice,
+    block_engine::{BlockEngine, PagedCacheConfig},
+    models::PagedKvCache,
+    response::{CompletionChoice, CompletionChunkChoice, CompletionLogprobs},
     CompletionResponse, RequestType,
 };
-use candle_core::Tensor;
+use candle_core::{DType, Tensor};
 use either::Either;
-use tracing::warn;
+use tokenizers::Tokenizer;
+use tracing::{info, warn};
 
 use crate::{
-    get_mut_arcmutex, handle_pipeline_forward_error, handle_seq_error, handle_seq_error_stateaware,
+    get_mut_arcmutex, grammars, handle_pipeline_forward_error, handle_seq_error,
+    handle_seq_error_stateaware,
     pipeline::Pipeline,
     prefix_cacher::{MatchingCache, PrefixCacheManager},
-    request::Request,
+    request::{
+        AdapterAction, AdapterRequest, DetokenizationRequest, EmbeddingPooling, EmbeddingRequest,
+        NormalRequest, Request, TokenizationRequest,
+    },
     response::{
-        ChatCompletionResponse, Choice, ChunkChoice, Delta, Logprobs, Response, ResponseLogprob,
-        ResponseMessage, SYSTEM_FINGERPRINT,
+        ChatCompletionResponse, Choice, ChunkChoice, Delta, DetokenizationResponse, Embedding,
+        EmbeddingResponse, EmbeddingUsage, Logprobs, Response, ResponseLogprob, ResponseMessage,
+        TokenizationResponse,
     },
-    sampler::Sampler,
-    scheduler::{Scheduler, SchedulerMethod},
+    sampler::{self, Sampler},
+    scheduler::{QueuePolicy, Scheduler, SchedulerMethod},
     sequence::{Sequence, SequenceGroup, SequenceRecognizer, SequenceState, StopReason},
+    tools::{self, ToolCall, ToolChoice},
     Constraint, StopTokens,
 };
 
+mod beam_search;
+mod speculative;
+
+pub use speculative::SpeculativeConfig;
+
 const SEED: u64 = 0;
 
 pub struct Engine {
@@ -41,72 +58,246 @@ pub struct Engine {
     truncate_sequence: bool,
     no_kv_cache: bool,
     prefix_cacher: PrefixCacheManager,
+    block_engine: Option<Arc<Mutex<BlockEngine>>>,
+    // With the paged cache, the ids of the seqs whose KV the model cache still holds after the last step, in
+    // the order of the batch, with the number of tokens it holds for each. The next step of the same seqs
+    // extends it instead of gathering it from the paged cache again.
+    paged_batch: Vec<(usize, usize)>,
+    prefill_chunk_size: Option<usize>,
+    max_request_time: Option<Duration>,
+    speculative: Option<SpeculativeConfig>,
+    // The embedding requests, oldest first, whose inputs are embedded one per step.
+    embeddings: VecDeque<PendingEmbedding>,
+}
+
+/// An embedding request with the embeddings of the inputs which were already embedded.
+struct PendingEmbedding {
+    inputs: std::vec::IntoIter<Either<String, Vec<u32>>>,
+    data: Vec<Embedding>,
+    prompt_tokens: usize,
+    request: EmbeddingRequest,
+}
+
+impl PendingEmbedding {
+    fn new(mut request: EmbeddingRequest) -> Self {
+        Self {
+            inputs: std::mem::take(&mut request.inputs).into_iter(),
+            data: Vec::new(),
+            prompt_tokens: 0,
+            request,
+        }
+    }
 }
 
 impl Engine {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         rx: Receiver<Request>,
         pipeline: Box<Mutex<dyn Pipeline>>,
         method: SchedulerMethod,
+        queue_policy: QueuePolicy,
         truncate_sequence: bool,
         no_kv_cache: bool,
         prefix_cache_n: usize,
+        paged_cache: Option<PagedCacheConfig>,
+        prefill_chunk_size: Option<usize>,
+        max_request_time: Option<Duration>,
+        speculative: Option<SpeculativeConfig>,
     ) -> Self {
         let device = get_mut_arcmutex!(pipeline).device().clone();
+        let has_adapters = get_mut_arcmutex!(pipeline).has_adapters();
+        let block_engine = match paged_cache {
+            Some(_) if no_kv_cache => {
+                warn!("The paged KV cache has no effect without a KV cache.");
+                None
+            }
+            Some(_) if has_adapters => {
+                warn!("The paged KV cache is not supported for X-LoRA and LoRA models, using the default KV cache.");
+                None
+            }
+            Some(config) => {
+                info!("Using the paged KV cache, prefix caching is disabled.");
+                Some(Arc::new(Mutex::new(BlockEngine::new(config))))
+            }
+            None => None,
+        };
+        let prefill_chunk_size = match prefill_chunk_size {
+            Some(_) if no_kv_cache => {
+                warn!("Chunked prefill needs a KV cache, prompts will be processed in one step.");
+                None
+            }
+            Some(_) if has_adapters => {
+                warn!("Chunked prefill is not supported for X-LoRA and LoRA models, prompts will be processed in one step.");
+                None
+            }
+            other => other,
+        };
+        let speculative = speculative.filter(|speculative| {
+            let draft = get_mut_arcmutex!(speculative.draft);
+            let vocab_size = get_mut_arcmutex!(pipeline).tok_trie().vocab_size();
+            if no_kv_cache || draft.has_no_kv_cache() {
+                warn!("Speculative decoding needs a KV cache, it is disabled.");
+                false
+            } else if block_engine.is_some() {
+                warn!("Speculative decoding is not supported with the paged KV cache, it is disabled.");
+                false
+            } else if has_adapters || draft.has_adapters() {
+                warn!("Speculative decoding is not supported for X-LoRA and LoRA models, it is disabled.");
+                false
+            } else if draft.tok_trie().vocab_size() != vocab_size {
+                warn!("The draft model has a different vocabulary than the model, speculative decoding is disabled.");
+                false
+            } else {
+                true
+            }
+        });
         Self {
             rx,
             pipeline,
-            scheduler: Scheduler::new(method),
+            scheduler: Scheduler::new(method, queue_policy, block_engine.clone(), has_adapters),
             id: 0,
             truncate_sequence,
             no_kv_cache,
             prefix_cacher: PrefixCacheManager::new(device, prefix_cache_n), // TODO(EricLBuehler): not have this hardcoded
+            block_engine,
+            paged_batch: Vec::new(),
+            prefill_chunk_size,
+            max_request_time,
+            speculative,
+            embeddings: VecDeque::new(),
         }
     }
 
     pub fn run(&mut self) {
         'lp: loop {
-            if let Ok(request) = self.rx.try_recv() {
-                self.add_request(request);
+            // Handle all pending requests before scheduling, so that they join this step.
+            loop {
+                match self.rx.try_recv() {
+                    Ok(Request::Normal(request)) => self.add_request(request),
+                    Ok(Request::Abort(id)) => self.scheduler.abort(id),
+                    Ok(Request::Embedding(request)) => {
+                        self.embeddings.push_back(PendingEmbedding::new(request))
+                    }
+                    Ok(Request::Tokenize(request)) => self.tokenize(request),
+                    Ok(Request::Detokenize(request)) => self.detokenize(request),
+                    Ok(Request::Adapter(request)) => self.manage_adapters(request),
+                    // The `MistralRs` is dropped, so no more requests can arrive. The engine stops, which frees the
+                    // pipeline, once the requests it has are finished.
+                    Err(TryRecvError::Disconnected)
+                        if self.scheduler.is_empty() && self.embeddings.is_empty() =>
+                    {
+                        return
+                    }
+                    Err(_) => break,
+                }
             }
+            let expired = self.scheduler.take_expired();
+            let canceled = self.scheduler.take_canceled();
             let mut scheduled = self.scheduler.schedule();
             let mut pipeline = get_mut_arcmutex!(self.pipeline);
+            for mut seq in expired {
+                Self::stop_seq(&mut *pipeline, &mut seq, StopReason::Timeout);
+            }
+            for mut seq in canceled {
+                Self::stop_seq(&mut *pipeline, &mut seq, StopReason::Canceled);
+            }
+            Self::embed_next_input(&mut self.embeddings, &mut *pipeline);
 
-            if scheduled.completion.len() > 0 {
-                // Run the completion seqs
-                if !self.no_kv_cache {
-                    Self::clone_in_cache(&mut *pipeline, &mut scheduled.completion);
-                }
-                let logits = pipeline.forward(&scheduled.completion, false);
-                let logits = handle_pipeline_forward_error!("completion", logits, &mut scheduled.completion, pipeline, 'lp);
+            // Prompt and completion seqs of any length share one forward pass. X-LoRA and LoRA models build their
+            // own attention masks, so they run the completion seqs and the prompt seqs separately. Beams which wait
+            // for the other beams of their search do not run.
+            let seqs = scheduled
+                .completion
+                .iter_mut()
+                .chain(scheduled.prompt.iter_mut())
+                .map(|seq| &mut **seq)
+                .filter(|seq| !seq.has_beam_candidates());
+            let batches = if pipeline.has_adapters() {
+                let (prompt, completion): (Vec<_>, Vec<_>) = seqs.partition(|seq| seq.is_prompt());
+                vec![completion, prompt]
+            } else {
+                vec![seqs.collect::<Vec<_>>()]
+            };
 
-                if !self.no_kv_cache {
-                    Self::clone_out_cache(&mut *pipeline, &mut scheduled.completion);
-                } else {
-                    Self::set_none_cache(&mut *pipeline);
+            for mut batch in batches.into_iter().filter(|batch| !batch.is_empty()) {
+                if let Some(speculative) = &self.speculative {
+                    let drafted =
+                        Self::draft_tokens(speculative, pipeline.get_max_seq_len(), &mut batch);
+                    handle_pipeline_forward_error!("draft", drafted, &mut batch, pipeline, 'lp);
                 }
-
-                let before_sample = Instant::now();
-                Self::sample_seqs(&mut *pipeline, &mut scheduled.completion, logits);
-                let sampling_time = before_sample.elapsed().as_millis();
-                for seq in scheduled.completion.iter_mut() {
-                    seq.total_sampling_time += sampling_time;
+                let is_prompt = batch.iter().all(|seq| seq.is_prompt());
+                let has_cache =
+                    !self.no_kv_cache && batch.iter().any(|seq| seq.input_range().0 > 0);
+                let paged_batch = std::mem::take(&mut self.paged_batch);
+                if !has_cache {
+                    Self::set_none_cache(&mut *pipeline);
+                } else if Self::holds_paged_batch(&*pipeline, &paged_batch, &batch) {
+                    // The model cache already holds the past of the batch, which the forward pass extends.
+                } else if let Some(block_engine) = &self.block_engine {
+                    let gathered = Self::gather_paged_cache(
+                        &mut *pipeline,
+                        &mut batch,
+                        &get_mut_arcmutex!(block_engine),
+                    );
+                    handle_pipeline_forward_error!("paged cache", gathered, &mut batch, pipeline, 'lp);
+                } else {
+                    let cloned = Self::clone_in_cache(&mut *pipeline, &mut batch);
+                    handle_pipeline_forward_error!("cache", cloned, &mut batch, pipeline, 'lp);
                 }
-            }
-
-            if scheduled.prompt.len() > 0 {
-                // Run the prompt seqs
-                Self::set_none_cache(&mut *pipeline);
-                let logits = pipeline.forward(&scheduled.prompt, true);
-                let logits = handle_pipeline_forward_error!("prompt", logits, &mut scheduled.prompt, pipeline, 'lp);
+                // The first input position and the number of rows of logits of each seq.
+                let logits_rows = batch
+                    .iter()
+                    .map(|seq| {
+                        let (start, end) = seq.input_range();
+                        let n_rows = if seq.wants_all_logits() {
+                            end - start
+                        } else {
+                            1
+                        };
+                        (seq.wants_all_logits(), start, n_rows)
+                    })
+                    .collect::<Vec<_>>();
+                let logits = pipeline.forward(&batch, is_prompt);
+                let logits =
+                    handle_pipeline_forward_error!("forward", logits, &mut batch, pipeline, 'lp);
 
-                if !self.no_kv_cache {
-                    Self::clone_out_cache(&mut *pipeline, &mut scheduled.prompt);
+                if let Some(block_engine) = &self.block_engine {
+                    let written = Self::write_paged_cache(
+                        &mut *pipeline,
+                        &mut batch,
+                        &get_mut_arcmutex!(block_engine),
+                    );
+                    handle_pipeline_forward_error!("paged cache", written, &mut batch, pipeline, 'lp);
+                    // The model cache can be extended by the next step if it is not padded between the past and
+                    // the new tokens, which is the case when all seqs have as many new tokens.
+                    let input_lens = batch
+                        .iter()
+                        .map(|seq| {
+                            let (start, end) = seq.input_range();
+                            end - start
+                        })
+                        .collect::<Vec<_>>();
+                    if input_lens.iter().all(|len| *len == input_lens[0]) {
+                        self.paged_batch = batch
+                            .iter()
+                            .map(|seq| (*seq.id(), seq.input_range().1))
+                            .collect();
+                    } else {
+                        Self::set_none_cache(&mut *pipeline);
+                    }
+                } else if !self.no_kv_cache {
+                    let cloned = Self::clone_out_cache(&mut *pipeline, &mut batch);
+                    handle_pipeline_forward_error!("cache", cloned, &mut batch, pipeline, 'lp);
                 } else {
                     Self::set_none_cache(&mut *pipeline);
                 }
 
-                for seq in scheduled.prompt.iter_mut() {
+                // A prompt seq starts its completion once its last chunk is prefilled.
+                let was_prompt = batch.iter().map(|seq| seq.is_prompt()).collect::<Vec<_>>();
+                for (seq, was_prompt) in zip(batch.iter_mut(), &was_prompt) {
+                    if !*was_prompt || !seq.advance_prompt_chunk() {
+                        continue;
+                    }
                     seq.set_state(SequenceState::RunningCompletion);
                     let now = SystemTime::now()
                         .duration_since(UNIX_EPOCH)
@@ -118,83 +309,334 @@ impl Engine {
                     seq.prompt_timestamp = Some(now);
                 }
 
-                for seq in scheduled
-                    .prompt
-                    .iter_mut()
-                    .take(self.prefix_cacher.n_on_device)
-                {
-                    self.prefix_cacher.add_sequence(seq);
+                // The prefix cacher stores per-sequence caches, which do not exist with the paged cache. The caches
+                // of seqs which apply LoRA adapters are not shared, as they depend on the adapters.
+                if self.block_engine.is_none() && was_prompt.contains(&true) {
+                    for (seq, _) in zip(batch.iter_mut(), &was_prompt)
+                        .filter(|(seq, was_prompt)| {
+                            **was_prompt && seq.is_completion() && seq.adapters().is_empty()
+                        })
+                        .take(self.prefix_cacher.n_on_device)
+                    {
+                        self.prefix_cacher.add_sequence(seq);
+                    }
+                    // Evict all the other seqs
+                    handle_pipeline_forward_error!("evict", self.prefix_cacher.evict_to_cpu(), &mut batch, pipeline, 'lp);
+                }
+
+                // A seq which wants the logits of all of its input positions has one row of logits per position,
+                // and the last one is sampled, unless the seq verifies drafted tokens with all of them. Prompt seqs
+                // which are not fully prefilled yet are not sampled, and neither are seqs with nothing to
+                // generate, like scored prompts.
+                let mut ready = Vec::new();
+                let mut ready_logits = Vec::new();
+                let mut row = 0;
+                for (seq, (wants_all_logits, start, n_rows)) in zip(batch, logits_rows) {
+                    // NOTE(EricLBuehler): Unwrap reasoning: There are `n_rows` rows of logits per sequence.
+                    let seq_logits = logits.narrow(0, row, n_rows).unwrap();
+                    row += n_rows;
+                    if wants_all_logits {
+                        Self::add_prompt_logprobs(seq, start, &seq_logits);
+                    }
+                    if seq.is_completion() && seq.max_len() == Some(0) {
+                        Self::stop_seq(&mut *pipeline, seq, StopReason::Length(0));
+                    } else if seq.is_completion() && seq.has_drafts() {
+                        ready.push(seq);
+                        ready_logits.push(seq_logits);
+                    } else if seq.is_completion() {
+                        ready.push(seq);
+                        ready_logits.push(seq_logits.narrow(0, n_rows - 1, 1).unwrap());
+                    }
+                }
+                if ready.is_empty() {
+                    continue;
                 }
-                // Evict all the other seqs
-                handle_pipeline_forward_error!("evict", self.prefix_cacher.evict_to_cpu(), &mut scheduled.prompt, pipeline, 'lp);
 
                 let before_sample = Instant::now();
-                Self::sample_seqs(&mut *pipeline, &mut scheduled.prompt, logits);
+                Self::sample_seqs(&mut *pipeline, &mut ready, ready_logits);
                 let sampling_time = before_sample.elapsed().as_millis();
-                for seq in scheduled.prompt.iter_mut() {
+                for seq in ready.iter_mut() {
                     seq.total_sampling_time += sampling_time;
                 }
             }
+            drop(pipeline);
+            self.step_beams();
         }
     }
 
-    fn sample_seqs(pipeline: &mut dyn Pipeline, seqs: &mut [&mut Sequence], logits: Tensor) {
-        let seqs_len = seqs.len();
-        let logits_seq = logits.chunk(seqs_len, 0).unwrap();
-        debug_assert_eq!(logits_seq.len(), seqs_len);
-        let eos_tok = pipeline.eos_tok();
-        for (logits_per_seq, seq) in zip(logits_seq, seqs.iter_mut()) {
+    /// Add the logprobs of the prompt tokens from the logits of all of the input positions of a prompt step,
+    /// which starts at position `start`. Each position predicts the token after it, and the last prompt token
+    /// predicts the first completion token, which is sampled instead.
+    fn add_prompt_logprobs(seq: &mut Sequence, start: usize, logits: &Tensor) {
+        let end = (start + logits.dims()[0]).min(seq.prompt_tokens() - 1);
+        if end <= start {
+            return;
+        }
+        let toks = seq.get_toks()[start + 1..end + 1].to_vec();
+        let logits = logits
+            .narrow(0, 0, end - start)
+            .and_then(|logits| logits.flatten_from(1));
+        let logits = handle_seq_error_stateaware!(logits, seq);
+        let logprobs = seq.sampler().score_tokens(&logits, &toks);
+        let logprobs = handle_seq_error_stateaware!(logprobs, seq);
+        seq.add_prompt_logprobs(logprobs);
+    }
+
+    /// Sample the next tokens of a batch. The seqs whose samplers are batchable are sampled together on the
+    /// device, and the others one at a time.
+    fn sample_seqs(pipeline: &mut dyn Pipeline, seqs: &mut [&mut Sequence], logits: Vec<Tensor>) {
+        debug_assert_eq!(logits.len(), seqs.len());
+        let mut batch_seqs = Vec::new();
+        let mut batch_logits = Vec::new();
+        for (logits_per_seq, seq) in zip(logits, seqs.iter_mut()) {
+            if seq.is_beam() {
+                Self::add_beam_candidates(pipeline, seq, logits_per_seq);
+                continue;
+            }
+            let drafts = seq.take_drafts();
+            if !drafts.is_empty() {
+                Self::verify_drafts(pipeline, seq, drafts, logits_per_seq);
+                continue;
+            }
+            if seq.sampler().is_batchable(logits_per_seq.elem_count()) {
+                batch_seqs.push(&mut **seq);
+                batch_logits.push(logits_per_seq);
+                continue;
+            }
             // Sample and extract next token
             let return_logprobs = seq.return_logprobs();
             let sampled = pipeline.sample(logits_per_seq, seq, return_logprobs);
             let next_token = handle_seq_error_stateaware!(sampled, seq);
-            let next_token_id = next_token.token;
+            Self::add_sampled_token(pipeline, seq, next_token);
+        }
+        if !batch_seqs.is_empty() {
+            Self::sample_batch(pipeline, &mut batch_seqs, batch_logits);
+        }
+    }
 
-            seq.add_token(
-                next_token.clone(),
-                pipeline.tok_trie().decode(&[next_token_id]),
-            );
-            let is_done = seq.is_done(next_token_id, eos_tok, pipeline.get_max_seq_len());
-            // Handle streaming requests
-            if seq.get_mut_group().is_streaming && seq.get_mut_group().is_chat {
-                let tokenizer = pipeline.tokenizer();
-                if let Some(delta) = handle_seq_error!(seq.get_delta(&tokenizer), seq.responder()) {
-                    seq.add_streaming_chunk_choice_to_group(ChunkChoice {
-                        delta: Delta {
-                            content: delta.clone(),
-                            role: "assistant".to_string(),
-                        },
-                        index: seq.get_response_index(),
-                        stopreason: is_done.map(|x| x.to_string()),
-                        logprobs: if seq.return_logprobs() {
-                            Some(ResponseLogprob {
-                                token: delta,
-                                bytes: next_token.bytes.clone().into_bytes(),
-                                logprob: next_token.logprob,
-                                top_logprobs: next_token.top_logprobs.unwrap().clone(),
-                            })
-                        } else {
-                            None
-                        },
-                    });
+    fn sample_batch(pipeline: &mut dyn Pipeline, seqs: &mut [&mut Sequence], logits: Vec<Tensor>) {
+        let sampled = logits
+            .iter()
+            .map(|logits| logits.flatten_all())
+            .collect::<candle_core::Result<Vec<_>>>()
+            .and_then(|logits| Tensor::stack(&logits, 0))
+            .map_err(anyhow::Error::from)
+            .and_then(|logits| pipeline.sample_batch(logits, seqs));
+        let next_tokens = match sampled {
+            Ok(next_tokens) => next_tokens,
+            Err(e) => {
+                for seq in seqs.iter_mut() {
+                    // NOTE(EricLBuehler): Unwrap reasoning: The receiver should really be there, otherwise it is their fault.
+                    seq.responder()
+                        .send(Response::InternalError(e.to_string().into()))
+                        .unwrap();
+                    seq.set_state(SequenceState::Error);
+                }
+                return;
+            }
+        };
+        for (seq, next_token) in zip(seqs.iter_mut(), next_tokens) {
+            Self::add_sampled_token(pipeline, seq, next_token);
+        }
+    }
 
-                    if let Some(reason) = is_done {
-                        seq.set_state(SequenceState::Done(reason));
-                    }
+    /// Add a sampled token to a seq, and stream it or finish the seq if it is done.
+    fn add_sampled_token(
+        pipeline: &mut dyn Pipeline,
+        seq: &mut Sequence,
+        next_token: sampler::Logprobs,
+    ) {
+        let eos_tok = pipeline.eos_tok();
+        let next_token_id = next_token.token;
+
+        seq.add_token(
+            next_token.clone(),
+            pipeline.tok_trie().decode(&[next_token_id]),
+        );
+        let is_done = seq.is_done(next_token_id, eos_tok, pipeline.get_max_seq_len());
+        // Handle streaming requests
+        if seq.get_mut_group().is_streaming && seq.get_mut_group().is_chat {
+            let tokenizer = pipeline.tokenizer();
+            if let Some(delta) = handle_seq_error!(seq.get_delta(&tokenizer), seq.responder()) {
+                let released = Self::release_tool_call_output(
+                    &tokenizer,
+                    seq,
+                    delta.clone(),
+                    is_done.is_some(),
+                );
+                let Some((content, tool_calls)) = handle_seq_error!(released, seq.responder())
+                else {
+                    return;
+                };
+                let content = seq.release_stop_string_text(content, is_done.is_some());
+                let stopreason = match tool_calls {
+                    Some(_) => Some("tool_calls".to_string()),
+                    None => is_done.map(|x| x.to_string()),
+                };
+                seq.add_streaming_chunk_choice_to_group(ChunkChoice {
+                    delta: Delta {
+                        content,
+                        role: "assistant".to_string(),
+                        tool_calls,
+                    },
+                    index: seq.get_response_index(),
+                    stopreason,
+                    logprobs: if seq.return_logprobs() {
+                        Some(ResponseLogprob {
+                            token: delta,
+                            bytes: next_token.bytes.clone().into_bytes(),
+                            logprob: next_token.logprob,
+                            top_logprobs: next_token.top_logprobs.unwrap().clone(),
+                        })
+                    } else {
+                        None
+                    },
+                });
 
-                    seq.get_mut_group()
-                        .maybe_send_streaming_response(seq, pipeline.name());
+                if let Some(reason) = is_done {
+                    seq.set_state(SequenceState::Done(reason));
                 }
-            } else if let Some(reason) = is_done {
-                Self::finish_seq(pipeline, seq, reason);
-                pipeline.reset_non_granular_state();
+
+                seq.get_mut_group()
+                    .maybe_send_streaming_response(seq, pipeline.name());
             }
+        } else if seq.get_mut_group().is_streaming {
+            let tokenizer = pipeline.tokenizer();
+            let delta = handle_seq_error!(seq.get_delta(&tokenizer), seq.responder());
+            // Without a delta, the new token is only part of a character, unless the seq is done.
+            if delta.is_some() || is_done.is_some() {
+                Self::add_completion_chunk(pipeline, seq, delta.unwrap_or_default(), is_done);
+            }
+        } else if let Some(reason) = is_done {
+            Self::finish_seq(pipeline, seq, reason);
+            pipeline.reset_non_granular_state();
+        }
+    }
+
+    /// The streamed output of a seq with tools is held back while it may be a tool call. Returns the content
+    /// and tool calls to stream, or `None` while the output is held back.
+    fn release_tool_call_output(
+        tokenizer: &Tokenizer,
+        seq: &mut Sequence,
+        delta: String,
+        is_done: bool,
+    ) -> Result<Option<(String, Option<Vec<ToolCall>>)>, tokenizers::Error> {
+        if !seq.is_tool_call_pending() {
+            return Ok(Some((delta, None)));
+        }
+        let output = tokenizer.decode(&seq.get_toks()[seq.prompt_tokens()..], false)?;
+        if !is_done && tools::may_be_tool_call(&output) {
+            return Ok(None);
+        }
+        seq.set_tool_call_pending(false);
+        let tool_calls = if is_done {
+            tools::parse_tool_calls(&output, &format!("call-{}", seq.id()))
+        } else {
+            None
+        };
+        match tool_calls {
+            Some(tool_calls) => Ok(Some((String::new(), Some(tool_calls)))),
+            None => Ok(Some((output, None))),
+        }
+    }
+
+    /// Add the text of a streaming completion seq to the next chunk, and finish the seq if it is done.
+    /// The first chunk of an echoed completion starts with the prompt.
+    fn add_completion_chunk(
+        pipeline: &mut dyn Pipeline,
+        seq: &mut Sequence,
+        text: String,
+        is_done: Option<StopReason>,
+    ) {
+        let (streamed_logprobs, streamed_text_len) = seq.streamed();
+        let echo = seq.prefix.is_some() && streamed_text_len == 0;
+        // Text held back for a stop string still counts for the offsets of the logprobs.
+        let mut text_len = text.chars().count();
+        let text = seq.release_stop_string_text(text, is_done.is_some());
+        let text = match &seq.prefix {
+            Some(prefix) if echo => {
+                text_len += prefix.chars().count();
+                format!("{prefix}{text}")
+            }
+            _ => text,
+        };
+        let logprobs = if seq.return_logprobs() {
+            Some(handle_seq_error!(
+                completion_logprobs(
+                    &pipeline.tokenizer(),
+                    seq,
+                    echo,
+                    streamed_logprobs,
+                    streamed_text_len
+                ),
+                seq.responder()
+            ))
+        } else {
+            None
+        };
+        seq.set_streamed(seq.logprobs().len(), streamed_text_len + text_len);
+        seq.add_streaming_completion_chunk_choice_to_group(CompletionChunkChoice {
+            stopreason: is_done.map(|x| x.to_string()),
+            index: seq.get_response_index(),
+            text,
+            logprobs,
+        });
+        if let Some(reason) = is_done {
+            seq.set_state(SequenceState::Done(reason));
+            seq.add_streaming_completion_done_to_group();
+        }
+        seq.get_mut_group()
+            .maybe_send_completion_streaming_response(seq, pipeline.name());
+    }
+
+    /// Finish a sequence without sampling a token, responding with the output generated so far. This is used
+    /// for sequences which overran their deadline or which have nothing to generate.
+    fn stop_seq(pipeline: &mut dyn Pipeline, seq: &mut Sequence, reason: StopReason) {
+        info!("Sequence {} was stopped early: {reason:?}.", seq.id());
+        if seq.get_mut_group().is_streaming && seq.get_mut_group().is_chat {
+            seq.set_state(SequenceState::Done(reason));
+            let content = seq.release_stop_string_text(String::new(), true);
+            seq.add_streaming_chunk_choice_to_group(ChunkChoice {
+                delta: Delta {
+                    content,
+                    role: "assistant".to_string(),
+                    tool_calls: None,
+                },
+                index: seq.get_response_index(),
+                stopreason: Some(reason.to_string()),
+                logprobs: None,
+            });
+            seq.get_mut_group()
+                .maybe_send_streaming_response(seq, pipeline.name());
+        } else if seq.get_mut_group().is_streaming {
+            Self::add_completion_chunk(pipeline, seq, String::new(), Some(reason));
+        } else {
+            Self::finish_seq(pipeline, seq, reason);
+            pipeline.reset_non_granular_state();
         }
     }
 
-    fn finish_seq(pipeline: &mut dyn Pipeline, seq: &Sequence, reason: StopReason) {
+    fn finish_seq(pipeline: &mut dyn Pipeline, seq: &mut Sequence, reason: StopReason) {
         seq.set_state(SequenceState::Done(reason));
 
+        // Like OpenAI, the output ends before the stop string, and so do its logprobs.
+        let truncated = match reason {
+            StopReason::StopString(_) => handle_seq_error!(
+                seq.truncate_at_stop_string(&pipeline.tokenizer(), pipeline.tok_trie()),
+                seq.responder()
+            ),
+            _ => None,
+        };
+        let res = match truncated {
+            Some(res) => res,
+            None => handle_seq_error!(
+                pipeline
+                    .tokenizer()
+                    .decode(&seq.get_toks()[seq.prompt_tokens()..], false),
+                seq.responder()
+            ),
+        };
+
         let logprobs = if seq.return_logprobs() {
             let tokenizer = pipeline.tokenizer().clone();
             let mut logprobs = Vec::new();
@@ -215,30 +657,45 @@ impl Engine {
             None
         };
 
-        let res = handle_seq_error!(
-            pipeline
-                .tokenizer()
-                .decode(&seq.get_toks()[seq.prompt_tokens()..], false),
-            seq.responder()
-        );
-
         if seq.get_mut_group().is_chat {
+            let tool_calls = if seq.get_mut_group().has_tools {
+                tools::parse_tool_calls(&res, &format!("call-{}", seq.id()))
+            } else {
+                None
+            };
+            let (content, stopreason) = match tool_calls {
+                Some(_) => (String::new(), "tool_calls".to_string()),
+                None => (res, reason.to_string()),
+            };
             let choice = Choice {
-                stopreason: reason.to_string(),
+                stopreason,
                 index: seq.get_response_index(),
                 message: ResponseMessage {
-                    content: res,
+                    content,
                     role: "assistant".to_string(),
+                    tool_calls,
                 },
                 logprobs: logprobs.map(|l| Logprobs { content: Some(l) }),
             };
             seq.add_choice_to_group(choice);
         } else {
+            let logprobs = if seq.return_logprobs() {
+                Some(handle_seq_error!(
+                    completion_logprobs(&pipeline.tokenizer(), seq, seq.prefix.is_some(), 0, 0),
+                    seq.responder()
+                ))
+            } else {
+                None
+            };
+            let text = match &seq.prefix {
+                Some(prefix) => format!("{prefix}{res}"),
+                None => res,
+            };
             let choice = CompletionChoice {
                 stopreason: reason.to_string(),
                 index: seq.get_response_index(),
-                text: res,
-                logprobs: None,
+                text,
+                logprobs,
             };
             seq.add_completion_choice_to_group(choice);
         }
@@ -251,7 +708,7 @@ impl Engine {
                     choices: group.get_choices().to_vec(),
                     created: seq.creation_time(),
                     model: pipeline.name(),
-                    system_fingerprint: SYSTEM_FINGERPRINT.to_string(),
+                    system_fingerprint: group.system_fingerprint.clone(),
                     object: "chat.completion".to_string(),
                     usage: group.get_usage(),
                 },
@@ -264,7 +721,7 @@ impl Engine {
                     choices: group.get_completion_choices().to_vec(),
                     created: seq.creation_time(),
                     model: pipeline.name(),
-                    system_fingerprint: SYSTEM_FINGERPRINT.to_string(),
+                    system_fingerprint: group.system_fingerprint.clone(),
                     object: "text_completion".to_string(),
                     usage: group.get_usage(),
                 },
@@ -273,60 +730,22 @@ impl Engine {
         }
     }
 
-    /// Clone the cache FROM the sequences' cache TO the model cache. Only used for completion seqs.
-    fn clone_in_cache(pipeline: &mut dyn Pipeline, seqs: &mut [&mut Sequence]) {
+    /// Clone the cache FROM the sequences' cache TO the model cache. Used when a seq of the batch has
+    /// cached tokens.
+    fn clone_in_cache(
+        pipeline: &mut dyn Pipeline,
+        seqs: &mut [&mut Sequence],
+    ) -> candle_core::Result<()> {
         let mut new_cache = Vec::new();
         for layer in 0..pipeline.num_hidden_layers() {
-            let mut k_vec = Vec::new();
-            let mut v_vec = Vec::new();
-            for seq in &mut *seqs {
-                let seq_cache = &*seq.cache();
-                let cache = seq_cache.get(layer).unwrap();
-                // Note(EricLBuehler): Unwrap reasoning: We are handling completions seqs so unwrap is OK.
-                let cache = cache.as_ref().unwrap();
-                k_vec.push(cache.0.clone());
-                v_vec.push(cache.1.clone());
-            }
-            // NOTE(EricLBuehler): Unwrap reasoning: We have the correct dims
-            new_cache.push(Some((
-                if k_vec.len() > 1 {
-                    Tensor::cat(&k_vec, 0).unwrap()
-                } else {
-                    k_vec[0].clone()
-                },
-                if v_vec.len() > 1 {
-                    Tensor::cat(&v_vec, 0).unwrap()
-                } else {
-                    v_vec[0].clone()
-                },
-            )));
+            let caches = seqs.iter_mut().map(|seq| seq.cache()[layer].clone());
+            new_cache.push(cat_padded_caches(caches.collect())?);
         }
         if pipeline.is_xlora() && !pipeline.has_no_kv_cache() {
             let mut new_cache = Vec::new();
             for layer in 0..pipeline.num_hidden_layers() {
-                let mut k_vec = Vec::new();
-                let mut v_vec = Vec::new();
-                for seq in &mut *seqs {
-                    let seq_cache = &*seq.xlora_cache();
-                    let cache = seq_cache.get(layer).unwrap();
-                    // Note(EricLBuehler): Unwrap reasoning: We are handling completions seqs so unwrap is OK.
-                    let cache = cache.as_ref().unwrap();
-                    k_vec.push(cache.0.clone());
-                    v_vec.push(cache.1.clone());
-                }
-                // NOTE(EricLBuehler): Unwrap reasoning: We have the correct dims
-                new_cache.push(Some((
-                    if k_vec.len() > 1 {
-                        Tensor::cat(&k_vec, 0).unwrap()
-                    } else {
-                        k_vec[0].clone()
-                    },
-                    if v_vec.len() > 1 {
-                        Tensor::cat(&v_vec, 0).unwrap()
-                    } else {
-                        v_vec[0].clone()
-                    },
-                )));
+                let caches = seqs.iter_mut().map(|seq| seq.xlora_cache()[layer].clone());
+                new_cache.push(cat_padded_caches(caches.collect())?);
             }
             *pipeline.cache().xlora_lock() = new_cache;
         }
@@ -334,6 +753,113 @@ impl Engine {
             *pipeline.cache().get_scalings_cache() = seqs[0].scaling_cache().clone();
         }
         *pipeline.cache().lock() = new_cache;
+        Ok(())
+    }
+
+    /// Whether the model cache holds the KV of the previous step of the same seqs, in the same order, such that
+    /// each seq continues after the tokens which it holds.
+    fn holds_paged_batch(
+        pipeline: &dyn Pipeline,
+        paged_batch: &[(usize, usize)],
+        batch: &[&mut Sequence],
+    ) -> bool {
+        paged_batch.len() == batch.len()
+            && zip(paged_batch, batch)
+                .all(|((id, end), seq)| id == seq.id() && *end == seq.input_range().0)
+            && pipeline
+                .cache()
+                .lock()
+                .first()
+                .is_some_and(|layer| layer.is_some())
+    }
+
+    /// Gather the KV cache FROM the paged cache TO the model cache. Used when a seq of the batch has
+    /// cached tokens, unless the model cache holds them from the previous step.
+    ///
+    /// Sequences with shorter contexts are padded on the left by repeating their first cached position,
+    /// which the attention mask hides.
+    fn gather_paged_cache(
+        pipeline: &mut dyn Pipeline,
+        seqs: &mut [&mut Sequence],
+        block_engine: &BlockEngine,
+    ) -> candle_core::Result<()> {
+        let max_cached = seqs.iter().map(|seq| seq.input_range().0).max().unwrap();
+        let mut slots = Vec::new();
+        for seq in seqs.iter() {
+            let seq_slots = block_engine.slot_mapping(*seq.id(), 0, seq.input_range().0);
+            let first = seq_slots.first().copied().unwrap_or(0);
+            slots.extend(repeat(first).take(max_cached - seq_slots.len()));
+            slots.extend(seq_slots);
+        }
+        let slots_len = slots.len();
+        let slots = Tensor::from_vec(slots, slots_len, pipeline.device())?;
+
+        let num_hidden_layers = pipeline.num_hidden_layers();
+        let paged_cache = pipeline.cache().paged_lock();
+        let Some(paged_cache) = paged_cache.as_ref() else {
+            candle_core::bail!("The paged cache is allocated by the first prompt step.");
+        };
+        let mut new_cache = Vec::new();
+        for layer in 0..num_hidden_layers {
+            new_cache.push(Some(paged_cache.gather(layer, &slots, seqs.len())?));
+        }
+        *pipeline.cache().lock() = new_cache;
+        Ok(())
+    }
+
+    /// Write the new entries FROM the model cache TO the paged cache. Used for prompt, completion seqs.
+    ///
+    /// The paged cache is allocated on the first call, using the shapes of the model cache.
+    fn write_paged_cache(
+        pipeline: &mut dyn Pipeline,
+        seqs: &mut [&mut Sequence],
+        block_engine: &BlockEngine,
+    ) -> candle_core::Result<()> {
+        let num_hidden_layers = pipeline.num_hidden_layers();
+        let cache = pipeline.cache().lock().clone();
+        let mut paged_cache = pipeline.cache().paged_lock();
+        if paged_cache.is_none() {
+            // NOTE(EricLBuehler): Unwrap reasoning: The forward pass has filled the model cache.
+            let (k, _) = cache[0].as_ref().unwrap();
+            let (_, num_kv_heads, _, head_dim) = k.dims4()?;
+            *paged_cache = Some(PagedKvCache::new(
+                num_hidden_layers,
+                block_engine.num_total_blocks() * block_engine.block_size(),
+                num_kv_heads,
+                head_dim,
+                k.dtype(),
+                k.device(),
+            )?);
+        }
+        // NOTE(EricLBuehler): Unwrap reasoning: It was allocated above.
+        let paged_cache = paged_cache.as_ref().unwrap();
+
+        let max_len = seqs
+            .iter()
+            .map(|seq| {
+                let (start, end) = seq.input_range();
+                end - start
+            })
+            .max()
+            .unwrap_or(0);
+        for (seq_i, seq) in seqs.iter().enumerate() {
+            let (start, end) = seq.input_range();
+            let slots = block_engine.slot_mapping(*seq.id(), start, end);
+            for (layer, layer_cache) in cache.iter().enumerate() {
+                // NOTE(EricLBuehler): Unwrap reasoning: The forward pass has filled the model cache.
+                let (k, v) = layer_cache.as_ref().unwrap();
+                let (k, v) = (k.get(seq_i)?, v.get(seq_i)?);
+                // The new tokens come after the padded past of the batch, and are padded on the right.
+                let offset = k.dim(1)? - max_len;
+                paged_cache.write(
+                    layer,
+                    &k.narrow(1, offset, slots.len())?,
+                    &v.narrow(1, offset, slots.len())?,
+                    &slots,
+                )?;
+            }
+        }
+        Ok(())
     }
 
     /// Set the model cache to all None. Only used for prompt seqs.
@@ -349,51 +875,32 @@ impl Engine {
     }
 
     /// Clone the cache FROM the model cache TO the sequences. Used for prompt, completion seqs.
-    fn clone_out_cache(pipeline: &mut dyn Pipeline, seqs: &mut [&mut Sequence]) {
+    fn clone_out_cache(
+        pipeline: &mut dyn Pipeline,
+        seqs: &mut [&mut Sequence],
+    ) -> candle_core::Result<()> {
+        let ranges = seqs.iter().map(|seq| seq.input_range()).collect::<Vec<_>>();
         let num_hidden_layers = pipeline.num_hidden_layers();
         for layer in 0..num_hidden_layers {
-            let cache = pipeline.cache().lock();
-            let cache = cache.get(layer).unwrap();
-            let k_cache = cache.as_ref().unwrap().0.clone();
-            let v_cache = cache.as_ref().unwrap().1.clone();
-
-            let k_caches = k_cache.chunk(seqs.len(), 0).unwrap();
-            debug_assert_eq!(k_caches.len(), seqs.len());
-            let v_caches = v_cache.chunk(seqs.len(), 0).unwrap();
-            debug_assert_eq!(v_caches.len(), seqs.len());
-
-            for (seq_i, seq) in seqs.iter_mut().enumerate() {
-                let seq_cache = seq.cache();
-                let seq_cache = seq_cache.get_mut(layer).unwrap();
-                *seq_cache = Some((
-                    k_caches.get(seq_i).unwrap().clone(),
-                    v_caches.get(seq_i).unwrap().clone(),
-                ));
+            let cache = pipeline.cache().lock()[layer].clone();
+            // NOTE(EricLBuehler): Unwrap reasoning: The forward pass has filled the model cache.
+            let caches = split_padded_cache(&cache.unwrap(), &ranges)?;
+            for (seq, cache) in zip(seqs.iter_mut(), caches) {
+                seq.cache()[layer] = Some(cache);
             }
             if pipeline.is_xlora() && !pipeline.has_no_kv_cache() {
-                let cache = pipeline.cache().xlora_lock();
-                let cache = cache.get(layer).unwrap();
-                let k_cache = cache.as_ref().unwrap().0.clone();
-                let v_cache = cache.as_ref().unwrap().1.clone();
-
-                let k_caches = k_cache.chunk(seqs.len(), 0).unwrap();
-                debug_assert_eq!(k_caches.len(), seqs.len());
-                let v_caches = v_cache.chunk(seqs.len(), 0).unwrap();
-                debug_assert_eq!(v_caches.len(), seqs.len());
-
-                for (seq_i, seq) in seqs.iter_mut().enumerate() {
-                    let seq_cache = seq.xlora_cache();
-                    let seq_cache = seq_cache.get_mut(layer).unwrap();
-                    *seq_cache = Some((
-                        k_caches.get(seq_i).unwrap().clone(),
-                        v_caches.get(seq_i).unwrap().clone(),
-                    ));
+                let cache = pipeline.cache().xlora_lock()[layer].clone();
+                // NOTE(EricLBuehler): Unwrap reasoning: The forward pass has filled the model cache.
+                let caches = split_padded_cache(&cache.unwrap(), &ranges)?;
+                for (seq, cache) in zip(seqs.iter_mut(), caches) {
+                    seq.xlora_cache()[layer] = Some(cache);
                 }
             }
-            if pipeline.is_xlora() {
-                *seqs[0].scaling_cache() = pipeline.cache().get_scalings_cache().clone();
-            }
         }
+        if pipeline.is_xlora() {
+            *seqs[0].scaling_cache() = pipeline.cache().get_scalings_cache().clone();
+        }
+        Ok(())
     }
 
     fn build_sequence_recognizer(constraint: &Constraint) -> anyhow::Result<SequenceRecognizer> {
@@ -402,12 +909,212 @@ impl Engine {
                 SequenceRecognizer::Regex(StackRecognizer::from(RecRx::from_rx(rx)?).into())
             }
             Constraint::Yacc(cfg) => SequenceRecognizer::Cfg(CfgParser::from_yacc(cfg)?.into()),
+            Constraint::JsonSchema(schema) => SequenceRecognizer::Cfg(
+                CfgParser::from_yacc(&grammars::json_schema_to_yacc(schema)?)?.into(),
+            ),
+            Constraint::Gbnf(gbnf) => SequenceRecognizer::Cfg(
+                CfgParser::from_yacc_with_viable_tokens(&grammars::gbnf_to_yacc(gbnf)?)?.into(),
+            ),
+            Constraint::Lark(lark) => SequenceRecognizer::Cfg(
+                CfgParser::from_yacc_with_viable_tokens(&grammars::lark_to_yacc(lark)?)?.into(),
+            ),
             Constraint::None => SequenceRecognizer::None,
         };
         Ok(recognizer)
     }
 
-    fn add_request(&mut self, request: Request) {
+    /// Embed the next input of the oldest embedding request, and respond once all of its inputs are embedded. Only
+    /// one input is embedded per step, so that embedding requests do not hold up the scheduled sequences. The
+    /// model's KV cache is replaced by the prefill of the input, which is fine between the forward passes because
+    /// each one sets the cache of its own batch.
+    fn embed_next_input(embeddings: &mut VecDeque<PendingEmbedding>, pipeline: &mut dyn Pipeline) {
+        let Some(mut pending) = embeddings.pop_front() else {
+            return;
+        };
+        if let Some(input) = pending.inputs.next() {
+            let request = &pending.request;
+            let index = pending.data.len();
+            let toks = match input {
+                Either::Left(text) => {
+                    handle_seq_error!(pipeline.tokenize_prompt(&text), request.response)
+                }
+                Either::Right(toks) => toks,
+            };
+            let vocab_size = pipeline.tok_trie().vocab_size();
+            let invalid = if toks.is_empty() {
+                Some(format!("Input {index} is empty."))
+            } else if toks.len() > pipeline.get_max_seq_len() {
+                Some(format!(
+                    "Input {index} has {} tokens, which is more than the maximum of {}.",
+                    toks.len(),
+                    pipeline.get_max_seq_len()
+                ))
+            } else {
+                toks.iter()
+                    .find(|tok| **tok as usize >= vocab_size)
+                    .map(|tok| {
+                        format!(
+                            "Input {index} has the token {tok}, which is not in the vocabulary."
+                        )
+                    })
+            };
+            if let Some(invalid) = invalid {
+                // NOTE(EricLBuehler): Unwrap reasoning: The receiver should really be there, otherwise it is their fault.
+                request
+                    .response
+                    .send(Response::ValidationError(invalid.into()))
+                    .unwrap();
+                return;
+            }
+
+            Self::set_none_cache(pipeline);
+            let embedding = pipeline.hidden_states(&toks).and_then(|hidden_states| {
+                Self::pool_hidden_states(
+                    &hidden_states,
+                    request.pooling,
+                    request.dimensions,
+                    request.normalize,
+                )
+            });
+            Self::set_none_cache(pipeline);
+            let embedding = handle_seq_error!(embedding, request.response);
+            pending.prompt_tokens += toks.len();
+            pending.data.push(Embedding {
+                object: "embedding".to_string(),
+                embedding,
+                index,
+            });
+        }
+        if !pending.inputs.as_slice().is_empty() {
+            embeddings.push_front(pending);
+            return;
+        }
+        // NOTE(EricLBuehler): Unwrap reasoning: The receiver should really be there, otherwise it is their fault.
+        pending
+            .request
+            .response
+            .send(Response::Embeddings(EmbeddingResponse {
+                object: "list".to_string(),
+                data: pending.data,
+                model: pipeline.name(),
+                usage: EmbeddingUsage {
+                    prompt_tokens: pending.prompt_tokens,
+                    total_tokens: pending.prompt_tokens,
+                },
+            }))
+            .unwrap();
+    }
+
+    /// Respond to a tokenization request, which is tokenized like the prompt of a request with the same text.
+    fn tokenize(&mut self, request: TokenizationRequest) {
+        let pipeline = get_mut_arcmutex!(self.pipeline);
+        let prompt = match request.text {
+            Either::Left(_) if !pipeline.get_chat_template().has_chat_template() => {
+                Err(anyhow::Error::msg(
+                    "Received messages for a model which does not have a chat template.",
+                ))
+            }
+            Either::Left(messages) => {
+                pipeline.apply_chat_template(messages, request.add_generation_prompt, None)
+            }
+            Either::Right(prompt) => Ok(prompt),
+        };
+        let tokenizer = pipeline.tokenizer();
+        let response = prompt
+            .and_then(|prompt| pipeline.tokenize_prompt(&prompt))
+            .map(|tokens| TokenizationResponse {
+                token_strs: tokens
+                    .iter()
+                    .map(|tok| tokenizer.id_to_token(*tok).unwrap_or_default())
+                    .collect(),
+                count: tokens.len(),
+                tokens,
+            });
+        // NOTE(EricLBuehler): Unwrap reasoning: The receiver should really be there, otherwise it is their fault.
+        request.response.send(response).unwrap();
+    }
+
+    fn detokenize(&mut self, request: DetokenizationRequest) {
+        let tokenizer = get_mut_arcmutex!(self.pipeline).tokenizer();
+        let vocab_size = tokenizer.get_vocab_size(true);
+        let response = match request
+            .tokens
+            .iter()
+            .find(|tok| **tok as usize >= vocab_size)
+        {
+            Some(tok) => Err(anyhow::Error::msg(format!(
+                "The token {tok} is not in the vocabulary."
+            ))),
+            None => tokenizer
+                .decode(&request.tokens, request.skip_special_tokens)
+                .map(|text| DetokenizationResponse { text })
+                .map_err(anyhow::Error::msg),
+        };
+        // NOTE(EricLBuehler): Unwrap reasoning: The receiver should really be there, otherwise it is their fault.
+        request.response.send(response).unwrap();
+    }
+
+    fn manage_adapters(&mut self, request: AdapterRequest) {
+        let mut pipeline = get_mut_arcmutex!(self.pipeline);
+        let result = match request.action {
+            AdapterAction::Load { name, path } => pipeline.load_lora_adapter(name, &path),
+            // The scalings of a batch are built from the loaded adapters, so an adapter which a sequence
+            // applies must stay loaded until the sequence is done.
+            AdapterAction::Unload { name } => {
+                if self
+                    .scheduler
+                    .seqs()
+                    .any(|seq| seq.adapters().contains(&name))
+                {
+                    Err(anyhow::Error::msg(format!(
+                        "The adapter `{name}` is applied by running or waiting requests."
+                    )))
+                } else {
+                    pipeline.unload_lora_adapter(&name)
+                }
+            }
+            AdapterAction::List => Ok(()),
+        };
+        let response = result.and_then(|()| {
+            pipeline
+                .lora_adapters()
+                .map(<[String]>::to_vec)
+                .ok_or_else(|| anyhow::Error::msg("This model does not support adapters."))
+        });
+        // NOTE(EricLBuehler): Unwrap reasoning: The receiver should really be there, otherwise it is their fault.
+        request.response.send(response).unwrap();
+    }
+
+    /// Pool hidden states of shape `(n_tokens, hidden_size)` into one embedding.
+    fn pool_hidden_states(
+        hidden_states: &Tensor,
+        pooling: EmbeddingPooling,
+        dimensions: Option<usize>,
+        normalize: bool,
+    ) -> candle_core::Result<Vec<f32>> {
+        let hidden_states = hidden_states.to_dtype(DType::F32)?;
+        let pooled = match pooling {
+            EmbeddingPooling::Mean => hidden_states.mean(0)?,
+            EmbeddingPooling::LastToken => hidden_states.get(hidden_states.dim(0)? - 1)?,
+        };
+        let pooled = match dimensions {
+            Some(dimensions) if dimensions > pooled.dim(0)? => candle_core::bail!(
+                "`dimensions` is {dimensions}, but the embeddings have {} dimensions.",
+                pooled.dim(0)?
+            ),
+            Some(dimensions) => pooled.narrow(0, 0, dimensions)?,
+            None => pooled,
+        };
+        let pooled = if normalize {
+            let norm = pooled.sqr()?.sum_all()?.sqrt()?.maximum(1e-12)?;
+            pooled.broadcast_div(&norm)?
+        } else {
+            pooled
+        };
+        pooled.to_vec1()
+    }
+
+    fn add_request(&mut self, request: NormalRequest) {
         if request.messages.is_left()
             && !get_mut_arcmutex!(self.pipeline)
                 .get_chat_template()
@@ -421,10 +1128,122 @@ impl Engine {
                     )).unwrap();
             return;
         }
+        let tool_choice = request.tool_choice.clone().unwrap_or(ToolChoice::Auto);
+        let tools = request
+            .tools
+            .filter(|tools| !tools.is_empty() && tool_choice != ToolChoice::None);
+        // A required tool call is enforced with a grammar.
+        let constraint = match (&tools, &tool_choice) {
+            (Some(_), ToolChoice::Required | ToolChoice::Function(_))
+                if !matches!(request.constraint, Constraint::None) =>
+            {
+                // NOTE(EricLBuehler): Unwrap reasoning: The receiver should really be there, otherwise it is their fault.
+                request
+                    .response
+                    .send(Response::ValidationError(
+                        "A grammar cannot be used when a tool call is required.".into(),
+                    ))
+                    .unwrap();
+                return;
+            }
+            (Some(tools), ToolChoice::Required) => {
+                let functions = tools.iter().map(|tool| &tool.function).collect::<Vec<_>>();
+                Constraint::JsonSchema(tools::tool_calls_schema(&functions))
+            }
+            (Some(tools), ToolChoice::Function(name)) => {
+                let Some(tool) = tools.iter().find(|tool| tool.function.name == *name) else {
+                    // NOTE(EricLBuehler): Unwrap reasoning: The receiver should really be there, otherwise it is their fault.
+                    request
+                        .response
+                        .send(Response::ValidationError(
+                            format!("The tool choice `{name}` is not one of the tools.").into(),
+                        ))
+                        .unwrap();
+                    return;
+                };
+                Constraint::JsonSchema(tools::tool_calls_schema(&[&tool.function]))
+            }
+            _ => request.constraint,
+        };
+        // LoRA models apply all of their loaded adapters unless the request selects some.
+        let adapters = match (
+            get_mut_arcmutex!(self.pipeline).lora_adapters(),
+            request.adapters,
+        ) {
+            (Some(loaded), None) => loaded.to_vec(),
+            (Some(loaded), Some(adapters)) => {
+                if let Some(name) = adapters.iter().find(|name| !loaded.contains(name)) {
+                    // NOTE(EricLBuehler): Unwrap reasoning: The receiver should really be there, otherwise it is their fault.
+                    request
+                        .response
+                        .send(Response::ValidationError(
+                            format!("The adapter `{name}` is not loaded.").into(),
+                        ))
+                        .unwrap();
+                    return;
+                }
+                adapters
+            }
+            (None, None) => Vec::new(),
+            (None, Some(_)) => {
+                // NOTE(EricLBuehler): Unwrap reasoning: The receiver should really be there, otherwise it is their fault.
+                request
+                    .response
+                    .send(Response::ValidationError(
+                        "Adapters can only be selected for LoRA models.".into(),
+                    ))
+                    .unwrap();
+                return;
+            }
+        };
+        if let Some(beam_search) = &request.sampling_params.beam_search {
+            let invalid = if beam_search.beam_width == 0 {
+                Some("The beam width must be at least 1.")
+            } else if request.sampling_params.n_choices > beam_search.beam_width {
+                Some("Beam search returns at most `beam_width` choices.")
+            } else if request.is_streaming {
+                Some("Beam search does not support streaming.")
+            } else if !matches!(constraint, Constraint::None) {
+                Some("Beam search does not support grammars.")
+            } else if self.block_engine.is_some() {
+                Some("Beam search is not supported with the paged KV cache.")
+            } else {
+                None
+            };
+            if let Some(invalid) = invalid {
+                // NOTE(EricLBuehler): Unwrap reasoning: The receiver should really be there, otherwise it is their fault.
+                request
+                    .response
+                    .send(Response::ValidationError(invalid.into()))
+                    .unwrap();
+                return;
+            }
+        }
+        if request
+            .sampling_params
+            .repetition_penalty
+            .is_some_and(|penalty| penalty <= 0.)
+        {
+            // NOTE(EricLBuehler): Unwrap reasoning: The receiver should really be there, otherwise it is their fault.
+            request
+                .response
+                .send(Response::ValidationError(
+                    "The repetition penalty must be positive.".into(),
+                ))
+                .unwrap();
+            return;
+        }
         let formatted_prompt = match request.messages {
-            Either::Left(messages) => {
+            Either::Left(mut messages) => {
+                let pipeline = get_mut_arcmutex!(self.pipeline);
+                // Chat templates which do not render the tools get them in the messages.
+                if let Some(tools) = &tools {
+                    if !pipeline.get_chat_template().renders_tools() {
+                        tools::add_tools_to_messages(&mut messages, tools, &tool_choice);
+                    }
+                }
                 handle_seq_error!(
-                    get_mut_arcmutex!(self.pipeline).apply_chat_template(messages, true),
+                    pipeline.apply_chat_template(messages, true, tools.as_deref()),
                     request.response
                 )
             }
@@ -471,10 +1290,45 @@ impl Engine {
                 warn!("Prompt for request {} was {} tokens over the model maximum length. The last {} tokens were truncated to make space for generation.", request.id, currently_over, prompt_len - prompt.len());
             }
         }
-        let prefill_cache = handle_seq_error!(
-            self.prefix_cacher.search_for_matching_cache(&prompt),
-            request.response
-        );
+        // A scored prompt is echoed with its logprobs, and nothing is generated.
+        let is_score = request.request_type == RequestType::Score;
+        let echo_prompt = is_score
+            || matches!(
+                request.request_type,
+                RequestType::Completion { echo_prompt: true }
+            );
+        let return_logprobs = request.return_logprobs || is_score;
+        let (max_len, n_choices) = if is_score {
+            (Some(0), 1)
+        } else {
+            (
+                request.sampling_params.max_len,
+                request.sampling_params.n_choices,
+            )
+        };
+        // Prompt logprobs need the logits of every prompt position, which X-LoRA and LoRA models do not return.
+        let return_prompt_logprobs = return_logprobs && echo_prompt;
+        if return_prompt_logprobs && get_mut_arcmutex!(self.pipeline).has_adapters() {
+            // NOTE(EricLBuehler): Unwrap reasoning: The receiver should really be there, otherwise it is their fault.
+            request
+                .response
+                .send(Response::ValidationError(
+                    "Prompt logprobs are not supported for X-LoRA and LoRA models.".into(),
+                ))
+                .unwrap();
+            return;
+        }
+        // A cached prefix is not run through the model, so its logprobs would be missing. Cached prefixes are
+        // computed without adapters.
+        let prefill_cache =
+            if self.block_engine.is_some() || return_prompt_logprobs || !adapters.is_empty() {
+                None
+            } else {
+                handle_seq_error!(
+                    self.prefix_cacher.search_for_matching_cache(&prompt),
+                    request.response
+                )
+            };
 
         let topk = request
             .sampling_params
@@ -533,11 +1387,21 @@ impl Engine {
             }
         };
 
+        // A beam search starts from one seq, which is forked into the beams, and its choices are the best beams.
+        let beam_search = request.sampling_params.beam_search.filter(|_| !is_score);
+        let (n_seqs, best_of) = match beam_search {
+            Some(_) => (1, Some(n_choices)),
+            None => (n_choices, request.best_of),
+        };
         let group = Rc::new(RefCell::new(SequenceGroup::new(
-            request.sampling_params.n_choices,
+            request.id,
+            n_choices,
             request.is_streaming,
             request.request_type == RequestType::Chat,
-            request.best_of,
+            best_of,
+            tools.is_some(),
+            beam_search,
+            request.sampling_params.seed,
         )));
         let now = SystemTime::now()
             .duration_since(UNIX_EPOCH)
@@ -546,17 +1410,24 @@ impl Engine {
         let tokenizer = get_mut_arcmutex!(self.pipeline).tokenizer();
 
         let sampler = Sampler::new(
-            SEED,
+            request.sampling_params.seed.unwrap_or(SEED),
             Some(request.sampling_params.temperature.unwrap_or(1.0)),
             request.sampling_params.top_n_logprobs,
             tokenizer,
             request.sampling_params.frequency_penalty,
             request.sampling_params.presence_penalty,
+            request.sampling_params.repetition_penalty,
+            request.sampling_params.no_repeat_ngram_size,
+            request.sampling_params.dry_params.clone(),
             request.sampling_params.logits_bias.clone(),
             topk,
             topp,
+            request.sampling_params.min_p.unwrap_or(0.0),
+            request.sampling_params.typical_p.unwrap_or(1.0),
+            request.sampling_params.tfs_z.unwrap_or(1.0),
+            request.sampling_params.mirostat,
         );
-        let recognizer = match Self::build_sequence_recognizer(&request.constraint) {
+        let recognizer = match Self::build_sequence_recognizer(&constraint) {
             Ok(recognizer) => recognizer,
             Err(err) => {
                 request
@@ -569,7 +1440,8 @@ impl Engine {
             }
         };
         // Add sequences
-        for response_index in 0..request.sampling_params.n_choices {
+        for response_index in 0..n_seqs {
+            let sampler = sampler.for_choice(request.sampling_params.seed, response_index);
             let seq = Sequence::new_waiting(
                 prompt.clone(),
                 formatted_prompt.clone(),
@@ -577,26 +1449,33 @@ impl Engine {
                 now.as_millis(),
                 num_hidden_layers,
                 request.response.clone(),
-                sampler.clone(),
+                sampler,
                 stop_toks.clone(),
                 stop_strings.clone(),
-                request.sampling_params.max_len,
-                request.return_logprobs,
+                max_len,
+                return_logprobs,
+                return_prompt_logprobs,
                 get_mut_arcmutex!(self.pipeline).is_xlora(),
                 group.clone(),
                 response_index,
                 now.as_secs(),
                 recognizer.clone(),
                 request.suffix.clone(),
-                if let RequestType::Completion { echo_prompt } = request.request_type.clone() {
-                    if echo_prompt {
-                        Some(formatted_prompt.clone())
-                    } else {
-                        None
-                    }
+                if echo_prompt {
+                    Some(formatted_prompt.clone())
                 } else {
                     None
                 },
+                self.prefill_chunk_size,
+                request.priority,
+                request.tenant.clone(),
+                request
+                    .timeout
+                    .into_iter()
+                    .chain(self.max_request_time)
+                    .min()
+                    .map(|timeout| (now + timeout).as_millis()),
+                adapters.clone(),
             );
             let seq = if let Some(prefill_cache) = prefill_cache.clone() {
                 match prefill_cache {
@@ -611,3 +1490,225 @@ impl Engine {
         }
     }
 }
+
+/// Build the legacy completions logprobs of the generated tokens of a seq from `start` on, whose text begins at
+/// `text_offset` in the text of the choice. With `with_prompt`, the prompt tokens come first.
+fn completion_logprobs(
+    tokenizer: &Tokenizer,
+    seq: &Sequence,
+    with_prompt: bool,
+    start: usize,
+    mut text_offset: usize,
+) -> Result<CompletionLogprobs, tokenizers::Error> {
+    let mut toks = Vec::new();
+    if with_prompt {
+        // The first prompt token is not predicted by anything.
+        toks.extend(seq.get_toks()[..1].iter().map(|tok| (*tok, None)));
+        toks.extend(seq.prompt_logprobs().iter().map(|l| (l.token, Some(l))));
+    }
+    toks.extend(seq.logprobs()[start..].iter().map(|l| (l.token, Some(l))));
+
+    let mut logprobs = CompletionLogprobs::default();
+    for (tok, logprob) in toks {
+        let token = tokenizer.decode(&[tok], true)?;
+        logprobs.text_offset.push(text_offset);
+        text_offset += token.chars().count();
+        logprobs.tokens.push(token);
+        logprobs.token_logprobs.push(logprob.map(|l| l.logprob));
+        logprobs.top_logprobs.push(
+            logprob
+                .and_then(|l| l.top_logprobs.as_ref())
+                .map(|top| top.iter().map(|t| (t.bytes.clone(), t.logprob)).collect()),
+        );
+    }
+    Ok(logprobs)
+}
+
+/// Concatenate the caches of a batch, padding each one on the left with zeros to the longest one. A seq
+/// without a cache is all padding.
+fn cat_padded_caches(
+    caches: Vec<Option<(Tensor, Tensor)>>,
+) -> candle_core::Result<Option<(Tensor, Tensor)>> {
+    let Some((template, _)) = caches.iter().flatten().next() else {
+        return Ok(None);
+    };
+    let (_, num_kv_heads, _, head_dim) = template.dims4()?;
+    let mut max_len = 0;
+    for (k, _) in caches.iter().flatten() {
+        max_len = max_len.max(k.dim(2)?);
+    }
+    let padding = Tensor::zeros(
+        (1, num_kv_heads, max_len, head_dim),
+        template.dtype(),
+        template.device(),
+    )?;
+    let mut k_vec = Vec::new();
+    let mut v_vec = Vec::new();
+    for cache in &caches {
+        match cache {
+            Some((k, v)) => {
+                let len = k.dim(2)?;
+                k_vec.push(k.pad_with_zeros(2, max_len - len, 0)?);
+                v_vec.push(v.pad_with_zeros(2, max_len - len, 0)?);
+            }
+            None => {
+                k_vec.push(padding.clone());
+                v_vec.push(padding.clone());
+            }
+        }
+    }
+    Ok(Some((Tensor::cat(&k_vec, 0)?, Tensor::cat(&v_vec, 0)?)))
+}
+
+/// Split the cache of a batch into the caches of its seqs, given the input range of each seq. A row holds
+/// the cached tokens padded on the left to the longest past, followed by the new tokens padded on the
+/// right to the longest input, and only the real tokens are kept.
+fn split_padded_cache(
+    (k, v): &(Tensor, Tensor),
+    ranges: &[(usize, usize)],
+) -> candle_core::Result<Vec<(Tensor, Tensor)>> {
+    let max_len = ranges
+        .iter()
+        .map(|(start, end)| end - start)
+        .max()
+        .unwrap_or(0);
+    let max_past = k.dim(2)? - max_len;
+    let unpad =
+        |xs: &Tensor, i: usize, (start, end): (usize, usize)| -> candle_core::Result<Tensor> {
+            let row = xs.narrow(0, i, 1)?;
+            if start == max_past && end - start == max_len {
+                return Ok(row);
+            }
+            let new = row.narrow(2, max_past, end - start)?;
+            if start == 0 {
+                Ok(new)
+            } else {
+                Tensor::cat(&[row.narrow(2, max_past - start, start)?, new], 2)
+            }
+        };
+    ranges
+        .iter()
+        .enumerate()
+        .map(|(i, range)| Ok((unpad(k, i, *range)?, unpad(v, i, *range)?)))
+        .collect::<candle_core::Result<Vec<_>>>()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use candle_core::{Device, Tensor};
+
+    use super::{cat_padded_caches, completion_logprobs, split_padded_cache, Engine};
+    use crate::{
+        request::EmbeddingPooling,
+        sampler::{tests::test_tokenizer, Logprobs, TopLogprob},
+        sequence::tests::test_sequence,
+    };
+
+    fn logprobs(token: u32, logprob: f32) -> Logprobs {
+        Logprobs {
+            token,
+            logprob,
+            bytes: format!("t{token}"),
+            top_logprobs: Some(vec![TopLogprob {
+                token,
+                logprob,
+                bytes: format!("t{token}"),
+            }]),
+        }
+    }
+
+    #[test]
+    fn completion_logprobs_with_and_without_the_prompt() {
+        let tokenizer = test_tokenizer(8);
+        let mut seq = test_sequence(0, 2, 0, 0, None, Vec::new());
+        seq.add_prompt_logprobs(vec![logprobs(1, -0.5)]);
+        seq.add_token(logprobs(2, -1.), Vec::new());
+        seq.add_token(logprobs(3, -2.), Vec::new());
+
+        // The first prompt token has no logprob, and the offsets count the characters of the tokens.
+        let echoed = completion_logprobs(&tokenizer, &seq, true, 0, 0).unwrap();
+        assert_eq!(echoed.tokens, vec!["t0", "t1", "t2", "t3"]);
+        assert_eq!(
+            echoed.token_logprobs,
+            vec![None, Some(-0.5), Some(-1.), Some(-2.)]
+        );
+        assert_eq!(echoed.text_offset, vec![0, 2, 4, 6]);
+        assert_eq!(echoed.top_logprobs[0], None);
+        assert_eq!(
+            echoed.top_logprobs[1],
+            Some(HashMap::from([("t1".to_string(), -0.5)]))
+        );
+
+        // A later chunk starts at the logprobs which were not streamed yet, after the streamed text.
+        let chunk = completion_logprobs(&tokenizer, &seq, false, 1, 5).unwrap();
+        assert_eq!(chunk.tokens, vec!["t3"]);
+        assert_eq!(chunk.token_logprobs, vec![Some(-2.)]);
+        assert_eq!(chunk.text_offset, vec![5]);
+    }
+
+    /// A cache of one head with a head dim of 1, holding the values as keys and their negations as values.
+    fn cache(values: &[f32]) -> (Tensor, Tensor) {
+        let k = Tensor::from_slice(values, (1, 1, values.len(), 1), &Device::Cpu).unwrap();
+        let v = k.neg().unwrap();
+        (k, v)
+    }
+
+    fn values(xs: &Tensor) -> Vec<f32> {
+        xs.flatten_all().unwrap().to_vec1::<f32>().unwrap()
+    }
+
+    #[test]
+    fn pool_hidden_states() {
+        let hidden_states = Tensor::new(&[[1f32, 2., 0.], [3., 4., 0.]], &Device::Cpu).unwrap();
+        let pool = |pooling, dimensions, normalize| {
+            Engine::pool_hidden_states(&hidden_states, pooling, dimensions, normalize)
+        };
+        assert_eq!(
+            pool(EmbeddingPooling::Mean, None, false).unwrap(),
+            vec![2., 3., 0.]
+        );
+        assert_eq!(
+            pool(EmbeddingPooling::LastToken, None, false).unwrap(),
+            vec![3., 4., 0.]
+        );
+        // The embedding is shortened before it is normalized.
+        let normalized = pool(EmbeddingPooling::LastToken, Some(2), true).unwrap();
+        assert_eq!(normalized.len(), 2);
+        assert!((normalized[0] - 0.6).abs() < 1e-6 && (normalized[1] - 0.8).abs() < 1e-6);
+        assert!(pool(EmbeddingPooling::Mean, Some(4), false).is_err());
+    }
+
+    #[test]
+    fn caches_of_mixed_lengths_round_trip() {
+        let pasts = [vec![1., 2.], vec![], vec![3., 4., 5.]];
+        let caches = pasts
+            .iter()
+            .map(|past| (!past.is_empty()).then(|| cache(past)))
+            .collect();
+        let (k, v) = cat_padded_caches(caches).unwrap().unwrap();
+        assert_eq!(k.dims4().unwrap(), (3, 1, 3, 1));
+        assert_eq!(values(&k), vec![0., 1., 2., 0., 0., 0., 3., 4., 5.]);
+        assert_eq!(values(&v), vec![0., -1., -2., 0., 0., 0., -3., -4., -5.]);
+
+        // The model appends the new tokens, padded on the right to the longest input.
+        let inputs = [vec![6.], vec![7., 8.], vec![9.]];
+        let new =
+            Tensor::from_slice(&[6f32, 0., 7., 8., 9., 0.], (3, 1, 2, 1), &Device::Cpu).unwrap();
+        let k = Tensor::cat(&[k, new.clone()], 2).unwrap();
+        let v = Tensor::cat(&[v, new.neg().unwrap()], 2).unwrap();
+        let ranges = [(2, 3), (0, 2), (3, 4)];
+        let split = split_padded_cache(&(k, v), &ranges).unwrap();
+        for ((past, input), (k, v)) in pasts.iter().zip(&inputs).zip(&split) {
+            let expected = [past.as_slice(), input.as_slice()].concat();
+            assert_eq!(values(k), expected);
+            assert_eq!(values(v), expected.iter().map(|x| -x).collect::<Vec<_>>());
+        }
+    }
+
+    #[test]
+    fn no_caches_cat_to_none() {
+        assert!(cat_padded_caches(vec![None, None]).unwrap().is_none());
+    }
+}
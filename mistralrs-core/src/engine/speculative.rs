@@ -0,0 +1,140 @@
+use std::sync::Mutex;
+
+use candle_core::Tensor;
+
+use crate::{
+    get_mut_arcmutex, handle_seq_error_stateaware,
+    pipeline::Pipeline,
+    sequence::{Sequence, SequenceRecognizer},
+};
+
+use super::Engine;
+
+/// Speculative decoding: a small draft model proposes `gamma` tokens per step, and the model verifies them
+/// in one forward pass. Rejection sampling keeps the output distributed exactly like that of the model, so
+/// the draft model only changes the speed. It must have the same vocabulary as the model.
+pub struct SpeculativeConfig {
+    pub draft: Box<Mutex<dyn Pipeline>>,
+    pub gamma: usize,
+}
+
+impl Engine {
+    /// Draft tokens with the draft model for the completion seqs of a batch, which the forward pass of the
+    /// model then verifies. Beams and seqs with a grammar or Mirostat are not drafted for, and no more tokens
+    /// are drafted than a seq may still generate, as the model samples one token after the drafts.
+    pub(super) fn draft_tokens(
+        speculative: &SpeculativeConfig,
+        max_model_len: usize,
+        batch: &mut [&mut Sequence],
+    ) -> candle_core::Result<()> {
+        let mut seqs = batch
+            .iter_mut()
+            .filter(|seq| {
+                seq.is_completion()
+                    && !seq.is_beam()
+                    && matches!(seq.recognizer, SequenceRecognizer::None)
+            })
+            .map(|seq| {
+                let generated = seq.len() - seq.prompt_tokens();
+                let remaining = seq
+                    .max_len()
+                    .map_or(usize::MAX, |max_len| max_len.saturating_sub(generated))
+                    .min(max_model_len.saturating_sub(seq.len()));
+                // Mirostat adapts to every sampled token, so it cannot verify several at once.
+                let gamma = if seq.sampler().has_mirostat() {
+                    0
+                } else {
+                    speculative.gamma
+                };
+                let n_drafts = gamma.min(remaining.saturating_sub(1));
+                (&mut **seq, n_drafts)
+            })
+            .collect::<Vec<_>>();
+
+        let mut draft = get_mut_arcmutex!(speculative.draft);
+        let num_hidden_layers = draft.num_hidden_layers();
+        let repeat_last_n = draft.get_repeat_last_n();
+        for step in 0..speculative.gamma {
+            let mut step_seqs = seqs
+                .iter_mut()
+                .filter(|(_, n_drafts)| *n_drafts > step)
+                .map(|(seq, _)| &mut **seq)
+                .collect::<Vec<_>>();
+            if step_seqs.is_empty() {
+                break;
+            }
+            // The cache of the draft model may be behind, so the input starts after its cached tokens.
+            for seq in step_seqs.iter_mut() {
+                seq.swap_draft_cache();
+                if seq.cache().len() != num_hidden_layers {
+                    *seq.cache() = vec![None; num_hidden_layers];
+                }
+                let cached = seq.cache()[0].as_ref().map_or(0, |(k, _)| k.dims()[2]);
+                seq.set_input_start(Some(cached));
+            }
+            let logits = Self::draft_forward(&mut *draft, &mut step_seqs);
+            for seq in step_seqs.iter_mut() {
+                seq.swap_draft_cache();
+            }
+            let logits = logits?;
+
+            // Only the first step may catch up on several tokens, and it has no drafts yet, so there is one
+            // row of logits per seq.
+            for (i, seq) in step_seqs.iter_mut().enumerate() {
+                let logits = logits.get(i)?.flatten_all()?;
+                let toks = seq.get_toks();
+                let ctxt = toks[toks.len().saturating_sub(repeat_last_n)..].to_vec();
+                let (tok, probs) = seq.sampler().sample_draft(&logits, Some(&ctxt))?;
+                seq.add_draft(tok, probs);
+            }
+        }
+
+        // The model verifies the drafts in one forward pass, starting at the last token before them.
+        for (seq, n_drafts) in seqs {
+            let start = (n_drafts > 0).then(|| seq.len() - n_drafts - 1);
+            seq.set_input_start(start);
+        }
+        Ok(())
+    }
+
+    fn draft_forward(
+        draft: &mut dyn Pipeline,
+        seqs: &mut [&mut Sequence],
+    ) -> candle_core::Result<Tensor> {
+        Self::clone_in_cache(draft, seqs)?;
+        let logits = draft.forward(seqs, false)?;
+        Self::clone_out_cache(draft, seqs)?;
+        Ok(logits)
+    }
+
+    /// Sample the tokens of a seq with drafted tokens, given the logits of the model at the positions of the
+    /// drafts and after them. The drafts are accepted up to the first rejected one, which is replaced by a
+    /// token of the model, or a token is sampled after them if all are accepted. The KV caches are then
+    /// truncated to the accepted tokens.
+    pub(super) fn verify_drafts(
+        pipeline: &mut dyn Pipeline,
+        seq: &mut Sequence,
+        drafts: Vec<(u32, Vec<f32>)>,
+        logits: Tensor,
+    ) {
+        let repeat_last_n = pipeline.get_repeat_last_n();
+        let return_logprobs = seq.return_logprobs();
+        for i in 0..=drafts.len() {
+            let logits = logits.get(i).and_then(|logits| logits.flatten_all());
+            let logits = handle_seq_error_stateaware!(logits, seq);
+            let toks = seq.get_toks();
+            let ctxt = toks[toks.len().saturating_sub(repeat_last_n)..].to_vec();
+            let draft = drafts.get(i).map(|(tok, probs)| (*tok, probs.as_slice()));
+            let sampled =
+                seq.sampler()
+                    .sample_speculative(&logits, Some(&ctxt), draft, return_logprobs);
+            let next_token = handle_seq_error_stateaware!(sampled, seq);
+            let accepted = draft.is_some_and(|(tok, _)| tok == next_token.token);
+            Self::add_sampled_token(pipeline, seq, next_token);
+            if !accepted || !seq.is_completion() {
+                break;
+            }
+        }
+        seq.truncate_cache();
+    }
+}
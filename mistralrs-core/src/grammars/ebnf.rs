@@ -0,0 +1,438 @@
+//! The EBNF grammars which GBNF and Lark grammars are parsed into, and their compilation into yacc grammars.
+
+use std::collections::{HashMap, HashSet};
+
+use super::{literal_token, regex_token};
+
+/// The largest bound of a counted repetition, which is unrolled into that many rules.
+pub(crate) const MAX_REPETITIONS: usize = 1024;
+
+/// An error in a source grammar, at a 1-based line and column.
+#[derive(Debug, thiserror::Error)]
+#[error("line {line}, column {column}: {message}")]
+pub(crate) struct GrammarError {
+    line: usize,
+    column: usize,
+    message: String,
+}
+
+/// A position in a source grammar.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Pos {
+    line: usize,
+    column: usize,
+}
+
+impl Pos {
+    pub(crate) fn error(self, message: impl Into<String>) -> GrammarError {
+        GrammarError {
+            line: self.line,
+            column: self.column,
+            message: message.into(),
+        }
+    }
+}
+
+pub(crate) enum Expr {
+    /// Matches the text literally.
+    Literal(String),
+    /// Matches the regex, as one token.
+    Regex(String),
+    /// A reference to the rule with this name.
+    Rule(String, Pos),
+    Seq(Vec<Expr>),
+    Alt(Vec<Expr>),
+    Repeat {
+        expr: Box<Expr>,
+        min: usize,
+        max: Option<usize>,
+    },
+}
+
+impl Expr {
+    pub(crate) fn repeat(self, min: usize, max: Option<usize>) -> Self {
+        Self::Repeat {
+            expr: Box::new(self),
+            min,
+            max,
+        }
+    }
+
+    /// A sequence, or the only expression if there is one.
+    pub(crate) fn seq(mut exprs: Vec<Expr>) -> Self {
+        if exprs.len() == 1 {
+            exprs.remove(0)
+        } else {
+            Self::Seq(exprs)
+        }
+    }
+
+    /// Alternatives, or the only expression if there is one.
+    pub(crate) fn alt(mut exprs: Vec<Expr>) -> Self {
+        if exprs.len() == 1 {
+            exprs.remove(0)
+        } else {
+            Self::Alt(exprs)
+        }
+    }
+}
+
+pub(crate) struct Rule {
+    pub(crate) name: String,
+    pub(crate) pos: Pos,
+    pub(crate) expr: Expr,
+}
+
+/// A cursor over a source grammar which keeps track of the line and column.
+#[derive(Clone)]
+pub(crate) struct Cursor<'a> {
+    src: &'a str,
+    offset: usize,
+    pos: Pos,
+}
+
+impl<'a> Cursor<'a> {
+    pub(crate) fn new(src: &'a str) -> Self {
+        Self {
+            src,
+            offset: 0,
+            pos: Pos { line: 1, column: 1 },
+        }
+    }
+
+    pub(crate) fn pos(&self) -> Pos {
+        self.pos
+    }
+
+    pub(crate) fn error(&self, message: impl Into<String>) -> GrammarError {
+        self.pos.error(message)
+    }
+
+    pub(crate) fn is_done(&self) -> bool {
+        self.offset == self.src.len()
+    }
+
+    pub(crate) fn rest(&self) -> &'a str {
+        &self.src[self.offset..]
+    }
+
+    pub(crate) fn peek(&self) -> Option<char> {
+        self.rest().chars().next()
+    }
+
+    pub(crate) fn bump(&mut self) -> Option<char> {
+        let ch = self.peek()?;
+        self.offset += ch.len_utf8();
+        if ch == '\n' {
+            self.pos.line += 1;
+            self.pos.column = 1;
+        } else {
+            self.pos.column += 1;
+        }
+        Some(ch)
+    }
+
+    /// Consume `text` if the source continues with it.
+    pub(crate) fn eat(&mut self, text: &str) -> bool {
+        if self.rest().starts_with(text) {
+            for _ in text.chars() {
+                self.bump();
+            }
+            true
+        } else {
+            false
+        }
+    }
+
+    pub(crate) fn expect(&mut self, text: &str) -> Result<(), GrammarError> {
+        if self.eat(text) {
+            Ok(())
+        } else {
+            Err(self.error(format!("Expected `{text}`.")))
+        }
+    }
+
+    pub(crate) fn take_while(&mut self, pred: impl Fn(char) -> bool) -> &'a str {
+        let start = self.offset;
+        while self.peek().is_some_and(&pred) {
+            self.bump();
+        }
+        &self.src[start..self.offset]
+    }
+
+    pub(crate) fn expect_char(&mut self) -> Result<char, GrammarError> {
+        self.bump()
+            .ok_or_else(|| self.error("Unexpected end of the grammar."))
+    }
+
+    /// Parse `n` hex digits into a character.
+    pub(crate) fn hex_char(&mut self, n: usize) -> Result<char, GrammarError> {
+        let pos = self.pos;
+        let digits = self.rest().chars().take(n).collect::<String>();
+        let ch = u32::from_str_radix(&digits, 16)
+            .ok()
+            .filter(|_| digits.len() == n)
+            .and_then(char::from_u32)
+            .ok_or_else(|| pos.error(format!("Invalid escape with {n} hex digits.")))?;
+        for _ in 0..n {
+            self.bump();
+        }
+        Ok(ch)
+    }
+
+    pub(crate) fn number(&mut self) -> Result<usize, GrammarError> {
+        let pos = self.pos;
+        let n = self
+            .take_while(|ch| ch.is_ascii_digit())
+            .parse::<usize>()
+            .map_err(|_| pos.error("Expected a number."))?;
+        if n > MAX_REPETITIONS {
+            return Err(pos.error(format!("Repetitions are limited to {MAX_REPETITIONS}.")));
+        }
+        Ok(n)
+    }
+}
+
+/// Compile rules into a yacc grammar which starts with the rule `start`. Tokens which match one of the `skip`
+/// regexes may appear between any two tokens.
+pub(crate) fn rules_to_yacc(
+    rules: &[Rule],
+    start: &str,
+    skip: &[String],
+) -> Result<String, GrammarError> {
+    let mut emitter = YaccEmitter {
+        names: HashMap::new(),
+        rules: Vec::new(),
+    };
+    // Rule names are made valid yacc identifiers which cannot clash with the generated rules.
+    let mut used = HashSet::new();
+    for rule in rules {
+        if emitter.names.contains_key(&rule.name) {
+            return Err(rule
+                .pos
+                .error(format!("The rule `{}` is defined twice.", rule.name)));
+        }
+        let sanitized = rule
+            .name
+            .chars()
+            .map(|ch| if ch.is_ascii_alphanumeric() { ch } else { '_' })
+            .collect::<String>();
+        let mut name = format!("rule_{sanitized}");
+        let mut n = 1;
+        while !used.insert(name.clone()) {
+            n += 1;
+            name = format!("rule_{sanitized}_{n}");
+        }
+        emitter.names.insert(rule.name.clone(), name);
+    }
+    let Some(start) = emitter.names.get(start).cloned() else {
+        return Err(Pos { line: 1, column: 1 }.error(format!("There is no `{start}` rule.")));
+    };
+
+    let mut compiled = Vec::new();
+    for rule in rules {
+        let alternatives = match &rule.expr {
+            Expr::Alt(exprs) => exprs
+                .iter()
+                .map(|expr| emitter.symbols(expr))
+                .collect::<Result<Vec<_>, _>>()?,
+            expr => vec![emitter.symbols(expr)?],
+        };
+        compiled.push(format!(
+            "{}: {} ;",
+            emitter.names[&rule.name],
+            alternatives.join(" | ")
+        ));
+    }
+
+    let mut grammar = format!("%start {start}\n%%\n");
+    if !skip.is_empty() {
+        let skip = skip
+            .iter()
+            .map(|rx| regex_token(rx))
+            .collect::<Vec<_>>()
+            .join(" | ");
+        grammar.push_str(&format!("\nSKIP: {skip} ;\n"));
+    }
+    for rule in compiled.into_iter().chain(emitter.rules) {
+        grammar.push('\n');
+        grammar.push_str(&rule);
+        grammar.push('\n');
+    }
+    Ok(grammar)
+}
+
+struct YaccEmitter {
+    names: HashMap<String, String>,
+    rules: Vec<String>,
+}
+
+/// Join symbol sequences, some of which may be empty.
+fn join(parts: impl IntoIterator<Item = String>) -> String {
+    parts
+        .into_iter()
+        .filter(|part| !part.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+impl YaccEmitter {
+    /// Add a rule with these alternatives, each a sequence of symbols. Returns the name of the rule.
+    fn add_rule(&mut self, kind: &str, alternatives: Vec<String>) -> String {
+        let name = format!("{kind}_{}", self.rules.len());
+        self.rules
+            .push(format!("{name}: {} ;", alternatives.join(" | ")));
+        name
+    }
+
+    /// Compile an expression into the sequence of symbols which matches it.
+    fn symbols(&mut self, expr: &Expr) -> Result<String, GrammarError> {
+        match expr {
+            Expr::Literal(text) if text.is_empty() => Ok(String::new()),
+            Expr::Literal(text) => Ok(literal_token(text)),
+            Expr::Regex(rx) => Ok(regex_token(rx)),
+            Expr::Rule(name, pos) => self
+                .names
+                .get(name)
+                .cloned()
+                .ok_or_else(|| pos.error(format!("The rule `{name}` is not defined."))),
+            Expr::Seq(exprs) => Ok(join(
+                exprs
+                    .iter()
+                    .map(|expr| self.symbols(expr))
+                    .collect::<Result<Vec<_>, _>>()?,
+            )),
+            Expr::Alt(exprs) => {
+                let alternatives = exprs
+                    .iter()
+                    .map(|expr| self.symbols(expr))
+                    .collect::<Result<Vec<_>, _>>()?;
+                Ok(self.add_rule("alt", alternatives))
+            }
+            Expr::Repeat { expr, min, max } => {
+                let item = self.symbols(expr)?;
+                let required = vec![item.clone(); *min];
+                let optional = match max {
+                    None => {
+                        let name = format!("repeat_{}", self.rules.len());
+                        self.rules.push(format!("{name}: | {name} {item} ;"));
+                        name
+                    }
+                    Some(max) => {
+                        // Each of the items after the required ones may end the repetition.
+                        let mut optional = String::new();
+                        for _ in *min..*max {
+                            optional = self.add_rule(
+                                "optional",
+                                vec![String::new(), join([item.clone(), optional])],
+                            );
+                        }
+                        optional
+                    }
+                };
+                Ok(join(required.into_iter().chain([optional])))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{rules_to_yacc, Cursor, Expr, Pos, Rule};
+    use crate::{aici::cfg::CfgParser, grammars::tests::accepts};
+
+    fn yacc_accepts(yacc: &str, text: &str) -> bool {
+        accepts(CfgParser::from_yacc_with_viable_tokens(yacc).unwrap(), text)
+    }
+
+    fn rule(name: &str, line: usize, expr: Expr) -> Rule {
+        Rule {
+            name: name.to_string(),
+            pos: Pos { line, column: 1 },
+            expr,
+        }
+    }
+
+    #[test]
+    fn compiles_rules_to_yacc() {
+        let rules = [
+            rule(
+                "root",
+                1,
+                Expr::seq(vec![
+                    Expr::Literal("(".to_string()),
+                    Expr::Rule("item".to_string(), Pos { line: 1, column: 5 }).repeat(1, Some(3)),
+                    Expr::Literal(")".to_string()),
+                ]),
+            ),
+            rule(
+                "item",
+                2,
+                Expr::alt(vec![
+                    Expr::Literal("x".to_string()),
+                    Expr::Regex("[0-9]+".to_string()),
+                ]),
+            ),
+        ];
+        let yacc = rules_to_yacc(&rules, "root", &["[ ]+".to_string()]).unwrap();
+        assert!(yacc_accepts(&yacc, "(x)"));
+        assert!(yacc_accepts(&yacc, "( x 12 x )"));
+        assert!(!yacc_accepts(&yacc, "()"));
+        assert!(!yacc_accepts(&yacc, "(x x x x)"));
+    }
+
+    #[test]
+    fn rule_names_are_sanitized() {
+        let rules = [
+            rule(
+                "a-b",
+                1,
+                Expr::Rule("a_b".to_string(), Pos { line: 1, column: 8 }),
+            ),
+            rule("a_b", 2, Expr::Literal("\"".to_string())),
+        ];
+        let yacc = rules_to_yacc(&rules, "a-b", &[]).unwrap();
+        assert!(yacc_accepts(&yacc, "\""));
+    }
+
+    #[test]
+    fn unknown_and_duplicate_rules_are_errors() {
+        let rules = [rule(
+            "root",
+            1,
+            Expr::Rule("item".to_string(), Pos { line: 3, column: 7 }),
+        )];
+        let err = rules_to_yacc(&rules, "root", &[]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "line 3, column 7: The rule `item` is not defined."
+        );
+
+        let rules = [
+            rule("root", 1, Expr::Literal("a".to_string())),
+            rule("root", 2, Expr::Literal("b".to_string())),
+        ];
+        let err = rules_to_yacc(&rules, "root", &[]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "line 2, column 1: The rule `root` is defined twice."
+        );
+    }
+
+    #[test]
+    fn cursors_track_lines_and_columns() {
+        let mut cursor = Cursor::new("ab\n\\u00e9 2000");
+        assert!(cursor.eat("ab\n"));
+        assert!(cursor.eat("\\u"));
+        assert_eq!(cursor.hex_char(4).unwrap(), 'é');
+        assert!(cursor.eat(" "));
+        assert_eq!(
+            cursor.number().unwrap_err().to_string(),
+            "line 2, column 8: Repetitions are limited to 1024."
+        );
+        assert_eq!(
+            cursor.expect("x").unwrap_err().to_string(),
+            "line 2, column 12: Expected `x`."
+        );
+    }
+}
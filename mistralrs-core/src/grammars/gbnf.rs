@@ -0,0 +1,253 @@
+//! GBNF, the grammar format of llama.cpp.
+
+use super::{
+    ebnf::{rules_to_yacc, Cursor, Expr, GrammarError, Rule},
+    regex_escape,
+};
+
+/// Compile a GBNF grammar into a yacc grammar. The grammar starts with the `root` rule.
+pub(crate) fn gbnf_to_yacc(src: &str) -> Result<String, GrammarError> {
+    let mut cursor = Cursor::new(src);
+    let mut rules = Vec::new();
+    loop {
+        skip_space(&mut cursor, true);
+        if cursor.is_done() {
+            break;
+        }
+        let pos = cursor.pos();
+        let name = parse_name(&mut cursor)?;
+        skip_space(&mut cursor, false);
+        cursor.expect("::=")?;
+        skip_space(&mut cursor, true);
+        let expr = parse_alternatives(&mut cursor, false)?;
+        rules.push(Rule { name, pos, expr });
+        if !cursor.is_done() && !cursor.eat("\n") && !cursor.eat("\r\n") {
+            return Err(cursor.error("Expected a newline after the rule."));
+        }
+    }
+    rules_to_yacc(&rules, "root", &[])
+}
+
+fn is_name_char(ch: char) -> bool {
+    ch.is_ascii_alphanumeric() || ch == '-' || ch == '_'
+}
+
+/// Skip spaces and comments, and newlines if they are allowed here. Newlines end a rule, but not within
+/// parentheses or after `|`.
+fn skip_space(cursor: &mut Cursor, newlines: bool) {
+    loop {
+        match cursor.peek() {
+            Some(' ' | '\t' | '\r') => {
+                cursor.bump();
+            }
+            Some('\n') if newlines => {
+                cursor.bump();
+            }
+            Some('#') => {
+                cursor.take_while(|ch| ch != '\n');
+            }
+            _ => break,
+        }
+    }
+}
+
+fn parse_name(cursor: &mut Cursor) -> Result<String, GrammarError> {
+    let name = cursor.take_while(is_name_char);
+    if name.is_empty() {
+        return Err(cursor.error("Expected a rule name."));
+    }
+    Ok(name.to_string())
+}
+
+fn parse_alternatives(cursor: &mut Cursor, nested: bool) -> Result<Expr, GrammarError> {
+    let mut alternatives = vec![parse_sequence(cursor, nested)?];
+    while cursor.eat("|") {
+        skip_space(cursor, true);
+        alternatives.push(parse_sequence(cursor, nested)?);
+    }
+    Ok(Expr::alt(alternatives))
+}
+
+fn parse_sequence(cursor: &mut Cursor, nested: bool) -> Result<Expr, GrammarError> {
+    let mut items = Vec::new();
+    loop {
+        let item = match cursor.peek() {
+            Some('"') => {
+                cursor.bump();
+                let mut text = String::new();
+                while !cursor.eat("\"") {
+                    text.push(parse_char(cursor)?);
+                }
+                Expr::Literal(text)
+            }
+            Some('[') => {
+                cursor.bump();
+                let mut class = String::from("[");
+                if cursor.eat("^") {
+                    class.push('^');
+                }
+                while !cursor.eat("]") {
+                    class.push_str(&regex_escape(parse_char(cursor)?));
+                    if cursor.rest().starts_with('-') && !cursor.rest().starts_with("-]") {
+                        cursor.bump();
+                        class.push('-');
+                        class.push_str(&regex_escape(parse_char(cursor)?));
+                    }
+                }
+                class.push(']');
+                Expr::Regex(class)
+            }
+            Some('.') => {
+                cursor.bump();
+                Expr::Regex("(?s:.)".to_string())
+            }
+            Some('(') => {
+                cursor.bump();
+                skip_space(cursor, true);
+                let expr = parse_alternatives(cursor, true)?;
+                cursor.expect(")")?;
+                expr
+            }
+            Some(ch) if is_name_char(ch) => {
+                let pos = cursor.pos();
+                Expr::Rule(parse_name(cursor)?, pos)
+            }
+            _ => break,
+        };
+        items.push(item);
+        skip_space(cursor, nested);
+
+        loop {
+            let pos = cursor.pos();
+            let (min, max) = match cursor.peek() {
+                Some('*') => (0, None),
+                Some('+') => (1, None),
+                Some('?') => (0, Some(1)),
+                Some('{') => {
+                    cursor.bump();
+                    skip_space(cursor, nested);
+                    let min = cursor.number()?;
+                    skip_space(cursor, nested);
+                    let max = if cursor.eat(",") {
+                        skip_space(cursor, nested);
+                        if cursor.peek() == Some('}') {
+                            None
+                        } else {
+                            Some(cursor.number()?)
+                        }
+                    } else {
+                        Some(min)
+                    };
+                    skip_space(cursor, nested);
+                    if cursor.peek() != Some('}') {
+                        return Err(cursor.error("Expected `}`."));
+                    }
+                    if max.is_some_and(|max| max < min) {
+                        return Err(pos.error("The maximum repetitions are less than the minimum."));
+                    }
+                    (min, max)
+                }
+                _ => break,
+            };
+            cursor.bump();
+            skip_space(cursor, nested);
+            // NOTE(EricLBuehler): Unwrap reasoning: An item was just pushed.
+            let item = items.pop().unwrap();
+            items.push(item.repeat(min, max));
+        }
+    }
+    Ok(Expr::seq(items))
+}
+
+/// Parse a character of a literal or a character class.
+fn parse_char(cursor: &mut Cursor) -> Result<char, GrammarError> {
+    let pos = cursor.pos();
+    match cursor.expect_char()? {
+        '\\' => match cursor.expect_char()? {
+            'x' => cursor.hex_char(2),
+            'u' => cursor.hex_char(4),
+            'U' => cursor.hex_char(8),
+            't' => Ok('\t'),
+            'r' => Ok('\r'),
+            'n' => Ok('\n'),
+            ch @ ('\\' | '"' | '[' | ']') => Ok(ch),
+            ch => Err(pos.error(format!("Unknown escape `\\{ch}`."))),
+        },
+        '\n' => Err(pos.error("Unterminated literal or character class.")),
+        ch => Ok(ch),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::gbnf_to_yacc;
+    use crate::{aici::cfg::CfgParser, grammars::tests::accepts};
+
+    fn gbnf_accepts(gbnf: &str, text: &str) -> bool {
+        let yacc = gbnf_to_yacc(gbnf).unwrap();
+        accepts(
+            CfgParser::from_yacc_with_viable_tokens(&yacc).unwrap(),
+            text,
+        )
+    }
+
+    #[test]
+    fn literals_and_char_classes() {
+        let gbnf = "root ::= \"ab\\n\" [0-9a-f] [^x] \"\\x41\"";
+        assert!(gbnf_accepts(gbnf, "ab\n7zA"));
+        assert!(gbnf_accepts(gbnf, "ab\nf A"));
+        assert!(!gbnf_accepts(gbnf, "ab\ngzA"));
+        assert!(!gbnf_accepts(gbnf, "ab\n7xA"));
+        assert!(!gbnf_accepts(gbnf, "ab\n7z"));
+    }
+
+    #[test]
+    fn repetitions_and_groups() {
+        let gbnf = r#"
+# A comment.
+root ::= item+ ("," item){0,2} end?
+item ::= ( "x" | "y" ) [0-9]*
+end  ::= ";"
+"#;
+        assert!(gbnf_accepts(gbnf, "x"));
+        assert!(gbnf_accepts(gbnf, "xy1,y22,x;"));
+        assert!(!gbnf_accepts(gbnf, ""));
+        assert!(!gbnf_accepts(gbnf, "x,x,x,x"));
+        assert!(!gbnf_accepts(gbnf, "x;;"));
+        assert!(!gbnf_accepts(gbnf, "z"));
+    }
+
+    #[test]
+    fn rules_may_continue_after_alternatives() {
+        let gbnf = "root ::= \"a\" |\n  \"b\"\n";
+        assert!(gbnf_accepts(gbnf, "a"));
+        assert!(gbnf_accepts(gbnf, "b"));
+    }
+
+    #[test]
+    fn unknown_rules_are_errors() {
+        let err = gbnf_to_yacc("root ::= item\nitem ::= \"a\" other").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "line 2, column 14: The rule `other` is not defined."
+        );
+        let err = gbnf_to_yacc("item ::= \"a\"").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "line 1, column 1: There is no `root` rule."
+        );
+    }
+
+    #[test]
+    fn syntax_errors_have_positions() {
+        let err = gbnf_to_yacc("root ::= \"a\"\nitem := \"b\"").unwrap_err();
+        assert_eq!(err.to_string(), "line 2, column 6: Expected `::=`.");
+        let err = gbnf_to_yacc("root ::= \"a\\q\"").unwrap_err();
+        assert_eq!(err.to_string(), "line 1, column 12: Unknown escape `\\q`.");
+        let err = gbnf_to_yacc("root ::= \"a\"{3,2}").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "line 1, column 13: The maximum repetitions are less than the minimum."
+        );
+    }
+}
@@ -0,0 +1,540 @@
+use std::collections::{BTreeSet, HashMap};
+
+use anyhow::{bail, Context, Result};
+use regex_syntax::{
+    hir::{
+        Capture, Class, ClassBytes, ClassBytesRange, ClassUnicode, ClassUnicodeRange, Hir, HirKind,
+        Literal, Look, Repetition,
+    },
+    utf8::Utf8Sequences,
+};
+use serde_json::{Map, Value};
+
+use super::{literal_token, regex_token};
+
+/// One character of a JSON string: an escape sequence, or a character which is not escaped. The lexer matches
+/// bytes, so non-ASCII characters are matched by their UTF-8 encodings.
+const STRING_CHAR: &str = r#"(\\(["\\/bfnrt]|u[0-9a-fA-F]{4})|[^"\\\x00-\x1f\x80-\xff]|[\xc0-\xdf][\x80-\xbf]|[\xe0-\xef][\x80-\xbf]{2}|[\xf0-\xf7][\x80-\xbf]{3})"#;
+
+/// Rules for the JSON values which are not constrained by the schema.
+const BASE_RULES: [(&str, &str); 9] = [
+    (
+        "STRING",
+        r#"STRING: '/"(\\(["\\/bfnrt]|u[0-9a-fA-F]{4})|[^"\\\x00-\x1f\x80-\xff]|[\xc0-\xdf][\x80-\xbf]|[\xe0-\xef][\x80-\xbf]{2}|[\xf0-\xf7][\x80-\xbf]{3})*"/' ;"#,
+    ),
+    (
+        "NUMBER",
+        r#"NUMBER: "/-?(0|[1-9][0-9]*)(\.[0-9]+)?([eE][+-]?[0-9]+)?/" ;"#,
+    ),
+    ("INTEGER", r#"INTEGER: "/-?(0|[1-9][0-9]*)/" ;"#),
+    (
+        "value",
+        r#"value: object | array | STRING | NUMBER | "true" | "false" | "null" ;"#,
+    ),
+    ("object", r#"object: "{" "}" | "{" members "}" ;"#),
+    ("members", r#"members: member | members "," member ;"#),
+    ("member", r#"member: STRING ":" value ;"#),
+    ("array", r#"array: "[" "]" | "[" values "]" ;"#),
+    ("values", r#"values: value | values "," value ;"#),
+];
+
+/// Compile a JSON schema into a yacc grammar which only accepts JSON documents valid under the schema.
+///
+/// The supported keywords are `type` (also a list of types), `properties`, `required`, `additionalProperties`
+/// without `properties`, `items`, `minItems`, `pattern`, `minLength`, `maxLength`, `enum`, `const`, `anyOf`,
+/// `oneOf`, `allOf` with one schema, and local `$ref`s. Other keywords are ignored. Object properties are
+/// generated in the order of the schema, and properties not in `properties` are not generated. A `pattern` only
+/// matches the characters which are not escaped in JSON strings.
+pub(crate) fn json_schema_to_yacc(schema: &Value) -> Result<String> {
+    let mut compiler = SchemaCompiler {
+        root: schema,
+        rules: Vec::new(),
+        refs: HashMap::new(),
+        base_rules: BTreeSet::new(),
+    };
+    let start = compiler.compile(schema)?;
+
+    let mut grammar = format!("%start {start}\n%%\n\nSKIP: \"/[ \\t\\n\\r]*/\" ;\n");
+    // The base rules refer to each other.
+    if compiler
+        .base_rules
+        .iter()
+        .any(|rule| ["value", "object", "members", "member", "array", "values"].contains(rule))
+    {
+        compiler.base_rules.extend([
+            "value", "object", "members", "member", "array", "values", "STRING", "NUMBER",
+        ]);
+    }
+    for (name, rule) in BASE_RULES {
+        if compiler.base_rules.contains(name) {
+            grammar.push('\n');
+            grammar.push_str(rule);
+            grammar.push('\n');
+        }
+    }
+    for rule in compiler.rules {
+        grammar.push('\n');
+        grammar.push_str(&rule);
+        grammar.push('\n');
+    }
+    Ok(grammar)
+}
+
+struct SchemaCompiler<'a> {
+    root: &'a Value,
+    rules: Vec<String>,
+    // The rules of the `$ref`s which were already compiled, so that recursive schemas terminate.
+    refs: HashMap<String, String>,
+    base_rules: BTreeSet<&'static str>,
+}
+
+impl<'a> SchemaCompiler<'a> {
+    /// Add a rule with these alternatives, each a sequence of symbols. Returns the name of the rule.
+    fn add_rule(&mut self, kind: &str, alternatives: Vec<String>) -> String {
+        let name = format!("{kind}_{}", self.rules.len());
+        self.rules
+            .push(format!("{name}: {} ;", alternatives.join(" | ")));
+        name
+    }
+
+    /// Reserve the name of a rule which is added later with `set_rule`.
+    fn reserve_rule(&mut self, kind: &str) -> (usize, String) {
+        let idx = self.rules.len();
+        let name = format!("{kind}_{idx}");
+        self.rules.push(String::new());
+        (idx, name)
+    }
+
+    fn set_rule(&mut self, idx: usize, name: &str, alternatives: Vec<String>) {
+        self.rules[idx] = format!("{name}: {} ;", alternatives.join(" | "));
+    }
+
+    fn base(&mut self, name: &'static str) -> String {
+        self.base_rules.insert(name);
+        name.to_string()
+    }
+
+    /// Compile a schema and return the symbol which matches it.
+    fn compile(&mut self, schema: &'a Value) -> Result<String> {
+        let schema = match schema {
+            Value::Bool(true) => return Ok(self.base("value")),
+            Value::Bool(false) => bail!("The schema `false` does not accept any value."),
+            Value::Object(schema) => schema,
+            other => bail!("Invalid schema `{other}`."),
+        };
+
+        if let Some(reference) = schema.get("$ref") {
+            return self.compile_ref(reference);
+        }
+        if let Some(value) = schema.get("const") {
+            return Ok(self.add_rule("const", vec![literal_token(&value.to_string())]));
+        }
+        if let Some(values) = schema.get("enum") {
+            let Value::Array(values) = values else {
+                bail!("`enum` must be a list.");
+            };
+            if values.is_empty() {
+                bail!("`enum` must not be empty.");
+            }
+            let alternatives = values
+                .iter()
+                .map(|value| literal_token(&value.to_string()))
+                .collect();
+            return Ok(self.add_rule("enum", alternatives));
+        }
+        for keyword in ["anyOf", "oneOf"] {
+            if let Some(schemas) = schema.get(keyword) {
+                let Value::Array(schemas) = schemas else {
+                    bail!("`{keyword}` must be a list.");
+                };
+                let alternatives = schemas
+                    .iter()
+                    .map(|schema| self.compile(schema))
+                    .collect::<Result<Vec<_>>>()?;
+                return Ok(self.add_rule("any_of", alternatives));
+            }
+        }
+        if let Some(schemas) = schema.get("allOf") {
+            match schemas {
+                Value::Array(schemas) if schemas.len() == 1 => return self.compile(&schemas[0]),
+                _ => bail!("`allOf` is only supported with one schema."),
+            }
+        }
+
+        match schema.get("type") {
+            Some(Value::String(tp)) => self.compile_type(tp, schema),
+            Some(Value::Array(types)) => {
+                let alternatives = types
+                    .iter()
+                    .map(|tp| match tp {
+                        Value::String(tp) => self.compile_type(tp, schema),
+                        other => bail!("Invalid type `{other}`."),
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+                Ok(self.add_rule("types", alternatives))
+            }
+            Some(other) => bail!("Invalid type `{other}`."),
+            None if schema.contains_key("properties") => self.compile_type("object", schema),
+            None if schema.contains_key("items") => self.compile_type("array", schema),
+            None => Ok(self.base("value")),
+        }
+    }
+
+    fn compile_ref(&mut self, reference: &Value) -> Result<String> {
+        let Some(reference) = reference.as_str() else {
+            bail!("`$ref` must be a string.");
+        };
+        if let Some(name) = self.refs.get(reference) {
+            return Ok(name.clone());
+        }
+        let Some(pointer) = reference.strip_prefix('#') else {
+            bail!("Only local `$ref`s are supported, not `{reference}`.");
+        };
+        let target = self
+            .root
+            .pointer(pointer)
+            .with_context(|| format!("`$ref` `{reference}` does not exist."))?;
+        let (idx, name) = self.reserve_rule("ref");
+        self.refs.insert(reference.to_string(), name.clone());
+        let symbol = self.compile(target)?;
+        self.set_rule(idx, &name, vec![symbol]);
+        Ok(name)
+    }
+
+    fn compile_type(&mut self, tp: &str, schema: &'a Map<String, Value>) -> Result<String> {
+        match tp {
+            "object" => self.compile_object(schema),
+            "array" => self.compile_array(schema),
+            "string" => self.compile_string(schema),
+            "number" => Ok(self.base("NUMBER")),
+            "integer" => Ok(self.base("INTEGER")),
+            "boolean" => Ok(self.add_rule(
+                "boolean",
+                vec!["\"true\"".to_string(), "\"false\"".to_string()],
+            )),
+            "null" => Ok(self.add_rule("null", vec!["\"null\"".to_string()])),
+            other => bail!("Unknown type `{other}`."),
+        }
+    }
+
+    fn compile_object(&mut self, schema: &'a Map<String, Value>) -> Result<String> {
+        let Some(properties) = schema.get("properties") else {
+            return match schema.get("additionalProperties") {
+                Some(Value::Object(_)) => {
+                    // NOTE(EricLBuehler): Unwrap reasoning: It was just checked to be there.
+                    let value = self.compile(schema.get("additionalProperties").unwrap())?;
+                    self.base("STRING");
+                    let member = self.add_rule("member", vec![format!("STRING \":\" {value}")]);
+                    let (idx, members) = self.reserve_rule("members");
+                    self.set_rule(
+                        idx,
+                        &members,
+                        vec![member.clone(), format!("{members} \",\" {member}")],
+                    );
+                    Ok(self.add_rule(
+                        "object",
+                        vec![
+                            "\"{\" \"}\"".to_string(),
+                            format!("\"{{\" {members} \"}}\""),
+                        ],
+                    ))
+                }
+                _ => Ok(self.base("object")),
+            };
+        };
+        let Value::Object(properties) = properties else {
+            bail!("`properties` must be an object.");
+        };
+        let required = match schema.get("required") {
+            Some(Value::Array(required)) => required
+                .iter()
+                .filter_map(|name| name.as_str())
+                .collect::<Vec<_>>(),
+            Some(_) => bail!("`required` must be a list."),
+            None => Vec::new(),
+        };
+
+        let mut members = Vec::new();
+        for (name, property) in properties {
+            let value = self.compile(property)?;
+            members.push((
+                format!(
+                    "{} \":\" {value}",
+                    literal_token(&Value::String(name.clone()).to_string())
+                ),
+                required.contains(&name.as_str()),
+            ));
+        }
+
+        // The members from the i-th property on are generated by `first[i]` if no member was generated before,
+        // and otherwise by `rest[i]`, which starts with a comma. Optional properties may be skipped.
+        let mut first = String::new();
+        let mut rest = String::new();
+        for (i, (member, is_required)) in members.into_iter().enumerate().rev() {
+            let mut first_alternatives = vec![format!("{member} {rest}")];
+            let mut rest_alternatives = vec![format!("\",\" {member} {rest}")];
+            if !is_required {
+                first_alternatives.push(first.clone());
+                rest_alternatives.push(rest.clone());
+            }
+            first = self.add_rule(&format!("first_{i}"), first_alternatives);
+            rest = self.add_rule(&format!("rest_{i}"), rest_alternatives);
+        }
+        Ok(self.add_rule("object", vec![format!("\"{{\" {first} \"}}\"")]))
+    }
+
+    fn compile_array(&mut self, schema: &'a Map<String, Value>) -> Result<String> {
+        let item = match schema.get("items") {
+            Some(items) => self.compile(items)?,
+            None => self.base("value"),
+        };
+        let min_items = schema
+            .get("minItems")
+            .and_then(|min_items| min_items.as_u64())
+            .unwrap_or(0);
+
+        let (idx, items) = self.reserve_rule("items");
+        self.set_rule(
+            idx,
+            &items,
+            vec![item.clone(), format!("{items} \",\" {item}")],
+        );
+        let mut alternatives = Vec::new();
+        if min_items == 0 {
+            alternatives.push("\"[\" \"]\"".to_string());
+        }
+        // The first `min_items - 1` items are required, and the list of items adds at least one more.
+        let required = (1..min_items)
+            .map(|_| format!("{item} \",\""))
+            .collect::<Vec<_>>()
+            .join(" ");
+        alternatives.push(format!("\"[\" {required} {items} \"]\""));
+        Ok(self.add_rule("array", alternatives))
+    }
+
+    fn compile_string(&mut self, schema: &'a Map<String, Value>) -> Result<String> {
+        if let Some(pattern) = schema.get("pattern") {
+            let Some(pattern) = pattern.as_str() else {
+                bail!("`pattern` must be a string.");
+            };
+            let hir = regex_syntax::parse(pattern)
+                .with_context(|| format!("Invalid `pattern` `{pattern}`."))?;
+            let rx = unescaped_rx(&hir)
+                .with_context(|| format!("Unsupported `pattern` `{pattern}`."))?;
+            return Ok(self.add_rule("string", vec![regex_token(&format!("\"({rx})\""))]));
+        }
+        let min_length = schema.get("minLength").and_then(|len| len.as_u64());
+        let max_length = schema.get("maxLength").and_then(|len| len.as_u64());
+        if min_length.is_none() && max_length.is_none() {
+            return Ok(self.base("STRING"));
+        }
+        let repeat = format!(
+            "{{{},{}}}",
+            min_length.unwrap_or(0),
+            max_length.map(|len| len.to_string()).unwrap_or_default()
+        );
+        Ok(self.add_rule("string", vec![format!("'/\"{STRING_CHAR}{repeat}\"/'")]))
+    }
+}
+
+/// Rewrite a `pattern` so that it only matches the characters which are not escaped in JSON strings, which keeps
+/// it from matching the closing quote, and so that the lexer can match it on bytes. The pattern matches the whole
+/// string, so `^` and `$` are removed.
+fn unescaped_rx(hir: &Hir) -> Result<Hir> {
+    let is_escaped = |byte: &u8| *byte < 0x20 || *byte == b'"' || *byte == b'\\';
+    let sanitized = match hir.kind() {
+        HirKind::Look(Look::Start | Look::End) => Hir::empty(),
+        HirKind::Empty | HirKind::Look(_) => hir.clone(),
+        HirKind::Literal(Literal(bytes)) => {
+            if bytes.iter().any(is_escaped) {
+                bail!("Quotes, backslashes and control characters cannot be matched.");
+            }
+            hir.clone()
+        }
+        HirKind::Class(Class::Unicode(class)) => {
+            let mut class = class.clone();
+            class.difference(&ClassUnicode::new([
+                ClassUnicodeRange::new('\0', '\x1f'),
+                ClassUnicodeRange::new('"', '"'),
+                ClassUnicodeRange::new('\\', '\\'),
+            ]));
+            // The lexer matches bytes, so the characters are matched by their UTF-8 encodings.
+            let sequences = class
+                .iter()
+                .flat_map(|range| Utf8Sequences::new(range.start(), range.end()))
+                .map(|sequence| {
+                    let bytes = sequence.as_slice().iter().map(|range| {
+                        let range = ClassBytesRange::new(range.start, range.end);
+                        Hir::class(Class::Bytes(ClassBytes::new([range])))
+                    });
+                    Hir::concat(bytes.collect())
+                });
+            Hir::alternation(sequences.collect())
+        }
+        HirKind::Class(Class::Bytes(class)) => {
+            let mut class = class.clone();
+            class.difference(&ClassBytes::new([
+                ClassBytesRange::new(0, 0x1f),
+                ClassBytesRange::new(b'"', b'"'),
+                ClassBytesRange::new(b'\\', b'\\'),
+            ]));
+            Hir::class(Class::Bytes(class))
+        }
+        HirKind::Repetition(repetition) => Hir::repetition(Repetition {
+            sub: Box::new(unescaped_rx(&repetition.sub)?),
+            ..repetition.clone()
+        }),
+        HirKind::Capture(capture) => Hir::capture(Capture {
+            sub: Box::new(unescaped_rx(&capture.sub)?),
+            ..capture.clone()
+        }),
+        HirKind::Concat(subs) => {
+            Hir::concat(subs.iter().map(unescaped_rx).collect::<Result<Vec<_>>>()?)
+        }
+        HirKind::Alternation(subs) => {
+            Hir::alternation(subs.iter().map(unescaped_rx).collect::<Result<Vec<_>>>()?)
+        }
+    };
+    Ok(sanitized)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::json_schema_to_yacc;
+    use crate::{aici::cfg::CfgParser, grammars::tests::accepts};
+
+    fn schema_accepts(schema: serde_json::Value, text: &str) -> bool {
+        let yacc = json_schema_to_yacc(&schema).unwrap();
+        accepts(CfgParser::from_yacc(&yacc).unwrap(), text)
+    }
+
+    #[test]
+    fn properties_keep_their_order_and_may_skip_optional_ones() {
+        let schema = json!({
+            "type": "object",
+            "properties": {
+                "a": {"type": "integer"},
+                "b": {"type": "string"},
+                "c": {"type": "boolean"}
+            },
+            "required": ["b"]
+        });
+        assert!(schema_accepts(
+            schema.clone(),
+            r#"{"a": 1, "b": "x", "c": true}"#
+        ));
+        assert!(schema_accepts(schema.clone(), r#"{"b": "x"}"#));
+        assert!(schema_accepts(schema.clone(), r#"{"a": -2, "b": "x"}"#));
+        assert!(schema_accepts(schema.clone(), r#"{"b": "x", "c": false}"#));
+        assert!(!schema_accepts(schema.clone(), r#"{"a": 1}"#));
+        assert!(!schema_accepts(schema.clone(), r#"{"b": "x", "a": 1}"#));
+        assert!(!schema_accepts(schema.clone(), r#"{"b": "x",}"#));
+        assert!(!schema_accepts(schema, "{}"));
+    }
+
+    #[test]
+    fn objects_without_required_properties_may_be_empty() {
+        let schema = json!({"properties": {"a": {"type": "null"}}});
+        assert!(schema_accepts(schema.clone(), "{}"));
+        assert!(schema_accepts(schema, r#"{"a": null}"#));
+    }
+
+    #[test]
+    fn arrays_have_at_least_min_items() {
+        let schema = json!({"type": "array", "items": {"type": "integer"}, "minItems": 2});
+        assert!(schema_accepts(schema.clone(), "[1, 2]"));
+        assert!(schema_accepts(schema.clone(), "[1, 2, 3]"));
+        assert!(!schema_accepts(schema.clone(), "[1]"));
+        assert!(!schema_accepts(schema.clone(), "[]"));
+        assert!(!schema_accepts(schema, r#"[1, "2"]"#));
+    }
+
+    #[test]
+    fn enum_and_const_only_accept_their_values() {
+        let schema = json!({"enum": ["red", 1, null]});
+        assert!(schema_accepts(schema.clone(), r#""red""#));
+        assert!(schema_accepts(schema.clone(), "1"));
+        assert!(schema_accepts(schema.clone(), "null"));
+        assert!(!schema_accepts(schema.clone(), r#""blue""#));
+        assert!(!schema_accepts(schema, "2"));
+
+        let schema = json!({"const": {"a": [1, "b"]}});
+        assert!(schema_accepts(schema.clone(), r#"{"a":[1,"b"]}"#));
+        assert!(!schema_accepts(schema, r#"{"a":[1,"c"]}"#));
+    }
+
+    #[test]
+    fn recursive_refs_terminate() {
+        let schema = json!({
+            "$defs": {
+                "node": {
+                    "type": "object",
+                    "properties": {
+                        "value": {"type": "integer"},
+                        "next": {"anyOf": [{"$ref": "#/$defs/node"}, {"type": "null"}]}
+                    },
+                    "required": ["value", "next"]
+                }
+            },
+            "$ref": "#/$defs/node"
+        });
+        assert!(schema_accepts(
+            schema.clone(),
+            r#"{"value": 1, "next": {"value": 2, "next": null}}"#
+        ));
+        assert!(!schema_accepts(
+            schema.clone(),
+            r#"{"value": 1, "next": {"value": 2}}"#
+        ));
+        assert!(json_schema_to_yacc(&json!({"$ref": "#/$defs/missing"})).is_err());
+    }
+
+    #[test]
+    fn patterns_match_the_whole_string() {
+        let schema = json!({"type": "string", "pattern": "^[a-z]+-[0-9]{2}$"});
+        assert!(schema_accepts(schema.clone(), r#""ab-12""#));
+        assert!(!schema_accepts(schema.clone(), r#""ab-1""#));
+        assert!(!schema_accepts(schema, r#""AB-12""#));
+
+        let schema = json!({"type": "string", "pattern": "^[a-zé]{2}$"});
+        assert!(schema_accepts(schema.clone(), r#""éa""#));
+        assert!(!schema_accepts(schema, r#""aéa""#));
+    }
+
+    #[test]
+    fn patterns_do_not_match_escaped_characters() {
+        let schema = json!({"type": "string", "pattern": ".*"});
+        assert!(schema_accepts(schema.clone(), r#""a b""#));
+        assert!(!schema_accepts(schema.clone(), r#""a"b""#));
+        assert!(!schema_accepts(schema.clone(), "\"a\nb\""));
+        assert!(!schema_accepts(schema, r#""a\b""#));
+
+        let schema = json!({"type": "string", "pattern": "[^a]+"});
+        assert!(!schema_accepts(schema, r#""b"b""#));
+
+        assert!(json_schema_to_yacc(&json!({"type": "string", "pattern": "a\"b"})).is_err());
+        assert!(json_schema_to_yacc(&json!({"type": "string", "pattern": "("})).is_err());
+    }
+
+    #[test]
+    fn strings_only_contain_valid_escapes() {
+        let schema = json!({"type": "string"});
+        assert!(schema_accepts(schema.clone(), r#""a\"b\\c\né""#));
+        assert!(!schema_accepts(schema.clone(), r#""a\qb""#));
+        assert!(!schema_accepts(schema.clone(), r#""\u00e""#));
+        assert!(!schema_accepts(schema.clone(), "\"a\nb\""));
+        assert!(!schema_accepts(schema, "\"a\tb\""));
+    }
+
+    #[test]
+    fn string_lengths_count_escapes_as_one_char() {
+        let schema = json!({"type": "string", "minLength": 2, "maxLength": 3});
+        assert!(schema_accepts(schema.clone(), r#""ab""#));
+        assert!(schema_accepts(schema.clone(), r#""é\n\"""#));
+        assert!(!schema_accepts(schema.clone(), r#""é""#));
+        assert!(!schema_accepts(schema.clone(), r#""abcd""#));
+        assert!(!schema_accepts(schema, "\"a\nb\""));
+    }
+}
@@ -0,0 +1,528 @@
+//! Lark EBNF grammars.
+
+use std::collections::HashMap;
+
+use super::{
+    ebnf::{rules_to_yacc, Cursor, Expr, GrammarError, Pos, Rule},
+    regex_escape,
+};
+
+/// The terminals of Lark's `common` grammar, as regexes.
+const COMMON_TERMINALS: [(&str, &str); 23] = [
+    ("DIGIT", "[0-9]"),
+    ("HEXDIGIT", "[a-fA-F0-9]"),
+    ("INT", "[0-9]+"),
+    ("SIGNED_INT", "[+-]?[0-9]+"),
+    ("DECIMAL", r"[0-9]+\.[0-9]*|\.[0-9]+"),
+    (
+        "FLOAT",
+        r"[0-9]+[eE][+-]?[0-9]+|([0-9]+\.[0-9]*|\.[0-9]+)([eE][+-]?[0-9]+)?",
+    ),
+    (
+        "SIGNED_FLOAT",
+        r"[+-]?([0-9]+[eE][+-]?[0-9]+|([0-9]+\.[0-9]*|\.[0-9]+)([eE][+-]?[0-9]+)?)",
+    ),
+    (
+        "NUMBER",
+        r"[0-9]+[eE][+-]?[0-9]+|([0-9]+\.[0-9]*|\.[0-9]+)([eE][+-]?[0-9]+)?|[0-9]+",
+    ),
+    (
+        "SIGNED_NUMBER",
+        r"[+-]?([0-9]+[eE][+-]?[0-9]+|([0-9]+\.[0-9]*|\.[0-9]+)([eE][+-]?[0-9]+)?|[0-9]+)",
+    ),
+    ("ESCAPED_STRING", r#""(\\.|[^\\"])*""#),
+    ("LCASE_LETTER", "[a-z]"),
+    ("UCASE_LETTER", "[A-Z]"),
+    ("LETTER", "[a-zA-Z]"),
+    ("WORD", "[a-zA-Z]+"),
+    ("CNAME", "[_a-zA-Z][_a-zA-Z0-9]*"),
+    ("WS_INLINE", r"[ \t]+"),
+    ("WS", r"[ \t\f\r\n]+"),
+    ("CR", r"\r"),
+    ("LF", r"\n"),
+    ("NEWLINE", r"(\r?\n)+"),
+    ("SH_COMMENT", "#[^\n]*"),
+    ("CPP_COMMENT", "//[^\n]*"),
+    ("C_COMMENT", r"/\*([^*]|\*+[^*/])*\*+/"),
+];
+
+/// Compile a Lark grammar into a yacc grammar. The grammar starts with the `start` rule.
+///
+/// Terminals, whose names are uppercase, are compiled into a single regex token each. `%ignore` and
+/// `%import common` are supported, rule templates and other directives are not. Aliases, priorities and the
+/// `?` and `!` rule modifiers do not change what the grammar accepts, so they are ignored.
+pub(crate) fn lark_to_yacc(src: &str) -> Result<String, GrammarError> {
+    let mut cursor = Cursor::new(src);
+    let mut rules = Vec::new();
+    let mut terminals = HashMap::new();
+    let mut ignored = Vec::new();
+    loop {
+        skip_space(&mut cursor, true);
+        if cursor.is_done() {
+            break;
+        }
+        let pos = cursor.pos();
+        if cursor.eat("%") {
+            let directive = cursor.take_while(|ch| ch.is_ascii_alphabetic());
+            skip_space(&mut cursor, false);
+            match directive {
+                "ignore" => ignored.push(parse_expansions(&mut cursor, false)?),
+                "import" => parse_import(&mut cursor, &mut terminals)?,
+                other => {
+                    return Err(pos.error(format!("The directive `%{other}` is not supported.")))
+                }
+            }
+        } else {
+            // The modifiers only change the parse tree.
+            if !cursor.eat("?") {
+                cursor.eat("!");
+            }
+            let name = parse_name(&mut cursor)?;
+            if cursor.eat(".") {
+                cursor.eat("-");
+                if cursor.take_while(|ch| ch.is_ascii_digit()).is_empty() {
+                    return Err(cursor.error("Expected a priority."));
+                }
+            }
+            if cursor.peek() == Some('{') {
+                return Err(cursor.error("Rule templates are not supported."));
+            }
+            skip_space(&mut cursor, false);
+            cursor.expect(":")?;
+            skip_space(&mut cursor, false);
+            let expr = parse_expansions(&mut cursor, false)?;
+            if is_terminal(&name) {
+                if terminals.insert(name.clone(), (pos, expr)).is_some() {
+                    return Err(pos.error(format!("The terminal `{name}` is defined twice.")));
+                }
+            } else {
+                rules.push(Rule { name, pos, expr });
+            }
+        }
+        skip_space(&mut cursor, false);
+        if !cursor.is_done() && !cursor.eat("\n") {
+            return Err(cursor.error("Expected a newline after the definition."));
+        }
+    }
+
+    let mut compiler = TerminalCompiler {
+        terminals: &terminals,
+        regexes: HashMap::new(),
+        in_progress: Vec::new(),
+    };
+    for rule in &mut rules {
+        compiler.resolve(&mut rule.expr)?;
+    }
+    let skip = ignored
+        .iter()
+        .map(|expr| compiler.regex(expr))
+        .collect::<Result<Vec<_>, _>>()?;
+    rules_to_yacc(&rules, "start", &skip)
+}
+
+fn is_terminal(name: &str) -> bool {
+    name.trim_start_matches('_')
+        .starts_with(|ch: char| ch.is_ascii_uppercase())
+}
+
+fn is_name_char(ch: char) -> bool {
+    ch.is_ascii_alphanumeric() || ch == '_'
+}
+
+/// Skip spaces and comments, and newlines if they are allowed here.
+fn skip_space(cursor: &mut Cursor, newlines: bool) {
+    loop {
+        match cursor.peek() {
+            Some(' ' | '\t' | '\r') => {
+                cursor.bump();
+            }
+            Some('\n') if newlines => {
+                cursor.bump();
+            }
+            Some('/') if cursor.rest().starts_with("//") => {
+                cursor.take_while(|ch| ch != '\n');
+            }
+            _ => break,
+        }
+    }
+}
+
+fn parse_name(cursor: &mut Cursor) -> Result<String, GrammarError> {
+    let name = cursor.take_while(is_name_char);
+    if name.is_empty() {
+        return Err(cursor.error("Expected a name."));
+    }
+    Ok(name.to_string())
+}
+
+/// Parse `%import common.NAME`, `%import common.NAME -> ALIAS` or `%import common (NAME, ...)`.
+fn parse_import(
+    cursor: &mut Cursor,
+    terminals: &mut HashMap<String, (Pos, Expr)>,
+) -> Result<(), GrammarError> {
+    let pos = cursor.pos();
+    let module = cursor.take_while(|ch| is_name_char(ch) || ch == '.');
+    let mut imports = Vec::new();
+    match module.strip_prefix("common.") {
+        Some(name) => {
+            skip_space(cursor, false);
+            let alias = if cursor.eat("->") {
+                skip_space(cursor, false);
+                parse_name(cursor)?
+            } else {
+                name.to_string()
+            };
+            imports.push((pos, name.to_string(), alias));
+        }
+        None if module == "common" => {
+            skip_space(cursor, false);
+            cursor.expect("(")?;
+            loop {
+                skip_space(cursor, true);
+                let pos = cursor.pos();
+                let name = parse_name(cursor)?;
+                imports.push((pos, name.clone(), name));
+                skip_space(cursor, true);
+                if !cursor.eat(",") {
+                    break;
+                }
+            }
+            cursor.expect(")")?;
+        }
+        None => {
+            return Err(pos.error(format!(
+                "Only the `common` grammar can be imported, not `{module}`."
+            )))
+        }
+    }
+    for (pos, name, alias) in imports {
+        let Some((_, rx)) = COMMON_TERMINALS.iter().find(|(common, _)| *common == name) else {
+            return Err(pos.error(format!("`common` has no terminal `{name}`.")));
+        };
+        if terminals
+            .insert(alias.clone(), (pos, Expr::Regex(rx.to_string())))
+            .is_some()
+        {
+            return Err(pos.error(format!("The terminal `{alias}` is defined twice.")));
+        }
+    }
+    Ok(())
+}
+
+/// Parse alternatives, which may continue on the next lines if those start with `|`.
+fn parse_expansions(cursor: &mut Cursor, nested: bool) -> Result<Expr, GrammarError> {
+    let mut alternatives = vec![parse_expansion(cursor, nested)?];
+    loop {
+        let mut lookahead = cursor.clone();
+        skip_space(&mut lookahead, true);
+        if !lookahead.eat("|") {
+            break;
+        }
+        skip_space(&mut lookahead, nested);
+        *cursor = lookahead;
+        alternatives.push(parse_expansion(cursor, nested)?);
+    }
+    Ok(Expr::alt(alternatives))
+}
+
+fn parse_expansion(cursor: &mut Cursor, nested: bool) -> Result<Expr, GrammarError> {
+    let mut items = Vec::new();
+    loop {
+        let pos = cursor.pos();
+        let item = match cursor.peek() {
+            Some('"') => {
+                let text = parse_string(cursor)?;
+                if cursor.eat("..") {
+                    let end = parse_string(cursor)?;
+                    let (mut start_chars, mut end_chars) = (text.chars(), end.chars());
+                    match (
+                        start_chars.next(),
+                        start_chars.next(),
+                        end_chars.next(),
+                        end_chars.next(),
+                    ) {
+                        (Some(start), None, Some(end), None) => {
+                            Expr::Regex(format!("[{}-{}]", regex_escape(start), regex_escape(end)))
+                        }
+                        _ => return Err(pos.error("A range must be between single characters.")),
+                    }
+                } else if cursor.eat("i") {
+                    let escaped = text.chars().map(regex_escape).collect::<String>();
+                    Expr::Regex(format!("(?i:{escaped})"))
+                } else {
+                    Expr::Literal(text)
+                }
+            }
+            Some('/') if !cursor.rest().starts_with("//") => {
+                cursor.bump();
+                let mut rx = String::new();
+                loop {
+                    match cursor.expect_char()? {
+                        '/' => break,
+                        '\n' => return Err(pos.error("Unterminated regex.")),
+                        '\\' if cursor.peek() == Some('/') => {
+                            cursor.bump();
+                            rx.push('/');
+                        }
+                        '\\' => {
+                            rx.push('\\');
+                            rx.push(cursor.expect_char()?);
+                        }
+                        ch => rx.push(ch),
+                    }
+                }
+                let flags = cursor.take_while(|ch| "imslux".contains(ch));
+                if flags.is_empty() {
+                    Expr::Regex(rx)
+                } else {
+                    Expr::Regex(format!("(?{flags}:{rx})"))
+                }
+            }
+            Some('(') => {
+                cursor.bump();
+                skip_space(cursor, true);
+                let expr = parse_expansions(cursor, true)?;
+                skip_space(cursor, true);
+                cursor.expect(")")?;
+                expr
+            }
+            Some('[') => {
+                cursor.bump();
+                skip_space(cursor, true);
+                let expr = parse_expansions(cursor, true)?;
+                skip_space(cursor, true);
+                cursor.expect("]")?;
+                expr.repeat(0, Some(1))
+            }
+            Some(ch) if is_name_char(ch) => Expr::Rule(parse_name(cursor)?, pos),
+            _ => break,
+        };
+        items.push(item);
+        skip_space(cursor, nested);
+
+        loop {
+            let pos = cursor.pos();
+            let (min, max) = if cursor.eat("*") {
+                (0, None)
+            } else if cursor.eat("+") {
+                (1, None)
+            } else if cursor.eat("?") {
+                (0, Some(1))
+            } else if cursor.eat("~") {
+                skip_space(cursor, nested);
+                let min = cursor.number()?;
+                let max = if cursor.eat("..") {
+                    cursor.number()?
+                } else {
+                    min
+                };
+                if max < min {
+                    return Err(pos.error("The maximum repetitions are less than the minimum."));
+                }
+                (min, Some(max))
+            } else {
+                break;
+            };
+            skip_space(cursor, nested);
+            // NOTE(EricLBuehler): Unwrap reasoning: An item was just pushed.
+            let item = items.pop().unwrap();
+            items.push(item.repeat(min, max));
+        }
+    }
+    // Aliases only name the alternative in the parse tree.
+    if cursor.eat("->") {
+        skip_space(cursor, nested);
+        parse_name(cursor)?;
+        skip_space(cursor, nested);
+    }
+    Ok(Expr::seq(items))
+}
+
+/// Parse a string literal with Python escapes.
+fn parse_string(cursor: &mut Cursor) -> Result<String, GrammarError> {
+    let start = cursor.pos();
+    cursor.expect("\"")?;
+    let mut text = String::new();
+    loop {
+        let pos = cursor.pos();
+        match cursor.expect_char()? {
+            '"' => break,
+            '\n' => return Err(start.error("Unterminated string.")),
+            '\\' => text.push(match cursor.expect_char()? {
+                'n' => '\n',
+                't' => '\t',
+                'r' => '\r',
+                'f' => '\x0c',
+                '0' => '\0',
+                'x' => cursor.hex_char(2)?,
+                'u' => cursor.hex_char(4)?,
+                'U' => cursor.hex_char(8)?,
+                ch @ ('\\' | '"' | '\'') => ch,
+                ch => return Err(pos.error(format!("Unknown escape `\\{ch}`."))),
+            }),
+            ch => text.push(ch),
+        }
+    }
+    Ok(text)
+}
+
+/// Compiles terminals into regexes, and replaces the references to terminals in rules with their regexes.
+struct TerminalCompiler<'a> {
+    terminals: &'a HashMap<String, (Pos, Expr)>,
+    regexes: HashMap<String, String>,
+    // The terminals being compiled, to find terminals which refer to themselves.
+    in_progress: Vec<String>,
+}
+
+impl<'a> TerminalCompiler<'a> {
+    fn resolve(&mut self, expr: &mut Expr) -> Result<(), GrammarError> {
+        match expr {
+            Expr::Rule(name, pos) if is_terminal(name) => {
+                *expr = Expr::Regex(self.terminal(name, *pos)?);
+            }
+            Expr::Seq(exprs) | Expr::Alt(exprs) => {
+                for expr in exprs {
+                    self.resolve(expr)?;
+                }
+            }
+            Expr::Repeat { expr, .. } => self.resolve(expr)?,
+            Expr::Literal(_) | Expr::Regex(_) | Expr::Rule(_, _) => {}
+        }
+        Ok(())
+    }
+
+    fn terminal(&mut self, name: &str, pos: Pos) -> Result<String, GrammarError> {
+        if let Some(rx) = self.regexes.get(name) {
+            return Ok(rx.clone());
+        }
+        let Some((_, expr)) = self.terminals.get(name) else {
+            return Err(pos.error(format!("The terminal `{name}` is not defined.")));
+        };
+        if self.in_progress.iter().any(|other| other == name) {
+            return Err(pos.error(format!("The terminal `{name}` refers to itself.")));
+        }
+        self.in_progress.push(name.to_string());
+        let rx = self.regex(expr)?;
+        self.in_progress.pop();
+        self.regexes.insert(name.to_string(), rx.clone());
+        Ok(rx)
+    }
+
+    /// Compile the expression of a terminal into a regex.
+    fn regex(&mut self, expr: &Expr) -> Result<String, GrammarError> {
+        Ok(match expr {
+            Expr::Literal(text) => text.chars().map(regex_escape).collect(),
+            Expr::Regex(rx) => format!("(?:{rx})"),
+            Expr::Rule(name, pos) if is_terminal(name) => {
+                format!("(?:{})", self.terminal(name, *pos)?)
+            }
+            Expr::Rule(name, pos) => {
+                return Err(pos.error(format!("Terminals cannot refer to the rule `{name}`.")))
+            }
+            Expr::Seq(exprs) => exprs
+                .iter()
+                .map(|expr| self.regex(expr))
+                .collect::<Result<String, _>>()?,
+            Expr::Alt(exprs) => format!(
+                "(?:{})",
+                exprs
+                    .iter()
+                    .map(|expr| self.regex(expr))
+                    .collect::<Result<Vec<_>, _>>()?
+                    .join("|")
+            ),
+            Expr::Repeat { expr, min, max } => {
+                let rx = self.regex(expr)?;
+                match max {
+                    Some(max) => format!("(?:{rx}){{{min},{max}}}"),
+                    None => format!("(?:{rx}){{{min},}}"),
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::lark_to_yacc;
+    use crate::{aici::cfg::CfgParser, grammars::tests::accepts};
+
+    fn lark_accepts(lark: &str, text: &str) -> bool {
+        let yacc = lark_to_yacc(lark).unwrap();
+        accepts(
+            CfgParser::from_yacc_with_viable_tokens(&yacc).unwrap(),
+            text,
+        )
+    }
+
+    #[test]
+    fn literals_terminals_and_ignored_whitespace() {
+        let lark = r#"
+start: greeting NAME "!"?
+greeting: "hello"i | "hi"
+NAME: ("a".."z")+
+%import common.WS
+%ignore WS
+"#;
+        assert!(lark_accepts(lark, "Hello world!"));
+        assert!(lark_accepts(lark, "hi  bob"));
+        assert!(!lark_accepts(lark, "Hi bob"));
+        assert!(!lark_accepts(lark, "hey bob"));
+        assert!(!lark_accepts(lark, "hello Bob"));
+    }
+
+    #[test]
+    fn repetitions_and_groups() {
+        let lark = r#"
+start: item ~ 2..3 [","] (X | Y)*
+item: "a" | /[0-9]+/
+X: "x"
+Y: "y"
+"#;
+        assert!(lark_accepts(lark, "a1"));
+        assert!(lark_accepts(lark, "a12a,xyx"));
+        assert!(lark_accepts(lark, "aa"));
+        assert!(!lark_accepts(lark, "a"));
+        assert!(!lark_accepts(lark, "aaaa"));
+        assert!(!lark_accepts(lark, "aa,,"));
+    }
+
+    #[test]
+    fn alternatives_may_continue_on_the_next_lines() {
+        let lark = "start: \"a\"\n     | \"b\" -> b\n     | \"c\"\n";
+        assert!(lark_accepts(lark, "b"));
+        assert!(lark_accepts(lark, "c"));
+    }
+
+    #[test]
+    fn unknown_rules_and_terminals_are_errors() {
+        let err = lark_to_yacc("start: item\nitem: \"a\" other").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "line 2, column 11: The rule `other` is not defined."
+        );
+        let err = lark_to_yacc("start: \"a\" FOO").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "line 1, column 12: The terminal `FOO` is not defined."
+        );
+        let err = lark_to_yacc("%import common.FOO").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "line 1, column 9: `common` has no terminal `FOO`."
+        );
+    }
+
+    #[test]
+    fn syntax_errors_have_positions() {
+        let err = lark_to_yacc("start: \"a\"\nfoo \"b\"").unwrap_err();
+        assert_eq!(err.to_string(), "line 2, column 5: Expected `:`.");
+        let err = lark_to_yacc("start: \"a\n").unwrap_err();
+        assert_eq!(err.to_string(), "line 1, column 8: Unterminated string.");
+        let err = lark_to_yacc("start: \"a\"\n%declare A").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "line 2, column 1: The directive `%declare` is not supported."
+        );
+    }
+}
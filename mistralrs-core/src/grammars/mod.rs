@@ -0,0 +1,72 @@
+//! Front-ends which compile other grammar formats into the yacc grammars of `aici::cfg::CfgParser`.
+
+mod ebnf;
+mod gbnf;
+mod json_schema;
+mod lark;
+
+pub(crate) use gbnf::gbnf_to_yacc;
+pub(crate) use json_schema::json_schema_to_yacc;
+pub(crate) use lark::lark_to_yacc;
+
+/// Escape a character for a regex. ASCII punctuation and whitespace are written as `\x{..}`, which also keeps
+/// them from ending the quoted yacc token.
+fn regex_escape(ch: char) -> String {
+    if ch.is_ascii_alphanumeric() || !ch.is_ascii() {
+        ch.to_string()
+    } else {
+        format!("\\x{{{:x}}}", ch as u32)
+    }
+}
+
+/// A yacc token which matches `text`, which must not be empty, literally. The lexer prefers literal tokens over
+/// regex tokens, so that `"name"` is not lexed as a string, for example. Text which cannot be quoted as a literal
+/// token is written as a regex token in which all ASCII punctuation is escaped.
+pub(crate) fn literal_token(text: &str) -> String {
+    let looks_like_rx = text.len() > 2 && text.starts_with('/') && text.ends_with('/');
+    if !looks_like_rx && !text.chars().any(|ch| ch.is_ascii_control()) {
+        if !text.contains('"') {
+            return format!("\"{text}\"");
+        }
+        if !text.contains('\'') {
+            return format!("'{text}'");
+        }
+    }
+    let escaped = text.chars().map(regex_escape).collect::<String>();
+    format!("'/{escaped}/'")
+}
+
+/// A yacc token which matches the regex `rx`. Quotes in the regex are escaped, as they would end the token.
+pub(crate) fn regex_token(rx: &str) -> String {
+    let mut escaped = String::new();
+    let mut chars = rx.chars();
+    while let Some(ch) = chars.next() {
+        match ch {
+            '\'' => escaped.push_str("\\x27"),
+            '\\' => match chars.next() {
+                Some('\'') => escaped.push_str("\\x27"),
+                Some(next) => {
+                    escaped.push('\\');
+                    escaped.push(next);
+                }
+                None => escaped.push('\\'),
+            },
+            ch => escaped.push(ch),
+        }
+    }
+    format!("'/{escaped}/'")
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use crate::aici::{
+        cfg::CfgParser,
+        toktree::{Recognizer, SpecialToken},
+    };
+
+    /// Whether the parser accepts `text` as a whole.
+    pub(crate) fn accepts(mut parser: CfgParser, text: &str) -> bool {
+        text.bytes().all(|byte| parser.try_push_byte(byte))
+            && parser.special_allowed(SpecialToken::EndOfSentence)
+    }
+}
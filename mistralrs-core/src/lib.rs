@@ -10,15 +10,19 @@ use std::{
         Arc, Mutex,
     },
     thread,
-    time::{SystemTime, UNIX_EPOCH},
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
+pub use block_engine::PagedCacheConfig;
 use engine::Engine;
+pub use engine::SpeculativeConfig;
 pub use mistralrs_lora::Ordering;
 pub use pipeline::Pipeline;
 
 mod aici;
+mod block_engine;
 mod engine;
+mod grammars;
 mod models;
 mod pipeline;
 mod prefix_cacher;
@@ -27,6 +31,7 @@ mod response;
 mod sampler;
 mod scheduler;
 mod sequence;
+mod tools;
 mod utils;
 mod xlora_models;
 
@@ -35,13 +40,25 @@ pub use pipeline::{
     MistralSpecificConfig, MixtralLoader, MixtralSpecificConfig, ModelKind, Phi2Loader,
     Phi2SpecificConfig, TokenSource,
 };
-pub use request::{Constraint, Request, RequestType};
+pub use request::{
+    AdapterAction, AdapterRequest, Constraint, DetokenizationRequest, EmbeddingPooling,
+    EmbeddingRequest, NormalRequest, Request, RequestType, TokenizationRequest,
+};
 pub use response::Response;
-pub use response::{ChatCompletionResponse, CompletionResponse, Usage};
-pub use sampler::{SamplingParams, StopTokens};
-pub use scheduler::SchedulerMethod;
+pub use response::{
+    ChatCompletionResponse, CompletionLogprobs, CompletionResponse, DetokenizationResponse,
+    Embedding, EmbeddingResponse, EmbeddingUsage, TokenizationResponse, Usage,
+};
+pub use sampler::{
+    BeamSearchParams, DryParams, Mirostat, MirostatVersion, SamplingParams, StopTokens,
+};
+pub use scheduler::{QueueOrder, QueuePolicy, SchedulerMethod};
 use serde::Serialize;
+pub use tools::{
+    format_tool_calls, CalledFunction, Function, Tool, ToolCall, ToolChoice, ToolType,
+};
 
+/// The handle to a model and its engine, which runs until the last handle is dropped and its requests are done.
 pub struct MistralRs {
     sender: Sender<Request>,
     log: Option<String>,
@@ -51,13 +68,19 @@ pub struct MistralRs {
 }
 
 impl MistralRs {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         pipeline: Box<Mutex<dyn Pipeline>>,
         method: SchedulerMethod,
+        queue_policy: QueuePolicy,
         log: Option<String>,
         truncate_sequence: bool,
         no_kv_cache: bool,
         prefix_cache_n: usize,
+        paged_cache: Option<PagedCacheConfig>,
+        prefill_chunk_size: Option<usize>,
+        max_request_time: Option<Duration>,
+        speculative: Option<SpeculativeConfig>,
     ) -> Arc<Self> {
         let (tx, rx) = channel();
 
@@ -77,9 +100,14 @@ impl MistralRs {
                 rx,
                 pipeline,
                 method,
+                queue_policy,
                 truncate_sequence,
                 no_kv_cache,
                 prefix_cache_n,
+                paged_cache,
+                prefill_chunk_size,
+                max_request_time,
+                speculative,
             );
             engine.run();
         });
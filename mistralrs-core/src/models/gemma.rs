@@ -2,12 +2,12 @@
 
 use std::sync::Arc;
 
-use candle_core::{DType, Device, IndexOp, Module, Result, Tensor, D};
+use candle_core::{DType, Device, Module, Result, Tensor};
 use candle_nn::{linear_b as linear, Linear, RotaryEmbedding, VarBuilder};
 
 use crate::pipeline::GEMMA_IS_GPTX;
 
-use super::{Cache, RmsNorm};
+use super::{select_logits_positions, Cache, RmsNorm};
 
 fn default_max_position_embeddings() -> usize {
     4096
@@ -277,61 +277,41 @@ impl Model {
         })
     }
 
-    fn prepare_decoder_attention_mask(
-        &self,
-        b_size: usize,
-        tgt_len: usize,
-        seqlen_offset: usize,
+    pub fn forward(
+        &mut self,
+        input_ids: &Tensor,
+        seqlen_offsets: &[usize],
+        start_offsets_kernel: Tensor,
+        attention_mask: Option<&Tensor>,
+        logits_positions: &[(usize, usize)],
     ) -> Result<Tensor> {
-        let mask: Vec<_> = (0..tgt_len)
-            .flat_map(|i| (0..tgt_len).map(move |j| if i < j { f32::NEG_INFINITY } else { 0. }))
-            .collect();
-        let mask = Tensor::from_slice(&mask, (tgt_len, tgt_len), &self.device)?;
-        let mask = if seqlen_offset > 0 {
-            let mask0 = Tensor::zeros((tgt_len, seqlen_offset), DType::F32, &self.device)?;
-            Tensor::cat(&[&mask0, &mask], D::Minus1)?
-        } else {
-            mask
-        };
-        mask.expand((b_size, 1, tgt_len, tgt_len + seqlen_offset))?
-            .to_dtype(self.dtype)
-    }
-
-    fn calculate_past_kv_len(&mut self, seq_len: usize) -> Result<usize> {
-        let cache = self.cache.lock();
-        let kv_cache_1 = cache.first().unwrap();
-        if kv_cache_1.is_none() {
-            return Ok(0);
-        }
-        let k_cache_1 = &kv_cache_1.as_ref().unwrap().0;
-        if k_cache_1.dims()[0] <= seq_len {
-            Ok(0)
-        } else {
-            let indexed = k_cache_1.i(seq_len)?;
-            let dims = indexed.dims();
-            Ok(dims[dims.len() - 2])
-        }
+        self.hidden_states(
+            input_ids,
+            seqlen_offsets,
+            start_offsets_kernel,
+            attention_mask,
+            logits_positions,
+        )?
+        .apply(&self.lm_head)
     }
 
-    pub fn forward(
+    /// The final hidden states at `logits_positions`, which are the inputs of the LM head.
+    pub fn hidden_states(
         &mut self,
         input_ids: &Tensor,
         seqlen_offsets: &[usize],
         start_offsets_kernel: Tensor,
+        attention_mask: Option<&Tensor>,
+        logits_positions: &[(usize, usize)],
     ) -> Result<Tensor> {
-        let (b_size, seq_len) = input_ids.dims2()?;
+        let b_size = input_ids.dim(0)?;
         if seqlen_offsets.len() > b_size {
             candle_core::bail!("Expected seqlen offsets have length equal to batch size.")
         }
 
-        let past_key_values_length = self.calculate_past_kv_len(seq_len)?;
-        let attention_mask = if seq_len <= 1 {
-            None
-        } else {
-            let mask =
-                self.prepare_decoder_attention_mask(b_size, seq_len, past_key_values_length)?;
-            Some(mask)
-        };
+        let attention_mask = attention_mask
+            .map(|mask| mask.to_dtype(self.dtype))
+            .transpose()?;
         let xs = self.embed_tokens.forward(input_ids)?;
         let mut xs = (xs * (self.hidden_size as f64).sqrt())?;
         let mut cache = self.cache.lock();
@@ -344,8 +324,6 @@ impl Model {
                 cache.get_mut(i).unwrap(),
             )?
         }
-        xs.narrow(1, seq_len - 1, 1)?
-            .apply(&self.norm)?
-            .apply(&self.lm_head)
+        select_logits_positions(&xs, logits_positions)?.apply(&self.norm)
     }
 }
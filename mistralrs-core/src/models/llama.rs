@@ -1,13 +1,13 @@
 #![allow(clippy::cast_possible_truncation, clippy::cast_precision_loss)]
 
-use candle_core::{DType, Device, IndexOp, Result, Tensor, D};
+use candle_core::{DType, Device, Result, Tensor, D};
 use candle_nn::{embedding, Embedding, Module, RotaryEmbedding, VarBuilder};
 use candle_transformers::models::with_tracing::{linear_no_bias as linear, Linear};
-use std::{collections::HashMap, sync::Arc};
+use std::sync::Arc;
 
 use crate::pipeline::LLAMA_IS_GPTX;
 
-use super::{flash_attn, RmsNorm};
+use super::{flash_attn, select_logits_positions, RmsNorm};
 
 pub const MAX_SEQ_LEN: usize = 4096;
 
@@ -59,31 +59,12 @@ pub struct Config {
 
 #[derive(Debug, Clone)]
 pub struct Cache {
-    masks: HashMap<usize, Tensor>,
     pub use_kv_cache: bool,
-    device: Device,
 }
 
 impl Cache {
-    pub fn new(use_kv_cache: bool, device: &Device) -> Result<Self> {
-        Ok(Self {
-            masks: HashMap::new(),
-            use_kv_cache,
-            device: device.clone(),
-        })
-    }
-
-    fn mask(&mut self, t: usize) -> Result<Tensor> {
-        if let Some(mask) = self.masks.get(&t) {
-            Ok(mask.clone())
-        } else {
-            let mask: Vec<_> = (0..t)
-                .flat_map(|i| (0..t).map(move |j| u8::from(j > i)))
-                .collect();
-            let mask = Tensor::from_slice(&mask, (t, t), &self.device)?;
-            self.masks.insert(t, mask.clone());
-            Ok(mask)
-        }
+    pub fn new(use_kv_cache: bool) -> Self {
+        Self { use_kv_cache }
     }
 }
 
@@ -101,9 +82,11 @@ struct CausalSelfAttention {
 }
 
 impl CausalSelfAttention {
+    #[allow(clippy::too_many_arguments)]
     fn forward(
         &self,
         x: &Tensor,
+        attention_mask: Option<&Tensor>,
         seqlen_offsets: &[usize],
         start_offsets_kernel: Tensor,
         block_idx: usize,
@@ -158,7 +141,8 @@ impl CausalSelfAttention {
         let k = self.repeat_kv(k)?;
         let v = self.repeat_kv(v)?;
 
-        let y = if self.use_flash_attn {
+        // Flash attention cannot apply the attention mask of a batch with padding.
+        let y = if self.use_flash_attn && (b_sz == 1 || attention_mask.is_none()) {
             // flash-attn expects (b_sz, seq_len, nheads, head_dim)
             let q = q.transpose(1, 2)?;
             let k = k.transpose(1, 2)?;
@@ -171,8 +155,10 @@ impl CausalSelfAttention {
             let k = k.to_dtype(DType::F32)?;
             let v = v.to_dtype(DType::F32)?;
             let att = (q.matmul(&k.t()?)? / (self.head_dim as f64).sqrt())?;
-            let mask = cache.mask(seq_len)?.broadcast_as(att.shape())?;
-            let att = masked_fill(&att, &mask, f32::NEG_INFINITY)?;
+            let att = match attention_mask {
+                None => att,
+                Some(mask) => att.broadcast_add(mask)?,
+            };
             let att = candle_nn::ops::softmax(&att, D::Minus1)?;
             // Convert to contiguous as matmul doesn't support strided vs for now.
             att.matmul(&v.contiguous()?)?.to_dtype(in_dtype)?
@@ -227,13 +213,6 @@ impl CausalSelfAttention {
     }
 }
 
-fn masked_fill(on_false: &Tensor, mask: &Tensor, on_true: f32) -> Result<Tensor> {
-    let shape = mask.shape();
-    let on_true = Tensor::new(on_true, on_false.device())?.broadcast_as(shape.dims())?;
-    let m = mask.where_cond(&on_true, on_false)?;
-    Ok(m)
-}
-
 #[derive(Debug, Clone)]
 struct Mlp {
     c_fc1: Linear,
@@ -270,9 +249,11 @@ struct Block {
 }
 
 impl Block {
+    #[allow(clippy::too_many_arguments)]
     fn forward(
         &self,
         x: &Tensor,
+        attention_mask: Option<&Tensor>,
         seqlen_offsets: &[usize],
         start_offsets_kernel: Tensor,
         block_idx: usize,
@@ -283,6 +264,7 @@ impl Block {
         let x = self.rms_1.forward(x)?;
         let x = (self.attn.forward(
             &x,
+            attention_mask,
             seqlen_offsets,
             start_offsets_kernel,
             block_idx,
@@ -329,13 +311,35 @@ impl Llama {
         x: &Tensor,
         seqlen_offsets: &[usize],
         start_offsets_kernel: Tensor,
+        attention_mask: Option<&Tensor>,
+        logits_positions: &[(usize, usize)],
+    ) -> Result<Tensor> {
+        let x = self.hidden_states(
+            x,
+            seqlen_offsets,
+            start_offsets_kernel,
+            attention_mask,
+            logits_positions,
+        )?;
+        let logits = self.lm_head.forward(&x)?;
+        logits.to_dtype(DType::F32)
+    }
+
+    /// The final hidden states at `logits_positions`, which are the inputs of the LM head.
+    pub fn hidden_states(
+        &mut self,
+        x: &Tensor,
+        seqlen_offsets: &[usize],
+        start_offsets_kernel: Tensor,
+        attention_mask: Option<&Tensor>,
+        logits_positions: &[(usize, usize)],
     ) -> Result<Tensor> {
-        let (_b_sz, seq_len) = x.dims2()?;
         let mut x = self.wte.forward(x)?;
         let mut cache = self.kv_cache.lock();
         for (block_idx, block) in self.blocks.iter().enumerate() {
             x = block.forward(
                 &x,
+                attention_mask,
                 seqlen_offsets,
                 start_offsets_kernel.clone(),
                 block_idx,
@@ -344,9 +348,9 @@ impl Llama {
             )?;
         }
         let x = self.ln_f.forward(&x)?;
-        let x = x.i((.., seq_len - 1, ..))?.contiguous()?;
-        let logits = self.lm_head.forward(&x)?;
-        logits.to_dtype(DType::F32)
+        select_logits_positions(&x, logits_positions)?
+            .squeeze(1)?
+            .contiguous()
     }
 
     pub fn load(vb: VarBuilder, cfg: &Config, device: &Device, no_kv_cache: bool) -> Result<Self> {
@@ -362,7 +366,7 @@ impl Llama {
             blocks,
             ln_f,
             lm_head,
-            cache: Cache::new(!no_kv_cache, device)?,
+            cache: Cache::new(!no_kv_cache),
             kv_cache: super::Cache::new(cfg.num_hidden_layers, false),
             device: device.clone(),
         })
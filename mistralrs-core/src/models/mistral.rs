@@ -1,14 +1,14 @@
 #![allow(clippy::cast_possible_truncation, clippy::cast_precision_loss)]
 
 /// Mistral LLM, https://github.com/mistralai/mistral-src
-use candle_core::{DType, Device, IndexOp, Module, Result, Tensor, D};
+use candle_core::{DType, Device, Module, Result, Tensor};
 use candle_nn::{Activation, RotaryEmbedding, VarBuilder};
 use candle_transformers::models::with_tracing::{linear_no_bias, Linear};
 use std::sync::Arc;
 
 use crate::pipeline::MISTRAL_IS_GPTX;
 
-use super::{flash_attn, Cache, RmsNorm};
+use super::{flash_attn, select_logits_positions, Cache, RmsNorm};
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct Config {
@@ -159,7 +159,8 @@ impl Attention {
         let k = self.repeat_kv(k)?;
         let v = self.repeat_kv(v)?;
 
-        let attn_output = if self.use_flash_attn {
+        // Flash attention cannot apply the attention mask of a batch with padding.
+        let attn_output = if self.use_flash_attn && (b_sz == 1 || attention_mask.is_none()) {
             // flash-attn expects (b_sz, seq_len, nheads, head_dim)
             let q = q.transpose(1, 2)?;
             let k = k.transpose(1, 2)?;
@@ -241,7 +242,7 @@ pub struct Model {
     layers: Vec<DecoderLayer>,
     norm: RmsNorm,
     lm_head: Linear,
-    sliding_window: Option<usize>,
+    pub sliding_window: Option<usize>,
     dtype: DType,
     pub device: Device,
     pub cache: Cache,
@@ -283,71 +284,41 @@ impl Model {
         })
     }
 
-    fn prepare_decoder_attention_mask(
-        &self,
-        b_size: usize,
-        tgt_len: usize,
-        seqlen_offset: usize,
+    pub fn forward(
+        &mut self,
+        input_ids: &Tensor,
+        seqlen_offsets: &[usize],
+        start_offsets_kernel: Tensor,
+        attention_mask: Option<&Tensor>,
+        logits_positions: &[(usize, usize)],
     ) -> Result<Tensor> {
-        // Sliding window mask
-        let sliding_window = self.sliding_window.unwrap_or(tgt_len + 1);
-        let mask: Vec<_> = (0..tgt_len)
-            .flat_map(|i| {
-                (0..tgt_len).map(move |j| {
-                    if i < j || j + sliding_window < i {
-                        f32::NEG_INFINITY
-                    } else {
-                        0.
-                    }
-                })
-            })
-            .collect();
-        let mask = Tensor::from_slice(&mask, (tgt_len, tgt_len), &self.device)?;
-        let mask = if seqlen_offset > 0 {
-            let mask0 = Tensor::zeros((tgt_len, seqlen_offset), DType::F32, &self.device)?;
-            Tensor::cat(&[&mask0, &mask], D::Minus1)?
-        } else {
-            mask
-        };
-        mask.expand((b_size, 1, tgt_len, tgt_len + seqlen_offset))?
-            .to_dtype(self.dtype)
-    }
-
-    fn calculate_past_kv_len(&mut self, seq_len: usize) -> Result<usize> {
-        let cache = self.cache.lock();
-        let kv_cache_1 = cache.first().unwrap();
-        if kv_cache_1.is_none() {
-            return Ok(0);
-        }
-        let k_cache_1 = &kv_cache_1.as_ref().unwrap().0;
-        if k_cache_1.dims()[0] <= seq_len {
-            Ok(0)
-        } else {
-            let indexed = k_cache_1.i(seq_len)?;
-            let dims = indexed.dims();
-            Ok(dims[dims.len() - 2])
-        }
+        self.hidden_states(
+            input_ids,
+            seqlen_offsets,
+            start_offsets_kernel,
+            attention_mask,
+            logits_positions,
+        )?
+        .apply(&self.lm_head)
     }
 
-    pub fn forward(
+    /// The final hidden states at `logits_positions`, which are the inputs of the LM head.
+    pub fn hidden_states(
         &mut self,
         input_ids: &Tensor,
         seqlen_offsets: &[usize],
         start_offsets_kernel: Tensor,
+        attention_mask: Option<&Tensor>,
+        logits_positions: &[(usize, usize)],
     ) -> Result<Tensor> {
-        let (b_size, seq_len) = input_ids.dims2()?;
+        let b_size = input_ids.dim(0)?;
         if seqlen_offsets.len() > b_size {
             candle_core::bail!("Expected seqlen offsets have length equal to batch size.")
         }
 
-        let past_key_values_length = self.calculate_past_kv_len(seq_len)?;
-        let attention_mask = if seq_len <= 1 {
-            None
-        } else {
-            let mask =
-                self.prepare_decoder_attention_mask(b_size, seq_len, past_key_values_length)?;
-            Some(mask)
-        };
+        let attention_mask = attention_mask
+            .map(|mask| mask.to_dtype(self.dtype))
+            .transpose()?;
         let mut xs = self.embed_tokens.forward(input_ids)?;
         let mut cache = self.cache.lock();
         for (i, layer) in self.layers.iter_mut().enumerate() {
@@ -359,8 +330,6 @@ impl Model {
                 cache.get_mut(i).unwrap(),
             )?
         }
-        xs.apply(&self.norm)?
-            .apply(&self.lm_head)?
-            .narrow(1, seq_len - 1, 1)
+        select_logits_positions(&xs, logits_positions)?.apply(&self.norm)
     }
 }
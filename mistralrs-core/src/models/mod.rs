@@ -1,6 +1,6 @@
 use std::sync::{Arc, Mutex, MutexGuard};
 
-use candle_core::{quantized::QTensor, Result, Tensor};
+use candle_core::{quantized::QTensor, DType, Device, Result, Tensor};
 use candle_nn::{
     layer_norm::{RmsNormNonQuantized, RmsNormQuantized},
     Module, VarBuilder,
@@ -22,6 +22,7 @@ pub struct Cache {
     cache: Arc<Mutex<LayerCaches>>,
     xlora_cache: Option<Arc<Mutex<LayerCaches>>>,
     scalings_cache: Option<Arc<Mutex<Option<Tensor>>>>,
+    paged_cache: Arc<Mutex<Option<PagedKvCache>>>,
 }
 
 impl Cache {
@@ -38,6 +39,7 @@ impl Cache {
             } else {
                 None
             },
+            paged_cache: Arc::new(Mutex::new(None)),
         }
     }
 
@@ -60,6 +62,111 @@ impl Cache {
     pub(crate) fn is_xlora(&self) -> bool {
         self.xlora_cache.is_some()
     }
+
+    /// The paged KV cache, if it has been allocated.
+    pub(crate) fn paged_lock(&self) -> MutexGuard<'_, Option<PagedKvCache>> {
+        get_mut_arcmutex!(self.paged_cache)
+    }
+}
+
+/// Take the positions whose logits are needed from a tensor of shape `(bs, seq_len, ...)`: for sequence `i`,
+/// the new tokens `logits_positions[i].0..logits_positions[i].1`. This is usually only the last new token, but
+/// all of the prompt positions are taken when their logprobs are requested. The new tokens are padded on the
+/// right. The selected positions of all sequences are stacked into a tensor of shape `(n_positions, 1, ...)`.
+pub(crate) fn select_logits_positions(
+    xs: &Tensor,
+    logits_positions: &[(usize, usize)],
+) -> Result<Tensor> {
+    let seq_len = xs.dim(1)?;
+    if logits_positions
+        .iter()
+        .all(|(start, end)| *start == seq_len - 1 && *end == seq_len)
+    {
+        return xs.narrow(1, seq_len - 1, 1);
+    }
+    let mut positions = Vec::with_capacity(logits_positions.len());
+    for (i, (start, end)) in logits_positions.iter().enumerate() {
+        let xs = xs.narrow(0, i, 1)?.narrow(1, *start, end - start)?;
+        positions.push(xs.transpose(0, 1)?);
+    }
+    Tensor::cat(&positions, 0)
+}
+
+/// Preallocated per-layer key and value pools of shape `(num_slots, num_kv_heads, head_dim)`.
+/// A slot is one token position of one block, see `BlockEngine::slot_mapping`.
+#[derive(Debug)]
+pub struct PagedKvCache {
+    k_pools: Vec<Tensor>,
+    v_pools: Vec<Tensor>,
+}
+
+impl PagedKvCache {
+    pub(crate) fn new(
+        num_layers: usize,
+        num_slots: usize,
+        num_kv_heads: usize,
+        head_dim: usize,
+        dtype: DType,
+        device: &Device,
+    ) -> Result<Self> {
+        let mut k_pools = Vec::with_capacity(num_layers);
+        let mut v_pools = Vec::with_capacity(num_layers);
+        for _ in 0..num_layers {
+            k_pools.push(Tensor::zeros(
+                (num_slots, num_kv_heads, head_dim),
+                dtype,
+                device,
+            )?);
+            v_pools.push(Tensor::zeros(
+                (num_slots, num_kv_heads, head_dim),
+                dtype,
+                device,
+            )?);
+        }
+        Ok(Self { k_pools, v_pools })
+    }
+
+    /// Write keys and values of shape `(num_kv_heads, seq_len, head_dim)` into the given slots, in place.
+    /// Runs of consecutive slots are written with one copy each.
+    pub(crate) fn write(&self, layer: usize, k: &Tensor, v: &Tensor, slots: &[u32]) -> Result<()> {
+        let k = k.transpose(0, 1)?.contiguous()?;
+        let v = v.transpose(0, 1)?.contiguous()?;
+        let mut start = 0;
+        while start < slots.len() {
+            let mut end = start + 1;
+            while end < slots.len() && slots[end] == slots[end - 1] + 1 {
+                end += 1;
+            }
+            let offset = slots[start] as usize;
+            self.k_pools[layer].slice_set(&k.narrow(0, start, end - start)?, 0, offset)?;
+            self.v_pools[layer].slice_set(&v.narrow(0, start, end - start)?, 0, offset)?;
+            start = end;
+        }
+        Ok(())
+    }
+
+    /// Gather keys and values for a batch of sequences which all cache the same number of tokens.
+    /// `slots` holds the concatenated slot mappings. Returns tensors of shape `(bs, num_kv_heads, seq_len, head_dim)`.
+    pub(crate) fn gather(
+        &self,
+        layer: usize,
+        slots: &Tensor,
+        bs: usize,
+    ) -> Result<(Tensor, Tensor)> {
+        let k = self.k_pools[layer].index_select(slots, 0)?;
+        let v = self.v_pools[layer].index_select(slots, 0)?;
+        let (n, num_kv_heads, head_dim) = k.dims3()?;
+        let seq_len = n / bs;
+        let k = k
+            .reshape((bs, seq_len, num_kv_heads, head_dim))?
+            .transpose(1, 2)?
+            .contiguous()?;
+        let v = v
+            .reshape((bs, seq_len, num_kv_heads, head_dim))?
+            .transpose(1, 2)?
+            .contiguous()?;
+        Ok((k, v))
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -14,7 +14,7 @@ use serde::Deserialize;
 
 use crate::pipeline::PHI2_IS_GPTX;
 
-use super::{flash_attn, Cache};
+use super::{flash_attn, select_logits_positions, Cache};
 
 // https://huggingface.co/microsoft/phi-2/blob/main/configuration_phi.py
 #[derive(Debug, Clone, PartialEq, Deserialize)]
@@ -89,20 +89,6 @@ struct Attention {
     use_flash_attn: bool,
 }
 
-fn get_mask(size: usize, device: &Device) -> Result<Tensor> {
-    let mask: Vec<_> = (0..size)
-        .flat_map(|i| (0..size).map(move |j| u8::from(j > i)))
-        .collect();
-    Tensor::from_slice(&mask, (size, size), device)
-}
-
-fn masked_fill(on_false: &Tensor, mask: &Tensor, on_true: f32) -> Result<Tensor> {
-    let shape = mask.shape();
-    let on_true = Tensor::new(on_true, on_false.device())?.broadcast_as(shape.dims())?;
-    let m = mask.where_cond(&on_true, on_false)?;
-    Ok(m)
-}
-
 impl Attention {
     fn new(cfg: &Config, vb: VarBuilder) -> Result<Self> {
         let num_heads = cfg.num_attention_heads;
@@ -219,7 +205,8 @@ impl Attention {
         let k = self.repeat_kv(k)?.contiguous()?;
         let v = self.repeat_kv(v)?.contiguous()?;
 
-        let attn_output = if self.use_flash_attn {
+        // Flash attention cannot apply the attention mask of a batch with padding.
+        let attn_output = if self.use_flash_attn && (b_size == 1 || mask.is_none()) {
             // flash-attn expects (b_sz, seq_len, nheads, head_dim)
             let q = q.transpose(1, 2)?;
             let k = k.transpose(1, 2)?;
@@ -233,11 +220,7 @@ impl Attention {
                 * self.softmax_scale)?;
             let attn_weights = match mask {
                 None => attn_weights,
-                Some(mask) => masked_fill(
-                    &attn_weights,
-                    &mask.broadcast_left((b_size, self.num_heads))?,
-                    f32::NEG_INFINITY,
-                )?,
+                Some(mask) => attn_weights.broadcast_add(mask)?,
             };
             let attn_weights =
                 candle_nn::ops::softmax_last_dim(&attn_weights)?.to_dtype(v.dtype())?;
@@ -335,26 +318,39 @@ impl Model {
         xs: &Tensor,
         seqlen_offsets: &[usize],
         start_offsets_kernel: Tensor,
+        attention_mask: Option<&Tensor>,
+        logits_positions: &[(usize, usize)],
+    ) -> Result<Tensor> {
+        self.hidden_states(
+            xs,
+            seqlen_offsets,
+            start_offsets_kernel,
+            attention_mask,
+            logits_positions,
+        )?
+        .apply(&self.lm_head)
+    }
+
+    /// The final hidden states at `logits_positions`, which are the inputs of the LM head.
+    pub fn hidden_states(
+        &mut self,
+        xs: &Tensor,
+        seqlen_offsets: &[usize],
+        start_offsets_kernel: Tensor,
+        attention_mask: Option<&Tensor>,
+        logits_positions: &[(usize, usize)],
     ) -> Result<Tensor> {
-        let (_b_size, seq_len) = xs.dims2()?;
         let mut xs = xs.apply(&self.embed_tokens)?;
-        let mask = if seq_len <= 1 {
-            None
-        } else {
-            Some(get_mask(seq_len, xs.device())?)
-        };
         let mut cache = self.cache.lock();
         for (i, layer) in self.layers.iter_mut().enumerate() {
             xs = layer.forward(
                 &xs,
-                mask.as_ref(),
+                attention_mask,
                 seqlen_offsets,
                 start_offsets_kernel.clone(),
                 cache.get_mut(i).unwrap(),
             )?;
         }
-        xs.apply(&self.final_layernorm)?
-            .narrow(1, seq_len - 1, 1)?
-            .apply(&self.lm_head)
+        select_logits_positions(&xs, logits_positions)?.apply(&self.final_layernorm)
     }
 }
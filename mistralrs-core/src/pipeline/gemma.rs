@@ -1,6 +1,6 @@
 use super::{
-    calculate_inputs, get_model_paths, get_xlora_paths, Loader, ModelInputs, ModelKind, ModelPaths,
-    Pipeline, TokenSource, XLoraPaths,
+    calculate_inputs, calculate_prefill_inputs, get_model_paths, get_xlora_paths, Loader,
+    LoraAdapters, ModelInputs, ModelKind, ModelPaths, Pipeline, TokenSource, XLoraPaths,
 };
 use crate::aici::bintokens::build_tok_trie;
 use crate::aici::toktree::TokTrie;
@@ -21,7 +21,7 @@ use serde::Deserialize;
 use serde_json::Value;
 use std::collections::HashMap;
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use std::sync::Arc;
 use std::sync::Mutex;
@@ -88,6 +88,7 @@ pub struct GemmaPipeline {
     non_granular_state: Option<NonGranularState>,
     model_id: String,
     is_lora: bool,
+    lora_adapters: Option<LoraAdapters>,
     eos_tok: u32,
 }
 
@@ -262,6 +263,7 @@ impl Loader for GemmaLoader {
         info!("Model config: {config:?}");
 
         let mut is_lora = false;
+        let mut lora_adapters = None;
         let model = match self.kind {
             ModelKind::QuantizedGGUF => unreachable!(),
             ModelKind::QuantizedGGML => unreachable!(),
@@ -311,13 +313,8 @@ impl Loader for GemmaLoader {
             ModelKind::LoraGGUF => unreachable!(),
             ModelKind::LoraGGML => unreachable!(),
             ModelKind::LoraNormal => {
-                let mut safetensors_paths = paths.get_weight_filenames().iter().collect::<Vec<_>>();
-                safetensors_paths.push(paths.get_classifier_path().as_ref().unwrap());
                 let vb = from_mmaped_safetensors(
-                    safetensors_paths
-                        .iter()
-                        .map(|x| (*x).to_owned())
-                        .collect::<Vec<_>>(),
+                    paths.get_weight_filenames().to_vec(),
                     paths
                         .get_adapter_filenames()
                         .as_ref()
@@ -334,9 +331,14 @@ impl Loader for GemmaLoader {
                     &config,
                     vb,
                     paths.get_adapter_configs().as_ref().unwrap(),
-                    Some(paths.get_classifier_config().as_ref().unwrap().clone()),
+                    None,
                     paths.get_ordering().as_ref().unwrap().clone(),
                 )?;
+                lora_adapters = Some(LoraAdapters::new(
+                    paths.get_adapter_configs().as_ref().unwrap(),
+                    paths.get_ordering().as_ref().unwrap(),
+                    dtype.unwrap_or(default_dtype),
+                ));
                 is_lora = true;
                 Model::XLoraNormal(model)
             }
@@ -363,6 +365,7 @@ impl Loader for GemmaLoader {
             }),
             model_id: self.model_id.clone(),
             is_lora,
+            lora_adapters,
         })))
     }
 
@@ -388,18 +391,30 @@ impl Pipeline for GemmaPipeline {
             seqlen_offsets_full,
             seqlen_offsets_kernel,
             seqlen_offsets_kernel_full,
+            attention_mask,
+            logits_positions,
         } = calculate_inputs(
             input_toks,
             is_prompt,
             self.is_xlora(),
             self.device(),
             self.no_kv_cache,
+            None,
         )
         .unwrap();
+        let lora_scalings = self
+            .lora_adapters
+            .as_ref()
+            .map(|lora_adapters| lora_adapters.scalings(input_toks, self.device()))
+            .transpose()?;
         match self.model {
-            Model::Normal(ref mut model) => {
-                model.forward(&input_ids, &seqlen_offsets, seqlen_offsets_kernel)
-            }
+            Model::Normal(ref mut model) => model.forward(
+                &input_ids,
+                &seqlen_offsets,
+                seqlen_offsets_kernel,
+                attention_mask.as_ref(),
+                &logits_positions,
+            ),
             Model::XLoraNormal(ref mut model) => model.forward(
                 &input_ids,
                 input_ids_full.as_ref().unwrap_or(&input_ids),
@@ -409,9 +424,34 @@ impl Pipeline for GemmaPipeline {
                 seqlen_offsets_kernel_full.unwrap_or(seqlen_offsets_kernel),
                 self.no_kv_cache,
                 &self.non_granular_state,
+                lora_scalings,
             ),
         }
     }
+    fn hidden_states(&mut self, toks: &[u32]) -> Result<Tensor, candle_core::Error> {
+        let ModelInputs {
+            input_ids,
+            seqlen_offsets,
+            seqlen_offsets_kernel,
+            attention_mask,
+            logits_positions,
+            ..
+        } = calculate_prefill_inputs(toks, self.device(), None)
+            .map_err(|e| candle_core::Error::Msg(e.to_string()))?;
+        let hidden_states = match self.model {
+            Model::Normal(ref mut model) => model.hidden_states(
+                &input_ids,
+                &seqlen_offsets,
+                seqlen_offsets_kernel,
+                attention_mask.as_ref(),
+                &logits_positions,
+            ),
+            Model::XLoraNormal(_) => {
+                candle_core::bail!("Hidden states are not supported for X-LoRA models.")
+            }
+        }?;
+        hidden_states.flatten_from(1)
+    }
     fn device(&self) -> &Device {
         match self.model {
             Model::Normal(ref model) => &model.device,
@@ -451,6 +491,33 @@ impl Pipeline for GemmaPipeline {
             Model::XLoraNormal(_) => !self.is_lora,
         }
     }
+    fn has_adapters(&self) -> bool {
+        matches!(self.model, Model::XLoraNormal(_))
+    }
+    fn lora_adapters(&self) -> Option<&[String]> {
+        self.lora_adapters.as_ref().map(LoraAdapters::names)
+    }
+    fn load_lora_adapter(&mut self, name: String, path: &Path) -> Result<()> {
+        let device = self.device().clone();
+        let Some(lora_adapters) = self.lora_adapters.as_mut() else {
+            anyhow::bail!("This model does not support loading adapters.");
+        };
+        let layers = match self.model {
+            Model::XLoraNormal(ref mut model) => model.lora_layers(),
+            _ => unreachable!(),
+        };
+        lora_adapters.load(name, path, layers, &device)
+    }
+    fn unload_lora_adapter(&mut self, name: &str) -> Result<()> {
+        let Some(lora_adapters) = self.lora_adapters.as_mut() else {
+            anyhow::bail!("This model does not support unloading adapters.");
+        };
+        let layers = match self.model {
+            Model::XLoraNormal(ref mut model) => model.lora_layers(),
+            _ => unreachable!(),
+        };
+        lora_adapters.unload(name, layers)
+    }
     fn has_no_kv_cache(&self) -> bool {
         self.no_kv_cache
     }
@@ -1,6 +1,6 @@
 use super::{
-    calculate_inputs, get_model_paths, get_xlora_paths, Loader, ModelInputs, ModelKind, ModelPaths,
-    Pipeline, TokenSource, XLoraPaths,
+    calculate_inputs, calculate_prefill_inputs, get_model_paths, get_xlora_paths, Loader,
+    LoraAdapters, ModelInputs, ModelKind, ModelPaths, Pipeline, TokenSource, XLoraPaths,
 };
 use crate::aici::bintokens::build_tok_trie;
 use crate::aici::toktree::TokTrie;
@@ -24,7 +24,7 @@ use serde::Deserialize;
 use serde_json::Value;
 use std::collections::HashMap;
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use std::sync::Arc;
 use std::sync::Mutex;
@@ -92,6 +92,7 @@ pub struct MixtralPipeline {
     non_granular_state: Option<NonGranularState>,
     model_id: String,
     is_lora: bool,
+    lora_adapters: Option<LoraAdapters>,
     eos_tok: u32,
 }
 
@@ -264,6 +265,7 @@ impl Loader for MixtralLoader {
         info!("Model config: {config:?}");
 
         let mut is_lora = false;
+        let mut lora_adapters = None;
         let model = match self.kind {
             ModelKind::QuantizedGGUF => {
                 let mut file = std::fs::File::open(paths.get_weight_filenames().first().unwrap())?;
@@ -371,6 +373,11 @@ impl Loader for MixtralLoader {
                     paths.get_ordering().as_ref().unwrap(),
                     None,
                 )?;
+                lora_adapters = Some(LoraAdapters::new(
+                    paths.get_adapter_configs().as_ref().unwrap(),
+                    paths.get_ordering().as_ref().unwrap(),
+                    DType::F32,
+                ));
                 is_lora = true;
                 Model::XLoraQuantized(model)
             }
@@ -423,6 +430,7 @@ impl Loader for MixtralLoader {
             }),
             model_id: self.model_id.clone(),
             is_lora,
+            lora_adapters,
         })))
     }
 
@@ -441,6 +449,10 @@ impl Pipeline for MixtralPipeline {
         input_toks: &[&mut Sequence],
         is_prompt: bool,
     ) -> Result<Tensor, candle_core::Error> {
+        let sliding_window = match self.model {
+            Model::Normal(ref model) => Some(model.sliding_window),
+            _ => None,
+        };
         let ModelInputs {
             input_ids,
             input_ids_full,
@@ -448,21 +460,37 @@ impl Pipeline for MixtralPipeline {
             seqlen_offsets_full,
             seqlen_offsets_kernel,
             seqlen_offsets_kernel_full,
+            attention_mask,
+            logits_positions,
         } = calculate_inputs(
             input_toks,
             is_prompt,
             self.is_xlora(),
             self.device(),
             self.no_kv_cache,
+            sliding_window,
         )
         .unwrap();
+        let lora_scalings = self
+            .lora_adapters
+            .as_ref()
+            .map(|lora_adapters| lora_adapters.scalings(input_toks, self.device()))
+            .transpose()?;
         match self.model {
-            Model::Normal(ref mut model) => {
-                model.forward(&input_ids, &seqlen_offsets, seqlen_offsets_kernel)
-            }
-            Model::Quantized(ref mut model) => {
-                model.forward(&input_ids, &seqlen_offsets, seqlen_offsets_kernel)
-            }
+            Model::Normal(ref mut model) => model.forward(
+                &input_ids,
+                &seqlen_offsets,
+                seqlen_offsets_kernel,
+                attention_mask.as_ref(),
+                &logits_positions,
+            ),
+            Model::Quantized(ref mut model) => model.forward(
+                &input_ids,
+                &seqlen_offsets,
+                seqlen_offsets_kernel,
+                attention_mask.as_ref(),
+                &logits_positions,
+            ),
             Model::XLoraNormal(ref mut model) => model.forward(
                 &input_ids,
                 input_ids_full.as_ref().unwrap_or(&input_ids),
@@ -482,9 +510,45 @@ impl Pipeline for MixtralPipeline {
                 seqlen_offsets_kernel_full.unwrap_or(seqlen_offsets_kernel),
                 self.no_kv_cache,
                 &self.non_granular_state,
+                lora_scalings,
             ),
         }
     }
+    fn hidden_states(&mut self, toks: &[u32]) -> Result<Tensor, candle_core::Error> {
+        let sliding_window = match self.model {
+            Model::Normal(ref model) => Some(model.sliding_window),
+            _ => None,
+        };
+        let ModelInputs {
+            input_ids,
+            seqlen_offsets,
+            seqlen_offsets_kernel,
+            attention_mask,
+            logits_positions,
+            ..
+        } = calculate_prefill_inputs(toks, self.device(), sliding_window)
+            .map_err(|e| candle_core::Error::Msg(e.to_string()))?;
+        let hidden_states = match self.model {
+            Model::Normal(ref mut model) => model.hidden_states(
+                &input_ids,
+                &seqlen_offsets,
+                seqlen_offsets_kernel,
+                attention_mask.as_ref(),
+                &logits_positions,
+            ),
+            Model::Quantized(ref mut model) => model.hidden_states(
+                &input_ids,
+                &seqlen_offsets,
+                seqlen_offsets_kernel,
+                attention_mask.as_ref(),
+                &logits_positions,
+            ),
+            Model::XLoraNormal(_) | Model::XLoraQuantized(_) => {
+                candle_core::bail!("Hidden states are not supported for X-LoRA models.")
+            }
+        }?;
+        hidden_states.flatten_from(1)
+    }
     fn device(&self) -> &Device {
         match self.model {
             Model::Normal(ref model) => &model.device,
@@ -530,6 +594,33 @@ impl Pipeline for MixtralPipeline {
             Model::XLoraNormal(_) | Model::XLoraQuantized(_) => !self.is_lora,
         }
     }
+    fn has_adapters(&self) -> bool {
+        matches!(self.model, Model::XLoraNormal(_) | Model::XLoraQuantized(_))
+    }
+    fn lora_adapters(&self) -> Option<&[String]> {
+        self.lora_adapters.as_ref().map(LoraAdapters::names)
+    }
+    fn load_lora_adapter(&mut self, name: String, path: &Path) -> Result<()> {
+        let device = self.device().clone();
+        let Some(lora_adapters) = self.lora_adapters.as_mut() else {
+            anyhow::bail!("This model does not support loading adapters.");
+        };
+        let layers = match self.model {
+            Model::XLoraQuantized(ref mut model) => model.lora_layers(),
+            _ => unreachable!(),
+        };
+        lora_adapters.load(name, path, layers, &device)
+    }
+    fn unload_lora_adapter(&mut self, name: &str) -> Result<()> {
+        let Some(lora_adapters) = self.lora_adapters.as_mut() else {
+            anyhow::bail!("This model does not support unloading adapters.");
+        };
+        let layers = match self.model {
+            Model::XLoraQuantized(ref mut model) => model.lora_layers(),
+            _ => unreachable!(),
+        };
+        lora_adapters.unload(name, layers)
+    }
     fn has_no_kv_cache(&self) -> bool {
         self.no_kv_cache
     }
@@ -4,7 +4,12 @@ mod mistral;
 mod mixtral;
 mod phi2;
 use crate::aici::toktree::TokTrie;
-use crate::{get_bias_if_not_allowed, sampler::Logprobs, sequence::SequenceRecognizer};
+use crate::{
+    get_bias_if_not_allowed,
+    sampler::{Logprobs, Sampler},
+    sequence::SequenceRecognizer,
+    tools::Tool,
+};
 use core::fmt;
 use either::Either;
 pub use gemma::{GemmaLoader, GemmaSpecificConfig, GEMMA_IS_GPTX};
@@ -16,12 +21,19 @@ use indexmap::IndexMap;
 pub use llama::{LlamaLoader, LlamaSpecificConfig, LLAMA_IS_GPTX};
 use minijinja::{context, Environment, ErrorKind};
 pub use mistral::{MistralLoader, MistralSpecificConfig, MISTRAL_IS_GPTX};
-use mistralrs_lora::{LoraConfig, Ordering};
+use mistralrs_lora::{LinearLayerLike, LoraConfig, Ordering};
 pub use mixtral::{MixtralLoader, MixtralSpecificConfig, MIXTRAL_IS_GPTX};
 pub use phi2::{Phi2Loader, Phi2SpecificConfig, PHI2_IS_GPTX};
 use serde::Deserialize;
 use std::sync::Arc;
-use std::{collections::HashMap, fs, iter::repeat, path::PathBuf, str::FromStr, sync::Mutex};
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    iter::{repeat, zip},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Mutex,
+};
 use tokenizers::Tokenizer;
 
 use anyhow::Result;
@@ -31,7 +43,7 @@ use crate::{
     get_mut_arcmutex,
     models::Cache,
     sequence::Sequence,
-    utils::tokens::get_token,
+    utils::{tokens::get_token, varbuilder_utils::from_mmaped_safetensors},
     xlora_models::{NonGranularState, XLoraConfig},
 };
 
@@ -89,6 +101,13 @@ impl ChatTemplate {
     pub fn has_chat_template(&self) -> bool {
         self.chat_template.is_some()
     }
+
+    /// Whether the chat template renders the tool definitions itself.
+    pub fn renders_tools(&self) -> bool {
+        self.chat_template
+            .as_ref()
+            .is_some_and(|template| template.contains("tools"))
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -209,6 +228,7 @@ fn raise_exception(msg: String) -> Result<String, minijinja::Error> {
 fn apply_chat_template_to(
     messages: Vec<IndexMap<String, String>>,
     add_generation_prompt: bool,
+    tools: Option<&[Tool]>,
     template: &str,
     bos_tok: &str,
     eos_tok: &str,
@@ -226,18 +246,129 @@ fn apply_chat_template_to(
     Ok(tmpl.render(context! {
         messages => messages,
         add_generation_prompt => add_generation_prompt,
+        tools => tools,
         bos_token => bos_tok,
         eos_token => eos_tok,
         unk_token => unk_tok,
     })?)
 }
 
+/// The LoRA adapters of a LoRA model, which are loaded and unloaded at runtime. Each sequence selects some of
+/// them, and the sequences of a batch are scaled row by row so that they each apply only their own adapters.
+pub(crate) struct LoraAdapters {
+    names: Vec<String>,
+    target_modules: HashSet<String>,
+    n_layers: usize,
+    dtype: DType,
+}
+
+impl LoraAdapters {
+    /// The adapters which the model was loaded with, whose target modules every new adapter must share.
+    /// `dtype` is the dtype which new adapters are loaded in.
+    pub(crate) fn new(configs: &[(String, LoraConfig)], ordering: &Ordering, dtype: DType) -> Self {
+        Self {
+            names: configs.iter().map(|(name, _)| name.clone()).collect(),
+            target_modules: configs
+                .first()
+                .map(|(_, config)| config.target_modules().clone())
+                .unwrap_or_default(),
+            n_layers: ordering.layers.len(),
+            dtype,
+        }
+    }
+
+    pub(crate) fn names(&self) -> &[String] {
+        &self.names
+    }
+
+    /// The scalings of the batch, of shape `(seqs.len(), 1, n_layers, n_adapters)`. They are 1 for each
+    /// adapter which a sequence selects and 0 for the others.
+    pub(crate) fn scalings(
+        &self,
+        seqs: &[&mut Sequence],
+        device: &Device,
+    ) -> candle_core::Result<Tensor> {
+        let n_adapters = self.names.len();
+        let mut scalings = vec![0f32; seqs.len() * n_adapters];
+        for (i, seq) in seqs.iter().enumerate() {
+            for name in seq.adapters() {
+                let Some(adapter) = self.names.iter().position(|x| x == name) else {
+                    candle_core::bail!("The adapter `{name}` is not loaded.");
+                };
+                scalings[i * n_adapters + adapter] = 1.;
+            }
+        }
+        Tensor::from_vec(scalings, (seqs.len(), 1, 1, n_adapters), device)?
+            .broadcast_as((seqs.len(), 1, self.n_layers, n_adapters))?
+            .to_dtype(self.dtype)
+    }
+
+    /// Load the adapter in the directory `path`, which holds an `adapter_config.json` and an
+    /// `adapter_model.safetensors` as written by PEFT, into `layers`.
+    pub(crate) fn load(
+        &mut self,
+        name: String,
+        path: &Path,
+        mut layers: Vec<&mut (dyn LinearLayerLike + Send + Sync)>,
+        device: &Device,
+    ) -> Result<()> {
+        if self.names.contains(&name) {
+            anyhow::bail!("The adapter `{name}` is already loaded.");
+        }
+        let config: LoraConfig =
+            serde_json::from_str(&fs::read_to_string(path.join("adapter_config.json"))?)?;
+        if config.target_modules() != &self.target_modules {
+            anyhow::bail!(
+                "The adapter `{name}` must target the same modules as the loaded adapters, {:?}.",
+                self.target_modules
+            );
+        }
+        let vb = from_mmaped_safetensors(
+            vec![path.join("adapter_model.safetensors")],
+            vec![],
+            self.dtype,
+            device,
+            true,
+        )?;
+        for i in 0..layers.len() {
+            if let Err(e) = layers[i].load_adapter(&config, &vb) {
+                // The layers which the adapter was loaded into so far are rolled back.
+                for layer in &mut layers[..i] {
+                    layer.unload_adapter(self.names.len())?;
+                }
+                return Err(e.into());
+            }
+        }
+        self.names.push(name);
+        Ok(())
+    }
+
+    /// Unload the adapter `name` from `layers`.
+    pub(crate) fn unload(
+        &mut self,
+        name: &str,
+        layers: Vec<&mut (dyn LinearLayerLike + Send + Sync)>,
+    ) -> Result<()> {
+        let Some(adapter) = self.names.iter().position(|x| x == name) else {
+            anyhow::bail!("The adapter `{name}` is not loaded.");
+        };
+        for layer in layers {
+            layer.unload_adapter(adapter)?;
+        }
+        self.names.remove(adapter);
+        Ok(())
+    }
+}
+
 pub trait Pipeline: Send + Sync {
     fn forward(
         &mut self,
         input_toks: &[&mut Sequence],
         is_prompt: bool,
     ) -> Result<Tensor, candle_core::Error>;
+    /// The final hidden states of the model for all of the tokens, of shape `(toks.len(), hidden_size)`. This is
+    /// a prefill of the tokens on their own, so the model's KV cache must be empty.
+    fn hidden_states(&mut self, toks: &[u32]) -> Result<Tensor, candle_core::Error>;
     fn tokenize_prompt(&self, prompt: &str) -> Result<Vec<u32>> {
         let encoding = self
             .tokenizer()
@@ -254,11 +385,26 @@ pub trait Pipeline: Send + Sync {
     fn name(&self) -> String;
     fn get_max_seq_len(&self) -> usize;
     fn is_xlora(&self) -> bool;
+    /// Whether the model applies adapters, as X-LoRA and LoRA models do.
+    fn has_adapters(&self) -> bool;
+    /// The LoRA adapters which are loaded, for LoRA models.
+    fn lora_adapters(&self) -> Option<&[String]> {
+        None
+    }
+    /// Load the LoRA adapter in the directory `path` under `name`, for LoRA models.
+    fn load_lora_adapter(&mut self, _name: String, _path: &Path) -> Result<()> {
+        anyhow::bail!("This model does not support loading adapters.")
+    }
+    /// Unload the LoRA adapter `name`, for LoRA models.
+    fn unload_lora_adapter(&mut self, _name: &str) -> Result<()> {
+        anyhow::bail!("This model does not support unloading adapters.")
+    }
     fn has_no_kv_cache(&self) -> bool;
     fn apply_chat_template(
         &self,
         messages: Vec<IndexMap<String, String>>,
         add_generation_prompt: bool,
+        tools: Option<&[Tool]>,
     ) -> Result<String> {
         let template = self.get_chat_template().chat_template.as_ref().unwrap();
         let bos_tok = match self.get_chat_template().bos_token {
@@ -276,6 +422,7 @@ pub trait Pipeline: Send + Sync {
         apply_chat_template_to(
             messages,
             add_generation_prompt,
+            tools,
             template,
             bos_tok,
             eos_tok,
@@ -348,6 +495,92 @@ pub trait Pipeline: Send + Sync {
         }
         Ok(second_logprobs_response)
     }
+
+    /// Sample a token for each of `seqs`, whose samplers must be batchable, from `logits` of shape
+    /// `(seqs.len(), vocab_size)`. Like `sample`, a seq whose grammar does not allow its token is sampled
+    /// again with the tokens it does not allow masked, and these seqs are sampled again as one batch.
+    fn sample_batch(
+        &mut self,
+        logits: Tensor,
+        seqs: &mut [&mut Sequence],
+    ) -> Result<Vec<Logprobs>> {
+        let logits = logits.to_dtype(DType::F32)?;
+        let repeat_last_n = self.get_repeat_last_n();
+        let ctxts = seqs
+            .iter()
+            .map(|seq| {
+                let toks = seq.get_toks();
+                toks[toks.len().saturating_sub(repeat_last_n)..].to_vec()
+            })
+            .collect::<Vec<_>>();
+        let ctxts = ctxts.iter().map(Vec::as_slice).collect::<Vec<_>>();
+        let return_logprobs = seqs
+            .iter()
+            .map(|seq| seq.return_logprobs())
+            .collect::<Vec<_>>();
+
+        let mut samplers = seqs.iter_mut().map(|seq| seq.sampler()).collect::<Vec<_>>();
+        let mut logprobs = Sampler::sample_batch(&mut samplers, &logits, &ctxts, &return_logprobs)?;
+
+        let mut masked_rows = Vec::new();
+        let mut masks = Vec::new();
+        for (i, seq) in seqs.iter_mut().enumerate() {
+            let bias_if_not_allowed = match &mut seq.recognizer {
+                SequenceRecognizer::Regex(ref mut rx) => {
+                    get_bias_if_not_allowed!(self, rx.as_mut(), logprobs[i].token)
+                }
+                SequenceRecognizer::Cfg(ref mut cfg) => {
+                    get_bias_if_not_allowed!(self, cfg.as_mut(), logprobs[i].token)
+                }
+                SequenceRecognizer::None => None,
+            };
+            if let Some(token_set) = bias_if_not_allowed {
+                let mut acc = vec![-f32::INFINITY; self.tok_trie().vocab_size()];
+                token_set.apply_to(&mut acc);
+                masked_rows.push(i);
+                masks.extend(acc);
+            }
+        }
+        if !masked_rows.is_empty() {
+            let rows = masked_rows.iter().map(|i| *i as u32).collect::<Vec<_>>();
+            let masks = Tensor::from_vec(
+                masks,
+                (masked_rows.len(), self.tok_trie().vocab_size()),
+                self.device(),
+            )?;
+            let new_logits =
+                (logits.index_select(&Tensor::new(rows.as_slice(), self.device())?, 0)? + masks)?;
+            let mut samplers = seqs
+                .iter_mut()
+                .enumerate()
+                .filter(|(i, _)| masked_rows.binary_search(i).is_ok())
+                .map(|(_, seq)| seq.sampler())
+                .collect::<Vec<_>>();
+            let ctxts = masked_rows.iter().map(|i| ctxts[*i]).collect::<Vec<_>>();
+            let return_logprobs = masked_rows
+                .iter()
+                .map(|i| return_logprobs[*i])
+                .collect::<Vec<_>>();
+            let resampled =
+                Sampler::sample_batch(&mut samplers, &new_logits, &ctxts, &return_logprobs)?;
+            for (i, next_token) in zip(masked_rows, resampled) {
+                logprobs[i] = next_token;
+            }
+        }
+
+        for (seq, next_token) in zip(seqs.iter_mut(), &logprobs) {
+            match seq.recognizer {
+                SequenceRecognizer::Regex(ref mut rx) => {
+                    self.tok_trie().append_token(rx.as_mut(), next_token.token);
+                }
+                SequenceRecognizer::Cfg(ref mut cfg) => {
+                    self.tok_trie().append_token(cfg.as_mut(), next_token.token);
+                }
+                SequenceRecognizer::None => {}
+            }
+        }
+        Ok(logprobs)
+    }
 }
 
 struct InputMetadata {
@@ -356,16 +589,36 @@ struct InputMetadata {
     positions_kernel: Tensor, // [bs, seq len]
 }
 
-fn get_prompt_input(input_toks: &[&mut Sequence], device: &Device) -> Result<InputMetadata> {
-    // NOTE(EricLBuehler): Unwrap reasoning: Get the maximum sequence length.
-    let max_len = input_toks.iter().map(|seq| seq.len()).max().unwrap();
+/// The range of tokens of each sequence which is the input of this forward pass. Without a KV cache, all
+/// tokens are processed again.
+fn get_input_ranges(input_toks: &[&mut Sequence], no_kv_cache: bool) -> Vec<(usize, usize)> {
+    input_toks
+        .iter()
+        .map(|seq| {
+            if no_kv_cache {
+                (0, seq.get_toks().len())
+            } else {
+                seq.input_range()
+            }
+        })
+        .collect()
+}
+
+fn get_input(
+    input_toks: &[&mut Sequence],
+    device: &Device,
+    no_kv_cache: bool,
+) -> Result<InputMetadata> {
+    let ranges = get_input_ranges(input_toks, no_kv_cache);
+    // NOTE(EricLBuehler): Unwrap reasoning: Get the maximum input length.
+    let max_len = ranges.iter().map(|(start, end)| end - start).max().unwrap();
     let padding_tok = 0;
     // Pad each sequence by the padding token to the max len.
     let mut seqs_tensors = Vec::new();
     let mut seqlen_offsets = Vec::new();
-    for seq in input_toks.iter() {
-        let mut ctxt = seq.get_toks().to_vec();
-        seqlen_offsets.push(0);
+    for (seq, (start, end)) in input_toks.iter().zip(&ranges) {
+        let mut ctxt = seq.get_toks()[*start..*end].to_vec();
+        seqlen_offsets.push(*start);
 
         ctxt.extend(repeat(padding_tok).take(max_len - ctxt.len()));
 
@@ -374,8 +627,13 @@ fn get_prompt_input(input_toks: &[&mut Sequence], device: &Device) -> Result<Inp
     }
 
     let mut tmp = Vec::new();
-    for pos in (0..seqs_tensors.len())
-        .map(|_| (0..max_len).map(|x| x as i64).collect::<Vec<_>>())
+    for pos in seqlen_offsets
+        .iter()
+        .map(|start| {
+            (*start..*start + max_len)
+                .map(|x| x as i64)
+                .collect::<Vec<_>>()
+        })
         .collect::<Vec<_>>()
     {
         tmp.push(Tensor::from_slice(&pos, pos.len(), device)?.unsqueeze(0)?);
@@ -389,39 +647,53 @@ fn get_prompt_input(input_toks: &[&mut Sequence], device: &Device) -> Result<Inp
     })
 }
 
-fn get_completion_input(
-    input_toks: &[&mut Sequence],
+/// Build the additive attention mask, of shape `(bs, 1, max_len, max_past + max_len)`, for a batch of
+/// sequences with different numbers of cached and new tokens.
+///
+/// The KV cache of each sequence is padded on the left to `max_past` tokens and its new tokens are padded
+/// on the right to `max_len` tokens. Each sequence attends to its own cached tokens and causally to its own
+/// new tokens, within the sliding window if there is one. Returns `None` if nothing needs to be masked.
+fn get_attention_mask(
+    ranges: &[(usize, usize)],
+    sliding_window: Option<usize>,
     device: &Device,
-    no_kv_cache: bool,
-) -> Result<InputMetadata> {
-    if no_kv_cache {
-        return get_prompt_input(input_toks, device);
-    }
-    // Pad each sequence by the padding token to the max len.
-    let mut seqs_tensors = Vec::new();
-    let mut seqlen_offsets = Vec::new();
-    for seq in input_toks.iter() {
-        let start_pos = seq.get_toks().len().saturating_sub(1);
-        let ctxt = seq.get_toks()[start_pos..].to_vec();
-        seqlen_offsets.push(start_pos);
-
-        // NOTE(EricLBuehler): Unwrap reasoning: The dimensions must match.
-        seqs_tensors.push(Tensor::new(ctxt, device).unwrap().unsqueeze(0).unwrap());
+) -> Result<Option<Tensor>> {
+    // NOTE(EricLBuehler): Unwrap reasoning: There is at least one sequence.
+    let max_len = ranges.iter().map(|(start, end)| end - start).max().unwrap();
+    let max_past = ranges.iter().map(|(start, _)| *start).max().unwrap();
+    if max_len <= 1 && ranges.iter().all(|(start, _)| *start == max_past) {
+        return Ok(None);
     }
-    // NOTE(EricLBuehler): Unwrap reasoning: Correct dimensions are provided.
-    let mut tmp = Vec::new();
-    for pos in (0..seqs_tensors.len())
-        .map(|i| vec![*seqlen_offsets.get(i).unwrap() as i64])
-        .collect::<Vec<_>>()
-    {
-        tmp.push(Tensor::from_slice(&pos, pos.len(), device)?.unsqueeze(0)?);
+    let mut mask = Vec::with_capacity(ranges.len() * max_len * (max_past + max_len));
+    for (start, end) in ranges {
+        let len = end - start;
+        for i in 0..max_len {
+            for j in 0..max_past + max_len {
+                let (visible, key_pos) = if j < max_past {
+                    let padding = max_past - start;
+                    (j >= padding, j.saturating_sub(padding))
+                } else {
+                    let j = j - max_past;
+                    (j < len && j <= i, start + j)
+                };
+                // Padding queries are left out of the window so that they always see a real token.
+                let in_window = match sliding_window {
+                    Some(window) if i < len => key_pos + window >= start + i,
+                    _ => true,
+                };
+                mask.push(if visible && in_window {
+                    0.
+                } else {
+                    f32::NEG_INFINITY
+                });
+            }
+        }
     }
-    let positions_kernel = Tensor::cat(&tmp, 0)?;
-    Ok(InputMetadata {
-        input: Tensor::cat(&seqs_tensors, 0).unwrap(),
-        positions: seqlen_offsets,
-        positions_kernel,
-    })
+    Ok(Some(Tensor::from_vec(
+        mask,
+        (ranges.len(), 1, max_len, max_past + max_len),
+        device,
+    )?))
 }
 
 struct ModelInputs {
@@ -431,26 +703,44 @@ struct ModelInputs {
     seqlen_offsets_full: Option<Vec<usize>>,
     seqlen_offsets_kernel: Tensor,
     seqlen_offsets_kernel_full: Option<Tensor>,
+    attention_mask: Option<Tensor>,
+    logits_positions: Vec<(usize, usize)>,
 }
 
+/// Prompt and completion seqs may be mixed in one batch. X-LoRA models build their own attention masks,
+/// so they are only given batches of one kind, as told by `is_prompt`.
 fn calculate_inputs(
     input_toks: &[&mut Sequence],
     is_prompt: bool,
     is_xlora: bool,
     device: &Device,
     no_kv_cache: bool,
+    sliding_window: Option<usize>,
 ) -> Result<ModelInputs> {
+    let ranges = get_input_ranges(input_toks, no_kv_cache);
+    let logits_positions = input_toks
+        .iter()
+        .zip(&ranges)
+        .map(|(seq, (start, end))| {
+            let len = end - start;
+            if seq.wants_all_logits() {
+                (0, len)
+            } else {
+                (len - 1, len)
+            }
+        })
+        .collect();
     if is_xlora && !is_prompt {
         let InputMetadata {
             input: input_ids_full,
             positions: seqlen_offsets_full,
             positions_kernel: seqlen_offsets_kernel_full,
-        } = get_prompt_input(input_toks, device)?;
+        } = get_input(input_toks, device, true)?;
         let InputMetadata {
             input: input_ids,
             positions: seqlen_offsets,
             positions_kernel: seqlen_offsets_kernel,
-        } = get_completion_input(input_toks, device, no_kv_cache)?;
+        } = get_input(input_toks, device, no_kv_cache)?;
         Ok(ModelInputs {
             input_ids,
             input_ids_full: Some(input_ids_full),
@@ -458,13 +748,15 @@ fn calculate_inputs(
             seqlen_offsets_full: Some(seqlen_offsets_full),
             seqlen_offsets_kernel,
             seqlen_offsets_kernel_full: Some(seqlen_offsets_kernel_full),
+            attention_mask: None,
+            logits_positions,
         })
     } else if is_xlora && is_prompt {
         let InputMetadata {
             input: input_ids,
             positions: seqlen_offsets,
             positions_kernel: seqlen_offsets_kernel,
-        } = get_prompt_input(input_toks, device)?;
+        } = get_input(input_toks, device, no_kv_cache)?;
         Ok(ModelInputs {
             input_ids: input_ids.clone(),
             input_ids_full: Some(input_ids),
@@ -472,27 +764,15 @@ fn calculate_inputs(
             seqlen_offsets_full: Some(seqlen_offsets),
             seqlen_offsets_kernel: seqlen_offsets_kernel.clone(),
             seqlen_offsets_kernel_full: Some(seqlen_offsets_kernel),
-        })
-    } else if is_prompt {
-        let InputMetadata {
-            input: input_ids,
-            positions: seqlen_offsets,
-            positions_kernel: seqlen_offsets_kernel,
-        } = get_prompt_input(input_toks, device)?;
-        Ok(ModelInputs {
-            input_ids,
-            input_ids_full: None,
-            seqlen_offsets,
-            seqlen_offsets_full: None,
-            seqlen_offsets_kernel,
-            seqlen_offsets_kernel_full: None,
+            attention_mask: None,
+            logits_positions,
         })
     } else {
         let InputMetadata {
             input: input_ids,
             positions: seqlen_offsets,
             positions_kernel: seqlen_offsets_kernel,
-        } = get_completion_input(input_toks, device, no_kv_cache)?;
+        } = get_input(input_toks, device, no_kv_cache)?;
         Ok(ModelInputs {
             input_ids,
             input_ids_full: None,
@@ -500,10 +780,33 @@ fn calculate_inputs(
             seqlen_offsets_full: None,
             seqlen_offsets_kernel,
             seqlen_offsets_kernel_full: None,
+            attention_mask: get_attention_mask(&ranges, sliding_window, device)?,
+            logits_positions,
         })
     }
 }
 
+/// The inputs of a prefill of `toks` as one sequence without a KV cache, for the hidden states of all of the tokens.
+fn calculate_prefill_inputs(
+    toks: &[u32],
+    device: &Device,
+    sliding_window: Option<usize>,
+) -> Result<ModelInputs> {
+    let positions = (0..toks.len()).map(|x| x as i64).collect::<Vec<_>>();
+    let ranges = [(0, toks.len())];
+    Ok(ModelInputs {
+        input_ids: Tensor::new(toks, device)?.unsqueeze(0)?,
+        input_ids_full: None,
+        seqlen_offsets: vec![0],
+        seqlen_offsets_full: None,
+        seqlen_offsets_kernel: Tensor::from_slice(&positions, positions.len(), device)?
+            .unsqueeze(0)?,
+        seqlen_offsets_kernel_full: None,
+        attention_mask: get_attention_mask(&ranges, sliding_window, device)?,
+        logits_positions: ranges.to_vec(),
+    })
+}
+
 struct XLoraPaths {
     adapter_configs: Option<Vec<(String, LoraConfig)>>,
     adapter_safetensors: Option<Vec<(String, PathBuf)>>,
@@ -785,6 +1088,7 @@ mod tests {
                     inputs.clone()
                 },
                 true,
+                None,
                 template,
                 bos,
                 eos,
@@ -806,3 +1110,71 @@ fn calculate_eos_tok(chat_template: &ChatTemplate, tokenizer: &Tokenizer) -> u32
         .copied()
         .unwrap_or_else(|| panic!("Unable to extract `{eos_tok}` EOS token."))
 }
+
+#[cfg(test)]
+mod tests {
+    use candle_core::Device;
+
+    use super::get_attention_mask;
+
+    /// Which keys each query of each sequence can see.
+    fn visible(ranges: &[(usize, usize)], sliding_window: Option<usize>) -> Vec<Vec<Vec<bool>>> {
+        let mask = get_attention_mask(ranges, sliding_window, &Device::Cpu)
+            .unwrap()
+            .unwrap();
+        mask.squeeze(1)
+            .unwrap()
+            .to_vec3::<f32>()
+            .unwrap()
+            .into_iter()
+            .map(|seq| {
+                seq.into_iter()
+                    .map(|row| row.into_iter().map(|x| x == 0.).collect())
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn mask_pads_the_past_on_the_left_and_the_input_on_the_right() {
+        // The first sequence has 2 cached tokens and 1 new token, the second one no cache and 2 new tokens.
+        let mask = visible(&[(2, 3), (0, 2)], None);
+        assert_eq!(
+            mask[0],
+            vec![vec![true, true, true, false], vec![true, true, true, false]]
+        );
+        assert_eq!(
+            mask[1],
+            vec![
+                vec![false, false, true, false],
+                vec![false, false, true, true]
+            ]
+        );
+    }
+
+    #[test]
+    fn mask_applies_the_sliding_window_to_real_queries() {
+        let mask = visible(&[(2, 3), (0, 2)], Some(1));
+        // The padding query of the first sequence still sees all of its tokens.
+        assert_eq!(
+            mask[0],
+            vec![
+                vec![false, true, true, false],
+                vec![true, true, true, false]
+            ]
+        );
+        assert_eq!(
+            mask[1],
+            vec![
+                vec![false, false, true, false],
+                vec![false, false, true, true]
+            ]
+        );
+    }
+
+    #[test]
+    fn no_mask_for_aligned_single_tokens() {
+        let mask = get_attention_mask(&[(3, 4), (3, 4)], None, &Device::Cpu).unwrap();
+        assert!(mask.is_none());
+    }
+}
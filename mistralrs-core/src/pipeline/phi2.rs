@@ -1,6 +1,6 @@
 use super::{
-    calculate_inputs, get_model_paths, get_xlora_paths, Loader, ModelInputs, ModelKind, ModelPaths,
-    Pipeline, TokenSource, XLoraPaths,
+    calculate_inputs, calculate_prefill_inputs, get_model_paths, get_xlora_paths, Loader,
+    ModelInputs, ModelKind, ModelPaths, Pipeline, TokenSource, XLoraPaths,
 };
 use crate::aici::bintokens::build_tok_trie;
 use crate::aici::toktree::TokTrie;
@@ -386,18 +386,25 @@ impl Pipeline for Phi2Pipeline {
             seqlen_offsets_full,
             seqlen_offsets_kernel,
             seqlen_offsets_kernel_full,
+            attention_mask,
+            logits_positions,
         } = calculate_inputs(
             input_toks,
             is_prompt,
             self.is_xlora(),
             self.device(),
             self.no_kv_cache,
+            None,
         )
         .unwrap();
         match self.model {
-            Model::Normal(ref mut model) => {
-                model.forward(&input_ids, &seqlen_offsets, seqlen_offsets_kernel)
-            }
+            Model::Normal(ref mut model) => model.forward(
+                &input_ids,
+                &seqlen_offsets,
+                seqlen_offsets_kernel,
+                attention_mask.as_ref(),
+                &logits_positions,
+            ),
             Model::XLoraNormal(ref mut model) => model.forward(
                 &input_ids,
                 input_ids_full.as_ref().unwrap_or(&input_ids),
@@ -410,6 +417,30 @@ impl Pipeline for Phi2Pipeline {
             ),
         }
     }
+    fn hidden_states(&mut self, toks: &[u32]) -> Result<Tensor, candle_core::Error> {
+        let ModelInputs {
+            input_ids,
+            seqlen_offsets,
+            seqlen_offsets_kernel,
+            attention_mask,
+            logits_positions,
+            ..
+        } = calculate_prefill_inputs(toks, self.device(), None)
+            .map_err(|e| candle_core::Error::Msg(e.to_string()))?;
+        let hidden_states = match self.model {
+            Model::Normal(ref mut model) => model.hidden_states(
+                &input_ids,
+                &seqlen_offsets,
+                seqlen_offsets_kernel,
+                attention_mask.as_ref(),
+                &logits_positions,
+            ),
+            Model::XLoraNormal(_) => {
+                candle_core::bail!("Hidden states are not supported for X-LoRA models.")
+            }
+        }?;
+        hidden_states.flatten_from(1)
+    }
     fn device(&self) -> &Device {
         match self.model {
             Model::Normal(ref model) => &model.device,
@@ -449,6 +480,9 @@ impl Pipeline for Phi2Pipeline {
             Model::XLoraNormal(_) => !self.is_lora,
         }
     }
+    fn has_adapters(&self) -> bool {
+        matches!(self.model, Model::XLoraNormal(_))
+    }
     fn has_no_kv_cache(&self) -> bool {
         self.no_kv_cache
     }
@@ -1,22 +1,50 @@
 use either::Either;
 use indexmap::IndexMap;
 
-use crate::{response::Response, sampler::SamplingParams};
-use std::{fmt::Debug, sync::mpsc::Sender};
+use crate::{
+    response::{DetokenizationResponse, Response, TokenizationResponse},
+    sampler::SamplingParams,
+    tools::{Tool, ToolChoice},
+};
+use std::{fmt::Debug, path::PathBuf, sync::mpsc::Sender, time::Duration};
 
 pub enum Constraint {
     Regex(String),
     Yacc(String),
+    /// Only generate JSON which is valid under this JSON schema.
+    JsonSchema(serde_json::Value),
+    /// A grammar in the GBNF format of llama.cpp, starting with the `root` rule.
+    Gbnf(String),
+    /// A Lark grammar, starting with the `start` rule.
+    Lark(String),
     None,
 }
 
 #[derive(Debug, PartialEq, Clone)]
 pub enum RequestType {
     Chat,
-    Completion { echo_prompt: bool },
+    Completion {
+        echo_prompt: bool,
+    },
+    /// Score the prompt without generating anything: the response is a completion which echoes the prompt,
+    /// with the logprob of each prompt token.
+    Score,
 }
 
-pub struct Request {
+/// A message to the engine.
+pub enum Request {
+    Normal(NormalRequest),
+    /// Cancel all sequences of the request with this id. They stop with `StopReason::Canceled`: a request
+    /// which is not streaming responds with the output generated so far, and a streaming one sends no
+    /// further responses.
+    Abort(usize),
+    Embedding(EmbeddingRequest),
+    Tokenize(TokenizationRequest),
+    Detokenize(DetokenizationRequest),
+    Adapter(AdapterRequest),
+}
+
+pub struct NormalRequest {
     pub messages: Either<Vec<IndexMap<String, String>>, String>,
     pub sampling_params: SamplingParams,
     pub response: Sender<Response>,
@@ -27,9 +55,23 @@ pub struct Request {
     pub request_type: RequestType,
     pub suffix: Option<String>,
     pub best_of: Option<usize>,
+    /// Requests of a higher priority are admitted first by `QueueOrder::Priority`.
+    pub priority: i32,
+    /// The tenant to which the request is accounted by `QueueOrder::WeightedFair`.
+    pub tenant: Option<String>,
+    /// Sequences which are not done this long after the request is received are finished with
+    /// `StopReason::Timeout`, responding with the output generated so far.
+    pub timeout: Option<Duration>,
+    /// Tools which the model may call in a chat request.
+    pub tools: Option<Vec<Tool>>,
+    /// Defaults to `ToolChoice::Auto` when there are tools.
+    pub tool_choice: Option<ToolChoice>,
+    /// The LoRA adapters to apply, for LoRA models. Defaults to all loaded adapters, and none applies the
+    /// base model.
+    pub adapters: Option<Vec<String>>,
 }
 
-impl Debug for Request {
+impl Debug for NormalRequest {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(
             f,
@@ -38,3 +80,59 @@ impl Debug for Request {
         )
     }
 }
+
+/// How the hidden states of the tokens of an input are pooled into its embedding.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EmbeddingPooling {
+    Mean,
+    LastToken,
+}
+
+/// Embed each input with the final hidden states of the model. The inputs are prefilled one by one, between
+/// the steps of the other requests.
+pub struct EmbeddingRequest {
+    /// Each input is a prompt or a list of token ids.
+    pub inputs: Vec<Either<String, Vec<u32>>>,
+    pub pooling: EmbeddingPooling,
+    /// Keep only the first dimensions of each embedding, before it is normalized.
+    pub dimensions: Option<usize>,
+    /// Scale each embedding to a unit L2 norm.
+    pub normalize: bool,
+    pub response: Sender<Response>,
+    pub id: usize,
+}
+
+/// Tokenize a prompt, or messages with the chat template applied, the way the prompt of a request would be.
+pub struct TokenizationRequest {
+    pub text: Either<Vec<IndexMap<String, String>>, String>,
+    /// Render the start of the assistant's turn after the messages.
+    pub add_generation_prompt: bool,
+    pub response: Sender<anyhow::Result<TokenizationResponse>>,
+}
+
+pub struct DetokenizationRequest {
+    pub tokens: Vec<u32>,
+    pub skip_special_tokens: bool,
+    pub response: Sender<anyhow::Result<DetokenizationResponse>>,
+}
+
+pub enum AdapterAction {
+    /// Load the LoRA adapter in a directory with an `adapter_config.json` and an
+    /// `adapter_model.safetensors`, as written by PEFT.
+    Load {
+        name: String,
+        path: PathBuf,
+    },
+    /// Unload a LoRA adapter, which no running or waiting request may apply.
+    Unload {
+        name: String,
+    },
+    List,
+}
+
+/// Manage the LoRA adapters of a LoRA model at runtime. The response is the adapters which are loaded
+/// afterwards.
+pub struct AdapterRequest {
+    pub action: AdapterAction,
+    pub response: Sender<anyhow::Result<Vec<String>>>,
+}
@@ -1,8 +1,8 @@
-use std::error::Error;
+use std::{collections::HashMap, error::Error};
 
 use serde::Serialize;
 
-use crate::sampler::TopLogprob;
+use crate::{sampler::TopLogprob, tools::ToolCall};
 
 pub const SYSTEM_FINGERPRINT: &str = "local";
 
@@ -10,12 +10,16 @@ pub const SYSTEM_FINGERPRINT: &str = "local";
 pub struct ResponseMessage {
     pub content: String,
     pub role: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tool_calls: Option<Vec<ToolCall>>,
 }
 
 #[derive(Debug, Clone, Serialize)]
 pub struct Delta {
     pub content: String,
     pub role: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tool_calls: Option<Vec<ToolCall>>,
 }
 
 #[derive(Debug, Clone, Serialize)]
@@ -85,13 +89,23 @@ pub struct ChatCompletionChunkResponse {
     pub object: String,
 }
 
+/// Logprobs in the format of the legacy completions API. There is one entry per token in each list, and
+/// `text_offset` is where the token starts in the text of the choice. The first prompt token has no logprobs.
+#[derive(Debug, Clone, Serialize, Default)]
+pub struct CompletionLogprobs {
+    pub tokens: Vec<String>,
+    pub token_logprobs: Vec<Option<f32>>,
+    pub top_logprobs: Vec<Option<HashMap<String, f32>>>,
+    pub text_offset: Vec<usize>,
+}
+
 #[derive(Debug, Clone, Serialize)]
 pub struct CompletionChoice {
     #[serde(rename = "finish_reason")]
     pub stopreason: String,
     pub index: usize,
     pub text: String,
-    pub logprobs: Option<()>,
+    pub logprobs: Option<CompletionLogprobs>,
 }
 
 #[derive(Debug, Clone, Serialize)]
@@ -105,6 +119,48 @@ pub struct CompletionResponse {
     pub usage: Usage,
 }
 
+#[derive(Debug, Clone, Serialize)]
+pub struct Embedding {
+    pub object: String,
+    pub embedding: Vec<f32>,
+    pub index: usize,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct EmbeddingUsage {
+    pub prompt_tokens: usize,
+    pub total_tokens: usize,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct EmbeddingResponse {
+    pub object: String,
+    pub data: Vec<Embedding>,
+    pub model: String,
+    pub usage: EmbeddingUsage,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CompletionChunkChoice {
+    #[serde(rename = "finish_reason")]
+    pub stopreason: Option<String>,
+    pub index: usize,
+    pub text: String,
+    pub logprobs: Option<CompletionLogprobs>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CompletionChunkResponse {
+    pub id: String,
+    pub choices: Vec<CompletionChunkChoice>,
+    pub created: u128,
+    pub model: String,
+    pub system_fingerprint: String,
+    pub object: String,
+    /// Only set for the final chunk of a request, which has no choices.
+    pub usage: Option<Usage>,
+}
+
 pub enum Response {
     InternalError(Box<dyn Error + Send + Sync>),
     ValidationError(Box<dyn Error + Send + Sync>),
@@ -115,4 +171,20 @@ pub enum Response {
     // Completion
     CompletionModelError(String, CompletionResponse),
     CompletionDone(CompletionResponse),
+    CompletionChunk(CompletionChunkResponse),
+    // Embedding
+    Embeddings(EmbeddingResponse),
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TokenizationResponse {
+    pub tokens: Vec<u32>,
+    /// The vocabulary entry of each token.
+    pub token_strs: Vec<String>,
+    pub count: usize,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DetokenizationResponse {
+    pub text: String,
 }
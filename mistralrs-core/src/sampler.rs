@@ -1,15 +1,27 @@
 #![allow(clippy::cast_possible_truncation, clippy::cast_precision_loss)]
 
-use std::{collections::HashMap, iter::zip, sync::Arc};
+use std::{
+    collections::{HashMap, HashSet},
+    iter::zip,
+    sync::Arc,
+};
 
-use candle_core::{bail, DType, Error, Result, Tensor, D};
+use candle_core::{bail, DType, Device, Error, Result, Tensor, D};
 use rand::{
     distributions::{Distribution, WeightedIndex},
-    SeedableRng,
+    Rng, SeedableRng,
 };
 use serde::{Deserialize, Serialize};
 use tokenizers::Tokenizer;
 
+/// The widest rows which `sort_last_dim` can sort on CUDA and Metal, where each row is sorted by one block of
+/// threads.
+const MAX_DEVICE_SORT_WIDTH: usize = 1024;
+
+/// The number of the most likely tokens which top-p without top-k first looks for its tokens in, on the device.
+/// They are doubled on the host until they hold the top-p.
+const TOP_P_CANDIDATES: usize = 64;
+
 #[derive(Clone, Debug)]
 pub enum StopTokens {
     Seqs(Vec<String>),
@@ -21,13 +33,110 @@ pub struct SamplingParams {
     pub temperature: Option<f64>,
     pub top_k: Option<usize>,
     pub top_p: Option<f64>,
+    pub min_p: Option<f64>,
+    pub typical_p: Option<f64>,
+    pub tfs_z: Option<f64>,
+    pub mirostat: Option<Mirostat>,
     pub top_n_logprobs: usize,
     pub frequency_penalty: Option<f32>,
     pub presence_penalty: Option<f32>,
+    pub repetition_penalty: Option<f32>,
+    pub no_repeat_ngram_size: Option<usize>,
+    pub dry_params: Option<DryParams>,
     pub stop_toks: Option<StopTokens>,
     pub max_len: Option<usize>,
     pub logits_bias: Option<HashMap<u32, f32>>,
     pub n_choices: usize,
+    pub beam_search: Option<BeamSearchParams>,
+    /// The seed of the sampler of the first choice. Choice `i` is sampled with the seed plus `i`.
+    pub seed: Option<u64>,
+}
+
+/// Beam search keeps the `beam_width` most likely sequences at each step instead of sampling, and returns the
+/// `n_choices` best finished ones. They are ranked by their cumulative logprob divided by their length to the
+/// power of `length_penalty`, so a larger `length_penalty` favors longer outputs. With `early_stopping`, the
+/// search stops once there are `beam_width` finished sequences; otherwise it stops once no live beam can score
+/// better than them.
+#[derive(Clone, Copy, Debug)]
+pub struct BeamSearchParams {
+    pub beam_width: usize,
+    pub length_penalty: f32,
+    pub early_stopping: bool,
+}
+
+/// Mirostat sampling adapts the truncation of the distribution so that the sampled tokens have a surprise
+/// of about `tau` bits, instead of truncating it by a fixed amount. After each token, the target is moved
+/// by the learning rate `eta` times the error. It replaces top-k, top-p, min-p, typical and tail-free
+/// sampling.
+#[derive(Clone, Copy, Debug)]
+pub struct Mirostat {
+    pub version: MirostatVersion,
+    pub tau: f32,
+    pub eta: f32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MirostatVersion {
+    /// Estimates the Zipf exponent of the distribution to choose a top-k.
+    V1,
+    /// Drops the tokens with a surprise above the target.
+    V2,
+}
+
+impl Mirostat {
+    pub const DEFAULT_TAU: f32 = 5.0;
+    pub const DEFAULT_ETA: f32 = 0.1;
+
+    /// Mirostat from the `mirostat`, `mirostat_tau` and `mirostat_eta` request parameters of llama.cpp: a
+    /// mode of 1 or 2 is the version, and any other mode disables it.
+    pub fn from_mode(mode: u8, tau: Option<f32>, eta: Option<f32>) -> Option<Self> {
+        let version = match mode {
+            1 => MirostatVersion::V1,
+            2 => MirostatVersion::V2,
+            _ => return None,
+        };
+        Some(Self {
+            version,
+            tau: tau.unwrap_or(Self::DEFAULT_TAU),
+            eta: eta.unwrap_or(Self::DEFAULT_ETA),
+        })
+    }
+}
+
+/// DRY ("don't repeat yourself") penalizes the tokens which would extend a sequence that already occurred in
+/// the context. A token which continues a repetition of `n >= allowed_length` tokens has its logit decreased
+/// by `multiplier * base^(n - allowed_length)`. Repetitions do not extend across the sequence breakers.
+#[derive(Clone, Debug)]
+pub struct DryParams {
+    pub multiplier: f32,
+    pub base: f32,
+    pub allowed_length: usize,
+    pub sequence_breakers: Vec<String>,
+}
+
+impl DryParams {
+    pub const DEFAULT_BASE: f32 = 1.75;
+    pub const DEFAULT_ALLOWED_LENGTH: usize = 2;
+    pub const DEFAULT_SEQUENCE_BREAKERS: [&'static str; 4] = ["\n", ":", "\"", "*"];
+
+    pub fn new(
+        multiplier: f32,
+        base: Option<f32>,
+        allowed_length: Option<usize>,
+        sequence_breakers: Option<Vec<String>>,
+    ) -> Self {
+        Self {
+            multiplier,
+            base: base.unwrap_or(Self::DEFAULT_BASE),
+            allowed_length: allowed_length.unwrap_or(Self::DEFAULT_ALLOWED_LENGTH),
+            sequence_breakers: sequence_breakers.unwrap_or_else(|| {
+                Self::DEFAULT_SEQUENCE_BREAKERS
+                    .iter()
+                    .map(|breaker| breaker.to_string())
+                    .collect()
+            }),
+        }
+    }
 }
 
 /// Sampler for sampling.
@@ -39,9 +148,20 @@ pub struct Sampler {
     tokenizer: Arc<Tokenizer>,
     frequency_penalty: Option<f32>,
     presence_penalty: Option<f32>,
+    repetition_penalty: Option<f32>,
+    no_repeat_ngram_size: Option<usize>,
+    dry_params: Option<DryParams>,
+    // The tokens of the DRY sequence breakers
+    dry_sequence_breakers: HashSet<u32>,
     logits_bias: Option<HashMap<u32, f32>>,
     topk: i64,
     topp: f64,
+    minp: f64,
+    typical_p: f64,
+    tfs_z: f64,
+    mirostat: Option<Mirostat>,
+    // The maximum surprise of Mirostat, which it learns as it samples.
+    mirostat_mu: f32,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
@@ -69,15 +189,29 @@ impl Sampler {
         tokenizer: Arc<Tokenizer>,
         frequency_penalty: Option<f32>,
         presence_penalty: Option<f32>,
+        repetition_penalty: Option<f32>,
+        no_repeat_ngram_size: Option<usize>,
+        dry_params: Option<DryParams>,
         logits_bias: Option<HashMap<u32, f32>>,
         topk: i64,
         topp: f64,
+        minp: f64,
+        typical_p: f64,
+        tfs_z: f64,
+        mirostat: Option<Mirostat>,
     ) -> Self {
         let temperature = if temperature.map_or(true, |v| v < 1e-7) {
             None
         } else {
             temperature
         };
+        // A sequence breaker is the last token of its encoding, as tokenizers may prefix it with a space.
+        let dry_sequence_breakers = dry_params
+            .iter()
+            .flat_map(|dry_params| &dry_params.sequence_breakers)
+            .filter_map(|breaker| tokenizer.encode(breaker.as_str(), false).ok())
+            .filter_map(|encoding| encoding.get_ids().last().copied())
+            .collect();
         Self {
             rng: rand::rngs::StdRng::seed_from_u64(seed),
             temperature,
@@ -85,22 +219,50 @@ impl Sampler {
             tokenizer,
             frequency_penalty,
             presence_penalty,
+            repetition_penalty,
+            no_repeat_ngram_size,
+            dry_params,
+            dry_sequence_breakers,
             logits_bias,
             topk,
             topp,
+            minp,
+            typical_p,
+            tfs_z,
+            mirostat,
+            mirostat_mu: mirostat.map_or(0., |mirostat| 2. * mirostat.tau),
+        }
+    }
+
+    pub fn reseed(&mut self, seed: u64) {
+        self.rng = rand::rngs::StdRng::seed_from_u64(seed);
+    }
+
+    /// The sampler of the choice `response_index` of a request. With a `seed`, each choice is reseeded with its
+    /// own seed, so that the choices differ but can be reproduced. Without one, the generator is kept.
+    pub fn for_choice(&self, seed: Option<u64>, response_index: usize) -> Self {
+        let mut sampler = self.clone();
+        if let Some(seed) = seed {
+            sampler.reseed(seed.wrapping_add(response_index as u64));
         }
+        sampler
     }
 
-    fn apply_logit_bias(&self, probs: &mut [f32]) -> Result<()> {
+    /// Whether the sampler uses Mirostat, which depends on the tokens sampled before.
+    pub fn has_mirostat(&self) -> bool {
+        self.mirostat.is_some()
+    }
+
+    fn apply_logit_bias(&self, logits: &mut [f32]) -> Result<()> {
         if let Some(ref bias) = self.logits_bias {
             for (id, bias_v) in bias {
-                let idx = probs.get_mut(*id as usize);
+                let idx = logits.get_mut(*id as usize);
                 if let Some(idx) = idx {
                     *idx += bias_v;
                 } else {
                     candle_core::bail!(
-                        "Token ID `{id}` out of range for probs of length `{}`.",
-                        probs.len()
+                        "Token ID `{id}` out of range for logits of length `{}`.",
+                        logits.len()
                     );
                 }
             }
@@ -108,60 +270,65 @@ impl Sampler {
         Ok(())
     }
 
-    fn get_top_logprobs(
-        &self,
-        probs: &[f32],
-        argsort_indices: &[usize],
-    ) -> Result<Vec<TopLogprob>> {
-        let mut argsort_indices_sorted = argsort_indices.to_vec();
-        // Sort by descending prob
-        argsort_indices_sorted.sort_by(|a, b| probs[*b].partial_cmp(&probs[*a]).unwrap());
-        // These are where the top n are
-        let top_n_toks_range = 0..self.top_n_logprobs;
-        // The top n's values
-        let top_n_logprobs = argsort_indices_sorted[top_n_toks_range.clone()]
-            .iter()
-            .map(|x| probs[*x].log(10.0))
-            .collect::<Vec<_>>();
-        // Find where they actually are in the logits
-        let mut top_n_toks = Vec::new();
-        for val in top_n_toks_range {
-            top_n_toks.push(argsort_indices[val]);
+    /// The `top_n_logprobs` most likely tokens, given the logprobs of all tokens. They are found with
+    /// `topk_last_dim`, and only their logprobs are copied from the device.
+    fn get_top_logprobs(&self, logprobs: &Tensor) -> Result<Vec<TopLogprob>> {
+        let n = self.top_n_logprobs.min(logprobs.dim(D::Minus1)?);
+        if n == 0 {
+            return Ok(Vec::new());
         }
+        let (mut top_n_logprobs, mut top_n_toks) = Self::topk_last_dim(&logprobs.unsqueeze(0)?, n)?;
+        // NOTE(EricLBuehler): Unwrap reasoning: There is one row.
+        self.top_logprobs(top_n_toks.pop().unwrap(), top_n_logprobs.pop().unwrap())
+    }
 
+    fn top_logprobs(&self, toks: Vec<u32>, logprobs: Vec<f32>) -> Result<Vec<TopLogprob>> {
         let mut bytes = Vec::new();
-        for tok in &top_n_toks {
+        for tok in &toks {
             bytes.push(
                 self.tokenizer
-                    .decode(&[*tok as u32], true)
+                    .decode(&[*tok], true)
                     .map_err(|x| Error::Msg(x.to_string()))?,
             );
         }
-        Ok(zip(bytes, zip(top_n_toks, top_n_logprobs))
+        Ok(zip(bytes, zip(toks, logprobs))
             .map(|(bytes, (token, logprob))| TopLogprob {
-                token: token as u32,
+                token,
                 logprob,
                 bytes,
             })
             .collect::<Vec<_>>())
     }
 
-    fn sample_argmax(&mut self, logits: Tensor, return_logprobs: bool) -> Result<Logprobs> {
-        let mut probs: Vec<f32> = logits.to_vec1()?;
-        let argsort_indices = (0..probs.len()).collect::<Vec<_>>();
-
-        self.apply_logit_bias(&mut probs)?;
-
-        let next_token = logits.argmax(D::Minus1)?.to_scalar::<u32>()?;
+    /// Sample an index of `probs`, which need not be normalized.
+    fn sample_multinomial(&mut self, probs: &[f32]) -> Result<usize> {
+        let distr = WeightedIndex::new(probs).map_err(Error::wrap)?;
+        Ok(distr.sample(&mut self.rng)) // "Find the first item which has a weight *higher* than the chosen weight."
+    }
 
-        let logprob = probs[next_token as usize].log(10.0);
+    /// The logprob of a token and the top logprobs, given the natural-log logprobs of all tokens.
+    fn token_logprobs(
+        &self,
+        logprobs: &Tensor,
+        next_token: u32,
+        return_logprobs: bool,
+    ) -> Result<Logprobs> {
+        let logprob = logprobs.get(next_token as usize)?.to_scalar::<f32>()?;
 
         let top_logprobs = if return_logprobs {
-            Some(self.get_top_logprobs(&probs, &argsort_indices)?)
+            Some(self.get_top_logprobs(logprobs)?)
         } else {
             None
         };
+        self.logprobs(next_token, logprob, top_logprobs)
+    }
 
+    fn logprobs(
+        &self,
+        next_token: u32,
+        logprob: f32,
+        top_logprobs: Option<Vec<TopLogprob>>,
+    ) -> Result<Logprobs> {
         Ok(Logprobs {
             token: next_token,
             logprob,
@@ -173,42 +340,159 @@ impl Sampler {
         })
     }
 
-    fn sample_multinomial(
-        &mut self,
-        probs: &mut Vec<f32>,
-        argsort_indices: Vec<usize>,
-        return_logprobs: bool,
-    ) -> Result<Logprobs> {
-        self.apply_logit_bias(probs)?;
+    /// The top-k, if it drops any of the `vocab_size` tokens.
+    fn top_k(&self, vocab_size: usize) -> Option<usize> {
+        usize::try_from(self.topk)
+            .ok()
+            .filter(|top_k| *top_k > 0 && *top_k < vocab_size)
+    }
+
+    /// The top-p, if it drops any tokens.
+    fn top_p(&self) -> Option<f32> {
+        (self.topp > 0.0 && self.topp < 1.0).then_some(self.topp as f32)
+    }
 
-        let distr = WeightedIndex::new(&*probs).map_err(Error::wrap)?;
-        let next_token = distr.sample(&mut self.rng); // "Find the first item which has a weight *higher* than the chosen weight."
-        let logprob = probs[next_token].log(10.0);
+    /// Whether top-k or top-p applies, so the logits must be sorted.
+    fn needs_sort(&self, vocab_size: usize) -> bool {
+        self.top_k(vocab_size).is_some() || self.top_p().is_some()
+    }
 
-        let top_logprobs = if return_logprobs {
-            Some(self.get_top_logprobs(probs, &argsort_indices)?)
+    /// Sample with top-k and top-p, from the candidates of `topkp_candidates`. Without either, no sort is
+    /// needed.
+    fn sample_topkp(&mut self, logits: &Tensor, temperature: f64) -> Result<u32> {
+        let logits = (logits / temperature)?;
+        let vocab_size = logits.dim(D::Minus1)?;
+        if !self.needs_sort(vocab_size) {
+            let logits: Vec<f32> = logits.to_vec1()?;
+            return Ok(self.sample_scaled_logits(&logits, None)? as u32);
+        }
+
+        let logprobs = candle_nn::ops::log_softmax(&logits, D::Minus1)?.unsqueeze(0)?;
+        let top_k = self.top_k(vocab_size);
+        let mut candidates = Self::topkp_candidates(&logprobs, &[(top_k, self.top_p())])?;
+        // NOTE(EricLBuehler): Unwrap reasoning: There is one row.
+        let (logprobs, tokens) = candidates.pop().unwrap();
+        self.sample_candidates(&logprobs, &tokens, top_k.is_some())
+    }
+
+    /// Sample one of the candidates of `topkp_candidates`, given their logprobs and whether they are the
+    /// top-k. Top-p is of the probability which the top-k keeps, like in `apply_topkp`, and otherwise of the
+    /// whole distribution, of which the candidates hold only a part.
+    fn sample_candidates(
+        &mut self,
+        logprobs: &[f32],
+        tokens: &[u32],
+        is_top_k: bool,
+    ) -> Result<u32> {
+        let top_p = if is_top_k {
+            self.top_p()
         } else {
-            None
+            let mass = logprobs.iter().map(|x| x.exp()).sum::<f32>();
+            self.top_p().map(|top_p| top_p / mass)
         };
+        let index = self.sample_scaled_logits(logprobs, top_p)?;
+        Ok(tokens[index])
+    }
 
-        Ok(Logprobs {
-            token: next_token as u32,
-            logprob,
-            top_logprobs,
-            bytes: self
-                .tokenizer
-                .decode(&[next_token.try_into().unwrap()], true)
-                .map_err(|x| Error::Msg(x.to_string()))?,
-        })
+    /// Sample an index of `logits`, which are divided by the temperature. With a `top_p`, of the probability
+    /// of the tokens of `logits`, they must be sorted in descending order, and top-p is applied with a linear
+    /// scan.
+    fn sample_scaled_logits(&mut self, logits: &[f32], top_p: Option<f32>) -> Result<usize> {
+        let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+        let mut probs = logits.iter().map(|x| (x - max).exp()).collect::<Vec<_>>();
+        let sum = probs.iter().sum::<f32>();
+        for prob in probs.iter_mut() {
+            *prob /= sum;
+        }
+        if let Some(top_p) = top_p {
+            // top-p sampling (or "nucleus sampling") samples from the smallest set of
+            // tokens that exceed probability top_p.
+            let mut cumsum = 0.;
+            let n_kept = probs
+                .iter()
+                .take_while(|&&p| {
+                    let keep = cumsum < top_p;
+                    cumsum += p;
+                    keep
+                })
+                .count();
+            probs.truncate(n_kept.max(1));
+        }
+        self.sample_multinomial(&probs)
     }
 
-    fn sample_topkp(
-        &mut self,
-        probs: &mut Vec<f32>,
-        top_k: i64,
-        top_p: f32,
-        return_logprobs: bool,
-    ) -> Result<Logprobs> {
+    /// Sample with the truncation samplers which need the whole sorted distribution on the CPU.
+    fn sample_truncated(&mut self, probs: &mut [f32]) -> Result<u32> {
+        self.apply_truncation(probs);
+
+        // Sample with clamped probabilities.
+        Ok(self.sample_multinomial(probs)? as u32)
+    }
+
+    fn sample_mirostat(&mut self, probs: &mut [f32], mirostat: Mirostat) -> Result<u32> {
+        let mut argsort_indices = (0..probs.len()).collect::<Vec<_>>();
+        argsort_indices.sort_by(|&i, &j| probs[j].total_cmp(&probs[i]));
+
+        let n_kept = match mirostat.version {
+            MirostatVersion::V1 => {
+                // Estimate the Zipf exponent `s` from the 100 most likely tokens, and keep the top-k which
+                // gives a surprise of `mu` for it.
+                let m = 100.min(probs.len());
+                let (mut sum_ti_bi, mut sum_ti_sq) = (0f32, 0f32);
+                for i in 0..m.saturating_sub(1) {
+                    let t_i = ((i + 2) as f32 / (i + 1) as f32).ln();
+                    let b_i = (probs[argsort_indices[i]] / probs[argsort_indices[i + 1]]).ln();
+                    sum_ti_bi += t_i * b_i;
+                    sum_ti_sq += t_i * t_i;
+                }
+                let s_hat = sum_ti_bi / sum_ti_sq;
+                let epsilon_hat = s_hat - 1.;
+                let k = ((epsilon_hat * 2f32.powf(self.mirostat_mu))
+                    / (1. - (probs.len() as f32).powf(-epsilon_hat)))
+                .powf(1. / s_hat);
+                // A degenerate estimate is NaN, which is cast to zero.
+                (k.round() as usize).clamp(1, probs.len())
+            }
+            MirostatVersion::V2 => argsort_indices
+                .iter()
+                .take_while(|&&i| -probs[i].log2() <= self.mirostat_mu)
+                .count()
+                .max(1),
+        };
+        for &index in &argsort_indices[n_kept..] {
+            probs[index] = 0.0;
+        }
+        let sum = probs.iter().sum::<f32>();
+        for prob in probs.iter_mut() {
+            *prob /= sum;
+        }
+
+        let next_token = self.sample_multinomial(probs)?;
+        let surprise = -probs[next_token].log2();
+        self.mirostat_mu -= mirostat.eta * (surprise - mirostat.tau);
+        Ok(next_token as u32)
+    }
+
+    /// Clamp the probabilities of the tokens which top-k, top-p, tail-free, min-p and typical sampling drop to
+    /// zero, in that order.
+    fn apply_truncation(&self, probs: &mut [f32]) {
+        let argsort_indices = Self::apply_topkp(probs, self.topk, self.topp as f32);
+        Self::apply_tfs(probs, &argsort_indices, self.tfs_z as f32);
+        Self::apply_minp(probs, &argsort_indices, self.minp as f32);
+        Self::apply_typical(probs, self.typical_p as f32);
+    }
+
+    /// Whether the truncation needs the whole sorted distribution on the CPU, rather than the top-k and top-p
+    /// of `sample_topkp`.
+    fn needs_cpu_truncation(&self) -> bool {
+        (self.minp > 0.0 && self.minp <= 1.0)
+            || (self.typical_p > 0.0 && self.typical_p < 1.0)
+            || (self.tfs_z > 0.0 && self.tfs_z < 1.0)
+    }
+
+    /// Clamp the probabilities of the tokens outside of the top-k and the top-p to zero. Returns the tokens
+    /// sorted by descending probability.
+    fn apply_topkp(probs: &mut [f32], top_k: i64, top_p: f32) -> Vec<usize> {
         let mut argsort_indices = (0..probs.len()).collect::<Vec<_>>();
 
         // Sort by descending probability.
@@ -224,7 +508,7 @@ impl Sampler {
         }
 
         if top_p <= 0.0 || top_p >= 1.0 {
-            return self.sample_multinomial(probs, argsort_indices, return_logprobs);
+            return argsort_indices;
         }
         // TOP P
 
@@ -232,7 +516,9 @@ impl Sampler {
         // tokens that exceed probability top_p. This way we never sample tokens that
         // have very low probabilities and are less likely to go "off the rails".
 
-        // Clamp smaller probabilities to zero.
+        // Clamp smaller probabilities to zero. The top-p is of the probability which the top-k keeps, like in
+        // `sample_topkp`.
+        let top_p = top_p * probs.iter().sum::<f32>();
         let mut cumsum = 0.;
         for index in &argsort_indices {
             if cumsum >= top_p {
@@ -241,26 +527,232 @@ impl Sampler {
                 cumsum += probs[*index];
             }
         }
+        argsort_indices
+    }
 
-        // Sample with clamped probabilities.
-        self.sample_multinomial(probs, argsort_indices, return_logprobs)
+    /// Tail-free sampling cuts off the tail of the sorted distribution where it flattens out: tokens are kept
+    /// until the absolute second differences of the sorted probabilities, normalized to sum to one, sum to
+    /// more than `tfs_z`.
+    fn apply_tfs(probs: &mut [f32], argsort_indices: &[usize], tfs_z: f32) {
+        if tfs_z <= 0.0 || tfs_z >= 1.0 {
+            return;
+        }
+        let kept = argsort_indices
+            .iter()
+            .map(|&i| probs[i])
+            .take_while(|&p| p > 0.0)
+            .collect::<Vec<_>>();
+        if kept.len() < 3 {
+            return;
+        }
+        let second_diffs = kept
+            .windows(3)
+            .map(|w| (w[0] - 2. * w[1] + w[2]).abs())
+            .collect::<Vec<_>>();
+        let sum = second_diffs.iter().sum::<f32>();
+        if sum <= 0.0 {
+            return;
+        }
+        let mut cumsum = 0.;
+        let mut n_kept = kept.len();
+        for (i, diff) in second_diffs.iter().enumerate() {
+            cumsum += diff / sum;
+            if cumsum > tfs_z {
+                n_kept = i.max(1);
+                break;
+            }
+        }
+        for &index in &argsort_indices[n_kept..] {
+            probs[index] = 0.0;
+        }
     }
 
-    fn apply_repeat_presence_penalty(
-        logits: &Tensor,
-        presence_penalty: f32,
-        frequency_penalty: f32,
-        context: &[u32],
-    ) -> Result<Tensor> {
-        //mu[j] -> mu[j] - c[j] * alpha_frequency - float(c[j] > 0) * alpha_presence
+    /// Min-p sampling keeps the tokens with at least `min_p` times the probability of the most likely one.
+    fn apply_minp(probs: &mut [f32], argsort_indices: &[usize], min_p: f32) {
+        if min_p <= 0.0 || min_p > 1.0 {
+            return;
+        }
+        let Some(&most_likely) = argsort_indices.first() else {
+            return;
+        };
+        let threshold = min_p * probs[most_likely];
+        for prob in probs.iter_mut() {
+            if *prob < threshold {
+                *prob = 0.0;
+            }
+        }
+    }
+
+    /// Locally typical sampling keeps the tokens whose surprise is closest to the entropy of the distribution,
+    /// up to a probability mass of `typical_p`.
+    fn apply_typical(probs: &mut [f32], typical_p: f32) {
+        if typical_p <= 0.0 || typical_p >= 1.0 {
+            return;
+        }
+        let sum = probs.iter().sum::<f32>();
+        let entropy = -probs
+            .iter()
+            .filter(|&&p| p > 0.0)
+            .map(|&p| (p / sum) * (p / sum).ln())
+            .sum::<f32>();
+        let deviation = |p: f32| (-(p / sum).ln() - entropy).abs();
+        let mut typical_indices = (0..probs.len())
+            .filter(|&i| probs[i] > 0.0)
+            .collect::<Vec<_>>();
+        typical_indices.sort_by(|&i, &j| deviation(probs[i]).total_cmp(&deviation(probs[j])));
+
+        let mut cumsum = 0.;
+        for index in typical_indices {
+            if cumsum >= typical_p {
+                probs[index] = 0.0;
+            } else {
+                cumsum += probs[index] / sum;
+            }
+        }
+    }
+
+    /// The distribution which tokens are sampled from with a temperature, given the processed logits, after
+    /// the truncation and normalized to sum to one.
+    fn sampling_probs(&self, logits: &Tensor, temperature: f64) -> Result<Vec<f32>> {
+        let probs = candle_nn::ops::softmax_last_dim(&(logits / temperature)?)?;
+        let mut probs: Vec<f32> = probs.to_vec1()?;
+        self.apply_truncation(&mut probs);
+        let sum = probs.iter().sum::<f32>();
+        for prob in probs.iter_mut() {
+            *prob /= sum;
+        }
+        Ok(probs)
+    }
+
+    /// The multiplicative repetition penalty of CTRL, which divides the positive logits of the tokens in the
+    /// context by the penalty and multiplies the negative ones, followed by the frequency and presence
+    /// penalties.
+    fn apply_repeat_presence_penalty(&self, logits: &mut [f32], context: &[u32]) {
+        let mut counts: HashMap<u32, usize> = HashMap::new();
+        for tok in context {
+            *counts.entry(*tok).or_default() += 1;
+        }
+        let presence_penalty = self.presence_penalty.unwrap_or(0.);
+        let frequency_penalty = self.frequency_penalty.unwrap_or(0.);
+        for (tok, count) in counts {
+            let Some(logit) = logits.get_mut(tok as usize) else {
+                continue;
+            };
+            if let Some(repetition_penalty) = self.repetition_penalty {
+                if *logit > 0. {
+                    *logit /= repetition_penalty;
+                } else {
+                    *logit *= repetition_penalty;
+                }
+            }
+            //mu[j] -> mu[j] - c[j] * alpha_frequency - float(c[j] > 0) * alpha_presence
+            *logit -= count as f32 * frequency_penalty + presence_penalty;
+        }
+    }
+
+    /// Ban the tokens which would complete an n-gram of `no_repeat_ngram_size` tokens which is in the context.
+    fn apply_no_repeat_ngram(&self, logits: &mut [f32], context: &[u32]) {
+        let Some(n) = self.no_repeat_ngram_size.filter(|n| *n > 0) else {
+            return;
+        };
+        if context.len() < n {
+            return;
+        }
+        let prefix = &context[context.len() - (n - 1)..];
+        for ngram in context.windows(n) {
+            if ngram[..n - 1] == *prefix {
+                if let Some(logit) = logits.get_mut(ngram[n - 1] as usize) {
+                    *logit = f32::NEG_INFINITY;
+                }
+            }
+        }
+    }
+
+    /// Penalize the tokens which would extend a repetition of the end of the context, by the length of the
+    /// longest such repetition.
+    fn apply_dry_penalty(&self, logits: &mut [f32], context: &[u32]) {
+        let Some(dry_params) = &self.dry_params else {
+            return;
+        };
+        let Some((&last, earlier)) = context.split_last() else {
+            return;
+        };
+        if dry_params.multiplier <= 0. || self.dry_sequence_breakers.contains(&last) {
+            return;
+        }
+
+        // Each earlier occurrence of the last token is followed by a token which would repeat it. The
+        // repetition is as long as the tokens before the occurrence match the end of the context.
+        let mut match_lengths: HashMap<u32, usize> = HashMap::new();
+        for (i, _) in earlier.iter().enumerate().filter(|(_, tok)| **tok == last) {
+            let next = context[i + 1];
+            if self.dry_sequence_breakers.contains(&next) {
+                continue;
+            }
+            let mut match_length = 1;
+            while match_length <= i {
+                let tok = context[i - match_length];
+                let end_tok = context[context.len() - 1 - match_length];
+                if tok != end_tok || self.dry_sequence_breakers.contains(&end_tok) {
+                    break;
+                }
+                match_length += 1;
+            }
+            let longest = match_lengths.entry(next).or_default();
+            *longest = (*longest).max(match_length);
+        }
+
+        for (tok, match_length) in match_lengths {
+            if match_length < dry_params.allowed_length {
+                continue;
+            }
+            if let Some(logit) = logits.get_mut(tok as usize) {
+                let exponent = (match_length - dry_params.allowed_length) as f32;
+                *logit -= dry_params.multiplier * dry_params.base.powf(exponent);
+            }
+        }
+    }
+
+    /// Get the logprobs of tokens which were not sampled, such as the prompt tokens. Row `i` of `logits`, of
+    /// shape `(n, vocab_size)`, are the logits for `toks[i]`.
+    pub fn score_tokens(&self, logits: &Tensor, toks: &[u32]) -> Result<Vec<Logprobs>> {
+        let logprobs = candle_nn::ops::log_softmax(&logits.to_dtype(DType::F32)?, D::Minus1)?;
+        let mut scored = Vec::with_capacity(toks.len());
+        for (i, tok) in toks.iter().enumerate() {
+            scored.push(self.token_logprobs(&logprobs.get(i)?, *tok, true)?);
+        }
+        Ok(scored)
+    }
+
+    /// The logits which tokens are sampled from and logprobs are computed from: after the penalties and the
+    /// logit bias, in `f32`.
+    fn process_logits(&self, logits: &Tensor, penalty_ctxt: Option<&[u32]>) -> Result<Tensor> {
+        let logits = logits.to_dtype(DType::F32)?;
+        if self.frequency_penalty.is_none()
+            && self.presence_penalty.is_none()
+            && self.repetition_penalty.is_none()
+            && self.no_repeat_ngram_size.is_none()
+            && self.dry_params.is_none()
+            && self.logits_bias.is_none()
+        {
+            return Ok(logits);
+        }
         let device = logits.device();
         let mut logits = logits.to_vec1::<f32>()?;
-        for (token_id, logit) in logits.iter_mut().enumerate() {
-            let count = context.iter().filter(|x| **x as usize == token_id).count();
-            *logit = *logit
-                - count as f32 * frequency_penalty
-                - if count > 0 { 1. } else { 0. } * presence_penalty;
+        if self.frequency_penalty.is_some()
+            || self.presence_penalty.is_some()
+            || self.repetition_penalty.is_some()
+            || self.no_repeat_ngram_size.is_some()
+            || self.dry_params.is_some()
+        {
+            let Some(context) = penalty_ctxt else {
+                bail!("Must specify penalty context.");
+            };
+            self.apply_repeat_presence_penalty(&mut logits, context);
+            self.apply_no_repeat_ngram(&mut logits, context);
+            self.apply_dry_penalty(&mut logits, context);
         }
+        self.apply_logit_bias(&mut logits)?;
         let logits_len = logits.len();
         Tensor::from_vec(logits, logits_len, device)
     }
@@ -269,45 +761,495 @@ impl Sampler {
     ///
     /// If the temperature is `None`, argmax sampling is used. Otherwise, the selected sampling is used.
     /// With `top-p` sampling, if the `top-p` value is `<= 0.0` or `>= 1.0`, multinomial sampling is used.
-    /// If `frequency_penalty.is_some()` or `presence_penalty.is_some()`, then `penalty_ctxt` must be provided.
+    /// Likewise, min-p sampling only applies with a value in `(0, 1]`, and typical and tail-free sampling with
+    /// a value in `(0, 1)`. Mirostat replaces all of them.
+    /// If any of the penalties, the no-repeat n-gram size or DRY is set, then `penalty_ctxt` must be provided.
+    ///
+    /// The logprobs are natural logs of the softmax of the logits after the penalties and the logit bias, so
+    /// the temperature and the truncation do not change them.
     pub fn sample(
         &mut self,
         logits: &Tensor,
         penalty_ctxt: Option<&[u32]>,
         return_logprobs: bool,
     ) -> Result<Logprobs> {
-        let logits = logits.to_dtype(DType::F32)?;
+        let logits = self.process_logits(logits, penalty_ctxt)?;
 
-        let logits = if self.frequency_penalty.is_none() && self.presence_penalty.is_none() {
-            logits
+        let next_token = match (self.temperature, self.mirostat) {
+            (None, _) => logits.argmax(D::Minus1)?.to_scalar::<u32>()?,
+            (Some(temperature), Some(mirostat)) => {
+                let mut probs: Vec<f32> =
+                    candle_nn::ops::softmax_last_dim(&(&logits / temperature)?)?.to_vec1()?;
+                self.sample_mirostat(&mut probs, mirostat)?
+            }
+            (Some(temperature), None) if self.needs_cpu_truncation() => {
+                let mut probs: Vec<f32> =
+                    candle_nn::ops::softmax_last_dim(&(&logits / temperature)?)?.to_vec1()?;
+                self.sample_truncated(&mut probs)?
+            }
+            (Some(temperature), None) => self.sample_topkp(&logits, temperature)?,
+        };
+        let logprobs = candle_nn::ops::log_softmax(&logits, D::Minus1)?;
+        self.token_logprobs(&logprobs, next_token, return_logprobs)
+    }
+
+    /// Whether `sample_batch` can sample with the sampler, which holds unless it needs Mirostat, the truncation
+    /// samplers of the CPU, the no-repeat n-grams or DRY, or its logit bias is out of range.
+    pub fn is_batchable(&self, vocab_size: usize) -> bool {
+        self.mirostat.is_none()
+            && !self.needs_cpu_truncation()
+            && self.no_repeat_ngram_size.is_none()
+            && self.dry_params.is_none()
+            && self.logits_bias.as_ref().map_or(true, |bias| {
+                bias.keys().all(|tok| (*tok as usize) < vocab_size)
+            })
+    }
+
+    /// Sample a token for each row of `logits`, of shape `(batch, vocab_size)`, with the sampler and the penalty
+    /// context of the row. The samplers must be batchable.
+    ///
+    /// The penalties, the logit bias, the temperature, the candidates of top-k and top-p and the logprobs are
+    /// computed for the whole batch at once, and only the candidates are sorted. The rows are sampled exactly
+    /// as `sample` samples them, so a seed gives the same tokens with or without batching.
+    pub fn sample_batch(
+        samplers: &mut [&mut Sampler],
+        logits: &Tensor,
+        penalty_ctxts: &[&[u32]],
+        return_logprobs: &[bool],
+    ) -> Result<Vec<Logprobs>> {
+        let logits = Self::process_logits_batch(samplers, logits, penalty_ctxts)?;
+        let (batch, vocab_size) = logits.dims2()?;
+        let mut next_tokens: Vec<u32> = logits.argmax(D::Minus1)?.to_vec1()?;
+
+        let (sorted_rows, unsorted_rows): (Vec<usize>, Vec<usize>) = (0..batch)
+            .filter(|i| samplers[*i].temperature.is_some())
+            .partition(|i| samplers[*i].needs_sort(vocab_size));
+        if !unsorted_rows.is_empty() {
+            let scaled: Vec<Vec<f32>> =
+                Self::scaled_rows(samplers, &logits, &unsorted_rows)?.to_vec2()?;
+            for (i, row) in zip(unsorted_rows, scaled) {
+                next_tokens[i] = samplers[i].sample_scaled_logits(&row, None)? as u32;
+            }
+        }
+        if !sorted_rows.is_empty() {
+            let params = sorted_rows
+                .iter()
+                .map(|i| (samplers[*i].top_k(vocab_size), samplers[*i].top_p()))
+                .collect::<Vec<_>>();
+            let scaled = Self::scaled_rows(samplers, &logits, &sorted_rows)?;
+            let logprobs = candle_nn::ops::log_softmax(&scaled, D::Minus1)?;
+            let candidates = Self::topkp_candidates(&logprobs, &params)?;
+            for ((i, (top_k, _)), (logprobs, tokens)) in zip(zip(sorted_rows, params), candidates) {
+                next_tokens[i] =
+                    samplers[i].sample_candidates(&logprobs, &tokens, top_k.is_some())?;
+            }
+        }
+
+        let logprobs = candle_nn::ops::log_softmax(&logits, D::Minus1)?;
+        let next_tokens_t = Tensor::new(next_tokens.as_slice(), logits.device())?;
+        let token_logprobs: Vec<f32> = logprobs
+            .gather(&next_tokens_t.unsqueeze(1)?, 1)?
+            .squeeze(1)?
+            .to_vec1()?;
+
+        // The top logprobs of the rows which return them, with one top-k.
+        let mut top_logprobs = vec![None; batch];
+        let top_rows = (0..batch)
+            .filter(|i| return_logprobs[*i])
+            .collect::<Vec<_>>();
+        let max_n = top_rows
+            .iter()
+            .map(|i| samplers[*i].top_n_logprobs.min(vocab_size))
+            .max()
+            .unwrap_or(0);
+        if max_n > 0 {
+            let rows = Self::row_indices(&top_rows, logits.device())?;
+            let (top_n_logprobs, top_n_toks) =
+                Self::topk_last_dim(&logprobs.index_select(&rows, 0)?, max_n)?;
+            for (i, (mut row_logprobs, mut row_toks)) in
+                zip(top_rows, zip(top_n_logprobs, top_n_toks))
+            {
+                let n = samplers[i].top_n_logprobs.min(vocab_size);
+                row_logprobs.truncate(n);
+                row_toks.truncate(n);
+                top_logprobs[i] = Some(samplers[i].top_logprobs(row_toks, row_logprobs)?);
+            }
         } else {
-            if penalty_ctxt.is_none() {
-                bail!("Must specify penalty context.");
+            for i in top_rows {
+                top_logprobs[i] = Some(Vec::new());
             }
-            Self::apply_repeat_presence_penalty(
-                &logits,
-                self.presence_penalty.unwrap_or(0.),
-                self.frequency_penalty.unwrap_or(0.),
-                penalty_ctxt.unwrap(),
-            )?
+        }
+
+        zip(
+            zip(samplers.iter(), next_tokens),
+            zip(token_logprobs, top_logprobs),
+        )
+        .map(|((sampler, token), (logprob, top_logprobs))| {
+            sampler.logprobs(token, logprob, top_logprobs)
+        })
+        .collect()
+    }
+
+    /// The `k` largest values of each row of `xs`, of shape `(rows, n)`, in descending order, with their
+    /// indices. They are selected on the device if the rows are at most `MAX_DEVICE_SORT_WIDTH` wide or `k` is at
+    /// most half of that. Otherwise, the rows are copied to the host, where only their top `k` are sorted.
+    fn topk_last_dim(xs: &Tensor, k: usize) -> Result<(Vec<Vec<f32>>, Vec<Vec<u32>>)> {
+        let n = xs.dim(D::Minus1)?;
+        let k = k.min(n);
+        if n <= MAX_DEVICE_SORT_WIDTH || k <= MAX_DEVICE_SORT_WIDTH / 2 {
+            let (values, indices) = Self::device_topk(xs, k)?;
+            return Ok((values.to_vec2()?, indices.to_vec2()?));
+        }
+        let rows: Vec<Vec<f32>> = xs.to_vec2()?;
+        Ok(rows.iter().map(|row| Self::host_topk(row, k)).unzip())
+    }
+
+    /// `topk_last_dim` on the device. Rows wider than `MAX_DEVICE_SORT_WIDTH` are split into chunks of that width,
+    /// which are sorted separately. The top `k` of each chunk are kept, and this is repeated until they fit in one
+    /// sort. With `k` at most half of the width, each round about halves the rows.
+    fn device_topk(xs: &Tensor, k: usize) -> Result<(Tensor, Tensor)> {
+        let rows = xs.dim(0)?;
+        let device = xs.device();
+        let mut values = xs.clone();
+        // The columns of `xs` which the columns of `values` hold, after the first round.
+        let mut indices: Option<Tensor> = None;
+        loop {
+            let n = values.dim(1)?;
+            let width = n.min(MAX_DEVICE_SORT_WIDTH);
+            let chunks = n / width;
+            let full = chunks * width;
+            let kept = k.min(width);
+            let (chunk_values, chunk_indices) = values
+                .narrow(1, 0, full)?
+                .reshape((rows * chunks, width))?
+                .sort_last_dim(false)?;
+            let offsets = (0..chunks).map(|c| (c * width) as u32).collect::<Vec<_>>();
+            let offsets = Tensor::from_vec(offsets, (1, chunks, 1), device)?;
+            let mut new_values = vec![chunk_values
+                .narrow(1, 0, kept)?
+                .reshape((rows, chunks * kept))?];
+            let mut new_indices = vec![chunk_indices
+                .narrow(1, 0, kept)?
+                .reshape((rows, chunks, kept))?
+                .broadcast_add(&offsets)?
+                .reshape((rows, chunks * kept))?];
+            // The columns which do not fill a chunk are sorted on their own.
+            if full < n {
+                let (rest_values, rest_indices) = values
+                    .narrow(1, full, n - full)?
+                    .contiguous()?
+                    .sort_last_dim(false)?;
+                let kept = k.min(n - full);
+                new_values.push(rest_values.narrow(1, 0, kept)?);
+                new_indices.push(
+                    rest_indices
+                        .narrow(1, 0, kept)?
+                        .broadcast_add(&Tensor::new(&[[full as u32]], device)?)?,
+                );
+            }
+            values = Tensor::cat(&new_values, 1)?;
+            let new_indices = Tensor::cat(&new_indices, 1)?;
+            let new_indices = match indices {
+                Some(indices) => indices.gather(&new_indices, 1)?,
+                None => new_indices,
+            };
+            if n <= MAX_DEVICE_SORT_WIDTH {
+                return Ok((values, new_indices));
+            }
+            indices = Some(new_indices);
+        }
+    }
+
+    /// The `k` largest values of a row and their indices, in descending order. Only the top `k` are sorted.
+    fn host_topk(row: &[f32], k: usize) -> (Vec<f32>, Vec<u32>) {
+        let n = row.len();
+        let descending = |i: &u32, j: &u32| row[*j as usize].total_cmp(&row[*i as usize]);
+        let mut indices = (0..n as u32).collect::<Vec<_>>();
+        if k < n {
+            indices.select_nth_unstable_by(k, descending);
+            indices.truncate(k);
+        }
+        indices.sort_by(descending);
+        (indices.iter().map(|i| row[*i as usize]).collect(), indices)
+    }
+
+    /// The tokens which top-k and top-p can keep, for each row of `logprobs` and its top-k and top-p, with
+    /// their logprobs in descending order. Without a top-k, they are the `TOP_P_CANDIDATES` most likely
+    /// tokens. The rows whose candidates do not hold the top-p are copied to the host once, where their
+    /// candidates are doubled until they do, so that the whole vocabulary is not sorted.
+    fn topkp_candidates(
+        logprobs: &Tensor,
+        params: &[(Option<usize>, Option<f32>)],
+    ) -> Result<Vec<(Vec<f32>, Vec<u32>)>> {
+        let vocab_size = logprobs.dim(D::Minus1)?;
+        let mass = |values: &[f32]| values.iter().map(|x| x.exp()).sum::<f32>();
+        let widths = params
+            .iter()
+            .map(|(top_k, _)| top_k.unwrap_or(TOP_P_CANDIDATES).min(vocab_size))
+            .collect::<Vec<_>>();
+        // NOTE(EricLBuehler): Unwrap reasoning: There is at least one row.
+        let max_width = *widths.iter().max().unwrap();
+        let (values, tokens) = Self::topk_last_dim(logprobs, max_width)?;
+        let mut candidates = Vec::with_capacity(params.len());
+        let mut short_rows = Vec::new();
+        for (i, (width, (mut values, mut tokens))) in zip(widths, zip(values, tokens)).enumerate() {
+            values.truncate(width);
+            tokens.truncate(width);
+            let (top_k, top_p) = params[i];
+            if top_k.is_none() && width < vocab_size && mass(&values) < top_p.unwrap_or(1.) {
+                short_rows.push(i);
+            }
+            candidates.push((values, tokens));
+        }
+        if short_rows.is_empty() {
+            return Ok(candidates);
+        }
+        let rows: Vec<Vec<f32>> = logprobs
+            .index_select(&Self::row_indices(&short_rows, logprobs.device())?, 0)?
+            .to_vec2()?;
+        for (i, row) in zip(short_rows, rows) {
+            let top_p = params[i].1.unwrap_or(1.);
+            let mut width = TOP_P_CANDIDATES;
+            loop {
+                width = (width * 2).min(vocab_size);
+                candidates[i] = Self::host_topk(&row, width);
+                if width == vocab_size || mass(&candidates[i].0) >= top_p {
+                    break;
+                }
+            }
+        }
+        Ok(candidates)
+    }
+
+    fn row_indices(rows: &[usize], device: &Device) -> Result<Tensor> {
+        let rows = rows.iter().map(|i| *i as u32).collect::<Vec<_>>();
+        Tensor::new(rows.as_slice(), device)
+    }
+
+    /// The `rows` of the processed logits, divided by the temperature of their sampler.
+    fn scaled_rows(samplers: &[&mut Sampler], logits: &Tensor, rows: &[usize]) -> Result<Tensor> {
+        // Like the division of a tensor by a scalar, this multiplies by the inverse of the temperature.
+        let inv_temperatures = rows
+            .iter()
+            .map(|i| (1. / samplers[*i].temperature.unwrap_or(1.)) as f32)
+            .collect::<Vec<_>>();
+        let inv_temperatures =
+            Tensor::from_vec(inv_temperatures, (rows.len(), 1), logits.device())?;
+        logits
+            .index_select(&Self::row_indices(rows, logits.device())?, 0)?
+            .broadcast_mul(&inv_temperatures)
+    }
+
+    /// `process_logits` for a batch of batchable samplers, on the device. The tokens of the penalty contexts
+    /// are counted with one scatter, from which the penalties of all rows are applied at once.
+    fn process_logits_batch(
+        samplers: &[&mut Sampler],
+        logits: &Tensor,
+        penalty_ctxts: &[&[u32]],
+    ) -> Result<Tensor> {
+        let mut logits = logits.to_dtype(DType::F32)?;
+        let (batch, vocab_size) = logits.dims2()?;
+        let device = logits.device().clone();
+
+        let penalized_rows = (0..batch)
+            .filter(|i| {
+                let sampler = &samplers[*i];
+                sampler.frequency_penalty.is_some()
+                    || sampler.presence_penalty.is_some()
+                    || sampler.repetition_penalty.is_some()
+            })
+            .collect::<Vec<_>>();
+        // Rows without a penalty get the penalties which do not change the logits.
+        let mut frequency_penalties = vec![0f32; batch];
+        let mut presence_penalties = vec![0f32; batch];
+        let mut repetition_penalties = vec![1f32; batch];
+        let mut indices = Vec::new();
+        for i in penalized_rows {
+            let sampler = &samplers[i];
+            frequency_penalties[i] = sampler.frequency_penalty.unwrap_or(0.);
+            presence_penalties[i] = sampler.presence_penalty.unwrap_or(0.);
+            repetition_penalties[i] = sampler.repetition_penalty.unwrap_or(1.);
+            indices.extend(
+                penalty_ctxts[i]
+                    .iter()
+                    .filter(|tok| (**tok as usize) < vocab_size)
+                    .map(|tok| (i * vocab_size) as u32 + tok),
+            );
+        }
+        if !indices.is_empty() {
+            let n_indices = indices.len();
+            let counts = Tensor::zeros(batch * vocab_size, DType::F32, &device)?
+                .index_add(
+                    &Tensor::from_vec(indices, n_indices, &device)?,
+                    &Tensor::ones(n_indices, DType::F32, &device)?,
+                    0,
+                )?
+                .reshape((batch, vocab_size))?;
+            let present = counts.gt(0f64)?;
+            let column = |values: Vec<f32>| Tensor::from_vec(values, (batch, 1), &device);
+
+            let repetition_penalties = column(repetition_penalties)?;
+            let repeated = logits.gt(0f64)?.where_cond(
+                &logits.broadcast_div(&repetition_penalties)?,
+                &logits.broadcast_mul(&repetition_penalties)?,
+            )?;
+            logits = present.where_cond(&repeated, &logits)?;
+            //mu[j] -> mu[j] - c[j] * alpha_frequency - float(c[j] > 0) * alpha_presence
+            let penalties = (counts.broadcast_mul(&column(frequency_penalties)?)?
+                + present
+                    .to_dtype(DType::F32)?
+                    .broadcast_mul(&column(presence_penalties)?)?)?;
+            logits = (logits - penalties)?;
+        }
+
+        let mut indices = Vec::new();
+        let mut biases = Vec::new();
+        for (i, sampler) in samplers.iter().enumerate() {
+            for (tok, bias) in sampler.logits_bias.iter().flatten() {
+                indices.push((i * vocab_size) as u32 + tok);
+                biases.push(*bias);
+            }
+        }
+        if !indices.is_empty() {
+            let n_indices = indices.len();
+            logits = logits
+                .flatten_all()?
+                .index_add(
+                    &Tensor::from_vec(indices, n_indices, &device)?,
+                    &Tensor::from_vec(biases, n_indices, &device)?,
+                    0,
+                )?
+                .reshape((batch, vocab_size))?;
+        }
+        Ok(logits)
+    }
+
+    /// The `n` most likely next tokens for beam search, from the distribution after the penalties and the logit
+    /// bias. The temperature, the truncation and Mirostat do not apply.
+    pub fn beam_candidates(
+        &self,
+        logits: &Tensor,
+        penalty_ctxt: Option<&[u32]>,
+        n: usize,
+        return_logprobs: bool,
+    ) -> Result<Vec<Logprobs>> {
+        let logits = self.process_logits(logits, penalty_ctxt)?;
+        let logprobs = candle_nn::ops::log_softmax(&logits, D::Minus1)?;
+        let n = n.min(logprobs.dim(D::Minus1)?);
+        let (_, mut candidates) = Self::topk_last_dim(&logprobs.unsqueeze(0)?, n)?;
+        // NOTE(EricLBuehler): Unwrap reasoning: There is one row.
+        candidates
+            .pop()
+            .unwrap()
+            .into_iter()
+            .map(|token| self.token_logprobs(&logprobs, token, return_logprobs))
+            .collect()
+    }
+
+    /// Draft a token with the draft model of speculative decoding. Returns the token and the distribution
+    /// it was drafted from, which is empty for argmax sampling.
+    pub fn sample_draft(
+        &mut self,
+        logits: &Tensor,
+        penalty_ctxt: Option<&[u32]>,
+    ) -> Result<(u32, Vec<f32>)> {
+        let Some(temperature) = self.temperature else {
+            return Ok((self.sample(logits, penalty_ctxt, false)?.token, Vec::new()));
         };
+        let logits = self.process_logits(logits, penalty_ctxt)?;
+        let probs = self.sampling_probs(&logits, temperature)?;
+        let next_token = self.sample_multinomial(&probs)?;
+        Ok((next_token as u32, probs))
+    }
 
-        let next_token = match self.temperature {
-            None => self.sample_argmax(logits, return_logprobs)?,
-            Some(temperature) => {
-                let logits = (&logits / temperature)?;
-                let probs = candle_nn::ops::softmax_last_dim(&logits)?;
-                let mut probs: Vec<f32> = probs.to_vec1()?;
-                self.sample_topkp(&mut probs, self.topk, self.topp as f32, return_logprobs)?
+    /// Sample a token with the target model of speculative decoding, at the position of a drafted token,
+    /// given with the distribution it was drafted from, or after the drafted tokens.
+    ///
+    /// A drafted token is kept with probability `min(1, p / q)`, where `p` and `q` are its probabilities for
+    /// the target and the draft model. Otherwise a token is sampled from `max(0, p - q)`, normalized. This
+    /// rejection sampling keeps the samples distributed exactly like those of the target model, and the
+    /// sampled token is the drafted one if and only if it is accepted. With argmax sampling, a drafted
+    /// token is accepted if it is the argmax.
+    pub fn sample_speculative(
+        &mut self,
+        logits: &Tensor,
+        penalty_ctxt: Option<&[u32]>,
+        draft: Option<(u32, &[f32])>,
+        return_logprobs: bool,
+    ) -> Result<Logprobs> {
+        let Some(temperature) = self.temperature else {
+            return self.sample(logits, penalty_ctxt, return_logprobs);
+        };
+        let logits = self.process_logits(logits, penalty_ctxt)?;
+        let probs = self.sampling_probs(&logits, temperature)?;
+        let next_token = match draft {
+            Some((token, draft_probs))
+                if self.rng.gen::<f32>() * draft_probs[token as usize] < probs[token as usize] =>
+            {
+                token as usize
+            }
+            Some((_, draft_probs)) => {
+                let residual = zip(&probs, draft_probs)
+                    .map(|(p, q)| (p - q).max(0.))
+                    .collect::<Vec<_>>();
+                self.sample_multinomial(&residual)?
             }
+            None => self.sample_multinomial(&probs)?,
         };
-        Ok(next_token)
+        let logprobs = candle_nn::ops::log_softmax(&logits, D::Minus1)?;
+        self.token_logprobs(&logprobs, next_token as u32, return_logprobs)
     }
 }
 
-mod tests {
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::{
+        collections::{HashMap, HashSet},
+        iter::zip,
+    };
+
     use hf_hub::{api::sync::ApiBuilder, Repo, RepoType};
-    use tokenizers::Tokenizer;
+    use tokenizers::{models::wordlevel::WordLevel, Tokenizer};
+
+    use candle_core::{DType, Device, Tensor};
+
+    use super::{DryParams, Mirostat, MirostatVersion, Sampler, TopLogprob};
+
+    /// A word-level tokenizer whose token `i` is `t{i}`, with `vocab_size` tokens, so that tests need no
+    /// download.
+    pub(crate) fn test_tokenizer(vocab_size: u32) -> Tokenizer {
+        let vocab = (0..vocab_size)
+            .map(|i| (format!("t{i}"), i))
+            .collect::<HashMap<_, _>>();
+        let model = WordLevel::builder()
+            .vocab(vocab)
+            .unk_token("t0".to_string())
+            .build()
+            .unwrap();
+        Tokenizer::new(model)
+    }
+
+    /// An argmax sampler without penalties or truncation.
+    pub(crate) fn test_sampler(vocab_size: u32) -> Sampler {
+        Sampler::new(
+            0,
+            None,
+            0,
+            test_tokenizer(vocab_size).into(),
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            -1,
+            1.,
+            0.,
+            1.,
+            1.,
+            None,
+        )
+    }
 
     #[allow(dead_code)]
     fn get_tokenizer() -> Tokenizer {
@@ -335,14 +1277,402 @@ mod tests {
             None,
             None,
             None,
+            None,
+            None,
+            None,
             32,
             0.1,
+            0.,
+            1.,
+            1.,
+            None,
         );
 
         let logits = Tensor::arange(0f64, 1024f64, &Device::Cpu).unwrap();
         let res = sampler.sample(&logits, None, false).unwrap();
         assert_eq!(res.token, 1023);
         assert_eq!(res.top_logprobs, None);
-        assert_eq!(res.logprob, 1023f64.log(10.) as f32)
+        // The log-softmax of the largest of the logits `0..1024` is `-ln(sum(e^-k))`, about `ln(1 - e^-1)`.
+        assert!((res.logprob - (1. - (-1f32).exp()).ln()).abs() < 1e-4)
+    }
+
+    fn assert_probs(probs: &[f32], expected: &[f32]) {
+        assert!(
+            zip(probs, expected).all(|(p, e)| (p - e).abs() < 1e-6),
+            "{probs:?} != {expected:?}"
+        );
+    }
+
+    #[test]
+    fn test_tfs_cuts_the_flat_tail() {
+        let mut probs = vec![0.05, 0.3, 0.4, 0.05, 0.2];
+        let argsort_indices = Sampler::apply_topkp(&mut probs, -1, 1.);
+        // The normalized second differences of `[0.4, 0.3, 0.2, 0.05, 0.05]` are `[0, 0.25, 0.75]`, so
+        // the third one takes their sum past `tfs_z`.
+        Sampler::apply_tfs(&mut probs, &argsort_indices, 0.5);
+        assert_probs(&probs, &[0., 0.3, 0.4, 0., 0.]);
+    }
+
+    #[test]
+    fn test_minp_keeps_tokens_at_the_cutoff() {
+        let mut probs = vec![0.5, 0.2, 0.1, 0.15, 0.05];
+        let argsort_indices = Sampler::apply_topkp(&mut probs, -1, 1.);
+        // The cutoff is `0.3 * 0.5`, which the token with `0.15` reaches.
+        Sampler::apply_minp(&mut probs, &argsort_indices, 0.3);
+        assert_probs(&probs, &[0.5, 0.2, 0., 0.15, 0.]);
+    }
+
+    #[test]
+    fn test_typical_keeps_the_mass_closest_to_the_entropy() {
+        // The entropy is 1.75 bits, and the surprises are 1, 2, 3 and 3 bits, so the tokens are kept in
+        // the order 1, 0, 2, 3 until their mass reaches `typical_p`.
+        let mut probs = vec![0.5, 0.25, 0.125, 0.125];
+        Sampler::apply_typical(&mut probs, 0.5);
+        assert_probs(&probs, &[0.5, 0.25, 0., 0.]);
+
+        let mut probs = vec![0.5, 0.25, 0.125, 0.125];
+        Sampler::apply_typical(&mut probs, 0.2);
+        assert_probs(&probs, &[0., 0.25, 0., 0.]);
+    }
+
+    fn mirostat_sampler(version: MirostatVersion, tau: f32, eta: f32) -> Sampler {
+        Sampler::new(
+            0,
+            Some(1.),
+            0,
+            test_tokenizer(4).into(),
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            -1,
+            1.,
+            0.,
+            1.,
+            1.,
+            Some(Mirostat { version, tau, eta }),
+        )
+    }
+
+    #[test]
+    fn test_mirostat_v1_mu() {
+        let mut sampler = mirostat_sampler(MirostatVersion::V1, 0.25, 0.1);
+        assert_eq!(sampler.mirostat_mu, 0.5);
+        // The estimated Zipf exponent gives a top-k of 1, so the token has a surprise of 0 and `mu` moves up
+        // by `eta * tau`.
+        let mut probs = vec![0.3, 0.6, 0.1];
+        let mirostat = sampler.mirostat.unwrap();
+        assert_eq!(sampler.sample_mirostat(&mut probs, mirostat).unwrap(), 1);
+        assert!((sampler.mirostat_mu - 0.525).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_mirostat_v2_mu() {
+        // Only the token with a surprise of at most `mu = 2` bits is kept, so `mu` moves up by `eta * tau`.
+        let mut sampler = mirostat_sampler(MirostatVersion::V2, 1., 0.1);
+        let mirostat = sampler.mirostat.unwrap();
+        let mut probs = vec![0.2, 0.7, 0.1];
+        assert_eq!(sampler.sample_mirostat(&mut probs, mirostat).unwrap(), 1);
+        assert!((sampler.mirostat_mu - 2.1).abs() < 1e-6);
+
+        // Both tokens have a surprise of 1 bit, which is 0.5 bits above `tau`.
+        let mut sampler = mirostat_sampler(MirostatVersion::V2, 0.5, 0.1);
+        let mirostat = sampler.mirostat.unwrap();
+        let mut probs = vec![0.5, 0.5];
+        sampler.sample_mirostat(&mut probs, mirostat).unwrap();
+        assert!((sampler.mirostat_mu - 0.95).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_no_repeat_ngram_bans_the_completion() {
+        let mut sampler = test_sampler(10);
+        sampler.no_repeat_ngram_size = Some(3);
+        let mut logits = vec![1.; 10];
+        // Only `3` would repeat a 3-gram, `1 2 3`, after the context ends with `1 2`.
+        sampler.apply_no_repeat_ngram(&mut logits, &[1, 2, 3, 4, 1, 2]);
+        for (tok, logit) in logits.iter().enumerate() {
+            if tok == 3 {
+                assert_eq!(*logit, f32::NEG_INFINITY);
+            } else {
+                assert_eq!(*logit, 1.);
+            }
+        }
+    }
+
+    fn dry_sampler(allowed_length: usize) -> Sampler {
+        let mut sampler = test_sampler(10);
+        sampler.dry_params = Some(DryParams::new(1., Some(2.), Some(allowed_length), None));
+        sampler.dry_sequence_breakers = HashSet::from([9]);
+        sampler
+    }
+
+    #[test]
+    fn test_dry_penalty_from_allowed_length() {
+        // `4` would extend the repetition of `1 2 3`, which is 3 tokens long.
+        let context = [1, 2, 3, 4, 5, 1, 2, 3];
+        let mut logits = vec![0.; 10];
+        dry_sampler(4).apply_dry_penalty(&mut logits, &context);
+        assert_eq!(logits, vec![0.; 10]);
+
+        let mut logits = vec![0.; 10];
+        dry_sampler(3).apply_dry_penalty(&mut logits, &context);
+        assert_eq!(logits[4], -1.);
+
+        let mut logits = vec![0.; 10];
+        dry_sampler(2).apply_dry_penalty(&mut logits, &context);
+        assert_eq!(logits[4], -2.);
+        assert_eq!(logits.iter().filter(|logit| **logit != 0.).count(), 1);
+    }
+
+    #[test]
+    fn test_dry_breaker_resets_the_match() {
+        // The repetition of `1 2 9 3` stops at the breaker `9`, so it is only 1 token long.
+        let context = [1, 2, 9, 3, 4, 1, 2, 9, 3];
+        let mut logits = vec![0.; 10];
+        dry_sampler(2).apply_dry_penalty(&mut logits, &context);
+        assert_eq!(logits, vec![0.; 10]);
+
+        let mut sampler = dry_sampler(2);
+        sampler.dry_sequence_breakers.clear();
+        let mut logits = vec![0.; 10];
+        sampler.apply_dry_penalty(&mut logits, &context);
+        assert_eq!(logits[4], -4.);
+    }
+
+    #[test]
+    fn test_repetition_penalty_on_positive_and_negative_logits() {
+        let mut sampler = test_sampler(4);
+        sampler.repetition_penalty = Some(2.);
+        let mut logits = vec![2., -2., 1., -1.];
+        sampler.apply_repeat_presence_penalty(&mut logits, &[0, 1, 1]);
+        assert_eq!(logits, vec![1., -4., 1., -1.]);
+
+        sampler.presence_penalty = Some(0.5);
+        sampler.frequency_penalty = Some(0.25);
+        let mut logits = vec![2., -2., 1., -1.];
+        sampler.apply_repeat_presence_penalty(&mut logits, &[0, 1, 1]);
+        assert_eq!(logits, vec![0.25, -5., 1., -1.]);
+    }
+
+    /// The tokens which the sampler samples from 20 uniform distributions over 100 tokens.
+    fn sample_stream(mut sampler: Sampler) -> Vec<u32> {
+        let logits = Tensor::zeros(100, DType::F32, &Device::Cpu).unwrap();
+        (0..20)
+            .map(|_| sampler.sample(&logits, None, false).unwrap().token)
+            .collect()
+    }
+
+    #[test]
+    fn test_choice_seeds() {
+        let mut sampler = test_sampler(100);
+        sampler.temperature = Some(1.);
+
+        let choice = |seed, response_index| sample_stream(sampler.for_choice(seed, response_index));
+        assert_eq!(choice(Some(42), 0), choice(Some(42), 0));
+        assert_eq!(choice(Some(42), 1), choice(Some(42), 1));
+        assert_ne!(choice(Some(42), 0), choice(Some(42), 1));
+        assert_ne!(choice(Some(42), 0), choice(Some(43), 0));
+        // Without a seed, each choice keeps the generator of the request's sampler.
+        assert_eq!(choice(None, 0), sample_stream(sampler.clone()));
+        assert_eq!(choice(None, 1), sample_stream(sampler.clone()));
+    }
+
+    #[test]
+    fn test_topk_wider_than_a_device_sort() {
+        // A permutation of `0..2048`, which is wider than one block of threads can sort.
+        let values = (0..2048u32)
+            .map(|i| ((i * 7) % 2048) as f32)
+            .collect::<Vec<_>>();
+        let xs = Tensor::from_vec(values.clone(), (1, 2048), &Device::Cpu).unwrap();
+        let (top, indices) = Sampler::topk_last_dim(&xs, 3).unwrap();
+        assert_eq!(top, vec![vec![2047., 2046., 2045.]]);
+        for (value, index) in zip(&top[0], &indices[0]) {
+            assert_eq!(values[*index as usize], *value);
+        }
+    }
+
+    #[test]
+    fn test_device_topk_matches_host_topk() {
+        // Two chunks of a device sort and a partial one, over a few rounds for the largest `k`.
+        let rows = [
+            (0..3000u32)
+                .map(|i| ((i * 7) % 3000) as f32)
+                .collect::<Vec<_>>(),
+            (0..3000u32)
+                .map(|i| ((i * 11) % 3000) as f32)
+                .collect::<Vec<_>>(),
+        ];
+        let xs = Tensor::from_vec(rows.concat(), (2, 3000), &Device::Cpu).unwrap();
+        for k in [1, 64, 512] {
+            let (values, indices) = Sampler::device_topk(&xs, k).unwrap();
+            let values: Vec<Vec<f32>> = values.to_vec2().unwrap();
+            let indices: Vec<Vec<u32>> = indices.to_vec2().unwrap();
+            for (row, (values, indices)) in zip(&rows, zip(values, indices)) {
+                assert_eq!((values, indices), Sampler::host_topk(row, k));
+            }
+        }
+    }
+
+    #[test]
+    fn test_sample_batch_matches_sample_with_a_seed() {
+        let mut base = test_sampler(100);
+        base.top_n_logprobs = 3;
+        let mut temperature = base.clone();
+        temperature.temperature = Some(0.7);
+        let mut topk = base.clone();
+        topk.temperature = Some(1.);
+        topk.topk = 10;
+        let mut topp = base.clone();
+        topp.temperature = Some(1.2);
+        topp.topp = 0.9;
+        let mut penalties = base.clone();
+        penalties.temperature = Some(1.);
+        penalties.frequency_penalty = Some(0.5);
+        penalties.presence_penalty = Some(0.3);
+        penalties.repetition_penalty = Some(1.3);
+        penalties.logits_bias = Some(HashMap::from([(7, 5.)]));
+        let samplers = [base, temperature, topk, topp, penalties]
+            .iter()
+            .enumerate()
+            .map(|(i, sampler)| sampler.for_choice(Some(42), i))
+            .collect::<Vec<_>>();
+        let (mut batched, mut single) = (samplers.clone(), samplers);
+
+        // Distinct logits in a different order for each row, so that no ties decide the candidates.
+        let logits = (0..5u32)
+            .flat_map(|i| {
+                (0..100u32).map(move |j| ((j * (i + 3)) % 17) as f32 * 0.3 + j as f32 * 1e-3)
+            })
+            .collect::<Vec<_>>();
+        let logits = Tensor::from_vec(logits, (5, 100), &Device::Cpu).unwrap();
+        let contexts: [&[u32]; 5] = [&[1, 2], &[], &[3, 3, 4], &[5], &[7, 7, 8, 9]];
+        for _ in 0..10 {
+            let batch = Sampler::sample_batch(
+                &mut batched.iter_mut().collect::<Vec<_>>(),
+                &logits,
+                &contexts,
+                &[true; 5],
+            )
+            .unwrap();
+            for (i, (sampler, batch)) in zip(&mut single, batch).enumerate() {
+                let row = logits.get(i).unwrap();
+                let single = sampler.sample(&row, Some(contexts[i]), true).unwrap();
+                assert_eq!(batch.token, single.token, "row {i}");
+                assert!((batch.logprob - single.logprob).abs() < 1e-5, "row {i}");
+                let top_tokens = |logprobs: &Option<Vec<TopLogprob>>| {
+                    logprobs
+                        .iter()
+                        .flatten()
+                        .map(|top| top.token)
+                        .collect::<Vec<_>>()
+                };
+                assert_eq!(
+                    top_tokens(&batch.top_logprobs),
+                    top_tokens(&single.top_logprobs),
+                    "row {i}"
+                );
+            }
+        }
+    }
+
+    /// A sampler over 2000 tokens, whose logits fall slowly so that the top-p needs hundreds of them.
+    fn wide_vocab_sampler(topk: i64, topp: f64) -> (Sampler, Tensor) {
+        let mut sampler = test_sampler(2000);
+        sampler.temperature = Some(1.);
+        sampler.topk = topk;
+        sampler.topp = topp;
+        let logits = Tensor::arange(0f32, 2000., &Device::Cpu)
+            .unwrap()
+            .affine(-1e-3, 0.)
+            .unwrap();
+        (sampler, logits)
+    }
+
+    #[test]
+    fn test_topkp_on_a_wide_vocab() {
+        // The top-p of 0.5 is held by about the 567 most likely tokens, many more than the first candidates.
+        let (mut sampler, logits) = wide_vocab_sampler(-1, 0.5);
+        let tokens = (0..50)
+            .map(|_| sampler.sample(&logits, None, false).unwrap().token)
+            .collect::<Vec<_>>();
+        assert!(tokens.iter().all(|token| *token < 570), "{tokens:?}");
+        assert!(tokens.iter().any(|token| *token >= 64), "{tokens:?}");
+
+        let (mut sampler, logits) = wide_vocab_sampler(3, 1.);
+        for _ in 0..20 {
+            assert!(sampler.sample(&logits, None, false).unwrap().token < 3);
+        }
+    }
+
+    #[test]
+    fn test_topkp_batch_matches_single_on_a_wide_vocab() {
+        let (top_p, logits) = wide_vocab_sampler(-1, 0.5);
+        let (top_kp, _) = wide_vocab_sampler(100, 0.5);
+        let batch_logits = Tensor::stack(&[&logits, &logits], 0).unwrap();
+
+        let (mut single_p, mut single_kp) = (top_p.clone(), top_kp.clone());
+        let (mut batch_p, mut batch_kp) = (top_p, top_kp);
+        for _ in 0..10 {
+            let batch = Sampler::sample_batch(
+                &mut [&mut batch_p, &mut batch_kp],
+                &batch_logits,
+                &[&[], &[]],
+                &[false, false],
+            )
+            .unwrap();
+            let single_p = single_p.sample(&logits, None, false).unwrap();
+            let single_kp = single_kp.sample(&logits, None, false).unwrap();
+            assert_eq!(batch[0].token, single_p.token);
+            assert_eq!(batch[1].token, single_kp.token);
+        }
+    }
+
+    #[test]
+    fn test_speculative_sampling_keeps_the_target_distribution() {
+        // The target model gives the tokens 0.75 and 0.25, and the draft model 0.25 and 0.75.
+        let mut sampler = test_sampler(2);
+        sampler.temperature = Some(1.);
+        let logits = Tensor::new(&[3f32.ln(), 0.], &Device::Cpu).unwrap();
+        let draft_probs = [0.25, 0.75];
+        let n = 4000;
+        let zeros = (0..n)
+            .filter(|i| {
+                // The drafted tokens follow the distribution of the draft model.
+                let drafted = if i % 4 == 0 { 0 } else { 1 };
+                let token = sampler
+                    .sample_speculative(
+                        &logits,
+                        None,
+                        Some((drafted, draft_probs.as_slice())),
+                        false,
+                    )
+                    .unwrap()
+                    .token;
+                token == 0
+            })
+            .count();
+        let frequency = zeros as f32 / n as f32;
+        assert!((frequency - 0.75).abs() < 0.03, "{frequency}");
+
+        // A token which the target model rules out is always rejected.
+        let logits = Tensor::new(&[0f32, f32::NEG_INFINITY], &Device::Cpu).unwrap();
+        for _ in 0..20 {
+            let token = sampler
+                .sample_speculative(&logits, None, Some((1, draft_probs.as_slice())), false)
+                .unwrap()
+                .token;
+            assert_eq!(token, 0);
+        }
+
+        // With argmax sampling, the argmax is kept whatever was drafted.
+        sampler.temperature = None;
+        let logits = Tensor::new(&[0f32, 1.], &Device::Cpu).unwrap();
+        let token = sampler
+            .sample_speculative(&logits, None, Some((0, draft_probs.as_slice())), false)
+            .unwrap()
+            .token;
+        assert_eq!(token, 1);
     }
 }
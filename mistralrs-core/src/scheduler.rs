@@ -1,9 +1,19 @@
-use std::collections::{
-    vec_deque::{Iter, IterMut},
-    HashMap, VecDeque,
+use std::{
+    cmp::{Ordering, Reverse},
+    collections::{
+        vec_deque::{Iter, IterMut},
+        HashMap, VecDeque,
+    },
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
-use crate::sequence::{Sequence, SequenceState};
+use crate::{
+    block_engine::BlockEngine,
+    get_mut_arcmutex,
+    sequence::{Sequence, SequenceState, StopReason},
+};
 use range_checked::UsizeBounded;
 
 pub trait FcfsBacker: Default {
@@ -13,7 +23,7 @@ pub trait FcfsBacker: Default {
     fn into_iter(self) -> impl Iterator<Item = Sequence>;
     fn iter(&self) -> impl Iterator<Item = &Sequence>;
     fn mut_iter(&mut self) -> impl Iterator<Item = &mut Sequence>;
-    fn sort_ascending_ids(&mut self);
+    fn sort_by(&mut self, compare: impl FnMut(&Sequence, &Sequence) -> Ordering);
 }
 
 impl FcfsBacker for VecDeque<Sequence> {
@@ -35,9 +45,8 @@ impl FcfsBacker for VecDeque<Sequence> {
     fn into_iter(self) -> impl Iterator<Item = Sequence> {
         <Self as IntoIterator>::into_iter(self)
     }
-    fn sort_ascending_ids(&mut self) {
-        let slice = self.make_contiguous();
-        slice.sort_by_key(|seq| *seq.id());
+    fn sort_by(&mut self, compare: impl FnMut(&Sequence, &Sequence) -> Ordering) {
+        self.make_contiguous().sort_by(compare);
     }
 }
 
@@ -47,21 +56,99 @@ pub struct SchedulerOutput<'a> {
 }
 
 pub enum SchedulerMethod {
+    /// Run at most this many sequences at once.
     Fixed(UsizeBounded<1, { usize::MAX }, false>),
+    /// Keep at most this many tokens in the KV cache. A waiting sequence is admitted if its prompt length plus
+    /// `max_len` fits in what the running sequences have not reserved. Running sequences which outgrow the budget
+    /// are preempted, newest first, and recomputed later.
+    TokenBudget(UsizeBounded<1, { usize::MAX }, false>),
+}
+
+/// The order in which waiting sequences are admitted.
+#[derive(Clone, Debug, Default)]
+pub enum QueueOrder {
+    /// First come, first served.
+    #[default]
+    Fcfs,
+    /// Higher `Request::priority` first, then first come, first served.
+    Priority,
+    /// Shortest prompt first.
+    ShortestPromptFirst,
+    /// Weighted fair queuing between the tenants of the requests. Each tenant receives a share of the prompt
+    /// tokens proportional to its weight, which is 1 if it is not listed. Requests without a tenant share
+    /// the default tenant.
+    WeightedFair { weights: HashMap<String, f64> },
+}
+
+impl FromStr for QueueOrder {
+    type Err = String;
+
+    /// Parse `fcfs`, `priority`, `shortest-prompt`, or `fair` with optional tenant weights, for example
+    /// `fair:interactive=4,batch=1`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<&str> = s.splitn(2, ':').collect();
+        match parts[0] {
+            "fcfs" => Ok(QueueOrder::Fcfs),
+            "priority" => Ok(QueueOrder::Priority),
+            "shortest-prompt" => Ok(QueueOrder::ShortestPromptFirst),
+            "fair" => {
+                let mut weights = HashMap::new();
+                for weight in parts.get(1).iter().flat_map(|w| w.split(',')) {
+                    let Some((tenant, weight)) = weight.split_once('=') else {
+                        return Err(format!("Expected `tenant=weight`, got `{weight}`"));
+                    };
+                    let weight = weight
+                        .parse::<f64>()
+                        .ok()
+                        .filter(|weight| *weight > 0.)
+                        .ok_or_else(|| format!("Invalid weight for tenant `{tenant}`"))?;
+                    weights.insert(tenant.to_string(), weight);
+                }
+                Ok(QueueOrder::WeightedFair { weights })
+            }
+            _ => Err("Invalid queue order".to_string()),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct QueuePolicy {
+    pub order: QueueOrder,
+    /// Sequences which have waited at least this long are admitted before all others, oldest first, so
+    /// that no sequence starves.
+    pub max_wait: Option<Duration>,
 }
 
 pub struct Scheduler<Backer: FcfsBacker> {
     waiting: Backer,
     running: Vec<Sequence>,
     method: SchedulerMethod,
+    policy: QueuePolicy,
+    block_engine: Option<Arc<Mutex<BlockEngine>>>,
+    bucket_by_length: bool,
+    // Weighted fair queuing: the virtual finish time of each tenant, and the virtual start time of the last
+    // admitted sequence.
+    tenant_finish: HashMap<String, f64>,
+    virtual_time: f64,
 }
 
 impl<Backer: FcfsBacker> Scheduler<Backer> {
-    pub fn new(method: SchedulerMethod) -> Self {
+    /// With `bucket_by_length`, only sequences of the same length are scheduled together.
+    pub fn new(
+        method: SchedulerMethod,
+        policy: QueuePolicy,
+        block_engine: Option<Arc<Mutex<BlockEngine>>>,
+        bucket_by_length: bool,
+    ) -> Self {
         Self {
             running: Vec::new(),
             waiting: Backer::new(),
             method,
+            policy,
+            block_engine,
+            bucket_by_length,
+            tenant_finish: HashMap::new(),
+            virtual_time: 0.,
         }
     }
 
@@ -74,15 +161,97 @@ impl<Backer: FcfsBacker> Scheduler<Backer> {
         }
     }
 
+    /// Whether there are no sequences which are running or waiting.
+    pub fn is_empty(&self) -> bool {
+        self.running.is_empty() && self.waiting.iter().next().is_none()
+    }
+
+    /// The sequences which are running or waiting.
+    pub fn seqs(&self) -> impl Iterator<Item = &Sequence> {
+        self.running.iter().chain(self.waiting.iter())
+    }
+
+    pub fn seqs_mut(&mut self) -> impl Iterator<Item = &mut Sequence> {
+        self.running.iter_mut().chain(self.waiting.mut_iter())
+    }
+
+    /// Cancel all sequences of a request. They are removed by the next call to `schedule`.
+    pub fn abort(&mut self, request_id: usize) {
+        for seq in self.seqs() {
+            let mut group = seq.get_mut_group();
+            if group.request_id == request_id {
+                group.is_canceled = true;
+            }
+        }
+    }
+
+    /// Remove the sequences which overran their deadline, so that they can be finished.
+    pub fn take_expired(&mut self) -> Vec<Sequence> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("Time travel has occurred!")
+            .as_millis();
+        self.take_matching(|seq| seq.deadline().is_some_and(|deadline| now >= deadline))
+    }
+
+    /// Remove the sequences of canceled requests which are not streaming, other than beams, so that they can
+    /// be finished with the output generated so far. The other canceled sequences are dropped by `schedule`.
+    pub fn take_canceled(&mut self) -> Vec<Sequence> {
+        self.take_matching(|seq| {
+            seq.is_canceled() && !seq.get_mut_group().is_streaming && !seq.is_beam()
+        })
+    }
+
+    /// Remove the running and waiting sequences which match `predicate`, freeing their blocks.
+    fn take_matching(&mut self, predicate: impl Fn(&Sequence) -> bool) -> Vec<Sequence> {
+        let matches = |seq: &Sequence| (seq.is_running() || seq.is_waiting()) && predicate(seq);
+        let (mut taken, running): (Vec<_>, Vec<_>) = std::mem::take(&mut self.running)
+            .into_iter()
+            .partition(matches);
+        self.running = running;
+        let mut waiting = Backer::new();
+        for seq in std::mem::take(&mut self.waiting).into_iter() {
+            if matches(&seq) {
+                taken.push(seq);
+            } else {
+                waiting.add(seq);
+            }
+        }
+        self.waiting = waiting;
+        self.free_blocks(&taken);
+        taken
+    }
+
     /// Schedule all sequences based on their state and the available space.
     pub fn schedule(&mut self) -> SchedulerOutput {
+        // Stop the sequences of canceled requests, and drop the waiting ones
+        let mut waiting = Backer::new();
+        let mut canceled = Vec::new();
+        for seq in std::mem::take(&mut self.waiting).into_iter() {
+            if seq.is_canceled() {
+                seq.set_state(SequenceState::Done(StopReason::Canceled));
+                canceled.push(seq);
+            } else if seq.is_waiting() || seq.is_running() {
+                waiting.add(seq);
+            } else {
+                // It finished while waiting, like a beam which the next beams of its search replaced.
+                canceled.push(seq);
+            }
+        }
+        self.free_blocks(&canceled);
+        for seq in self
+            .running
+            .iter()
+            .filter(|seq| seq.is_running() && seq.is_canceled())
+        {
+            seq.set_state(SequenceState::Done(StopReason::Canceled));
+        }
+
         // Filter out all done sequences
         let running = std::mem::take(&mut self.running);
-        let mut waiting = std::mem::take(&mut self.waiting);
-        let mut running = running
-            .into_iter()
-            .filter(|seq| seq.is_running())
-            .collect::<Vec<_>>();
+        let (mut running, done): (Vec<_>, Vec<_>) =
+            running.into_iter().partition(|seq| seq.is_running());
+        self.free_blocks(&done);
 
         match (waiting.iter().count(), running.len()) {
             (0, 0) => {
@@ -93,34 +262,52 @@ impl<Backer: FcfsBacker> Scheduler<Backer> {
                 };
             }
             (_, 0) => {
+                self.sort_waiting(&mut waiting);
+                let mut new_waiting = Backer::new();
                 for seq in waiting.into_iter() {
-                    seq.set_state(SequenceState::RunningPrompt);
-                    self.running.push(seq);
+                    if self.sequence_fits(&self.running, &seq) && self.allocate_blocks(&seq) {
+                        if seq.is_waiting() {
+                            self.charge_tenant(&seq);
+                        }
+                        seq.set_state(SequenceState::RunningPrompt);
+                        self.running.push(seq);
+                    } else if seq.is_waiting() {
+                        new_waiting.add(seq);
+                    } else {
+                        // It still holds a cache from before it was moved to waiting.
+                        self.preempt(seq, &mut new_waiting);
+                    }
                 }
-                self.waiting = Backer::new();
+                self.waiting = new_waiting;
                 return SchedulerOutput {
                     prompt: self.running.iter_mut().collect::<Vec<_>>().into(),
                     completion: vec![].into(),
                 };
             }
             (0, _) => {
-                self.running = running;
+                let mut new_waiting = Backer::new();
+                self.running = self.preempt_if_needed(running, &mut new_waiting);
+                self.waiting = new_waiting;
+                // Chunked prompts stay running between their prompt steps.
+                let (prompt, completion): (Vec<_>, Vec<_>) =
+                    self.running.iter_mut().partition(|seq| seq.is_prompt());
                 return SchedulerOutput {
-                    prompt: vec![].into(),
-                    completion: self.running.iter_mut().collect::<Vec<_>>().into(),
+                    prompt: prompt.into(),
+                    completion: completion.into(),
                 };
             }
             _ => {}
         }
 
         // Sort the waiting seqs
-        waiting.sort_ascending_ids();
+        self.sort_waiting(&mut waiting);
 
         // If the waiting sequence will fit, add it. Otherwise remove it
         let mut new_waiting = Backer::new();
         for seq in waiting.into_iter() {
-            if self.sequence_fits(&running, &seq) {
+            if self.sequence_fits(&running, &seq) && self.allocate_blocks(&seq) {
                 if seq.is_waiting() {
+                    self.charge_tenant(&seq);
                     seq.set_state(SequenceState::RunningPrompt);
                 }
                 running.push(seq);
@@ -129,7 +316,39 @@ impl<Backer: FcfsBacker> Scheduler<Backer> {
             }
         }
 
-        // Now, get the sequences with the smallest sequence lengths, and allow them to catch up.
+        // Sequences of any length share a batch, except with X-LoRA: then get the sequences with the smallest
+        // sequence lengths, and allow them to catch up.
+        let (running, mut new_waiting) = if self.bucket_by_length {
+            Self::bucket_smallest(running, new_waiting)
+        } else {
+            (running, new_waiting)
+        };
+        if self.block_engine.is_some() {
+            new_waiting = self.preempt_parked(new_waiting);
+        }
+
+        self.running = self.preempt_if_needed(running, &mut new_waiting);
+        self.waiting = new_waiting;
+
+        let mut completion = Vec::new();
+        let mut prompt = Vec::new();
+        for seq in &mut self.running {
+            if seq.is_completion() {
+                completion.push(seq);
+            } else {
+                prompt.push(seq);
+            }
+        }
+
+        SchedulerOutput {
+            completion: completion.into(),
+            prompt: prompt.into(),
+        }
+    }
+
+    /// Keep the sequences with the smallest length running, and move the others to waiting without changing their
+    /// states.
+    fn bucket_smallest(running: Vec<Sequence>, mut new_waiting: Backer) -> (Vec<Sequence>, Backer) {
         let mut seq_buckets: HashMap<usize, Vec<Sequence>> = HashMap::new();
         let mut min_len = usize::MAX;
         for seq in running {
@@ -144,7 +363,7 @@ impl<Backer: FcfsBacker> Scheduler<Backer> {
                 }
             }
         }
-        let (running, new_waiting) = if seq_buckets.len() <= 1 {
+        if seq_buckets.len() <= 1 {
             // Full steam ahead or have everything
             (
                 seq_buckets
@@ -164,30 +383,408 @@ impl<Backer: FcfsBacker> Scheduler<Backer> {
             }
             // Know min_seqs.len < running.len() <= max
             (min_seqs, new_waiting)
+        }
+    }
+
+    /// Order the waiting sequences by the queue policy. Sequences which have waited at least `max_wait` go
+    /// first, and ties are broken by arrival.
+    fn sort_waiting(&self, waiting: &mut Backer) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("Time travel has occurred!")
+            .as_millis();
+        let starved = |seq: &Sequence| {
+            self.policy
+                .max_wait
+                .is_some_and(|max_wait| now.saturating_sub(seq.timestamp()) >= max_wait.as_millis())
+        };
+        let finish_times = match &self.policy.order {
+            QueueOrder::WeightedFair { weights } => self.finish_times(waiting, weights),
+            _ => HashMap::new(),
         };
+        waiting.sort_by(|a, b| {
+            let by_policy = match (starved(a), starved(b)) {
+                (true, true) => Ordering::Equal,
+                (true, false) => Ordering::Less,
+                (false, true) => Ordering::Greater,
+                (false, false) => match &self.policy.order {
+                    QueueOrder::Fcfs => Ordering::Equal,
+                    QueueOrder::Priority => Reverse(a.priority()).cmp(&Reverse(b.priority())),
+                    QueueOrder::ShortestPromptFirst => a.prompt_tokens().cmp(&b.prompt_tokens()),
+                    QueueOrder::WeightedFair { .. } => {
+                        finish_times[a.id()].total_cmp(&finish_times[b.id()])
+                    }
+                },
+            };
+            by_policy.then_with(|| a.id().cmp(b.id()))
+        });
+    }
 
-        self.waiting = new_waiting;
-        self.running = running;
+    /// The virtual finish time of each waiting sequence under weighted fair queuing: the sequences of a tenant
+    /// are served in arrival order, each taking its prompt tokens divided by the weight of the tenant.
+    #[allow(clippy::cast_precision_loss)]
+    fn finish_times(
+        &self,
+        waiting: &Backer,
+        weights: &HashMap<String, f64>,
+    ) -> HashMap<usize, f64> {
+        let mut seqs = waiting.iter().collect::<Vec<_>>();
+        seqs.sort_by_key(|seq| *seq.id());
+        let mut tenant_finish = HashMap::new();
+        let mut finish_times = HashMap::new();
+        for seq in seqs {
+            let tenant = seq.tenant().unwrap_or_default();
+            let weight = weights.get(tenant).copied().unwrap_or(1.);
+            let start = *tenant_finish
+                .entry(tenant)
+                .or_insert_with(|| self.tenant_start(tenant));
+            let finish = start + seq.prompt_tokens() as f64 / weight;
+            tenant_finish.insert(tenant, finish);
+            finish_times.insert(*seq.id(), finish);
+        }
+        finish_times
+    }
 
-        let mut completion = Vec::new();
-        let mut prompt = Vec::new();
-        for seq in &mut self.running {
-            if seq.is_completion() {
-                completion.push(seq);
+    /// The virtual start time of the next sequence of a tenant. A tenant which was idle does not get credit
+    /// for the time it did not use.
+    fn tenant_start(&self, tenant: &str) -> f64 {
+        self.tenant_finish
+            .get(tenant)
+            .copied()
+            .unwrap_or(0.)
+            .max(self.virtual_time)
+    }
+
+    /// Account the prompt tokens of a newly admitted sequence to its tenant.
+    #[allow(clippy::cast_precision_loss)]
+    fn charge_tenant(&mut self, seq: &Sequence) {
+        let QueueOrder::WeightedFair { weights } = &self.policy.order else {
+            return;
+        };
+        let tenant = seq.tenant().unwrap_or_default();
+        let weight = weights.get(tenant).copied().unwrap_or(1.);
+        let start = self.tenant_start(tenant);
+        self.tenant_finish.insert(
+            tenant.to_string(),
+            start + seq.prompt_tokens() as f64 / weight,
+        );
+        self.virtual_time = start;
+    }
+
+    fn sequence_fits(&self, running: &[Sequence], seq: &Sequence) -> bool {
+        match &self.method {
+            SchedulerMethod::Fixed(n) => (running.len() + 1) <= **n,
+            SchedulerMethod::TokenBudget(_) => {
+                let reserved = running.iter().map(Self::reserved_tokens).sum::<usize>();
+                self.within_token_budget(running.is_empty(), reserved, seq)
+            }
+        }
+    }
+
+    /// Whether the sequence fits in the token budget next to running sequences which reserve `reserved` tokens.
+    /// Admission and preemption both use this, so that an admitted sequence is not preempted on its next step.
+    fn within_token_budget(&self, nothing_running: bool, reserved: usize, seq: &Sequence) -> bool {
+        match &self.method {
+            SchedulerMethod::Fixed(_) => true,
+            // Always admit a sequence if nothing is running, otherwise it could never run.
+            SchedulerMethod::TokenBudget(n) => {
+                nothing_running || reserved + Self::reserved_tokens(seq) <= **n
+            }
+        }
+    }
+
+    /// The number of tokens the sequence may need to cache: its prompt length plus `max_len`.
+    /// Without a `max_len`, this is only what is needed for the next step.
+    fn reserved_tokens(seq: &Sequence) -> usize {
+        let requested = seq.prompt_tokens() + seq.max_len().unwrap_or(0);
+        requested.max(seq.len() + 1)
+    }
+
+    /// Check that the running sequences fit in the KV cache for this step, oldest first. When a sequence does
+    /// not get the blocks it needs, the newest sequences are preempted until it does, and it is preempted
+    /// itself if it is the newest. A sequence which does not fit in the token budget next to the older ones is
+    /// preempted.
+    fn preempt_if_needed(&self, mut seqs: Vec<Sequence>, waiting: &mut Backer) -> Vec<Sequence> {
+        seqs.sort_by_key(|seq| *seq.id());
+        let mut seqs = VecDeque::from(seqs);
+        let mut running = Vec::new();
+        let mut reserved = 0;
+        while let Some(seq) = seqs.pop_front() {
+            if !self.within_token_budget(running.is_empty(), reserved, &seq) {
+                self.preempt(seq, waiting);
+                continue;
+            }
+            loop {
+                if self.allocate_blocks(&seq) {
+                    reserved += Self::reserved_tokens(&seq);
+                    running.push(seq);
+                    break;
+                }
+                match seqs.pop_back() {
+                    Some(newest) => self.preempt(newest, waiting),
+                    None => {
+                        self.preempt(seq, waiting);
+                        break;
+                    }
+                }
+            }
+        }
+        running
+    }
+
+    /// Preempt a sequence by recomputation: its KV cache is dropped and it is moved to waiting, from where it
+    /// is prefilled again with all of its tokens.
+    fn preempt(&self, mut seq: Sequence, waiting: &mut Backer) {
+        self.free_blocks(std::slice::from_ref(&seq));
+        seq.reset_cache();
+        seq.set_state(SequenceState::Waiting);
+        waiting.add(seq);
+    }
+
+    /// Preempt the sequences which are waiting while they still hold a cache from when they were running, so
+    /// that their blocks of the paged cache are free for the running sequences.
+    fn preempt_parked(&self, waiting: Backer) -> Backer {
+        let mut new_waiting = Backer::new();
+        for seq in waiting.into_iter() {
+            if seq.is_waiting() {
+                new_waiting.add(seq);
             } else {
-                prompt.push(seq);
+                self.preempt(seq, &mut new_waiting);
             }
         }
+        new_waiting
+    }
 
-        SchedulerOutput {
-            completion: completion.into(),
-            prompt: prompt.into(),
+    /// Grow the KV cache blocks of the sequence to hold all of its tokens. Always succeeds without a paged cache.
+    fn allocate_blocks(&self, seq: &Sequence) -> bool {
+        match &self.block_engine {
+            Some(block_engine) => get_mut_arcmutex!(block_engine).allocate(*seq.id(), seq.len()),
+            None => true,
         }
     }
 
-    fn sequence_fits(&self, running: &[Sequence], _seq: &Sequence) -> bool {
-        match &self.method {
-            SchedulerMethod::Fixed(n) => (running.len() + 1) <= **n,
+    fn free_blocks(&self, seqs: &[Sequence]) {
+        if let Some(block_engine) = &self.block_engine {
+            let mut block_engine = get_mut_arcmutex!(block_engine);
+            for seq in seqs {
+                block_engine.free_sequence(*seq.id());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::{HashMap, VecDeque},
+        time::{Duration, SystemTime, UNIX_EPOCH},
+    };
+
+    use super::{FcfsBacker, QueueOrder, QueuePolicy, Scheduler, SchedulerMethod};
+    use crate::{
+        sampler::Logprobs,
+        sequence::{tests::test_sequence, Sequence, SequenceState, StopReason},
+    };
+
+    fn now() -> u128 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis()
+    }
+
+    /// The ids of the waiting sequences in the order of the policy. Each sequence is given as its id, prompt
+    /// length, age in seconds, priority and tenant, and they are queued in the given order.
+    fn sorted_ids(
+        order: QueueOrder,
+        max_wait: Option<Duration>,
+        seqs: &[(usize, usize, u128, i32, Option<&str>)],
+    ) -> Vec<usize> {
+        let scheduler = Scheduler::<VecDeque<Sequence>>::new(
+            SchedulerMethod::Fixed(8.try_into().unwrap()),
+            QueuePolicy { order, max_wait },
+            None,
+            false,
+        );
+        let now = now();
+        let mut waiting = VecDeque::new();
+        for (id, prompt_len, age, priority, tenant) in seqs {
+            let seq = test_sequence(
+                *id,
+                *prompt_len,
+                now - age * 1000,
+                *priority,
+                *tenant,
+                Vec::new(),
+            );
+            waiting.add(seq);
+        }
+        scheduler.sort_waiting(&mut waiting);
+        waiting.iter().map(|seq| *seq.id()).collect()
+    }
+
+    /// A scheduler with a token budget, and waiting sequences with the given prompt lengths and ids from 0.
+    fn budget_scheduler(budget: usize, prompt_lens: &[usize]) -> Scheduler<VecDeque<Sequence>> {
+        let mut scheduler = Scheduler::<VecDeque<Sequence>>::new(
+            SchedulerMethod::TokenBudget(budget.try_into().unwrap()),
+            QueuePolicy::default(),
+            None,
+            false,
+        );
+        for (id, prompt_len) in prompt_lens.iter().enumerate() {
+            scheduler.add_seq(test_sequence(id, *prompt_len, now(), 0, None, Vec::new()));
+        }
+        scheduler
+    }
+
+    /// The ids of the scheduled prompt and completion sequences.
+    fn schedule_ids(scheduler: &mut Scheduler<VecDeque<Sequence>>) -> (Vec<usize>, Vec<usize>) {
+        let output = scheduler.schedule();
+        let ids = |seqs: &[&mut Sequence]| seqs.iter().map(|seq| *seq.id()).collect::<Vec<_>>();
+        (ids(&output.prompt), ids(&output.completion))
+    }
+
+    fn generate_tokens(scheduler: &mut Scheduler<VecDeque<Sequence>>, n: usize) {
+        for seq in &mut scheduler.running {
+            seq.set_state(SequenceState::RunningCompletion);
+            for _ in 0..n {
+                let tok = Logprobs {
+                    token: 0,
+                    logprob: 0.,
+                    bytes: String::new(),
+                    top_logprobs: None,
+                };
+                seq.add_token(tok, Vec::new());
+            }
+        }
+    }
+
+    #[test]
+    fn token_budget_admits_what_fits() {
+        // Without a `max_len`, each sequence reserves its length plus the next token.
+        let mut scheduler = budget_scheduler(10, &[4, 4, 4]);
+        assert_eq!(schedule_ids(&mut scheduler), (vec![0, 1], vec![]));
+        let waiting = scheduler.waiting.iter().map(|seq| *seq.id());
+        assert_eq!(waiting.collect::<Vec<_>>(), vec![2]);
+    }
+
+    #[test]
+    fn token_budget_preempts_newest_first_and_requeues() {
+        let mut scheduler = budget_scheduler(12, &[4, 4]);
+        assert_eq!(schedule_ids(&mut scheduler), (vec![0, 1], vec![]));
+        // Both sequences now reserve 7 tokens, so only the oldest one keeps running.
+        generate_tokens(&mut scheduler, 2);
+        assert_eq!(schedule_ids(&mut scheduler), (vec![], vec![0]));
+        let preempted = scheduler.waiting.iter().next().unwrap();
+        assert_eq!(*preempted.id(), 1);
+        assert!(preempted.is_waiting());
+        // It is recomputed with all of its tokens once the running sequence is done.
+        scheduler.running[0].set_state(SequenceState::Done(StopReason::Length(6)));
+        assert_eq!(schedule_ids(&mut scheduler), (vec![1], vec![]));
+        assert_eq!(scheduler.running[0].len(), 6);
+    }
+
+    #[test]
+    fn fcfs_orders_by_arrival() {
+        let seqs = [(2, 1, 0, 0, None), (0, 8, 0, 5, None), (1, 4, 0, 0, None)];
+        assert_eq!(sorted_ids(QueueOrder::Fcfs, None, &seqs), vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn priority_orders_by_priority_then_arrival() {
+        let seqs = [
+            (0, 1, 0, 0, None),
+            (1, 1, 0, 3, None),
+            (2, 1, 0, -1, None),
+            (3, 1, 0, 3, None),
+        ];
+        assert_eq!(
+            sorted_ids(QueueOrder::Priority, None, &seqs),
+            vec![1, 3, 0, 2]
+        );
+    }
+
+    #[test]
+    fn shortest_prompt_first() {
+        let seqs = [
+            (0, 30, 0, 0, None),
+            (1, 10, 0, 0, None),
+            (2, 20, 0, 0, None),
+            (3, 10, 0, 0, None),
+        ];
+        assert_eq!(
+            sorted_ids(QueueOrder::ShortestPromptFirst, None, &seqs),
+            vec![1, 3, 2, 0]
+        );
+    }
+
+    #[test]
+    fn weighted_fair_shares_prompt_tokens() {
+        let weights = HashMap::from([("a".to_string(), 2.), ("b".to_string(), 1.)]);
+        // The virtual finish times are 5 and 10 for the seqs of `a`, 4 for the seq of `b` and 3 for the
+        // seq without a tenant, which has the weight 1.
+        let seqs = [
+            (0, 10, 0, 0, Some("a")),
+            (1, 10, 0, 0, Some("a")),
+            (2, 4, 0, 0, Some("b")),
+            (3, 3, 0, 0, None),
+        ];
+        assert_eq!(
+            sorted_ids(QueueOrder::WeightedFair { weights }, None, &seqs),
+            vec![3, 2, 0, 1]
+        );
+    }
+
+    #[test]
+    fn max_wait_promotes_starved_seqs() {
+        let seqs = [
+            (0, 1, 0, 5, None),
+            (1, 1, 20, 0, None),
+            (2, 1, 0, 1, None),
+            (3, 1, 30, -5, None),
+        ];
+        // The seqs which waited for at least 10 seconds go first, by arrival, and then the others by priority.
+        assert_eq!(
+            sorted_ids(QueueOrder::Priority, Some(Duration::from_secs(10)), &seqs),
+            vec![1, 3, 0, 2]
+        );
+        assert_eq!(
+            sorted_ids(QueueOrder::Priority, Some(Duration::from_secs(60)), &seqs),
+            vec![0, 2, 1, 3]
+        );
+    }
+
+    #[test]
+    fn queue_order_from_str() {
+        assert!(matches!("fcfs".parse::<QueueOrder>(), Ok(QueueOrder::Fcfs)));
+        assert!(matches!(
+            "priority".parse::<QueueOrder>(),
+            Ok(QueueOrder::Priority)
+        ));
+        assert!(matches!(
+            "shortest-prompt".parse::<QueueOrder>(),
+            Ok(QueueOrder::ShortestPromptFirst)
+        ));
+        match "fair".parse::<QueueOrder>() {
+            Ok(QueueOrder::WeightedFair { weights }) => assert!(weights.is_empty()),
+            _ => panic!("Expected weighted fair queuing."),
+        }
+        match "fair:interactive=4,batch=0.5".parse::<QueueOrder>() {
+            Ok(QueueOrder::WeightedFair { weights }) => {
+                assert_eq!(weights.len(), 2);
+                assert_eq!(weights["interactive"], 4.);
+                assert_eq!(weights["batch"], 0.5);
+            }
+            _ => panic!("Expected weighted fair queuing."),
         }
+        assert_eq!(
+            "fair:interactive".parse::<QueueOrder>().unwrap_err(),
+            "Expected `tenant=weight`, got `interactive`"
+        );
+        assert_eq!(
+            "fair:batch=0".parse::<QueueOrder>().unwrap_err(),
+            "Invalid weight for tenant `batch`"
+        );
+        assert!("lifo".parse::<QueueOrder>().is_err());
     }
 }
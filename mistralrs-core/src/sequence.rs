@@ -6,15 +6,18 @@ use std::{
 };
 
 use crate::{
-    aici::{cfg::CfgParser, recognizer::StackRecognizer, rx::RecRx},
+    aici::{cfg::CfgParser, recognizer::StackRecognizer, rx::RecRx, toktree::TokTrie},
     response::CompletionChoice,
     CompletionResponse,
 };
 use crate::{
     get_mut_group,
     models::LayerCaches,
-    response::{ChatCompletionChunkResponse, Choice, ChunkChoice, Response, SYSTEM_FINGERPRINT},
-    sampler::{Logprobs, Sampler},
+    response::{
+        ChatCompletionChunkResponse, Choice, ChunkChoice, CompletionChunkChoice,
+        CompletionChunkResponse, Response, SYSTEM_FINGERPRINT,
+    },
+    sampler::{BeamSearchParams, Logprobs, Sampler},
     ChatCompletionResponse, Usage,
 };
 use candle_core::Tensor;
@@ -28,6 +31,8 @@ pub enum StopReason {
     Length(usize),
     ModelLength(usize),
     StopString(/* Index in the list of stop strings */ usize),
+    Canceled,
+    Timeout,
 }
 
 impl ToString for StopReason {
@@ -36,6 +41,10 @@ impl ToString for StopReason {
             StopReason::Eos => "stop".to_string(),
             StopReason::Length(_) | StopReason::ModelLength(_) => "length".to_string(),
             StopReason::StopTok(_) | StopReason::StopString(_) => "stop".to_string(),
+            // This is not an OpenAI finish reason, so the cause is only logged.
+            StopReason::Canceled => "stop".to_string(),
+            // Unlike `length`, this tells clients that the response was cut short by its deadline.
+            StopReason::Timeout => "timeout".to_string(),
         }
     }
 }
@@ -67,10 +76,17 @@ pub struct Sequence {
     stop_tokens: Vec<u32>,
     stop_strings: Vec<String>,
     return_logprobs: bool,
+    return_prompt_logprobs: bool,
     responder: Sender<Response>,
     response_index: usize,
     creation_time: u64,
     prefill_prompt_toks: Option<Vec<u32>>,
+    prefill_chunk_size: Option<usize>,
+    priority: i32,
+    tenant: Option<String>,
+    deadline: Option<u128>,
+    // The LoRA adapters which are applied to the sequence
+    adapters: Vec<String>,
     pub suffix: Option<String>,
     pub prefix: Option<String>,
 
@@ -78,13 +94,34 @@ pub struct Sequence {
     scaling_cache: Option<Tensor>,
     cache: LayerCaches,
     xlora_cache: Option<LayerCaches>,
+    // Number of prompt tokens already in the cache during a chunked prefill
+    prefilled_len: usize,
+    // Speculative decoding: the cache of the draft model, the distributions of the drafted tokens at the
+    // end of `tokens`, and the start of the input of the next forward pass if it is not the last token.
+    draft_cache: LayerCaches,
+    drafts: Vec<Vec<f32>>,
+    input_start: Option<usize>,
 
     // Mutables
     tokens: Vec<u32>,
     decoded_tokens: Option<Vec<u8>>,
     logprobs: Vec<Logprobs>,
+    // Logprobs of the prompt tokens after the first one
+    prompt_logprobs: Vec<Logprobs>,
+    // Number of generated tokens and characters already sent in streaming completion chunks
+    streamed_logprobs: usize,
+    streamed_text_len: usize,
+    // Whether streamed output is held back because it may be a tool call
+    tool_call_pending: bool,
+    // The end of the streamed output, held back because it may be the start of a stop string
+    held_back_text: String,
+    // The generated tokens of the stop string which ends the output, which the response leaves out
+    stop_string_toks: usize,
     cumulative_logprob: f32,
     completion_bytes: Vec<u8>,
+    // Beam search: the candidates for the next token, which the beam waits with until all beams of its group
+    // have theirs
+    beam_candidates: Option<Vec<Logprobs>>,
 
     // GPU things
     pub prompt_tok_per_sec: f32,
@@ -109,6 +146,7 @@ impl Sequence {
         stop_strings: Vec<String>,
         max_len: Option<usize>,
         return_logprobs: bool,
+        return_prompt_logprobs: bool,
         is_xlora: bool,
         group: Rc<RefCell<SequenceGroup>>,
         response_index: usize,
@@ -116,13 +154,25 @@ impl Sequence {
         recognizer: SequenceRecognizer,
         suffix: Option<String>,
         prefix: Option<String>,
+        prefill_chunk_size: Option<usize>,
+        priority: i32,
+        tenant: Option<String>,
+        deadline: Option<u128>,
+        adapters: Vec<String>,
     ) -> Self {
         let prompt_len = tokens.len();
+        let tool_call_pending = group.borrow().has_tools;
         Self {
             tokens,
             original_prompt,
             decoded_tokens: None,
             logprobs: Vec::new(),
+            prompt_logprobs: Vec::new(),
+            streamed_logprobs: 0,
+            streamed_text_len: 0,
+            held_back_text: String::new(),
+            stop_string_toks: 0,
+            tool_call_pending,
             prompt_len,
             id,
             timestamp,
@@ -139,6 +189,7 @@ impl Sequence {
             stop_strings,
             max_len,
             return_logprobs,
+            return_prompt_logprobs,
             prompt_tok_per_sec: 0.,
             prompt_timestamp: None,
             group,
@@ -148,10 +199,73 @@ impl Sequence {
             creation_time,
             recognizer,
             prefill_prompt_toks: None,
+            prefill_chunk_size,
+            priority,
+            tenant,
+            deadline,
+            adapters,
+            prefilled_len: 0,
+            draft_cache: Vec::new(),
+            drafts: Vec::new(),
+            input_start: None,
             suffix,
             prefix,
             cumulative_logprob: 0.,
             completion_bytes: Vec::new(),
+            beam_candidates: None,
+        }
+    }
+
+    /// Fork a beam into a new sequence with the same tokens. The KV cache is shared until either sequence
+    /// extends it, as cache updates create new tensors.
+    pub fn fork(&self, id: usize) -> Self {
+        Self {
+            id,
+            original_prompt: self.original_prompt.clone(),
+            prompt_len: self.prompt_len,
+            max_len: self.max_len,
+            timestamp: self.timestamp,
+            sampler: self.sampler.clone(),
+            stop_tokens: self.stop_tokens.clone(),
+            stop_strings: self.stop_strings.clone(),
+            return_logprobs: self.return_logprobs,
+            return_prompt_logprobs: self.return_prompt_logprobs,
+            responder: self.responder.clone(),
+            response_index: self.response_index,
+            creation_time: self.creation_time,
+            prefill_prompt_toks: self.prefill_prompt_toks.clone(),
+            prefill_chunk_size: self.prefill_chunk_size,
+            priority: self.priority,
+            tenant: self.tenant.clone(),
+            deadline: self.deadline,
+            adapters: self.adapters.clone(),
+            suffix: self.suffix.clone(),
+            prefix: self.prefix.clone(),
+            scaling_cache: self.scaling_cache.clone(),
+            cache: self.cache.clone(),
+            xlora_cache: self.xlora_cache.clone(),
+            prefilled_len: self.prefilled_len,
+            draft_cache: self.draft_cache.clone(),
+            drafts: Vec::new(),
+            input_start: None,
+            tokens: self.tokens.clone(),
+            decoded_tokens: self.decoded_tokens.clone(),
+            logprobs: self.logprobs.clone(),
+            prompt_logprobs: self.prompt_logprobs.clone(),
+            streamed_logprobs: self.streamed_logprobs,
+            streamed_text_len: self.streamed_text_len,
+            tool_call_pending: self.tool_call_pending,
+            held_back_text: self.held_back_text.clone(),
+            stop_string_toks: self.stop_string_toks,
+            cumulative_logprob: self.cumulative_logprob,
+            completion_bytes: self.completion_bytes.clone(),
+            beam_candidates: None,
+            prompt_tok_per_sec: self.prompt_tok_per_sec,
+            prompt_timestamp: self.prompt_timestamp,
+            group: self.group.clone(),
+            total_sampling_time: self.total_sampling_time,
+            state: Cell::new(self.state.get()),
+            recognizer: self.recognizer.clone(),
         }
     }
 
@@ -162,7 +276,17 @@ impl Sequence {
             .as_millis();
         self.prompt_timestamp = Some(now);
 
-        self.cache = cache;
+        // The last prompt token is the input of the first completion step, so it is dropped from the cache.
+        // NOTE(EricLBuehler): Unwrap reasoning: The cache of a prompt has one position per prompt token.
+        self.cache = cache
+            .into_iter()
+            .map(|layer| {
+                layer.map(|(k, v)| {
+                    let len = k.dim(2).unwrap() - 1;
+                    (k.narrow(2, 0, len).unwrap(), v.narrow(2, 0, len).unwrap())
+                })
+            })
+            .collect();
         self.set_state(SequenceState::RunningCompletion);
         self
     }
@@ -174,6 +298,55 @@ impl Sequence {
         self
     }
 
+    /// Drop the KV cache so that all tokens are processed again by the next prompt step.
+    pub fn reset_cache(&mut self) {
+        self.cache = vec![None; self.cache.len()];
+        if let Some(xlora_cache) = &mut self.xlora_cache {
+            *xlora_cache = vec![None; xlora_cache.len()];
+        }
+        self.scaling_cache = None;
+        self.draft_cache.clear();
+        self.prefill_prompt_toks = None;
+        self.prefilled_len = 0;
+        self.prompt_logprobs.clear();
+    }
+
+    /// The range of tokens processed by the next prompt step. With chunked prefill, a long prompt is
+    /// processed in chunks of at most `prefill_chunk_size` tokens, and the sequence stays in a prompt
+    /// state until the last chunk is done.
+    fn prompt_chunk(&self) -> (usize, usize) {
+        let len = self.get_toks().len();
+        match self.prefill_chunk_size {
+            Some(chunk_size) => (self.prefilled_len, len.min(self.prefilled_len + chunk_size)),
+            None => (0, len),
+        }
+    }
+
+    /// The range of tokens which are the input of the next forward pass: the current prompt chunk, or the
+    /// last token of a completion. All tokens before the start of the range are in the KV cache.
+    ///
+    /// With speculative decoding, the input of a completion starts earlier to catch up the cache of the
+    /// draft model, or to verify the drafted tokens.
+    pub fn input_range(&self) -> (usize, usize) {
+        if self.is_prompt() {
+            self.prompt_chunk()
+        } else {
+            let len = self.get_toks().len();
+            (self.input_start.unwrap_or(len - 1), len)
+        }
+    }
+
+    pub fn set_input_start(&mut self, input_start: Option<usize>) {
+        self.input_start = input_start;
+    }
+
+    /// Mark the current prompt chunk as processed. Returns `true` once the whole prompt is prefilled.
+    pub fn advance_prompt_chunk(&mut self) -> bool {
+        let (_, end) = self.prompt_chunk();
+        self.prefilled_len = end;
+        end == self.get_toks().len()
+    }
+
     pub fn len(&self) -> usize {
         self.tokens.len()
     }
@@ -212,6 +385,11 @@ impl Sequence {
         &mut self.cache
     }
 
+    /// Swap the KV caches of the target and the draft model, for the forward passes of the draft model.
+    pub fn swap_draft_cache(&mut self) {
+        std::mem::swap(&mut self.cache, &mut self.draft_cache);
+    }
+
     pub fn xlora_cache(&mut self) -> &mut Vec<Option<(Tensor, Tensor)>> {
         self.xlora_cache.as_mut().unwrap()
     }
@@ -235,6 +413,96 @@ impl Sequence {
         self.logprobs.push(tok);
     }
 
+    /// Add a token drafted for speculative decoding, with the distribution it was drafted from. It is not
+    /// part of the output until the target model accepts it.
+    pub fn add_draft(&mut self, tok: u32, probs: Vec<f32>) {
+        self.tokens.push(tok);
+        self.drafts.push(probs);
+    }
+
+    pub fn has_drafts(&self) -> bool {
+        !self.drafts.is_empty()
+    }
+
+    /// Remove the drafted tokens once they are verified, returning them with their distributions.
+    pub fn take_drafts(&mut self) -> Vec<(u32, Vec<f32>)> {
+        self.input_start = None;
+        let drafts = std::mem::take(&mut self.drafts);
+        let toks = self.tokens.split_off(self.tokens.len() - drafts.len());
+        toks.into_iter().zip(drafts).collect()
+    }
+
+    /// Truncate the KV caches to the tokens before the last one, which drops the positions of rejected
+    /// drafted tokens.
+    pub fn truncate_cache(&mut self) {
+        let len = self.tokens.len() - 1;
+        for cache in [&mut self.cache, &mut self.draft_cache] {
+            for (k, v) in cache.iter_mut().flatten() {
+                if k.dims()[2] > len {
+                    // NOTE(EricLBuehler): Unwrap reasoning: The cache is longer than `len`.
+                    *k = k.narrow(2, 0, len).unwrap();
+                    *v = v.narrow(2, 0, len).unwrap();
+                }
+            }
+        }
+    }
+
+    /// Whether the sequence is a beam of a beam search.
+    pub fn is_beam(&self) -> bool {
+        get_mut_group!(self).beam_search.is_some()
+    }
+
+    pub fn set_beam_candidates(&mut self, candidates: Vec<Logprobs>) {
+        self.beam_candidates = Some(candidates);
+    }
+
+    pub fn has_beam_candidates(&self) -> bool {
+        self.beam_candidates.is_some()
+    }
+
+    pub fn take_beam_candidates(&mut self) -> Vec<Logprobs> {
+        self.beam_candidates.take().unwrap_or_default()
+    }
+
+    pub fn cumulative_logprob(&self) -> f32 {
+        self.cumulative_logprob
+    }
+
+    /// The score of a beam: its cumulative logprob divided by its length to the power of the length penalty.
+    #[allow(clippy::cast_precision_loss)]
+    pub fn beam_score(&self, length_penalty: f32) -> f32 {
+        let len = self.tokens.len() - self.prompt_len;
+        self.cumulative_logprob / (len.max(1) as f32).powf(length_penalty)
+    }
+
+    /// The output of a finished beam, to keep once the beam itself is gone.
+    pub fn beam_hypothesis(&self, reason: StopReason, length_penalty: f32) -> BeamHypothesis {
+        BeamHypothesis {
+            score: self.beam_score(length_penalty),
+            reason,
+            tokens: self.tokens.clone(),
+            logprobs: self.logprobs.clone(),
+            completion_bytes: self.completion_bytes.clone(),
+        }
+    }
+
+    /// Fork a beam into a sequence with the output of a finished beam, to respond with it. Its cumulative
+    /// logprob is the score of the hypothesis, by which the choices are ranked.
+    pub fn fork_hypothesis(
+        &self,
+        id: usize,
+        response_index: usize,
+        hypothesis: BeamHypothesis,
+    ) -> Self {
+        let mut seq = self.fork(id);
+        seq.response_index = response_index;
+        seq.tokens = hypothesis.tokens;
+        seq.logprobs = hypothesis.logprobs;
+        seq.completion_bytes = hypothesis.completion_bytes;
+        seq.cumulative_logprob = hypothesis.score;
+        seq
+    }
+
     pub fn responder(&self) -> Sender<Response> {
         self.responder.clone()
     }
@@ -282,10 +550,139 @@ impl Sequence {
         self.return_logprobs
     }
 
+    pub fn prompt_logprobs(&self) -> &[Logprobs] {
+        &self.prompt_logprobs
+    }
+
+    pub fn add_prompt_logprobs(&mut self, logprobs: Vec<Logprobs>) {
+        self.prompt_logprobs.extend(logprobs);
+    }
+
+    /// Whether the next forward pass needs the logits of all of the input positions, rather than only the
+    /// last one. This is the case for the prompt steps of a sequence which returns its prompt logprobs, and
+    /// for the verification of drafted tokens.
+    pub fn wants_all_logits(&self) -> bool {
+        (self.return_prompt_logprobs && self.is_prompt()) || !self.drafts.is_empty()
+    }
+
+    pub fn is_tool_call_pending(&self) -> bool {
+        self.tool_call_pending
+    }
+
+    pub fn set_tool_call_pending(&mut self, tool_call_pending: bool) {
+        self.tool_call_pending = tool_call_pending;
+    }
+
+    /// The byte index of the first stop string in `text`, at which the output ends.
+    pub fn find_stop_string(&self, text: &str) -> Option<usize> {
+        self.stop_strings
+            .iter()
+            .filter(|s| !s.is_empty())
+            .filter_map(|s| text.find(s.as_str()))
+            .min()
+    }
+
+    /// End the output before the first stop string, which is found in the raw bytes of the completion like
+    /// `is_done` finds it. The tokens which end after the stop string starts are left out of the logprobs and
+    /// the usage, so a token which starts with whitespace before the stop string is left out too. Returns the
+    /// decoded kept tokens followed by the bytes of the next token before the stop string, or `None` if there
+    /// is no stop string.
+    pub fn truncate_at_stop_string(
+        &mut self,
+        tokenizer: &Tokenizer,
+        tok_trie: &TokTrie,
+    ) -> Result<Option<String>, tokenizers::Error> {
+        let Some(stop) = self
+            .stop_strings
+            .iter()
+            .filter(|s| !s.is_empty())
+            .filter_map(|s| galil_seiferas::gs_find(&self.completion_bytes, s.as_bytes()))
+            .min()
+        else {
+            return Ok(None);
+        };
+        // Walk back through the bytes of the tokens, which make up the completion bytes.
+        let completion = &self.tokens[self.prompt_len..];
+        let mut n_kept = completion.len();
+        let mut kept_bytes = self.completion_bytes.len();
+        while n_kept > 0 && kept_bytes > stop {
+            n_kept -= 1;
+            kept_bytes = kept_bytes.saturating_sub(tok_trie.token(completion[n_kept]).len());
+        }
+        let mut output = tokenizer.decode(&completion[..n_kept], false)?;
+        output.push_str(&String::from_utf8_lossy(
+            &self.completion_bytes[kept_bytes.min(stop)..stop],
+        ));
+        self.stop_string_toks = completion.len() - n_kept;
+        self.logprobs.truncate(n_kept);
+        Ok(Some(output))
+    }
+
+    /// The part of a streaming delta which may be sent. The end of the output is held back while it may be
+    /// the start of a stop string, and sent with the next delta once it is not. Once a stop string is found,
+    /// the output ends before it, and the held back text is sent when the sequence is done otherwise.
+    pub fn release_stop_string_text(&mut self, delta: String, is_done: bool) -> String {
+        if self.stop_strings.is_empty() {
+            return delta;
+        }
+        let mut text = std::mem::take(&mut self.held_back_text);
+        text.push_str(&delta);
+        if let Some(stop) = self.find_stop_string(&text) {
+            text.truncate(stop);
+            return text;
+        }
+        if is_done {
+            return text;
+        }
+        // The longest end of the text which is a proper prefix of a stop string.
+        let held_back_len = self
+            .stop_strings
+            .iter()
+            .flat_map(|s| s.char_indices().skip(1).map(|(i, _)| &s[..i]))
+            .filter(|prefix| text.ends_with(prefix))
+            .map(str::len)
+            .max()
+            .unwrap_or(0);
+        self.held_back_text = text.split_off(text.len() - held_back_len);
+        text
+    }
+
+    /// The number of generated tokens and characters already sent in streaming completion chunks.
+    pub fn streamed(&self) -> (usize, usize) {
+        (self.streamed_logprobs, self.streamed_text_len)
+    }
+
+    pub fn set_streamed(&mut self, streamed_logprobs: usize, streamed_text_len: usize) {
+        self.streamed_logprobs = streamed_logprobs;
+        self.streamed_text_len = streamed_text_len;
+    }
+
     pub fn prompt_tokens(&self) -> usize {
         self.prompt_len
     }
 
+    pub fn priority(&self) -> i32 {
+        self.priority
+    }
+
+    pub fn tenant(&self) -> Option<&str> {
+        self.tenant.as_deref()
+    }
+
+    /// The time in milliseconds since the epoch at which the sequence times out.
+    pub fn deadline(&self) -> Option<u128> {
+        self.deadline
+    }
+
+    /// The LoRA adapters which the sequence applies, which is none for the base model.
+    pub fn adapters(&self) -> &[String] {
+        &self.adapters
+    }
+
+    pub fn max_len(&self) -> Option<usize> {
+        self.max_len
+    }
+
     /// Returns the delta between the last two decoded sequences
     pub fn get_delta(
         &mut self,
@@ -343,7 +740,7 @@ impl Sequence {
         get_mut_group!(self).total_time += now - self.timestamp;
 
         get_mut_group!(self).total_prompt_toks += self.prompt_len;
-        get_mut_group!(self).total_toks += self.len();
+        get_mut_group!(self).total_toks += self.len() - self.stop_string_toks;
 
         get_mut_group!(self).total_sampling_time += self.total_sampling_time;
     }
@@ -371,9 +768,62 @@ impl Sequence {
     pub fn add_streaming_chunk_choice_to_group(&self, chunk: ChunkChoice) {
         get_mut_group!(self).streaming_chunks.push(chunk);
     }
+
+    pub fn add_streaming_completion_chunk_choice_to_group(&self, chunk: CompletionChunkChoice) {
+        get_mut_group!(self).completion_streaming_chunks.push(chunk);
+    }
+
+    /// Account a finished streaming completion choice in the usage of the group.
+    pub fn add_streaming_completion_done_to_group(&self) {
+        get_mut_group!(self).completion_streaming_done += 1;
+        self.update_time_info();
+    }
+
+    /// Whether the request of this sequence was aborted, or its client disconnected.
+    pub fn is_canceled(&self) -> bool {
+        get_mut_group!(self).is_canceled
+    }
+}
+
+/// A finished beam of a beam search.
+#[derive(Clone)]
+pub struct BeamHypothesis {
+    pub score: f32,
+    pub reason: StopReason,
+    tokens: Vec<u32>,
+    logprobs: Vec<Logprobs>,
+    completion_bytes: Vec<u8>,
+}
+
+/// The state of the beam search of a group: the best finished beams, best first.
+pub struct BeamSearch {
+    pub params: BeamSearchParams,
+    finished: Vec<BeamHypothesis>,
+}
+
+impl BeamSearch {
+    /// Keep a finished beam if it is among the `beam_width` best ones.
+    pub fn add_hypothesis(&mut self, hypothesis: BeamHypothesis) {
+        self.finished.push(hypothesis);
+        self.finished.sort_by(|a, b| b.score.total_cmp(&a.score));
+        self.finished.truncate(self.params.beam_width);
+    }
+
+    /// Whether the search is done, given the best score which a live beam has now, if there is one.
+    pub fn is_done(&self, best_live_score: Option<f32>) -> bool {
+        let Some(best_live_score) = best_live_score else {
+            return true;
+        };
+        if self.finished.len() < self.params.beam_width {
+            return false;
+        }
+        // NOTE(EricLBuehler): Unwrap reasoning: There are `beam_width` finished beams.
+        self.params.early_stopping || self.finished.last().unwrap().score >= best_live_score
+    }
 }
 
 pub struct SequenceGroup {
+    pub request_id: usize,
     n_choices: usize, // The target number of choices to return. Can be decreased if an error is thrown.
     best_of: Option<usize>, // Top n seqs based on cumulative logprobs.
     pub total_prompt_toks: usize,
@@ -385,18 +835,32 @@ pub struct SequenceGroup {
     choices: Vec<Choice>,
     completion_choices: Vec<(f32, CompletionChoice)>,
     pub streaming_chunks: Vec<ChunkChoice>,
+    pub completion_streaming_chunks: Vec<CompletionChunkChoice>,
+    completion_streaming_done: usize,
     pub is_streaming: bool,
     pub is_chat: bool,
+    pub is_canceled: bool,
+    /// Whether the output is parsed for tool calls.
+    pub has_tools: bool,
+    pub beam_search: Option<BeamSearch>,
+    /// Echoes the seed of the request, if it has one.
+    pub system_fingerprint: String,
 }
 
 impl SequenceGroup {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
+        request_id: usize,
         n_choices: usize,
         is_streaming: bool,
         is_chat: bool,
         best_of: Option<usize>,
+        has_tools: bool,
+        beam_search: Option<BeamSearchParams>,
+        seed: Option<u64>,
     ) -> Self {
         Self {
+            request_id,
             choices: Vec::new(),
             completion_choices: Vec::new(),
             n_choices,
@@ -407,12 +871,36 @@ impl SequenceGroup {
             total_completion_time: 0,
             total_sampling_time: 0,
             streaming_chunks: Vec::new(),
+            completion_streaming_chunks: Vec::new(),
+            completion_streaming_done: 0,
             is_streaming,
             is_chat,
             best_of,
+            is_canceled: false,
+            has_tools,
+            beam_search: beam_search.map(|params| BeamSearch {
+                params,
+                finished: Vec::new(),
+            }),
+            system_fingerprint: match seed {
+                Some(seed) => format!("{SYSTEM_FINGERPRINT}-seed-{seed}"),
+                None => SYSTEM_FINGERPRINT.to_string(),
+            },
         }
     }
 
+    /// Take the best finished beams of a done beam search, which are the choices of the response.
+    pub fn take_beam_hypotheses(&mut self) -> Vec<BeamHypothesis> {
+        let Some(beam_search) = &mut self.beam_search else {
+            return Vec::new();
+        };
+        let mut hypotheses = std::mem::take(&mut beam_search.finished);
+        hypotheses.truncate(self.n_choices);
+        // There may be fewer finished beams than choices if the vocabulary is tiny.
+        self.n_choices = hypotheses.len();
+        hypotheses
+    }
+
     /// This does not apply best_of.
     pub fn get_choices(&self) -> &[Choice] {
         &self.choices
@@ -464,20 +952,62 @@ impl SequenceGroup {
 
     pub fn maybe_send_streaming_response(&mut self, seq: &Sequence, model: String) {
         if self.streaming_chunks.len() == self.n_choices && self.is_streaming {
-            seq.responder()
+            let sent = seq
+                .responder()
                 .send(Response::Chunk(ChatCompletionChunkResponse {
                     id: seq.id.to_string(),
                     choices: self.streaming_chunks.clone(),
                     created: seq.timestamp,
                     model: model.clone(),
-                    system_fingerprint: SYSTEM_FINGERPRINT.to_string(),
+                    system_fingerprint: self.system_fingerprint.clone(),
                     object: "chat.completion.chunk".to_string(),
-                }))
-                .unwrap();
+                }));
+            // The receiver is gone if the client disconnected, so there is no point in generating more.
+            if sent.is_err() {
+                self.is_canceled = true;
+            }
             self.streaming_chunks.clear();
         }
     }
 
+    /// Send the chunks of a streaming completion once every choice which was still running has one. After the
+    /// last chunks, a final chunk without choices carries the usage.
+    pub fn maybe_send_completion_streaming_response(&mut self, seq: &Sequence, model: String) {
+        // The choices which finish in this batch are already counted as done.
+        let finished_in_batch = self
+            .completion_streaming_chunks
+            .iter()
+            .filter(|chunk| chunk.stopreason.is_some())
+            .count();
+        let finished_before = self.completion_streaming_done - finished_in_batch;
+        if self.completion_streaming_chunks.len() + finished_before == self.n_choices
+            && self.is_streaming
+        {
+            let mut chunks = vec![std::mem::take(&mut self.completion_streaming_chunks)];
+            if self.completion_streaming_done == self.n_choices {
+                chunks.push(Vec::new());
+            }
+            for choices in chunks {
+                let usage = choices.is_empty().then(|| self.get_usage());
+                let sent =
+                    seq.responder()
+                        .send(Response::CompletionChunk(CompletionChunkResponse {
+                            id: seq.id.to_string(),
+                            choices,
+                            created: seq.timestamp,
+                            model: model.clone(),
+                            system_fingerprint: self.system_fingerprint.clone(),
+                            object: "text_completion".to_string(),
+                            usage,
+                        }));
+                // The receiver is gone if the client disconnected, so there is no point in generating more.
+                if sent.is_err() {
+                    self.is_canceled = true;
+                }
+            }
+        }
+    }
+
     pub fn maybe_send_completion_done_response(
         &self,
         response: CompletionResponse,
@@ -489,3 +1019,289 @@ impl SequenceGroup {
         }
     }
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::{
+        cell::RefCell,
+        rc::Rc,
+        sync::mpsc::{channel, Receiver, Sender},
+    };
+
+    use super::{
+        BeamHypothesis, BeamSearch, Sequence, SequenceGroup, SequenceRecognizer, StopReason,
+    };
+    use crate::{
+        aici::{bytes::TokRxInfo, toktree::TokTrie},
+        response::{CompletionChunkChoice, Response},
+        sampler::{
+            tests::{test_sampler, test_tokenizer},
+            BeamSearchParams, Logprobs,
+        },
+    };
+
+    /// A waiting sequence of a completion request, with the prompt tokens `0..prompt_len`.
+    pub(crate) fn test_sequence(
+        id: usize,
+        prompt_len: usize,
+        timestamp: u128,
+        priority: i32,
+        tenant: Option<&str>,
+        stop_strings: Vec<String>,
+    ) -> Sequence {
+        let group = SequenceGroup::new(id, 1, false, false, None, false, None, None);
+        Sequence::new_waiting(
+            (0..prompt_len as u32).collect(),
+            String::new(),
+            id,
+            timestamp,
+            1,
+            channel().0,
+            test_sampler(64),
+            Vec::new(),
+            stop_strings,
+            None,
+            false,
+            false,
+            false,
+            Rc::new(RefCell::new(group)),
+            0,
+            0,
+            SequenceRecognizer::None,
+            None,
+            None,
+            None,
+            priority,
+            tenant.map(ToString::to_string),
+            None,
+            Vec::new(),
+        )
+    }
+
+    fn stop_sequence(stop_strings: &[&str]) -> Sequence {
+        let stop_strings = stop_strings.iter().map(ToString::to_string).collect();
+        test_sequence(0, 1, 0, 0, None, stop_strings)
+    }
+
+    #[test]
+    fn find_stop_string_finds_the_first() {
+        let seq = stop_sequence(&["STOP", "", "END"]);
+        assert_eq!(seq.find_stop_string("a END b STOP"), Some(2));
+        assert_eq!(seq.find_stop_string("a STO"), None);
+    }
+
+    #[test]
+    fn stop_string_split_across_deltas() {
+        let mut seq = stop_sequence(&["STOP"]);
+        assert_eq!(
+            seq.release_stop_string_text("Hello ST".to_string(), false),
+            "Hello "
+        );
+        // The output ends before the stop string, so neither it nor the text after it is sent.
+        assert_eq!(seq.release_stop_string_text("OP and".to_string(), true), "");
+    }
+
+    #[test]
+    fn partial_stop_string_is_released() {
+        let mut seq = stop_sequence(&["STOP"]);
+        assert_eq!(
+            seq.release_stop_string_text("abc S".to_string(), false),
+            "abc "
+        );
+        assert_eq!(seq.release_stop_string_text("T".to_string(), false), "");
+        assert_eq!(
+            seq.release_stop_string_text("ay".to_string(), false),
+            "STay"
+        );
+    }
+
+    #[test]
+    fn done_flushes_held_back_text() {
+        let mut seq = stop_sequence(&["STOP"]);
+        assert_eq!(
+            seq.release_stop_string_text("x ST".to_string(), false),
+            "x "
+        );
+        assert_eq!(seq.release_stop_string_text(String::new(), true), "ST");
+    }
+
+    #[test]
+    fn stop_string_tokens_are_dropped() {
+        // Like with SentencePiece, the raw bytes of each token start with a space which decoding drops.
+        let words = (0..8)
+            .map(|i| format!(" t{i}").into_bytes())
+            .collect::<Vec<_>>();
+        let tok_trie = TokTrie::from(
+            &TokRxInfo {
+                vocab_size: 8,
+                tok_eos: 0,
+            },
+            &words,
+        );
+        let tokenizer = test_tokenizer(8);
+        let completion = |stop_string: &str| {
+            let mut seq = stop_sequence(&[stop_string]);
+            for tok in 1..5 {
+                let logprob = Logprobs {
+                    token: tok,
+                    logprob: 0.,
+                    bytes: format!("t{tok}"),
+                    top_logprobs: None,
+                };
+                seq.add_token(logprob, tok_trie.token(tok).to_vec());
+            }
+            let output = seq
+                .truncate_at_stop_string(&tokenizer, &tok_trie)
+                .unwrap()
+                .unwrap();
+            let kept = seq.logprobs().iter().map(|l| l.token).collect::<Vec<_>>();
+            (output, seq.stop_string_toks, kept)
+        };
+        // The token of `t3` starts with the space before it.
+        assert_eq!(completion("t3"), ("t1 t2 ".to_string(), 2, vec![1, 2]));
+        // The stop string is found where `is_done` found it, even though the decoded output has no leading space.
+        assert_eq!(completion(" t1"), (String::new(), 4, vec![]));
+    }
+
+    /// A running choice of a streaming completion request with `group`.
+    fn streaming_choice(
+        group: &Rc<RefCell<SequenceGroup>>,
+        response_index: usize,
+        responder: Sender<Response>,
+    ) -> Sequence {
+        Sequence::new_waiting(
+            vec![0],
+            String::new(),
+            response_index,
+            0,
+            1,
+            responder,
+            test_sampler(64),
+            Vec::new(),
+            Vec::new(),
+            None,
+            false,
+            false,
+            false,
+            group.clone(),
+            response_index,
+            0,
+            SequenceRecognizer::None,
+            None,
+            None,
+            None,
+            0,
+            None,
+            None,
+            Vec::new(),
+        )
+    }
+
+    /// Add the next chunk of a streaming completion choice, as the engine does after each step.
+    fn add_chunk(seq: &Sequence, done: bool) {
+        seq.add_streaming_completion_chunk_choice_to_group(CompletionChunkChoice {
+            stopreason: done.then(|| "stop".to_string()),
+            index: seq.get_response_index(),
+            text: String::new(),
+            logprobs: None,
+        });
+        if done {
+            seq.add_streaming_completion_done_to_group();
+        }
+        seq.get_mut_group()
+            .maybe_send_completion_streaming_response(seq, String::new());
+    }
+
+    /// The indices of the choices of the next chunk, and whether it carries the usage.
+    fn next_chunk(rx: &Receiver<Response>) -> (Vec<usize>, bool) {
+        match rx.try_recv() {
+            Ok(Response::CompletionChunk(chunk)) => (
+                chunk.choices.iter().map(|choice| choice.index).collect(),
+                chunk.usage.is_some(),
+            ),
+            _ => panic!("Expected a completion chunk."),
+        }
+    }
+
+    #[test]
+    fn streaming_choices_finishing_on_different_steps() {
+        let group = Rc::new(RefCell::new(SequenceGroup::new(
+            0, 2, true, false, None, false, None, None,
+        )));
+        let (tx, rx) = channel();
+        let first = streaming_choice(&group, 0, tx.clone());
+        let second = streaming_choice(&group, 1, tx);
+
+        add_chunk(&first, true);
+        assert!(rx.try_recv().is_err());
+        add_chunk(&second, false);
+        assert_eq!(next_chunk(&rx), (vec![0, 1], false));
+
+        // Only the second choice is still running.
+        add_chunk(&second, false);
+        assert_eq!(next_chunk(&rx), (vec![1], false));
+        add_chunk(&second, true);
+        assert_eq!(next_chunk(&rx), (vec![1], false));
+        assert_eq!(next_chunk(&rx), (vec![], true));
+        assert!(rx.try_recv().is_err());
+    }
+
+    /// A finished beam whose generated tokens have these logprobs.
+    fn hypothesis(logprobs: &[f32], length_penalty: f32) -> BeamHypothesis {
+        let mut seq = test_sequence(0, 1, 0, 0, None, Vec::new());
+        for logprob in logprobs {
+            let tok = Logprobs {
+                token: 1,
+                logprob: *logprob,
+                bytes: "t1".to_string(),
+                top_logprobs: None,
+            };
+            seq.add_token(tok, Vec::new());
+        }
+        seq.beam_hypothesis(StopReason::Eos, length_penalty)
+    }
+
+    fn beam_search(length_penalty: f32, early_stopping: bool) -> BeamSearch {
+        BeamSearch {
+            params: BeamSearchParams {
+                beam_width: 2,
+                length_penalty,
+                early_stopping,
+            },
+            finished: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn beam_search_keeps_the_best_hypotheses() {
+        // The scores are -1, -0.5 and -0.75 with a length penalty of 1, and -1, -2 and -1.5 without one.
+        let outputs = [vec![-1.], vec![-0.5; 4], vec![-0.75; 2]];
+        for (length_penalty, expected) in [(1., vec![-0.5, -0.75]), (0., vec![-1., -1.5])] {
+            let mut search = beam_search(length_penalty, false);
+            for output in &outputs {
+                search.add_hypothesis(hypothesis(output, length_penalty));
+            }
+            let scores = search.finished.iter().map(|h| h.score).collect::<Vec<_>>();
+            assert_eq!(scores, expected);
+        }
+    }
+
+    #[test]
+    fn beam_search_stops_when_no_live_beam_can_do_better() {
+        let mut search = beam_search(1., false);
+        search.add_hypothesis(hypothesis(&[-1.], 1.));
+        assert!(search.is_done(None));
+        assert!(!search.is_done(Some(-2.)));
+        search.add_hypothesis(hypothesis(&[-2.], 1.));
+        // The worst finished score is -2.
+        assert!(search.is_done(Some(-3.)));
+        assert!(!search.is_done(Some(-1.5)));
+
+        // With early stopping, the search is done once it has `beam_width` finished beams.
+        let mut search = beam_search(1., true);
+        search.add_hypothesis(hypothesis(&[-1.], 1.));
+        assert!(!search.is_done(Some(-1.5)));
+        search.add_hypothesis(hypothesis(&[-2.], 1.));
+        assert!(search.is_done(Some(-1.5)));
+    }
+}
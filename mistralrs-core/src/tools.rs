@@ -0,0 +1,220 @@
+use indexmap::IndexMap;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum ToolType {
+    #[serde(rename = "function")]
+    Function,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Function {
+    pub name: String,
+    pub description: Option<String>,
+    /// The JSON schema of the arguments.
+    pub parameters: Option<Value>,
+}
+
+/// A tool which the model may call.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Tool {
+    #[serde(rename = "type")]
+    pub tp: ToolType,
+    pub function: Function,
+}
+
+/// Whether and which tools the model calls.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ToolChoice {
+    /// Do not call tools, they are not given to the model.
+    None,
+    /// The model either answers or calls tools.
+    Auto,
+    /// The model must call at least one tool.
+    Required,
+    /// The model must call the function with this name.
+    Function(String),
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct CalledFunction {
+    pub name: String,
+    /// The arguments as a JSON string.
+    pub arguments: String,
+}
+
+/// A tool call parsed from the output of the model.
+#[derive(Clone, Debug, Serialize)]
+pub struct ToolCall {
+    pub index: usize,
+    pub id: String,
+    #[serde(rename = "type")]
+    pub tp: ToolType,
+    pub function: CalledFunction,
+}
+
+/// Format tool calls the way the model is asked to write them, for the assistant messages of a conversation.
+pub fn format_tool_calls(calls: &[CalledFunction]) -> String {
+    let calls = calls
+        .iter()
+        .map(|call| {
+            let arguments = serde_json::from_str(&call.arguments)
+                .unwrap_or_else(|_| Value::String(call.arguments.clone()));
+            serde_json::json!({ "name": call.name, "arguments": arguments })
+        })
+        .collect::<Vec<_>>();
+    Value::Array(calls).to_string()
+}
+
+/// Add the tool definitions and instructions on how to call them to the messages. This is only needed for chat
+/// templates which do not render the tools themselves. The instructions go into the system message if there is
+/// one, otherwise into the last user message. Tool results are turned into user messages, as such templates only
+/// know the system, user and assistant roles.
+pub(crate) fn add_tools_to_messages(
+    messages: &mut [IndexMap<String, String>],
+    tools: &[Tool],
+    choice: &ToolChoice,
+) {
+    for message in messages.iter_mut() {
+        if message.get("role").is_some_and(|role| role == "tool") {
+            let content = message.get("content").cloned().unwrap_or_default();
+            message.insert("role".to_string(), "user".to_string());
+            message.insert(
+                "content".to_string(),
+                format!("The tool call returned: {content}"),
+            );
+        }
+    }
+
+    let definitions = tools
+        .iter()
+        .map(|tool| serde_json::to_value(&tool.function).unwrap_or(Value::Null))
+        .collect::<Vec<_>>();
+    let mut instructions = format!(
+        "You can call the following tools:\n{}\nTo call tools, answer with only a JSON list of the calls, like \
+         [{{\"name\": \"tool_name\", \"arguments\": {{\"argument\": \"value\"}}}}].",
+        Value::Array(definitions)
+    );
+    match choice {
+        ToolChoice::Required => instructions.push_str(" You must call at least one tool."),
+        ToolChoice::Function(name) => {
+            instructions.push_str(&format!(" You must call the tool `{name}`."))
+        }
+        ToolChoice::None | ToolChoice::Auto => {}
+    }
+
+    let target = messages
+        .iter()
+        .position(|message| message.get("role").is_some_and(|role| role == "system"))
+        .or_else(|| {
+            messages
+                .iter()
+                .rposition(|message| message.get("role").is_some_and(|role| role == "user"))
+        });
+    if let Some(content) = target.and_then(|i| messages[i].get_mut("content")) {
+        *content = format!("{instructions}\n\n{content}");
+    }
+}
+
+/// A JSON schema for `Constraint::JsonSchema` which only accepts a JSON list of calls to these functions, with
+/// arguments valid under their parameters.
+pub(crate) fn tool_calls_schema(functions: &[&Function]) -> Value {
+    let calls = functions
+        .iter()
+        .map(|function| {
+            serde_json::json!({
+                "type": "object",
+                "properties": {
+                    "name": { "const": function.name },
+                    "arguments": function
+                        .parameters
+                        .clone()
+                        .unwrap_or_else(|| serde_json::json!({ "type": "object" })),
+                },
+                "required": ["name", "arguments"],
+            })
+        })
+        .collect::<Vec<_>>();
+    serde_json::json!({
+        "type": "array",
+        "items": { "anyOf": calls },
+        "minItems": 1,
+    })
+}
+
+/// Parse the tool calls from the output of the model: a JSON list of calls, or a single call, each with a `name`
+/// and `arguments`. Returns `None` if the output is not a tool call.
+pub(crate) fn parse_tool_calls(output: &str, id_prefix: &str) -> Option<Vec<ToolCall>> {
+    let output = output.trim();
+    // Some models mark their tool calls.
+    let output = output.strip_prefix("[TOOL_CALLS]").unwrap_or(output).trim();
+    let calls = match serde_json::from_str::<Value>(output).ok()? {
+        Value::Array(calls) => calls,
+        call @ Value::Object(_) => vec![call],
+        _ => return None,
+    };
+    if calls.is_empty() {
+        return None;
+    }
+    calls
+        .into_iter()
+        .enumerate()
+        .map(|(index, call)| {
+            let name = call.get("name")?.as_str()?.to_string();
+            let arguments = match call.get("arguments").or_else(|| call.get("parameters"))? {
+                Value::String(arguments) => arguments.clone(),
+                arguments => arguments.to_string(),
+            };
+            Some(ToolCall {
+                index,
+                id: format!("{id_prefix}-{index}"),
+                tp: ToolType::Function,
+                function: CalledFunction { name, arguments },
+            })
+        })
+        .collect()
+}
+
+/// Whether output which is still being generated may turn out to be a tool call.
+pub(crate) fn may_be_tool_call(output: &str) -> bool {
+    let output = output.trim_start();
+    output.is_empty() || output.starts_with('[') || output.starts_with('{')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{may_be_tool_call, parse_tool_calls};
+
+    #[test]
+    fn parse_a_list_of_calls() {
+        let output = r#" [TOOL_CALLS] [{"name": "get_weather", "arguments": {"city": "Paris"}},
+            {"name": "get_time", "parameters": "{\"zone\": \"CET\"}"}] "#;
+        let calls = parse_tool_calls(output, "call-7").unwrap();
+        assert_eq!(calls.len(), 2);
+        assert_eq!(calls[0].id, "call-7-0");
+        assert_eq!(calls[0].function.name, "get_weather");
+        assert_eq!(calls[0].function.arguments, r#"{"city":"Paris"}"#);
+        // Arguments which are already a JSON string are kept as they are.
+        assert_eq!(calls[1].index, 1);
+        assert_eq!(calls[1].function.name, "get_time");
+        assert_eq!(calls[1].function.arguments, r#"{"zone": "CET"}"#);
+    }
+
+    #[test]
+    fn parse_a_single_call() {
+        let calls = parse_tool_calls(r#"{"name": "f", "arguments": {}}"#, "call-0").unwrap();
+        assert_eq!(calls.len(), 1);
+        assert_eq!(calls[0].function.arguments, "{}");
+    }
+
+    #[test]
+    fn other_output_is_not_a_tool_call() {
+        assert!(parse_tool_calls("The weather is nice.", "call-0").is_none());
+        assert!(parse_tool_calls("[]", "call-0").is_none());
+        assert!(parse_tool_calls(r#"[{"name": "f"}]"#, "call-0").is_none());
+        assert!(parse_tool_calls(r#"[{"arguments": {}}]"#, "call-0").is_none());
+        assert!(may_be_tool_call("  [{\"na"));
+        assert!(!may_be_tool_call("The"));
+    }
+}
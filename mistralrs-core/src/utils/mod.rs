@@ -53,7 +53,6 @@ macro_rules! handle_pipeline_forward_error {
                 use $crate::response::Response;
                 use $crate::sequence::SequenceState;
                 use $crate::Engine;
-                use $crate::response::SYSTEM_FINGERPRINT;
                 use tracing::error;
                 error!("{} - Model failed with error: {:?}", $stage, &e);
                 for seq in $seq_slice.iter_mut() {
@@ -73,6 +72,7 @@ macro_rules! handle_pipeline_forward_error {
                             message: ResponseMessage {
                                 content: res,
                                 role: "assistant".to_string(),
+                                tool_calls: None,
                             },
                             logprobs: None,
                         };
@@ -97,34 +97,32 @@ macro_rules! handle_pipeline_forward_error {
                             choices: group.get_choices().to_vec(),
                             created: seq.creation_time(),
                             model: $pipeline.name(),
-                            system_fingerprint: SYSTEM_FINGERPRINT.to_string(),
+                            system_fingerprint: group.system_fingerprint.clone(),
                             object: "chat.completion".to_string(),
                             usage: group.get_usage(),
                         };
 
-                        seq.responder()
-                            .send(Response::ModelError(
-                                e.to_string(),
-                                partial_completion_response
-                            ))
-                            .unwrap();
+                        // Ignore the error: the client may be gone, which must not stop the engine.
+                        let _ = seq.responder().send(Response::ModelError(
+                            e.to_string(),
+                            partial_completion_response
+                        ));
                     } else {
                         let partial_completion_response = CompletionResponse {
                             id: seq.id().to_string(),
                             choices: group.get_completion_choices().to_vec(),
                             created: seq.creation_time(),
                             model: $pipeline.name(),
-                            system_fingerprint: SYSTEM_FINGERPRINT.to_string(),
+                            system_fingerprint: group.system_fingerprint.clone(),
                             object: "text_completion".to_string(),
                             usage: group.get_usage(),
                         };
 
-                        seq.responder()
-                            .send(Response::CompletionModelError(
-                                e.to_string(),
-                                partial_completion_response
-                            ))
-                            .unwrap();
+                        // Ignore the error: the client may be gone, which must not stop the engine.
+                        let _ = seq.responder().send(Response::CompletionModelError(
+                            e.to_string(),
+                            partial_completion_response
+                        ));
                     }
                 }
                 for seq in $seq_slice.iter_mut() {
@@ -11,7 +11,9 @@ use crate::{
     pipeline::GEMMA_IS_GPTX,
 };
 
-use super::{classifier::XLoraClassifier, NonGranularState, ScalingsMaker, XLoraConfig};
+use super::{
+    classifier::XLoraClassifier, lora_layer, NonGranularState, ScalingsMaker, XLoraConfig,
+};
 
 fn default_max_position_embeddings() -> usize {
     4096
@@ -514,6 +516,7 @@ impl XLoraModel {
         start_offsets_kernel_full: Tensor,
         no_kv_cache: bool,
         non_granular_state: &Option<NonGranularState>,
+        lora_scalings: Option<Tensor>,
     ) -> Result<Tensor> {
         let (_, seq_len) = input_ids.dims2()?;
 
@@ -560,12 +563,13 @@ impl XLoraModel {
                 .i((.., seq_len - 1, ..))
             }
         } else {
+            // LoRA models apply the adapters which each sequence selects.
             let (_, seq_len) = input_ids.dims2()?;
             self.inner_forward(
                 input_ids,
                 seqlen_offsets,
                 start_offsets_kernel,
-                None,
+                lora_scalings,
                 false,
                 no_kv_cache,
                 None,
@@ -575,6 +579,25 @@ impl XLoraModel {
             .i((.., seq_len - 1, ..))
         }
     }
+
+    /// The layers which LoRA adapters are loaded into and unloaded from.
+    pub fn lora_layers(&mut self) -> Vec<&mut (dyn LinearLayerLike + Send + Sync)> {
+        self.layers
+            .iter_mut()
+            .flat_map(|layer| {
+                [
+                    &mut layer.self_attn.q_proj,
+                    &mut layer.self_attn.k_proj,
+                    &mut layer.self_attn.v_proj,
+                    &mut layer.self_attn.o_proj,
+                    &mut layer.mlp.gate_proj,
+                    &mut layer.mlp.up_proj,
+                    &mut layer.mlp.down_proj,
+                ]
+            })
+            .map(lora_layer)
+            .collect()
+    }
 }
 
 impl ScalingsMaker for XLoraModel {
@@ -14,7 +14,9 @@ use crate::{
     pipeline::LLAMA_IS_GPTX,
 };
 
-use super::{classifier::XLoraClassifier, NonGranularState, ScalingsMaker, XLoraConfig};
+use super::{
+    classifier::XLoraClassifier, lora_layer, NonGranularState, ScalingsMaker, XLoraConfig,
+};
 
 #[derive(Debug, Clone)]
 pub struct Cache {
@@ -410,6 +412,7 @@ impl XLoraLlama {
         start_offsets_kernel_full: Tensor,
         no_kv_cache: bool,
         non_granular_state: &Option<NonGranularState>,
+        lora_scalings: Option<Tensor>,
     ) -> Result<Tensor> {
         let (_, seq_len) = input_ids.dims2()?;
 
@@ -456,12 +459,13 @@ impl XLoraLlama {
                 .i((.., seq_len - 1, ..))
             }
         } else {
+            // LoRA models apply the adapters which each sequence selects.
             let (_, seq_len) = input_ids.dims2()?;
             self.inner_forward(
                 input_ids,
                 seqlen_offsets,
                 start_offsets_kernel,
-                None,
+                lora_scalings,
                 false,
                 no_kv_cache,
                 None,
@@ -514,6 +518,25 @@ impl XLoraLlama {
             dtype,
         })
     }
+
+    /// The layers which LoRA adapters are loaded into and unloaded from.
+    pub fn lora_layers(&mut self) -> Vec<&mut (dyn LinearLayerLike + Send + Sync)> {
+        self.blocks
+            .iter_mut()
+            .flat_map(|block| {
+                [
+                    &mut block.attn.q_proj,
+                    &mut block.attn.k_proj,
+                    &mut block.attn.v_proj,
+                    &mut block.attn.o_proj,
+                    &mut block.mlp.c_fc1,
+                    &mut block.mlp.c_fc2,
+                    &mut block.mlp.c_proj,
+                ]
+            })
+            .map(lora_layer)
+            .collect()
+    }
 }
 
 impl ScalingsMaker for XLoraLlama {
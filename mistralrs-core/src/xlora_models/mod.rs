@@ -14,7 +14,7 @@ pub use config::XLoraConfig;
 pub use gemma::XLoraModel as XLoraGemma;
 pub use llama::XLoraLlama;
 pub use mistral::XLoraModel as XLoraMistral;
-use mistralrs_lora::Ordering;
+use mistralrs_lora::{LinearLayerLike, Ordering};
 pub use mixtral::XLoraModel as XLoraMixtral;
 pub use phi2::Model as XLoraPhi2;
 pub use quantized_llama::ModelWeights as XLoraModelWeights;
@@ -23,6 +23,13 @@ use crate::{get_mut_arcmutex, models::Cache};
 
 use self::classifier::XLoraClassifier;
 
+/// The LoRA layer behind `layer`, which only its model holds.
+fn lora_layer(
+    layer: &mut Arc<dyn LinearLayerLike + Send + Sync>,
+) -> &mut (dyn LinearLayerLike + Send + Sync) {
+    Arc::get_mut(layer).expect("LoRA layers are only held by their model.")
+}
+
 pub struct NonGranularState {
     pub non_granular_index: Arc<Mutex<usize>>,
     pub tgt_non_granular_index: usize,
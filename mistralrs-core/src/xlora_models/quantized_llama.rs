@@ -6,7 +6,7 @@ use candle_core::quantized::QMatMul;
 use candle_core::quantized::{ggml_file, gguf_file};
 use candle_core::{DType, Device, IndexOp, Result, Tensor};
 use candle_nn::{Embedding, Module, RotaryEmbedding, VarBuilder};
-use mistralrs_lora::{get_lora_cfg, LinearLayerLike, LoraConfig, Merge, Ordering, QLoraLinear};
+use mistralrs_lora::{get_lora_cfg, LinearLayerLike, LoraConfig, Ordering, QLoraLinear};
 
 use crate::models::{verify_sanity_gguf, Cache, QRmsNorm};
 
@@ -628,33 +628,6 @@ impl ModelWeights {
                 rotary: rotary.clone(),
             })
         }
-        if xlora_config.is_none() {
-            // We are now a LoRA model so we must merge the weights
-            for layer in &mut layers {
-                layer.attention_wk.merge_weights()?;
-                layer.attention_wo.merge_weights()?;
-                layer.attention_wq.merge_weights()?;
-                layer.attention_wv.merge_weights()?;
-                match &mut layer.mlp_or_moe {
-                    MlpOrMoe::Mlp(ref mut m) => {
-                        m.feed_forward_w1.merge_weights()?;
-                        m.feed_forward_w2.merge_weights()?;
-                        m.feed_forward_w3.merge_weights()?;
-                    }
-                    MlpOrMoe::MoE {
-                        n_expert_used: _,
-                        feed_forward_gate_inp: _,
-                        experts,
-                    } => {
-                        for expert in experts {
-                            expert.feed_forward_w1.merge_weights()?;
-                            expert.feed_forward_w2.merge_weights()?;
-                            expert.feed_forward_w3.merge_weights()?;
-                        }
-                    }
-                }
-            }
-        }
         Ok(Self {
             tok_embeddings: Embedding::new(tok_embeddings, embedding_length),
             layers,
@@ -765,6 +738,7 @@ impl ModelWeights {
         start_offsets_kernel_full: Tensor,
         no_kv_cache: bool,
         non_granular_state: &Option<NonGranularState>,
+        lora_scalings: Option<Tensor>,
     ) -> Result<Tensor> {
         let (_, seq_len) = input_ids.dims2()?;
 
@@ -811,12 +785,13 @@ impl ModelWeights {
                 .i((.., seq_len - 1, ..))
             }
         } else {
+            // LoRA models apply the adapters which each sequence selects.
             let (_, seq_len) = input_ids.dims2()?;
             self.inner_forward(
                 input_ids,
                 seqlen_offsets,
                 start_offsets_kernel,
-                None,
+                lora_scalings,
                 false,
                 no_kv_cache,
                 None,
@@ -826,6 +801,27 @@ impl ModelWeights {
             .i((.., seq_len - 1, ..))
         }
     }
+
+    /// The layers which LoRA adapters are loaded into and unloaded from.
+    pub fn lora_layers(&mut self) -> Vec<&mut (dyn LinearLayerLike + Send + Sync)> {
+        let mut lora_layers: Vec<&mut (dyn LinearLayerLike + Send + Sync)> = Vec::new();
+        for layer in &mut self.layers {
+            lora_layers.push(&mut layer.attention_wq);
+            lora_layers.push(&mut layer.attention_wk);
+            lora_layers.push(&mut layer.attention_wv);
+            lora_layers.push(&mut layer.attention_wo);
+            let mlps = match &mut layer.mlp_or_moe {
+                MlpOrMoe::Mlp(mlp) => vec![mlp],
+                MlpOrMoe::MoE { experts, .. } => experts.iter_mut().collect(),
+            };
+            for mlp in mlps {
+                lora_layers.push(&mut mlp.feed_forward_w1);
+                lora_layers.push(&mut mlp.feed_forward_w2);
+                lora_layers.push(&mut mlp.feed_forward_w3);
+            }
+        }
+        lora_layers
+    }
 }
 
 impl ScalingsMaker for ModelWeights {
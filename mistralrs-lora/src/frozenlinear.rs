@@ -1,7 +1,7 @@
 use candle_core::{Module, Result, Shape, Tensor};
-use candle_nn::Linear;
+use candle_nn::{Linear, VarBuilder};
 
-use crate::LinearLayerLike;
+use crate::{LinearLayerLike, LoraConfig};
 
 /// Linear, but with a `new` implementation that ensures the weight and/or biases are detached (frozen).
 #[derive(Debug)]
@@ -46,4 +46,10 @@ impl LinearLayerLike for FrozenLinear {
     ) -> Result<Tensor> {
         self.linear.forward(x)
     }
+    fn load_adapter(&mut self, _config: &LoraConfig, _vb: &VarBuilder) -> Result<()> {
+        Ok(())
+    }
+    fn unload_adapter(&mut self, _adapter: usize) -> Result<()> {
+        Ok(())
+    }
 }
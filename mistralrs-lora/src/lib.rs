@@ -1,7 +1,8 @@
 use std::{collections::HashSet, fmt::Debug, sync::Arc};
 
 use candle_core::{quantized::QTensor, IndexOp, Result, Shape, Tensor, D};
-use candle_nn::{Linear, Module, VarBuilder};
+use candle_nn::{init, Linear, Module, VarBuilder};
+use either::Either;
 use loralinear::LoraLinear;
 pub use qloralinear::QLoraLinear;
 use serde::Deserialize;
@@ -46,6 +47,84 @@ pub struct LoraConfig {
     target_modules: HashSet<String>,
 }
 
+/// Load the `lora_A` and `lora_B` weights of an adapter for the layer at `prefix` in `vb`.
+fn load_adapter_weights(
+    linear_config: &LoraLinearConfig,
+    config: &LoraConfig,
+    vb: &VarBuilder,
+    prefix: &str,
+) -> Result<(Linear, Linear)> {
+    let vb = vb.pp(prefix);
+    let a_pp = vb.pp("lora_A");
+    let b_pp = vb.pp("lora_B");
+    if !a_pp.contains_tensor("weight") || !b_pp.contains_tensor("weight") {
+        candle_core::bail!("The adapter has no weights for `{prefix}`.");
+    }
+    let a = a_pp.get_with_hints(
+        (config.rank, linear_config.in_features),
+        "weight",
+        init::DEFAULT_KAIMING_NORMAL,
+    )?;
+    let b = b_pp.get_with_hints(
+        (linear_config.out_features, config.rank),
+        "weight",
+        init::ZERO,
+    )?;
+    Ok((Linear::new(a, None), Linear::new(b, None)))
+}
+
+/// The adapters of a layer, which are stacked along with their list when they can be applied at once.
+type Adapters = Either<Vec<Linear>, (Tensor, Vec<Linear>)>;
+
+/// Stack the adapters when they all have the same rank, scale and dropout, so that a forward pass can apply them
+/// at once. The stacked `lora_A` weights are multiplied by the scales of the adapters.
+fn stack_adapters(
+    a_adapters: Vec<Linear>,
+    b_adapters: Vec<Linear>,
+    scale_adapters: &[f64],
+    dropout_adapters: &[Option<f32>],
+) -> Result<(Adapters, Adapters)> {
+    let rank = |a: &Linear| a.weight().dims()[0];
+    let all_same = a_adapters.iter().all(|a| rank(a) == rank(&a_adapters[0]))
+        && scale_adapters.iter().all(|x| *x == scale_adapters[0])
+        && dropout_adapters.iter().all(|x| *x == dropout_adapters[0]);
+    if a_adapters.is_empty() || !all_same {
+        return Ok((Either::Left(a_adapters), Either::Left(b_adapters)));
+    }
+    let a_adapters_stack = Tensor::cat(
+        &a_adapters
+            .iter()
+            .map(|x| x.weight().unsqueeze(0))
+            .collect::<Result<Vec<_>>>()?,
+        0,
+    )?;
+    let b_adapters_stack = Tensor::cat(
+        &b_adapters
+            .iter()
+            .map(|x| x.weight().unsqueeze(0))
+            .collect::<Result<Vec<_>>>()?,
+        0,
+    )?;
+    let scale_adapters_t = Tensor::from_vec(
+        scale_adapters.to_vec(),
+        (scale_adapters.len(), 1, 1),
+        a_adapters_stack.device(),
+    )?
+    .to_dtype(a_adapters_stack.dtype())?;
+    let a_adapters_stack = a_adapters_stack.broadcast_mul(&scale_adapters_t)?;
+    Ok((
+        Either::Right((a_adapters_stack, a_adapters)),
+        Either::Right((b_adapters_stack, b_adapters)),
+    ))
+}
+
+/// The list of the adapters, whether or not they are stacked.
+fn adapter_list(adapters: &Adapters) -> &[Linear] {
+    match adapters {
+        Either::Left(adapters) | Either::Right((_, adapters)) => adapters,
+    }
+}
+
 fn apply_scalings_to_x(x: Tensor, scalings_layer: &Tensor, adapter: usize) -> Result<Tensor> {
     let scalings = scalings_layer.i((.., .., adapter))?.unsqueeze(D::Minus1)?;
     let res = x.broadcast_mul(&scalings)?;
@@ -66,6 +145,18 @@ impl LoraConfig {
             target_modules,
         }
     }
+
+    pub fn target_modules(&self) -> &HashSet<String> {
+        &self.target_modules
+    }
+
+    fn scale(&self) -> f64 {
+        if self.rank > 0 {
+            self.alpha / self.rank as f64
+        } else {
+            1.0
+        }
+    }
 }
 
 /// Any layer that is linear-like.
@@ -80,6 +171,10 @@ pub trait LinearLayerLike: Debug {
         global_scaling_weight: f64,
         is_scaling_pass: Option<f64>,
     ) -> Result<Tensor>;
+    /// Load an adapter as the last one. `vb` holds its weights under the names of the layers of the model.
+    fn load_adapter(&mut self, config: &LoraConfig, vb: &VarBuilder) -> Result<()>;
+    /// Unload the adapter at `adapter`, which moves the adapters after it down by one.
+    fn unload_adapter(&mut self, adapter: usize) -> Result<()>;
 }
 
 pub trait Merge {
@@ -108,6 +203,13 @@ impl LinearLayerLike for Linear {
     ) -> Result<Tensor> {
         self.forward(x)
     }
+    // A layer which the adapters do not target has no adapter weights.
+    fn load_adapter(&mut self, _config: &LoraConfig, _vb: &VarBuilder) -> Result<()> {
+        Ok(())
+    }
+    fn unload_adapter(&mut self, _adapter: usize) -> Result<()> {
+        Ok(())
+    }
 }
 
 pub fn linear(
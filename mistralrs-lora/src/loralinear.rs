@@ -5,19 +5,22 @@ use candle_nn::{init, Dropout, Linear, VarBuilder};
 use either::Either;
 
 use crate::{
-    apply_scalings_to_x, frozenlinear::FrozenLinear, get_maybe_topk_scalings, LinearLayerLike,
-    LoraConfig, LoraLinearConfig, Merge,
+    adapter_list, apply_scalings_to_x, frozenlinear::FrozenLinear, get_maybe_topk_scalings,
+    load_adapter_weights, stack_adapters, Adapters, LinearLayerLike, LoraConfig, LoraLinearConfig,
+    Merge,
 };
 
 #[derive(Debug)]
 pub struct LoraLinear {
     old: FrozenLinear,
-    a_adapters: Either<Vec<Linear>, (Tensor, Vec<Linear>)>,
-    b_adapters: Either<Vec<Linear>, (Tensor, Vec<Linear>)>,
+    a_adapters: Adapters,
+    b_adapters: Adapters,
     scale_adapters: Vec<f64>,
-    dropout_adapters: Vec<Option<Dropout>>,
+    dropout_adapters: Vec<Option<f32>>,
     layer_n: usize,
     merged: bool,
+    linear_config: LoraLinearConfig,
+    prefix: String,
 }
 
 impl LoraLinear {
@@ -34,8 +37,6 @@ impl LoraLinear {
         let mut dropout_adapters = Vec::with_capacity(config.len());
         let a_vb = vb.pp("lora_A".to_string());
         let b_vb = vb.pp("lora_B".to_string());
-        let mut state = None;
-        let mut all_same = true;
         for (name, cfg) in config.iter() {
             let a_pp = a_vb.pp(name);
             assert!(a_pp.contains_tensor("weight"));
@@ -50,76 +51,23 @@ impl LoraLinear {
                 b_pp.get_with_hints((linear_config.out_features, cfg.rank), "weight", init::ZERO)?;
             a_adapters.push(Linear::new(a, None));
             b_adapters.push(Linear::new(b, None));
-            scale_adapters.push(if cfg.rank > 0 {
-                cfg.alpha / cfg.rank as f64
-            } else {
-                1.0
-            });
-            dropout_adapters.push(cfg.dropout.map(Dropout::new));
-            if state.is_some_and(|x| {
-                x == (
-                    cfg.rank,
-                    linear_config.in_features,
-                    linear_config.out_features,
-                    cfg.alpha,
-                    cfg.dropout,
-                )
-            }) || state.is_none()
-            {
-                state = Some((
-                    cfg.rank,
-                    linear_config.in_features,
-                    linear_config.out_features,
-                    cfg.alpha,
-                    cfg.dropout,
-                ));
-            } else {
-                all_same = false;
-            }
+            scale_adapters.push(cfg.scale());
+            dropout_adapters.push(cfg.dropout);
         }
 
-        if all_same {
-            let a_adapters_stack = Tensor::cat(
-                &a_adapters
-                    .iter()
-                    .map(|x| x.weight().unsqueeze(0))
-                    .collect::<Result<Vec<_>>>()?,
-                0,
-            )?;
-            let b_adapters_stack = Tensor::cat(
-                &b_adapters
-                    .iter()
-                    .map(|x| x.weight().unsqueeze(0))
-                    .collect::<Result<Vec<_>>>()?,
-                0,
-            )?;
-            let scale_adapters_t = Tensor::from_vec(
-                scale_adapters.clone(),
-                (scale_adapters.len(), 1, 1),
-                a_adapters_stack.device(),
-            )?
-            .to_dtype(a_adapters_stack.dtype())?;
-            let a_adapters_stack = a_adapters_stack.broadcast_mul(&scale_adapters_t)?;
-            Ok(LoraLinear {
-                old: FrozenLinear::new_from_linear(old)?,
-                a_adapters: Either::Right((a_adapters_stack.clone(), a_adapters)),
-                b_adapters: Either::Right((b_adapters_stack, b_adapters)),
-                scale_adapters,
-                dropout_adapters,
-                layer_n,
-                merged: false,
-            })
-        } else {
-            Ok(LoraLinear {
-                old: FrozenLinear::new_from_linear(old)?,
-                a_adapters: Either::Left(a_adapters),
-                b_adapters: Either::Left(b_adapters),
-                scale_adapters,
-                dropout_adapters,
-                layer_n,
-                merged: false,
-            })
-        }
+        let (a_adapters, b_adapters) =
+            stack_adapters(a_adapters, b_adapters, &scale_adapters, &dropout_adapters)?;
+        Ok(LoraLinear {
+            old: FrozenLinear::new_from_linear(old)?,
+            a_adapters,
+            b_adapters,
+            scale_adapters,
+            dropout_adapters,
+            layer_n,
+            merged: false,
+            linear_config: linear_config.clone(),
+            prefix: vb.prefix(),
+        })
     }
 }
 
@@ -176,7 +124,9 @@ impl LinearLayerLike for LoraLinear {
         let scalings = scalings.unwrap();
 
         let scalings = get_maybe_topk_scalings(scalings, self.layer_n)?;
-        if self.a_adapters.is_left() || scalings.dims3()?.1 != 1 {
+        // The stacked adapters are scaled once for the whole batch, so they need a single row of scalings.
+        let (b_size, seq_len, _) = scalings.dims3()?;
+        if self.a_adapters.is_left() || b_size != 1 || seq_len != 1 {
             let a_adapters = if self.a_adapters.is_right() {
                 self.a_adapters.as_ref().unwrap_right().1.clone()
             } else {
@@ -200,8 +150,8 @@ impl LinearLayerLike for LoraLinear {
                 let mut input_new = input.to_dtype(adapter_a.weight().dtype())?;
                 input_new = apply_scalings_to_x(input_new.clone(), &scalings, i)?;
 
-                input_new = if let Some(ref dropout) = adapter_dropout {
-                    dropout.forward(&input_new, true)?
+                input_new = if let Some(p) = adapter_dropout {
+                    Dropout::new(*p).forward(&input_new, true)?
                 } else {
                     input_new.clone()
                 };
@@ -228,8 +178,8 @@ impl LinearLayerLike for LoraLinear {
                 .broadcast_mul(&scalings)?
                 .mul(global_scaling_weight)?;
 
-            let input = if let Some(ref d) = dropout {
-                d.forward(input, true)?
+            let input = if let Some(p) = dropout {
+                Dropout::new(*p).forward(input, true)?
             } else {
                 input.clone()
             };
@@ -243,4 +193,209 @@ impl LinearLayerLike for LoraLinear {
             out + result
         }
     }
+    fn load_adapter(&mut self, config: &LoraConfig, vb: &VarBuilder) -> Result<()> {
+        if self.merged {
+            candle_core::bail!("Adapters cannot be loaded into a layer whose adapters are merged.");
+        }
+        let (a, b) = load_adapter_weights(&self.linear_config, config, vb, &self.prefix)?;
+        let mut a_adapters = adapter_list(&self.a_adapters).to_vec();
+        let mut b_adapters = adapter_list(&self.b_adapters).to_vec();
+        let mut scale_adapters = self.scale_adapters.clone();
+        let mut dropout_adapters = self.dropout_adapters.clone();
+        a_adapters.push(a);
+        b_adapters.push(b);
+        scale_adapters.push(config.scale());
+        dropout_adapters.push(config.dropout);
+        // The adapters are restacked so that a forward pass can still apply them at once, and the layer is only
+        // changed if this succeeds.
+        (self.a_adapters, self.b_adapters) =
+            stack_adapters(a_adapters, b_adapters, &scale_adapters, &dropout_adapters)?;
+        self.scale_adapters = scale_adapters;
+        self.dropout_adapters = dropout_adapters;
+        Ok(())
+    }
+    fn unload_adapter(&mut self, adapter: usize) -> Result<()> {
+        if self.merged {
+            candle_core::bail!(
+                "Adapters cannot be unloaded from a layer whose adapters are merged."
+            );
+        }
+        if adapter >= self.scale_adapters.len() {
+            candle_core::bail!("There is no adapter {adapter} to unload.");
+        }
+        let mut a_adapters = adapter_list(&self.a_adapters).to_vec();
+        let mut b_adapters = adapter_list(&self.b_adapters).to_vec();
+        let mut scale_adapters = self.scale_adapters.clone();
+        let mut dropout_adapters = self.dropout_adapters.clone();
+        a_adapters.remove(adapter);
+        b_adapters.remove(adapter);
+        scale_adapters.remove(adapter);
+        dropout_adapters.remove(adapter);
+        (self.a_adapters, self.b_adapters) =
+            stack_adapters(a_adapters, b_adapters, &scale_adapters, &dropout_adapters)?;
+        self.scale_adapters = scale_adapters;
+        self.dropout_adapters = dropout_adapters;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{HashMap, HashSet};
+
+    use candle_core::{DType, Device, Module, Result, Tensor};
+    use candle_nn::{Linear, VarBuilder};
+
+    use super::LoraLinear;
+    use crate::{LinearLayerLike, LoraConfig, LoraLinearConfig, Merge};
+
+    const IN: usize = 4;
+    const OUT: usize = 3;
+    const RANK: usize = 2;
+
+    fn config(rank: usize) -> LoraConfig {
+        LoraConfig::new(
+            rank,
+            2. * rank as f64,
+            None,
+            HashSet::from(["proj".to_string()]),
+        )
+    }
+
+    fn weights(rank: usize, seed: f32) -> Result<(Tensor, Tensor)> {
+        let a = Tensor::arange(0., (rank * IN) as f32, &Device::Cpu)?
+            .affine(0.1, seed as f64)?
+            .reshape((rank, IN))?;
+        let b = Tensor::arange(0., (OUT * rank) as f32, &Device::Cpu)?
+            .affine(-0.2, seed as f64)?
+            .reshape((OUT, rank))?;
+        Ok((a, b))
+    }
+
+    /// A layer at `proj` with the adapters `a0` and `a1`, which are stacked.
+    fn layer() -> Result<LoraLinear> {
+        let weight = Tensor::arange(0., (OUT * IN) as f32, &Device::Cpu)?.reshape((OUT, IN))?;
+        let old = Linear::new(weight, None);
+        let mut tensors = HashMap::new();
+        for (name, seed) in [("a0", 1.), ("a1", -1.)] {
+            let (a, b) = weights(RANK, seed)?;
+            tensors.insert(format!("proj.lora_A.{name}.weight"), a);
+            tensors.insert(format!("proj.lora_B.{name}.weight"), b);
+        }
+        let vb = VarBuilder::from_tensors(tensors, DType::F32, &Device::Cpu);
+        LoraLinear::new(
+            &old,
+            &LoraLinearConfig::new(IN, OUT),
+            &[
+                ("a0".to_string(), config(RANK)),
+                ("a1".to_string(), config(RANK)),
+            ],
+            &vb.pp("proj"),
+            0,
+        )
+    }
+
+    /// The weights of an adapter as PEFT writes them, for the layer at `proj`.
+    fn adapter(rank: usize, seed: f32) -> Result<VarBuilder<'static>> {
+        let (a, b) = weights(rank, seed)?;
+        let tensors = HashMap::from([
+            ("proj.lora_A.weight".to_string(), a),
+            ("proj.lora_B.weight".to_string(), b),
+        ]);
+        Ok(VarBuilder::from_tensors(tensors, DType::F32, &Device::Cpu))
+    }
+
+    fn input(b_size: usize) -> Result<Tensor> {
+        Tensor::arange(0., (b_size * IN) as f32, &Device::Cpu)?
+            .affine(0.5, -1.)?
+            .reshape((b_size, 1, IN))
+    }
+
+    /// The scalings of the layer, with one row of `n_adapters` scalings per sequence.
+    fn scalings(rows: &[&[f32]]) -> Result<Tensor> {
+        let n_adapters = rows[0].len();
+        Tensor::from_vec(rows.concat(), (rows.len(), 1, 1, n_adapters), &Device::Cpu)
+    }
+
+    /// The output of the layer, computed from the weights of each adapter on their own.
+    fn expected(layer: &LoraLinear, x: &Tensor, scalings: &[f32]) -> Result<Tensor> {
+        let mut result = layer.old.forward(x)?;
+        for (adapter, scaling) in scalings.iter().enumerate() {
+            let delta = layer.get_delta_weight(adapter)?;
+            result = (result
+                + Linear::new(delta, None)
+                    .forward(x)?
+                    .affine(*scaling as f64, 0.)?)?;
+        }
+        Ok(result)
+    }
+
+    fn assert_close(a: &Tensor, b: &Tensor) -> Result<()> {
+        let diff = (a - b)?.abs()?.flatten_all()?.max(0)?.to_scalar::<f32>()?;
+        assert!(diff < 1e-4, "{a} != {b}");
+        Ok(())
+    }
+
+    #[test]
+    fn loading_and_unloading_restack_the_adapters() -> Result<()> {
+        let mut layer = layer()?;
+        assert!(layer.a_adapters.is_right());
+
+        layer.load_adapter(&config(RANK), &adapter(RANK, 0.5)?)?;
+        assert!(layer.a_adapters.is_right() && layer.b_adapters.is_right());
+        assert_eq!(layer.scale_adapters.len(), 3);
+        let x = input(1)?;
+        let s = [1., 0., 1.];
+        let out = layer.lora_forward(&x, Some(scalings(&[&s])?), 1., None)?;
+        assert_close(&out, &expected(&layer, &x, &s)?)?;
+
+        // An adapter of another rank cannot be stacked with the others.
+        layer.load_adapter(&config(1), &adapter(1, 0.5)?)?;
+        assert!(layer.a_adapters.is_left());
+        layer.unload_adapter(3)?;
+        assert!(layer.a_adapters.is_right());
+
+        layer.unload_adapter(0)?;
+        assert!(layer.a_adapters.is_right());
+        assert_eq!(layer.scale_adapters.len(), 2);
+        let s = [0., 1.];
+        let out = layer.lora_forward(&x, Some(scalings(&[&s])?), 1., None)?;
+        assert_close(&out, &expected(&layer, &x, &s)?)?;
+
+        assert!(layer.unload_adapter(2).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn failed_load_leaves_the_layer_unchanged() -> Result<()> {
+        let mut layer = layer()?;
+        let x = input(1)?;
+        let s = scalings(&[&[1., 1.]])?;
+        let before = layer.lora_forward(&x, Some(s.clone()), 1., None)?;
+
+        let empty = VarBuilder::from_tensors(HashMap::new(), DType::F32, &Device::Cpu);
+        assert!(layer.load_adapter(&config(RANK), &empty).is_err());
+        // The weights do not have the rank of the config.
+        assert!(layer
+            .load_adapter(&config(RANK + 1), &adapter(RANK, 0.5)?)
+            .is_err());
+
+        assert!(layer.a_adapters.is_right());
+        assert_eq!(layer.scale_adapters.len(), 2);
+        assert_eq!(layer.dropout_adapters.len(), 2);
+        let after = layer.lora_forward(&x, Some(s), 1., None)?;
+        assert_close(&before, &after)
+    }
+
+    #[test]
+    fn scalings_apply_per_sequence() -> Result<()> {
+        let layer = layer()?;
+        let x = input(2)?;
+        let out = layer.lora_forward(&x, Some(scalings(&[&[1., 0.], &[0., 1.]])?), 1., None)?;
+        for (i, s) in [[1., 0.], [0., 1.]].iter().enumerate() {
+            let x_i = x.narrow(0, i, 1)?;
+            assert_close(&out.narrow(0, i, 1)?, &expected(&layer, &x_i, s)?)?;
+        }
+        Ok(())
+    }
 }
@@ -8,19 +8,21 @@ use candle_nn::{init, Dropout, Linear, VarBuilder};
 use either::Either;
 
 use crate::{
-    apply_scalings_to_x, get_maybe_topk_scalings, LinearLayerLike, LoraConfig, LoraLinearConfig,
-    Merge, Ordering,
+    adapter_list, apply_scalings_to_x, get_maybe_topk_scalings, load_adapter_weights,
+    stack_adapters, Adapters, LinearLayerLike, LoraConfig, LoraLinearConfig, Merge, Ordering,
 };
 
 #[derive(Debug)]
 pub struct QLoraLinear {
     old: QMatMul,
-    a_adapters: Either<Vec<Linear>, (Tensor, Vec<Linear>)>,
-    b_adapters: Either<Vec<Linear>, (Tensor, Vec<Linear>)>,
+    a_adapters: Adapters,
+    b_adapters: Adapters,
     scale_adapters: Vec<f64>,
-    dropout_adapters: Vec<Option<Dropout>>,
+    dropout_adapters: Vec<Option<f32>>,
     layer_n: usize,
     merged: bool,
+    linear_config: LoraLinearConfig,
+    prefix: String,
 }
 
 impl QLoraLinear {
@@ -50,6 +52,8 @@ impl QLoraLinear {
                 dropout_adapters: vec![],
                 layer_n: usize::MAX,
                 merged: false,
+                linear_config: linear_config.clone(),
+                prefix,
             });
         }
 
@@ -62,8 +66,6 @@ impl QLoraLinear {
         let vb = vb.pp(prefix.clone());
         let a_vb = vb.pp("lora_A".to_string());
         let b_vb = vb.pp("lora_B".to_string());
-        let mut state = None;
-        let mut all_same = true;
         for (name, cfg) in config.iter() {
             let a_pp = a_vb.pp(name);
             assert!(a_pp.contains_tensor("weight"));
@@ -81,77 +83,24 @@ impl QLoraLinear {
                 .to_dtype(DType::F32)?;
             a_adapters.push(Linear::new(a, None));
             b_adapters.push(Linear::new(b, None));
-            scale_adapters.push(if cfg.rank > 0 {
-                cfg.alpha / cfg.rank as f64
-            } else {
-                1.0
-            });
-            dropout_adapters.push(cfg.dropout.map(Dropout::new));
-            if state.is_some_and(|x| {
-                x == (
-                    cfg.rank,
-                    linear_config.in_features,
-                    linear_config.out_features,
-                    cfg.alpha,
-                    cfg.dropout,
-                )
-            }) || state.is_none()
-            {
-                state = Some((
-                    cfg.rank,
-                    linear_config.in_features,
-                    linear_config.out_features,
-                    cfg.alpha,
-                    cfg.dropout,
-                ));
-            } else {
-                all_same = false;
-            }
+            scale_adapters.push(cfg.scale());
+            dropout_adapters.push(cfg.dropout);
         }
         let layer = *ordering.layers.get(&prefix).unwrap();
 
-        if all_same {
-            let a_adapters_stack = Tensor::cat(
-                &a_adapters
-                    .iter()
-                    .map(|x| x.weight().unsqueeze(0))
-                    .collect::<Result<Vec<_>>>()?,
-                0,
-            )?;
-            let b_adapters_stack = Tensor::cat(
-                &b_adapters
-                    .iter()
-                    .map(|x| x.weight().unsqueeze(0))
-                    .collect::<Result<Vec<_>>>()?,
-                0,
-            )?;
-            let scale_adapters_t = Tensor::from_vec(
-                scale_adapters.clone(),
-                (scale_adapters.len(), 1, 1),
-                a_adapters_stack.device(),
-            )?
-            .to_dtype(a_adapters_stack.dtype())?;
-            let a_adapters_stack = a_adapters_stack.broadcast_mul(&scale_adapters_t)?;
-            Ok(QLoraLinear {
-                old,
-                a_adapters: Either::Right((a_adapters_stack.clone(), a_adapters)),
-                b_adapters: Either::Right((b_adapters_stack.clone(), b_adapters)),
-                scale_adapters,
-                dropout_adapters,
-                layer_n: layer,
-                merged: false,
-            })
-        } else {
-            Ok(QLoraLinear {
-                old,
-                a_adapters: Either::Left(a_adapters),
-                b_adapters: Either::Left(b_adapters),
-                scale_adapters,
-                dropout_adapters,
-                layer_n: layer,
-                merged: false,
-            })
-        }
+        let (a_adapters, b_adapters) =
+            stack_adapters(a_adapters, b_adapters, &scale_adapters, &dropout_adapters)?;
+        Ok(QLoraLinear {
+            old,
+            a_adapters,
+            b_adapters,
+            scale_adapters,
+            dropout_adapters,
+            layer_n: layer,
+            merged: false,
+            linear_config: linear_config.clone(),
+            prefix,
+        })
     }
 }
 
@@ -217,7 +166,9 @@ impl LinearLayerLike for QLoraLinear {
             return Ok(result);
         }
         let scalings = get_maybe_topk_scalings(scalings, self.layer_n)?;
-        if self.a_adapters.is_left() || scalings.dims3()?.1 != 1 {
+        // The stacked adapters are scaled once for the whole batch, so they need a single row of scalings.
+        let (b_size, seq_len, _) = scalings.dims3()?;
+        if self.a_adapters.is_left() || b_size != 1 || seq_len != 1 {
             let a_adapters = if self.a_adapters.is_right() {
                 self.a_adapters.as_ref().unwrap_right().1.clone()
             } else {
@@ -239,8 +190,8 @@ impl LinearLayerLike for QLoraLinear {
             {
                 let mut input_new = apply_scalings_to_x(input.clone(), &scalings, i)?;
 
-                input_new = if let Some(ref dropout) = adapter_dropout {
-                    dropout.forward(&input_new, true)?
+                input_new = if let Some(p) = adapter_dropout {
+                    Dropout::new(*p).forward(&input_new, true)?
                 } else {
                     input_new.clone()
                 };
@@ -266,8 +217,8 @@ impl LinearLayerLike for QLoraLinear {
                 .broadcast_mul(&scalings)?
                 .mul(global_scaling_weight)?;
 
-            let input = if let Some(ref d) = dropout {
-                d.forward(input, true)?
+            let input = if let Some(p) = dropout {
+                Dropout::new(*p).forward(input, true)?
             } else {
                 input.clone()
             };
@@ -281,4 +232,55 @@ impl LinearLayerLike for QLoraLinear {
             out + result
         }
     }
+    fn load_adapter(&mut self, config: &LoraConfig, vb: &VarBuilder) -> Result<()> {
+        // A layer which the adapters do not target has no adapter weights.
+        if self.layer_n == usize::MAX {
+            return Ok(());
+        }
+        if self.merged {
+            candle_core::bail!("Adapters cannot be loaded into a layer whose adapters are merged.");
+        }
+        let (a, b) = load_adapter_weights(&self.linear_config, config, vb, &self.prefix)?;
+        let a = Linear::new(a.weight().to_dtype(DType::F32)?, None);
+        let b = Linear::new(b.weight().to_dtype(DType::F32)?, None);
+        let mut a_adapters = adapter_list(&self.a_adapters).to_vec();
+        let mut b_adapters = adapter_list(&self.b_adapters).to_vec();
+        let mut scale_adapters = self.scale_adapters.clone();
+        let mut dropout_adapters = self.dropout_adapters.clone();
+        a_adapters.push(a);
+        b_adapters.push(b);
+        scale_adapters.push(config.scale());
+        dropout_adapters.push(config.dropout);
+        (self.a_adapters, self.b_adapters) =
+            stack_adapters(a_adapters, b_adapters, &scale_adapters, &dropout_adapters)?;
+        self.scale_adapters = scale_adapters;
+        self.dropout_adapters = dropout_adapters;
+        Ok(())
+    }
+    fn unload_adapter(&mut self, adapter: usize) -> Result<()> {
+        if self.layer_n == usize::MAX {
+            return Ok(());
+        }
+        if self.merged {
+            candle_core::bail!(
+                "Adapters cannot be unloaded from a layer whose adapters are merged."
+            );
+        }
+        if adapter >= self.scale_adapters.len() {
+            candle_core::bail!("There is no adapter {adapter} to unload.");
+        }
+        let mut a_adapters = adapter_list(&self.a_adapters).to_vec();
+        let mut b_adapters = adapter_list(&self.b_adapters).to_vec();
+        let mut scale_adapters = self.scale_adapters.clone();
+        let mut dropout_adapters = self.dropout_adapters.clone();
+        a_adapters.remove(adapter);
+        b_adapters.remove(adapter);
+        scale_adapters.remove(adapter);
+        dropout_adapters.remove(adapter);
+        (self.a_adapters, self.b_adapters) =
+            stack_adapters(a_adapters, b_adapters, &scale_adapters, &dropout_adapters)?;
+        self.scale_adapters = scale_adapters;
+        self.dropout_adapters = dropout_adapters;
+        Ok(())
+    }
 }
@@ -9,7 +9,8 @@ use std::{
 };
 
 use ::mistralrs::{
-    Constraint, MistralRs, Request as _Request, RequestType, Response, SamplingParams, StopTokens,
+    Constraint, DryParams, Mirostat, MistralRs, NormalRequest, Request as _Request, RequestType,
+    Response, SamplingParams, StopTokens,
 };
 use candle_core::Device;
 use loaders::{
@@ -114,14 +115,38 @@ impl Runner {
                     ));
                 }
                 Constraint::Yacc(request.grammar.as_ref().unwrap().clone())
+            } else if request.grammar_type == Some("json_schema".to_string()) {
+                if request.grammar.is_none() {
+                    return Err(PyValueError::new_err(
+                        "Grammar type is specified but not grammar text",
+                    ));
+                }
+                Constraint::JsonSchema(
+                    serde_json::from_str(request.grammar.as_ref().unwrap())
+                        .map_err(|e| PyValueError::new_err(e.to_string()))?,
+                )
+            } else if request.grammar_type == Some("gbnf".to_string()) {
+                if request.grammar.is_none() {
+                    return Err(PyValueError::new_err(
+                        "Grammar type is specified but not grammar text",
+                    ));
+                }
+                Constraint::Gbnf(request.grammar.as_ref().unwrap().clone())
+            } else if request.grammar_type == Some("lark".to_string()) {
+                if request.grammar.is_none() {
+                    return Err(PyValueError::new_err(
+                        "Grammar type is specified but not grammar text",
+                    ));
+                }
+                Constraint::Lark(request.grammar.as_ref().unwrap().clone())
             } else if request.grammar_type.is_some() {
                 return Err(PyValueError::new_err(
-                    "Grammar type is specified but is not `regex` or `yacc`",
+                    "Grammar type is specified but is not `regex`, `yacc`, `json_schema`, `gbnf` or `lark`",
                 ));
             } else {
                 Constraint::None
             };
-            let model_request = _Request {
+            let model_request = NormalRequest {
                 id: {
                     let l = NEXT_REQUEST_ID.lock().unwrap();
                     let last = &mut *l.borrow_mut();
@@ -134,13 +159,22 @@ impl Runner {
                     temperature: request.temperature,
                     top_k: request.top_k,
                     top_p: request.top_p,
+                    min_p: request.min_p,
+                    typical_p: request.typical_p,
+                    tfs_z: request.tfs_z,
+                    mirostat: request.mirostat,
                     top_n_logprobs: request.top_logprobs.unwrap_or(1),
                     frequency_penalty: request.frequency_penalty,
                     presence_penalty: request.presence_penalty,
+                    repetition_penalty: request.repetition_penalty,
+                    no_repeat_ngram_size: request.no_repeat_ngram_size,
+                    dry_params: request.dry_params.clone(),
                     max_len: request.max_tokens,
                     stop_toks,
                     logits_bias: request.logit_bias.clone(),
                     n_choices: request.n_choices,
+                    beam_search: None,
+                    seed: request.seed,
                 },
                 response: tx,
                 return_logprobs: request.logprobs,
@@ -149,11 +183,17 @@ impl Runner {
                 request_type: RequestType::Chat,
                 suffix: None,
                 best_of: None,
+                priority: 0,
+                tenant: None,
+                timeout: None,
+                tools: None,
+                tool_choice: None,
+                adapters: None,
             };
 
             MistralRs::maybe_log_request(self.runner.clone(), format!("{request:?}"));
             let sender = self.runner.get_sender();
-            sender.send(model_request).unwrap();
+            sender.send(_Request::Normal(model_request)).unwrap();
             let response = rx.recv().unwrap();
 
             match response {
@@ -168,6 +208,8 @@ impl Runner {
                 Response::Chunk(_) => unreachable!(),
                 Response::CompletionDone(_) => unreachable!(),
                 Response::CompletionModelError(_, _) => unreachable!(),
+                Response::CompletionChunk(_) => unreachable!(),
+                Response::Embeddings(_) => unreachable!(),
             }
         })
     }
@@ -195,14 +237,38 @@ impl Runner {
                     ));
                 }
                 Constraint::Yacc(request.grammar.as_ref().unwrap().clone())
+            } else if request.grammar_type == Some("json_schema".to_string()) {
+                if request.grammar.is_none() {
+                    return Err(PyValueError::new_err(
+                        "Grammar type is specified but not grammar text",
+                    ));
+                }
+                Constraint::JsonSchema(
+                    serde_json::from_str(request.grammar.as_ref().unwrap())
+                        .map_err(|e| PyValueError::new_err(e.to_string()))?,
+                )
+            } else if request.grammar_type == Some("gbnf".to_string()) {
+                if request.grammar.is_none() {
+                    return Err(PyValueError::new_err(
+                        "Grammar type is specified but not grammar text",
+                    ));
+                }
+                Constraint::Gbnf(request.grammar.as_ref().unwrap().clone())
+            } else if request.grammar_type == Some("lark".to_string()) {
+                if request.grammar.is_none() {
+                    return Err(PyValueError::new_err(
+                        "Grammar type is specified but not grammar text",
+                    ));
+                }
+                Constraint::Lark(request.grammar.as_ref().unwrap().clone())
             } else if request.grammar_type.is_some() {
                 return Err(PyValueError::new_err(
-                    "Grammar type is specified but is not `regex` or `yacc`",
+                    "Grammar type is specified but is not `regex`, `yacc`, `json_schema`, `gbnf` or `lark`",
                 ));
             } else {
                 Constraint::None
             };
-            let model_request = _Request {
+            let model_request = NormalRequest {
                 id: {
                     let l = NEXT_REQUEST_ID.lock().unwrap();
                     let last = &mut *l.borrow_mut();
@@ -215,13 +281,22 @@ impl Runner {
                     temperature: request.temperature,
                     top_k: request.top_k,
                     top_p: request.top_p,
+                    min_p: request.min_p,
+                    typical_p: request.typical_p,
+                    tfs_z: request.tfs_z,
+                    mirostat: request.mirostat,
                     top_n_logprobs: 1,
                     frequency_penalty: request.frequency_penalty,
                     presence_penalty: request.presence_penalty,
+                    repetition_penalty: request.repetition_penalty,
+                    no_repeat_ngram_size: request.no_repeat_ngram_size,
+                    dry_params: request.dry_params.clone(),
                     max_len: request.max_tokens,
                     stop_toks,
                     logits_bias: request.logit_bias.clone(),
                     n_choices: request.n_choices,
+                    beam_search: None,
+                    seed: request.seed,
                 },
                 response: tx,
                 return_logprobs: false,
@@ -232,11 +307,17 @@ impl Runner {
                 },
                 suffix: request.suffix.clone(),
                 best_of: Some(request.best_of),
+                priority: 0,
+                tenant: None,
+                timeout: None,
+                tools: None,
+                tool_choice: None,
+                adapters: None,
             };
 
             MistralRs::maybe_log_request(self.runner.clone(), format!("{request:?}"));
             let sender = self.runner.get_sender();
-            sender.send(model_request).unwrap();
+            sender.send(_Request::Normal(model_request)).unwrap();
             let response = rx.recv().unwrap();
 
             match response {
@@ -251,11 +332,26 @@ impl Runner {
                 Response::Chunk(_) => unreachable!(),
                 Response::CompletionDone(_) => unreachable!(),
                 Response::CompletionModelError(_, _) => unreachable!(),
+                Response::CompletionChunk(_) => unreachable!(),
+                Response::Embeddings(_) => unreachable!(),
             }
         })
     }
 }
 
+/// Mirostat from the `mirostat` mode of llama.cpp: 0 disables it, and 1 and 2 are the versions.
+fn parse_mirostat(
+    mode: Option<u8>,
+    tau: Option<f32>,
+    eta: Option<f32>,
+) -> PyResult<Option<Mirostat>> {
+    match mode {
+        Some(mode) if mode > 2 => Err(PyValueError::new_err("`mirostat` must be 0, 1 or 2.")),
+        Some(mode) => Ok(Mirostat::from_mode(mode, tau, eta)),
+        None => Ok(None),
+    }
+}
+
 #[pyclass]
 #[derive(Debug)]
 /// An OpenAI API compatible completion request.
@@ -274,6 +370,14 @@ struct CompletionRequest {
     top_p: Option<f64>,
     suffix: Option<String>,
     top_k: Option<usize>,
+    min_p: Option<f64>,
+    typical_p: Option<f64>,
+    tfs_z: Option<f64>,
+    mirostat: Option<Mirostat>,
+    repetition_penalty: Option<f32>,
+    no_repeat_ngram_size: Option<usize>,
+    dry_params: Option<DryParams>,
+    seed: Option<u64>,
     grammar: Option<String>,
     grammar_type: Option<String>,
 }
@@ -281,7 +385,7 @@ struct CompletionRequest {
 #[pymethods]
 impl CompletionRequest {
     #[new]
-    #[pyo3(signature = (prompt, model, best_of = 1, echo_prompt = false, presence_penalty=None,frequency_penalty=None,logit_bias=None,max_tokens=None,n_choices=1,stop_seqs=None,temperature=None,top_p=None,suffix=None,top_k=None, grammar = None, grammar_type = None))]
+    #[pyo3(signature = (prompt, model, best_of = 1, echo_prompt = false, presence_penalty=None,frequency_penalty=None,logit_bias=None,max_tokens=None,n_choices=1,stop_seqs=None,temperature=None,top_p=None,suffix=None,top_k=None, grammar = None, grammar_type = None, min_p = None, typical_p = None, tfs_z = None, mirostat = None, mirostat_tau = None, mirostat_eta = None, repetition_penalty = None, no_repeat_ngram_size = None, dry_multiplier = None, dry_base = None, dry_allowed_length = None, dry_sequence_breakers = None, seed = None))]
     #[allow(clippy::too_many_arguments)]
     fn new(
         prompt: String,
@@ -300,7 +404,29 @@ impl CompletionRequest {
         top_k: Option<usize>,
         grammar: Option<String>,
         grammar_type: Option<String>,
+        min_p: Option<f64>,
+        typical_p: Option<f64>,
+        tfs_z: Option<f64>,
+        mirostat: Option<u8>,
+        mirostat_tau: Option<f32>,
+        mirostat_eta: Option<f32>,
+        repetition_penalty: Option<f32>,
+        no_repeat_ngram_size: Option<usize>,
+        dry_multiplier: Option<f32>,
+        dry_base: Option<f32>,
+        dry_allowed_length: Option<usize>,
+        dry_sequence_breakers: Option<Vec<String>>,
+        seed: Option<u64>,
     ) -> PyResult<Self> {
+        let mirostat = parse_mirostat(mirostat, mirostat_tau, mirostat_eta)?;
+        let dry_params = dry_multiplier.map(|multiplier| {
+            DryParams::new(
+                multiplier,
+                dry_base,
+                dry_allowed_length,
+                dry_sequence_breakers,
+            )
+        });
         Ok(Self {
             prompt,
             best_of,
@@ -316,6 +442,14 @@ impl CompletionRequest {
             temperature,
             top_p,
             top_k,
+            min_p,
+            typical_p,
+            tfs_z,
+            mirostat,
+            repetition_penalty,
+            no_repeat_ngram_size,
+            dry_params,
+            seed,
             grammar,
             grammar_type,
         })
@@ -340,6 +474,14 @@ struct ChatCompletionRequest {
     top_p: Option<f64>,
     stream: bool,
     top_k: Option<usize>,
+    min_p: Option<f64>,
+    typical_p: Option<f64>,
+    tfs_z: Option<f64>,
+    mirostat: Option<Mirostat>,
+    repetition_penalty: Option<f32>,
+    no_repeat_ngram_size: Option<usize>,
+    dry_params: Option<DryParams>,
+    seed: Option<u64>,
     grammar: Option<String>,
     grammar_type: Option<String>,
 }
@@ -347,7 +489,7 @@ struct ChatCompletionRequest {
 #[pymethods]
 impl ChatCompletionRequest {
     #[new]
-    #[pyo3(signature = (messages, model, logprobs = false, n_choices = 1, logit_bias = None, top_logprobs = None, max_tokens = None, presence_penalty = None, frequency_penalty = None, stop_seqs = None, temperature = None, top_p = None, top_k = None, stream=false, grammar = None, grammar_type = None))]
+    #[pyo3(signature = (messages, model, logprobs = false, n_choices = 1, logit_bias = None, top_logprobs = None, max_tokens = None, presence_penalty = None, frequency_penalty = None, stop_seqs = None, temperature = None, top_p = None, top_k = None, stream=false, grammar = None, grammar_type = None, min_p = None, typical_p = None, tfs_z = None, mirostat = None, mirostat_tau = None, mirostat_eta = None, repetition_penalty = None, no_repeat_ngram_size = None, dry_multiplier = None, dry_base = None, dry_allowed_length = None, dry_sequence_breakers = None, seed = None))]
     #[allow(clippy::too_many_arguments)]
     fn new(
         messages: Py<PyAny>,
@@ -366,7 +508,29 @@ impl ChatCompletionRequest {
         stream: Option<bool>,
         grammar: Option<String>,
         grammar_type: Option<String>,
+        min_p: Option<f64>,
+        typical_p: Option<f64>,
+        tfs_z: Option<f64>,
+        mirostat: Option<u8>,
+        mirostat_tau: Option<f32>,
+        mirostat_eta: Option<f32>,
+        repetition_penalty: Option<f32>,
+        no_repeat_ngram_size: Option<usize>,
+        dry_multiplier: Option<f32>,
+        dry_base: Option<f32>,
+        dry_allowed_length: Option<usize>,
+        dry_sequence_breakers: Option<Vec<String>>,
+        seed: Option<u64>,
     ) -> PyResult<Self> {
+        let mirostat = parse_mirostat(mirostat, mirostat_tau, mirostat_eta)?;
+        let dry_params = dry_multiplier.map(|multiplier| {
+            DryParams::new(
+                multiplier,
+                dry_base,
+                dry_allowed_length,
+                dry_sequence_breakers,
+            )
+        });
         let messages = Python::with_gil(|py| {
             if let Ok(messages) = messages.bind(py).downcast_exact::<PyList>() {
                 let mut messages_vec = Vec::new();
@@ -413,6 +577,14 @@ impl ChatCompletionRequest {
             top_p,
             top_k,
             stream: stream.unwrap_or(false),
+            min_p,
+            typical_p,
+            tfs_z,
+            mirostat,
+            repetition_penalty,
+            no_repeat_ngram_size,
+            dry_params,
+            seed,
             grammar,
             grammar_type,
         })
@@ -3,7 +3,7 @@ use std::fs::File;
 use candle_core::DType as _DType;
 use mistralrs::{
     GemmaLoader as _GemmaLoader, GemmaSpecificConfig, Loader, MistralRs, ModelKind as _ModelKind,
-    SchedulerMethod, TokenSource,
+    QueuePolicy, SchedulerMethod, TokenSource,
 };
 use pyo3::{exceptions::PyValueError, prelude::*};
 
@@ -213,10 +213,15 @@ impl GemmaLoader {
         let mistralrs = MistralRs::new(
             pipeline,
             SchedulerMethod::Fixed(maxseqs.try_into().unwrap()),
+            QueuePolicy::default(),
             logfile,
             truncate_sequence,
             self.no_kv_cache,
             prefix_cache_n,
+            None,
+            None,
+            None,
+            None,
         );
 
         Ok(Runner { runner: mistralrs })
@@ -1,7 +1,7 @@
 use candle_core::DType as _DType;
 use mistralrs::{
     LlamaLoader as _LlamaLoader, LlamaSpecificConfig, Loader, MistralRs, ModelKind as _ModelKind,
-    SchedulerMethod, TokenSource,
+    QueuePolicy, SchedulerMethod, TokenSource,
 };
 use pyo3::{exceptions::PyValueError, prelude::*};
 use std::fs::File;
@@ -217,10 +217,15 @@ impl LlamaLoader {
         let mistralrs = MistralRs::new(
             pipeline,
             SchedulerMethod::Fixed(maxseqs.try_into().unwrap()),
+            QueuePolicy::default(),
             logfile,
             truncate_sequence,
             self.no_kv_cache,
             prefix_cache_n,
+            None,
+            None,
+            None,
+            None,
         );
 
         Ok(Runner { runner: mistralrs })
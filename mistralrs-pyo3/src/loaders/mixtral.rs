@@ -1,7 +1,7 @@
 use candle_core::DType as _DType;
 use mistralrs::{
     Loader, MistralRs, MixtralLoader as _MixtralLoader, MixtralSpecificConfig,
-    ModelKind as _ModelKind, SchedulerMethod, TokenSource,
+    ModelKind as _ModelKind, QueuePolicy, SchedulerMethod, TokenSource,
 };
 use pyo3::{exceptions::PyValueError, prelude::*};
 use std::fs::File;
@@ -216,10 +216,15 @@ impl MixtralLoader {
         let mistralrs = MistralRs::new(
             pipeline,
             SchedulerMethod::Fixed(maxseqs.try_into().unwrap()),
+            QueuePolicy::default(),
             logfile,
             truncate_sequence,
             self.no_kv_cache,
             prefix_cache_n,
+            None,
+            None,
+            None,
+            None,
         );
 
         Ok(Runner { runner: mistralrs })
@@ -0,0 +1,134 @@
+use std::{error::Error, path::PathBuf, sync::mpsc::channel, sync::Arc};
+
+use crate::{
+    openai::{AdapterList, AdapterListRequest, AdapterLoadRequest, AdapterUnloadRequest},
+    registry::{ModelRegistry, RegistryError},
+};
+use axum::{
+    extract::{Json, Query, State},
+    http::{self, StatusCode},
+    response::IntoResponse,
+};
+use mistralrs_core::{AdapterAction, AdapterRequest, Request};
+use serde::Serialize;
+
+pub enum AdapterResponder {
+    Json(AdapterList),
+    InternalError(Box<dyn Error>),
+    ValidationError(Box<dyn Error>),
+}
+
+trait ErrorToResponse: Serialize {
+    fn to_response(&self, code: StatusCode) -> axum::response::Response {
+        let mut r = Json(self).into_response();
+        *r.status_mut() = code;
+        r
+    }
+}
+
+#[derive(Serialize)]
+struct JsonError {
+    message: String,
+}
+
+impl JsonError {
+    fn new(message: String) -> Self {
+        Self { message }
+    }
+}
+impl ErrorToResponse for JsonError {}
+
+impl IntoResponse for AdapterResponder {
+    fn into_response(self) -> axum::response::Response {
+        match self {
+            AdapterResponder::Json(s) => Json(s).into_response(),
+            AdapterResponder::InternalError(e) => {
+                JsonError::new(e.to_string()).to_response(http::StatusCode::INTERNAL_SERVER_ERROR)
+            }
+            AdapterResponder::ValidationError(e) => {
+                JsonError::new(e.to_string()).to_response(http::StatusCode::UNPROCESSABLE_ENTITY)
+            }
+        }
+    }
+}
+
+/// Send an adapter action to the engine of the model, responding with the adapters which are loaded afterwards.
+async fn manage_adapters(
+    registry: &ModelRegistry,
+    model: Option<&str>,
+    action: AdapterAction,
+) -> AdapterResponder {
+    let state = match registry.get(model).await {
+        Ok(state) => state,
+        Err(RegistryError::UnknownModel(e)) => return AdapterResponder::ValidationError(e.into()),
+        Err(e @ RegistryError::Load(_)) => return AdapterResponder::InternalError(e.into()),
+    };
+    let (tx, rx) = channel();
+    state
+        .get_sender()
+        .send(Request::Adapter(AdapterRequest {
+            action,
+            response: tx,
+        }))
+        .unwrap();
+
+    match rx.recv().unwrap() {
+        Ok(adapters) => AdapterResponder::Json(AdapterList { adapters }),
+        Err(e) => AdapterResponder::ValidationError(e.into()),
+    }
+}
+
+#[utoipa::path(
+    get,
+    tag = "Mistral.rs",
+    path = "/v1/adapters",
+    params(("model" = Option<String>, Query, description = "Only needed if the server serves several models.")),
+    responses((status = 200, description = "The loaded LoRA adapters", body = AdapterList))
+)]
+pub async fn list_adapters(
+    State(registry): State<Arc<ModelRegistry>>,
+    Query(request): Query<AdapterListRequest>,
+) -> AdapterResponder {
+    manage_adapters(&registry, request.model.as_deref(), AdapterAction::List).await
+}
+
+#[utoipa::path(
+    post,
+    tag = "Mistral.rs",
+    path = "/v1/adapters/load",
+    request_body = AdapterLoadRequest,
+    responses((status = 200, description = "The loaded LoRA adapters", body = AdapterList))
+)]
+pub async fn load_adapter(
+    State(registry): State<Arc<ModelRegistry>>,
+    Json(request): Json<AdapterLoadRequest>,
+) -> AdapterResponder {
+    manage_adapters(
+        &registry,
+        request.model.as_deref(),
+        AdapterAction::Load {
+            name: request.name,
+            path: PathBuf::from(request.path),
+        },
+    )
+    .await
+}
+
+#[utoipa::path(
+    post,
+    tag = "Mistral.rs",
+    path = "/v1/adapters/unload",
+    request_body = AdapterUnloadRequest,
+    responses((status = 200, description = "The loaded LoRA adapters", body = AdapterList))
+)]
+pub async fn unload_adapter(
+    State(registry): State<Arc<ModelRegistry>>,
+    Json(request): Json<AdapterUnloadRequest>,
+) -> AdapterResponder {
+    manage_adapters(
+        &registry,
+        request.model.as_deref(),
+        AdapterAction::Unload { name: request.name },
+    )
+    .await
+}
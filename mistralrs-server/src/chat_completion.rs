@@ -10,7 +10,10 @@ use std::{
     time::Duration,
 };
 
-use crate::openai::{ChatCompletionRequest, Grammar, StopTokens};
+use crate::openai::{
+    ChatCompletionRequest, Grammar, Message, ResponseFormat, StopTokens, ToolChoice, ToolChoiceMode,
+};
+use crate::registry::{ModelRegistry, RegistryError};
 use anyhow::Result;
 use axum::{
     extract::{Json, State},
@@ -23,8 +26,11 @@ use axum::{
 use either::Either;
 use indexmap::IndexMap;
 use mistralrs_core::{
-    ChatCompletionResponse, Constraint, MistralRs, Request, RequestType, Response, SamplingParams,
-    StopTokens as InternalStopTokens,
+    format_tool_calls, BeamSearchParams, CalledFunction as InternalCalledFunction,
+    ChatCompletionResponse, Constraint, DryParams, Function as InternalFunction, Mirostat,
+    MistralRs, NormalRequest, Request, RequestType, Response, SamplingParams,
+    StopTokens as InternalStopTokens, Tool as InternalTool, ToolChoice as InternalToolChoice,
+    ToolType as InternalToolType,
 };
 use serde::Serialize;
 
@@ -40,6 +46,21 @@ pub struct Streamer {
     rx: Receiver<Response>,
     is_done: bool,
     state: Arc<MistralRs>,
+    request_id: usize,
+}
+
+impl Drop for Streamer {
+    /// The stream is dropped early if the client disconnects. Then abort the request so that the engine
+    /// stops generating for it.
+    fn drop(&mut self) {
+        if !self.is_done {
+            // Ignore the error: if the engine is gone, there is nothing to abort.
+            let _ = self
+                .state
+                .get_sender()
+                .send(Request::Abort(self.request_id));
+        }
+    }
 }
 
 impl futures::Stream for Streamer {
@@ -75,6 +96,8 @@ impl futures::Stream for Streamer {
                 Response::Done(_) => unreachable!(),
                 Response::CompletionDone(_) => unreachable!(),
                 Response::CompletionModelError(_, _) => unreachable!(),
+                Response::CompletionChunk(_) => unreachable!(),
+                Response::Embeddings(_) => unreachable!(),
             },
             Err(_) => Poll::Pending,
         }
@@ -145,11 +168,36 @@ impl IntoResponse for ChatCompletionResponder {
     }
 }
 
+/// Convert messages into the maps which the chat template is rendered with.
+pub(crate) fn messages_to_maps(messages: Vec<Message>) -> Vec<IndexMap<String, String>> {
+    let mut maps = Vec::new();
+    for message in messages {
+        // The tool calls of the assistant are given to the model the way it writes them.
+        let content = match message.tool_calls {
+            Some(tool_calls) => format_tool_calls(
+                &tool_calls
+                    .into_iter()
+                    .map(|call| InternalCalledFunction {
+                        name: call.function.name,
+                        arguments: call.function.arguments,
+                    })
+                    .collect::<Vec<_>>(),
+            ),
+            None => message.content.unwrap_or_default(),
+        };
+        let mut message_map = IndexMap::new();
+        message_map.insert("role".to_string(), message.role);
+        message_map.insert("content".to_string(), content);
+        maps.push(message_map);
+    }
+    maps
+}
+
 fn parse_request(
     oairequest: ChatCompletionRequest,
     state: Arc<MistralRs>,
     tx: Sender<Response>,
-) -> Request {
+) -> NormalRequest {
     let repr = serde_json::to_string(&oairequest).unwrap();
     MistralRs::maybe_log_request(state.clone(), repr);
 
@@ -159,44 +207,91 @@ fn parse_request(
         None => None,
     };
     let messages = match oairequest.messages {
-        Either::Left(req_messages) => {
-            let mut messages = Vec::new();
-            for message in req_messages {
-                let mut message_map = IndexMap::new();
-                message_map.insert("role".to_string(), message.role);
-                message_map.insert("content".to_string(), message.content);
-                messages.push(message_map);
-            }
-            Either::Left(messages)
-        }
+        Either::Left(req_messages) => Either::Left(messages_to_maps(req_messages)),
         Either::Right(prompt) => Either::Right(prompt),
     };
 
-    Request {
+    NormalRequest {
         id: state.next_request_id(),
         messages,
         sampling_params: SamplingParams {
             temperature: oairequest.temperature,
             top_k: oairequest.top_k,
             top_p: oairequest.top_p,
+            min_p: oairequest.min_p,
+            typical_p: oairequest.typical_p,
+            tfs_z: oairequest.tfs_z,
+            mirostat: oairequest.mirostat.and_then(|mode| {
+                Mirostat::from_mode(mode, oairequest.mirostat_tau, oairequest.mirostat_eta)
+            }),
             top_n_logprobs: oairequest.top_logprobs.unwrap_or(1),
             frequency_penalty: oairequest.frequency_penalty,
             presence_penalty: oairequest.presence_penalty,
+            repetition_penalty: oairequest.repetition_penalty,
+            no_repeat_ngram_size: oairequest.no_repeat_ngram_size,
+            dry_params: oairequest.dry_multiplier.map(|multiplier| {
+                DryParams::new(
+                    multiplier,
+                    oairequest.dry_base,
+                    oairequest.dry_allowed_length,
+                    oairequest.dry_sequence_breakers,
+                )
+            }),
             max_len: oairequest.max_tokens,
             stop_toks,
             logits_bias: oairequest.logit_bias,
             n_choices: oairequest.n_choices,
+            beam_search: oairequest.beam_width.map(|beam_width| BeamSearchParams {
+                beam_width,
+                length_penalty: oairequest.length_penalty.unwrap_or(1.),
+                early_stopping: oairequest.early_stopping.unwrap_or(false),
+            }),
+            seed: oairequest.seed,
         },
         response: tx,
         return_logprobs: oairequest.logprobs,
         is_streaming: oairequest.stream.unwrap_or(false),
         suffix: None,
         best_of: None,
+        priority: oairequest.priority.unwrap_or(0),
+        tenant: oairequest.tenant,
+        timeout: oairequest.timeout_ms.map(Duration::from_millis),
+        tools: oairequest.tools.map(|tools| {
+            tools
+                .into_iter()
+                .map(|tool| InternalTool {
+                    tp: InternalToolType::Function,
+                    function: InternalFunction {
+                        name: tool.function.name,
+                        description: tool.function.description,
+                        parameters: tool.function.parameters,
+                    },
+                })
+                .collect()
+        }),
+        tool_choice: oairequest.tool_choice.map(|choice| match choice {
+            ToolChoice::Mode(ToolChoiceMode::None) => InternalToolChoice::None,
+            ToolChoice::Mode(ToolChoiceMode::Auto) => InternalToolChoice::Auto,
+            ToolChoice::Mode(ToolChoiceMode::Required) => InternalToolChoice::Required,
+            ToolChoice::Function(named) => InternalToolChoice::Function(named.function.name),
+        }),
+        adapters: oairequest.adapters,
 
-        constraint: match oairequest.grammar {
-            Some(Grammar::Yacc(yacc)) => Constraint::Yacc(yacc),
-            Some(Grammar::Regex(regex)) => Constraint::Regex(regex),
-            None => Constraint::None,
+        constraint: match (oairequest.grammar, oairequest.response_format) {
+            (Some(Grammar::Yacc(yacc)), _) => Constraint::Yacc(yacc),
+            (Some(Grammar::Regex(regex)), _) => Constraint::Regex(regex),
+            (Some(Grammar::JsonSchema(schema)), _) => Constraint::JsonSchema(schema),
+            (Some(Grammar::Gbnf(gbnf)), _) => Constraint::Gbnf(gbnf),
+            (Some(Grammar::Lark(lark)), _) => Constraint::Lark(lark),
+            (None, Some(ResponseFormat::JsonObject)) => {
+                Constraint::JsonSchema(serde_json::json!({ "type": "object" }))
+            }
+            (None, Some(ResponseFormat::JsonSchema { json_schema })) => Constraint::JsonSchema(
+                json_schema
+                    .schema
+                    .unwrap_or_else(|| serde_json::json!({ "type": "object" })),
+            ),
+            (None, Some(ResponseFormat::Text) | None) => Constraint::None,
         },
 
         request_type: RequestType::Chat,
@@ -211,20 +306,42 @@ fn parse_request(
     responses((status = 200, description = "Chat completions"))
 )]
 pub async fn chatcompletions(
-    State(state): State<Arc<MistralRs>>,
+    State(registry): State<Arc<ModelRegistry>>,
     Json(oairequest): Json<ChatCompletionRequest>,
 ) -> ChatCompletionResponder {
+    let state = match registry.get(Some(&oairequest.model)).await {
+        Ok(state) => state,
+        Err(RegistryError::UnknownModel(e)) => {
+            return ChatCompletionResponder::ValidationError(e.into())
+        }
+        Err(e @ RegistryError::Load(_)) => return ChatCompletionResponder::InternalError(e.into()),
+    };
+    if oairequest.grammar.is_some()
+        && matches!(
+            oairequest.response_format,
+            Some(ResponseFormat::JsonObject | ResponseFormat::JsonSchema { .. })
+        )
+    {
+        return ChatCompletionResponder::ValidationError(
+            "A grammar and a JSON `response_format` cannot be used together.".into(),
+        );
+    }
+    if oairequest.mirostat.is_some_and(|mode| mode > 2) {
+        return ChatCompletionResponder::ValidationError("`mirostat` must be 0, 1 or 2.".into());
+    }
     let (tx, rx) = channel();
     let request = parse_request(oairequest, state.clone(), tx);
     let is_streaming = request.is_streaming;
+    let request_id = request.id;
     let sender = state.get_sender();
-    sender.send(request).unwrap();
+    sender.send(Request::Normal(request)).unwrap();
 
     if is_streaming {
         let streamer = Streamer {
             rx,
             is_done: false,
             state,
+            request_id,
         };
 
         ChatCompletionResponder::Sse(
@@ -239,7 +356,12 @@ pub async fn chatcompletions(
             ),
         )
     } else {
-        let response = rx.recv().unwrap();
+        // The channel closes without a response if the request is dropped by the engine.
+        let Ok(response) = rx.recv() else {
+            return ChatCompletionResponder::InternalError(
+                "The request was dropped before it finished.".into(),
+            );
+        };
 
         match response {
             Response::InternalError(e) => {
@@ -259,6 +381,8 @@ pub async fn chatcompletions(
             Response::Chunk(_) => unreachable!(),
             Response::CompletionDone(_) => unreachable!(),
             Response::CompletionModelError(_, _) => unreachable!(),
+            Response::CompletionChunk(_) => unreachable!(),
+            Response::Embeddings(_) => unreachable!(),
         }
     }
 }
@@ -1,24 +1,32 @@
 use std::{
+    env,
     error::Error,
+    pin::Pin,
     sync::{
-        mpsc::{channel, Sender},
+        mpsc::{channel, Receiver, Sender},
         Arc,
     },
+    task::{Context, Poll},
+    time::Duration,
 };
 
 use crate::openai::{CompletionRequest, Grammar, StopTokens};
+use crate::registry::{ModelRegistry, RegistryError};
 use axum::{
     extract::{Json, State},
     http::{self, StatusCode},
-    response::IntoResponse,
+    response::{
+        sse::{Event, KeepAlive},
+        IntoResponse, Sse,
+    },
 };
 use either::Either;
 use mistralrs_core::{
-    CompletionResponse, Constraint, MistralRs, Request, RequestType, Response, SamplingParams,
+    BeamSearchParams, CompletionResponse, Constraint, DryParams, Mirostat, MistralRs,
+    NormalRequest, Request, RequestType, Response, SamplingParams,
     StopTokens as InternalStopTokens,
 };
 use serde::Serialize;
-use tracing::warn;
 
 #[derive(Debug)]
 struct ModelErrorMessage(String);
@@ -28,7 +36,75 @@ impl std::fmt::Display for ModelErrorMessage {
     }
 }
 impl std::error::Error for ModelErrorMessage {}
+pub struct CompletionStreamer {
+    rx: Receiver<Response>,
+    is_done: bool,
+    state: Arc<MistralRs>,
+    request_id: usize,
+}
+
+impl Drop for CompletionStreamer {
+    /// The stream is dropped early if the client disconnects. Then abort the request so that the engine
+    /// stops generating for it.
+    fn drop(&mut self) {
+        if !self.is_done {
+            // Ignore the error: if the engine is gone, there is nothing to abort.
+            let _ = self
+                .state
+                .get_sender()
+                .send(Request::Abort(self.request_id));
+        }
+    }
+}
+
+impl futures::Stream for CompletionStreamer {
+    type Item = Result<Event, axum::Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if self.is_done {
+            return Poll::Ready(None);
+        }
+        match self.rx.try_recv() {
+            Ok(resp) => match resp {
+                // An error ends the request, so the stream ends after it.
+                Response::CompletionModelError(msg, _) => {
+                    self.is_done = true;
+                    MistralRs::maybe_log_error(
+                        self.state.clone(),
+                        &ModelErrorMessage(msg.to_string()),
+                    );
+                    Poll::Ready(Some(Ok(Event::default().data(msg))))
+                }
+                Response::ValidationError(e) => {
+                    self.is_done = true;
+                    Poll::Ready(Some(Ok(Event::default().data(e.to_string()))))
+                }
+                Response::InternalError(e) => {
+                    self.is_done = true;
+                    MistralRs::maybe_log_error(self.state.clone(), &*e);
+                    Poll::Ready(Some(Ok(Event::default().data(e.to_string()))))
+                }
+                Response::CompletionChunk(response) => {
+                    // The final chunk carries the usage.
+                    if response.usage.is_some() {
+                        self.is_done = true;
+                    }
+                    MistralRs::maybe_log_response(self.state.clone(), &response);
+                    Poll::Ready(Some(Event::default().json_data(response)))
+                }
+                Response::CompletionDone(_) => unreachable!(),
+                Response::Chunk(_) => unreachable!(),
+                Response::Done(_) => unreachable!(),
+                Response::ModelError(_, _) => unreachable!(),
+                Response::Embeddings(_) => unreachable!(),
+            },
+            Err(_) => Poll::Pending,
+        }
+    }
+}
+
 pub enum CompletionResponder {
+    Sse(Sse<CompletionStreamer>),
     Json(CompletionResponse),
     ModelError(String, CompletionResponse),
     InternalError(Box<dyn Error>),
@@ -75,6 +151,7 @@ impl ErrorToResponse for JsonModelError {}
 impl IntoResponse for CompletionResponder {
     fn into_response(self) -> axum::response::Response {
         match self {
+            CompletionResponder::Sse(s) => s.into_response(),
             CompletionResponder::Json(s) => Json(s).into_response(),
             CompletionResponder::InternalError(e) => {
                 JsonError::new(e.to_string()).to_response(http::StatusCode::INTERNAL_SERVER_ERROR)
@@ -92,7 +169,7 @@ fn parse_request(
     oairequest: CompletionRequest,
     state: Arc<MistralRs>,
     tx: Sender<Response>,
-) -> Request {
+) -> NormalRequest {
     let repr = serde_json::to_string(&oairequest).unwrap();
     MistralRs::maybe_log_request(state.clone(), repr);
 
@@ -102,43 +179,71 @@ fn parse_request(
         None => None,
     };
 
-    if oairequest.logprobs.is_some() {
-        warn!("Completion requests do not support logprobs.");
-    }
-
-    if oairequest._stream.is_some_and(|x| x) {
-        warn!("Completion requests do not support streaming.");
-    }
-
-    Request {
+    NormalRequest {
         id: state.next_request_id(),
         messages: Either::Right(oairequest.prompt),
         sampling_params: SamplingParams {
             temperature: oairequest.temperature,
             top_k: oairequest.top_k,
             top_p: oairequest.top_p,
-            top_n_logprobs: 1,
+            min_p: oairequest.min_p,
+            typical_p: oairequest.typical_p,
+            tfs_z: oairequest.tfs_z,
+            mirostat: oairequest.mirostat.and_then(|mode| {
+                Mirostat::from_mode(mode, oairequest.mirostat_tau, oairequest.mirostat_eta)
+            }),
+            top_n_logprobs: oairequest.logprobs.unwrap_or(1),
             frequency_penalty: oairequest.frequency_penalty,
             presence_penalty: oairequest.presence_penalty,
+            repetition_penalty: oairequest.repetition_penalty,
+            no_repeat_ngram_size: oairequest.no_repeat_ngram_size,
+            dry_params: oairequest.dry_multiplier.map(|multiplier| {
+                DryParams::new(
+                    multiplier,
+                    oairequest.dry_base,
+                    oairequest.dry_allowed_length,
+                    oairequest.dry_sequence_breakers,
+                )
+            }),
             max_len: oairequest.max_tokens,
             stop_toks,
             logits_bias: oairequest.logit_bias,
             n_choices: oairequest.n_choices,
+            beam_search: oairequest.beam_width.map(|beam_width| BeamSearchParams {
+                beam_width,
+                length_penalty: oairequest.length_penalty.unwrap_or(1.),
+                early_stopping: oairequest.early_stopping.unwrap_or(false),
+            }),
+            seed: oairequest.seed,
         },
         response: tx,
-        return_logprobs: false,
-        is_streaming: false,
+        return_logprobs: oairequest.logprobs.is_some(),
+        is_streaming: oairequest.stream.unwrap_or(false),
         suffix: oairequest.suffix,
         best_of: Some(oairequest.best_of),
+        priority: oairequest.priority.unwrap_or(0),
+        tenant: oairequest.tenant,
+        timeout: oairequest.timeout_ms.map(Duration::from_millis),
+        tools: None,
+        tool_choice: None,
+        adapters: oairequest.adapters,
 
         constraint: match oairequest.grammar {
             Some(Grammar::Yacc(yacc)) => Constraint::Yacc(yacc),
             Some(Grammar::Regex(regex)) => Constraint::Regex(regex),
+            Some(Grammar::JsonSchema(schema)) => Constraint::JsonSchema(schema),
+            Some(Grammar::Gbnf(gbnf)) => Constraint::Gbnf(gbnf),
+            Some(Grammar::Lark(lark)) => Constraint::Lark(lark),
             None => Constraint::None,
         },
 
-        request_type: RequestType::Completion {
-            echo_prompt: oairequest.echo_prompt,
+        // Echoing the prompt without generating anything scores it.
+        request_type: if oairequest.echo_prompt && oairequest.max_tokens == Some(0) {
+            RequestType::Score
+        } else {
+            RequestType::Completion {
+                echo_prompt: oairequest.echo_prompt,
+            }
         },
     }
 }
@@ -151,29 +256,59 @@ fn parse_request(
     responses((status = 200, description = "Completions"))
 )]
 pub async fn completions(
-    State(state): State<Arc<MistralRs>>,
+    State(registry): State<Arc<ModelRegistry>>,
     Json(oairequest): Json<CompletionRequest>,
 ) -> CompletionResponder {
+    let state = match registry.get(Some(&oairequest.model)).await {
+        Ok(state) => state,
+        Err(RegistryError::UnknownModel(e)) => {
+            return CompletionResponder::ValidationError(e.into())
+        }
+        Err(e @ RegistryError::Load(_)) => return CompletionResponder::InternalError(e.into()),
+    };
+    if oairequest.mirostat.is_some_and(|mode| mode > 2) {
+        return CompletionResponder::ValidationError("`mirostat` must be 0, 1 or 2.".into());
+    }
+    if oairequest.stream.unwrap_or(false) && oairequest.best_of > 1 {
+        return CompletionResponder::ValidationError(
+            "Completion requests do not support `best_of` when streaming.".into(),
+        );
+    }
     let (tx, rx) = channel();
     let request = parse_request(oairequest, state.clone(), tx);
     let is_streaming = request.is_streaming;
+    let request_id = request.id;
     let sender = state.get_sender();
 
-    if request.return_logprobs {
-        return CompletionResponder::ValidationError(
-            "Completion requests do not support logprobs.".into(),
-        );
-    }
+    sender.send(Request::Normal(request)).unwrap();
 
     if is_streaming {
-        return CompletionResponder::ValidationError(
-            "Completion requests do not support streaming.".into(),
+        let streamer = CompletionStreamer {
+            rx,
+            is_done: false,
+            state,
+            request_id,
+        };
+
+        return CompletionResponder::Sse(
+            Sse::new(streamer).keep_alive(
+                KeepAlive::new()
+                    .interval(Duration::from_millis(
+                        env::var("KEEP_ALIVE_INTERVAL")
+                            .map(|val| val.parse::<u64>().unwrap_or(1000))
+                            .unwrap_or(1000),
+                    ))
+                    .text("keep-alive-text"),
+            ),
         );
     }
 
-    sender.send(request).unwrap();
-
-    let response = rx.recv().unwrap();
+    // The channel closes without a response if the request is dropped by the engine.
+    let Ok(response) = rx.recv() else {
+        return CompletionResponder::InternalError(
+            "The request was dropped before it finished.".into(),
+        );
+    };
 
     match response {
         Response::InternalError(e) => {
@@ -193,5 +328,7 @@ pub async fn completions(
         Response::Chunk(_) => unreachable!(),
         Response::Done(_) => unreachable!(),
         Response::ModelError(_, _) => unreachable!(),
+        Response::CompletionChunk(_) => unreachable!(),
+        Response::Embeddings(_) => unreachable!(),
     }
 }
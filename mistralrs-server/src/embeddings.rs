@@ -0,0 +1,192 @@
+use std::{error::Error, sync::mpsc::channel, sync::Arc};
+
+use crate::openai::{EmbeddingInput, EmbeddingPooling, EmbeddingRequest, EncodingFormat};
+use crate::registry::{ModelRegistry, RegistryError};
+use axum::{
+    extract::{Json, State},
+    http::{self, StatusCode},
+    response::IntoResponse,
+};
+use either::Either;
+use mistralrs_core::{
+    EmbeddingPooling as InternalEmbeddingPooling, EmbeddingRequest as InternalEmbeddingRequest,
+    EmbeddingResponse, EmbeddingUsage, MistralRs, Request, Response,
+};
+use serde::Serialize;
+
+const BASE64_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Encode an embedding as base64 of its little-endian `f32`s, like the OpenAI API.
+fn base64_embedding(embedding: &[f32]) -> String {
+    let bytes = embedding
+        .iter()
+        .flat_map(|x| x.to_le_bytes())
+        .collect::<Vec<_>>();
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, byte)| n | (u32::from(*byte) << (16 - 8 * i)));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(BASE64_ALPHABET[((n >> (18 - 6 * i)) & 0x3f) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+#[derive(Serialize)]
+pub struct Base64Embedding {
+    object: String,
+    embedding: String,
+    index: usize,
+}
+
+#[derive(Serialize)]
+pub struct Base64EmbeddingResponse {
+    object: String,
+    data: Vec<Base64Embedding>,
+    model: String,
+    usage: EmbeddingUsage,
+}
+
+impl From<EmbeddingResponse> for Base64EmbeddingResponse {
+    fn from(response: EmbeddingResponse) -> Self {
+        Self {
+            object: response.object,
+            data: response
+                .data
+                .into_iter()
+                .map(|embedding| Base64Embedding {
+                    object: embedding.object,
+                    embedding: base64_embedding(&embedding.embedding),
+                    index: embedding.index,
+                })
+                .collect(),
+            model: response.model,
+            usage: response.usage,
+        }
+    }
+}
+
+pub enum EmbeddingResponder {
+    Json(EmbeddingResponse),
+    Base64(Base64EmbeddingResponse),
+    InternalError(Box<dyn Error>),
+    ValidationError(Box<dyn Error>),
+}
+
+trait ErrorToResponse: Serialize {
+    fn to_response(&self, code: StatusCode) -> axum::response::Response {
+        let mut r = Json(self).into_response();
+        *r.status_mut() = code;
+        r
+    }
+}
+
+#[derive(Serialize)]
+struct JsonError {
+    message: String,
+}
+
+impl JsonError {
+    fn new(message: String) -> Self {
+        Self { message }
+    }
+}
+impl ErrorToResponse for JsonError {}
+
+impl IntoResponse for EmbeddingResponder {
+    fn into_response(self) -> axum::response::Response {
+        match self {
+            EmbeddingResponder::Json(s) => Json(s).into_response(),
+            EmbeddingResponder::Base64(s) => Json(s).into_response(),
+            EmbeddingResponder::InternalError(e) => {
+                JsonError::new(e.to_string()).to_response(http::StatusCode::INTERNAL_SERVER_ERROR)
+            }
+            EmbeddingResponder::ValidationError(e) => {
+                JsonError::new(e.to_string()).to_response(http::StatusCode::UNPROCESSABLE_ENTITY)
+            }
+        }
+    }
+}
+
+#[utoipa::path(
+    post,
+    tag = "Mistral.rs",
+    path = "/v1/embeddings",
+    request_body = EmbeddingRequest,
+    responses((status = 200, description = "Embeddings"))
+)]
+pub async fn embeddings(
+    State(registry): State<Arc<ModelRegistry>>,
+    Json(oairequest): Json<EmbeddingRequest>,
+) -> EmbeddingResponder {
+    let state = match registry.get(Some(&oairequest.model)).await {
+        Ok(state) => state,
+        Err(RegistryError::UnknownModel(e)) => {
+            return EmbeddingResponder::ValidationError(e.into())
+        }
+        Err(e @ RegistryError::Load(_)) => return EmbeddingResponder::InternalError(e.into()),
+    };
+    let repr = serde_json::to_string(&oairequest).unwrap();
+    MistralRs::maybe_log_request(state.clone(), repr);
+
+    let inputs = match oairequest.input {
+        EmbeddingInput::Single(input) => vec![Either::Left(input)],
+        EmbeddingInput::Multi(inputs) => inputs.into_iter().map(Either::Left).collect(),
+        EmbeddingInput::Tokens(toks) => vec![Either::Right(toks)],
+        EmbeddingInput::MultiTokens(toks) => toks.into_iter().map(Either::Right).collect(),
+    };
+    if inputs.is_empty() {
+        return EmbeddingResponder::ValidationError("`input` must not be empty.".into());
+    }
+    if oairequest.dimensions == Some(0) {
+        return EmbeddingResponder::ValidationError("`dimensions` must be at least 1.".into());
+    }
+
+    let (tx, rx) = channel();
+    let request = InternalEmbeddingRequest {
+        inputs,
+        pooling: match oairequest.pooling {
+            EmbeddingPooling::Mean => InternalEmbeddingPooling::Mean,
+            EmbeddingPooling::LastToken => InternalEmbeddingPooling::LastToken,
+        },
+        dimensions: oairequest.dimensions,
+        normalize: oairequest.normalize,
+        response: tx,
+        id: state.next_request_id(),
+    };
+    state
+        .get_sender()
+        .send(Request::Embedding(request))
+        .unwrap();
+
+    let response = rx.recv().unwrap();
+
+    match response {
+        Response::InternalError(e) => {
+            MistralRs::maybe_log_error(state, &*e);
+            EmbeddingResponder::InternalError(e)
+        }
+        Response::ValidationError(e) => EmbeddingResponder::ValidationError(e),
+        Response::Embeddings(response) => {
+            MistralRs::maybe_log_response(state, &response);
+            match oairequest.encoding_format {
+                EncodingFormat::Float => EmbeddingResponder::Json(response),
+                EncodingFormat::Base64 => EmbeddingResponder::Base64(response.into()),
+            }
+        }
+        Response::Chunk(_) => unreachable!(),
+        Response::Done(_) => unreachable!(),
+        Response::ModelError(_, _) => unreachable!(),
+        Response::CompletionDone(_) => unreachable!(),
+        Response::CompletionModelError(_, _) => unreachable!(),
+        Response::CompletionChunk(_) => unreachable!(),
+    }
+}
@@ -5,7 +5,9 @@ use std::{
 
 use either::Either;
 use indexmap::IndexMap;
-use mistralrs_core::{Constraint, MistralRs, Request, RequestType, Response, SamplingParams};
+use mistralrs_core::{
+    Constraint, MistralRs, NormalRequest, Request, RequestType, Response, SamplingParams,
+};
 use tracing::{error, info};
 
 pub fn interactive_mode(mistralrs: Arc<MistralRs>) {
@@ -16,13 +18,22 @@ pub fn interactive_mode(mistralrs: Arc<MistralRs>) {
         temperature: Some(0.1),
         top_k: Some(32),
         top_p: Some(0.1),
+        min_p: None,
+        typical_p: None,
+        tfs_z: None,
+        mirostat: None,
         top_n_logprobs: 0,
         frequency_penalty: Some(0.1),
         presence_penalty: Some(0.1),
+        repetition_penalty: None,
+        no_repeat_ngram_size: None,
+        dry_params: None,
         max_len: Some(4096),
         stop_toks: None,
         logits_bias: None,
         n_choices: 1,
+        beam_search: None,
+        seed: None,
     };
     info!("Starting interactive loop with sampling params: {sampling_params:?}");
     'outer: loop {
@@ -38,7 +49,7 @@ pub fn interactive_mode(mistralrs: Arc<MistralRs>) {
         messages.push(user_message);
 
         let (tx, rx) = channel();
-        let req = Request {
+        let req = NormalRequest {
             id: mistralrs.next_request_id(),
             messages: Either::Left(messages.clone()),
             sampling_params: sampling_params.clone(),
@@ -49,8 +60,14 @@ pub fn interactive_mode(mistralrs: Arc<MistralRs>) {
             request_type: RequestType::Chat,
             suffix: None,
             best_of: None,
+            priority: 0,
+            tenant: None,
+            timeout: None,
+            tools: None,
+            tool_choice: None,
+            adapters: None,
         };
-        sender.send(req).unwrap();
+        sender.send(Request::Normal(req)).unwrap();
 
         let mut assistant_output = String::new();
         loop {
@@ -85,6 +102,8 @@ pub fn interactive_mode(mistralrs: Arc<MistralRs>) {
                     Response::Done(_) => unreachable!(),
                     Response::CompletionDone(_) => unreachable!(),
                     Response::CompletionModelError(_, _) => unreachable!(),
+                    Response::CompletionChunk(_) => unreachable!(),
+                    Response::Embeddings(_) => unreachable!(),
                 }
             }
         }
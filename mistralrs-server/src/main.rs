@@ -1,6 +1,6 @@
-use std::{fs::File, sync::Arc};
+use std::{fs::File, sync::Arc, time::Duration};
 
-use anyhow::Result;
+use anyhow::{Context, Result};
 use axum::{
     extract::{Json, State},
     http::{self, Method},
@@ -11,23 +11,43 @@ use candle_core::Device;
 use clap::Parser;
 use mistralrs_core::{
     GemmaLoader, GemmaSpecificConfig, LlamaLoader, LlamaSpecificConfig, Loader, MistralLoader,
-    MistralRs, MistralSpecificConfig, MixtralLoader, MixtralSpecificConfig, ModelKind, Phi2Loader,
-    Phi2SpecificConfig, SchedulerMethod, TokenSource,
+    MistralRs, MistralSpecificConfig, MixtralLoader, MixtralSpecificConfig, ModelKind,
+    PagedCacheConfig, Phi2Loader, Phi2SpecificConfig, QueueOrder, QueuePolicy, SchedulerMethod,
+    SpeculativeConfig, TokenSource,
 };
 use model_selected::ModelSelected;
-use openai::{ChatCompletionRequest, Message, ModelObjects, StopTokens};
+use openai::{
+    AdapterList, AdapterLoadRequest, AdapterUnloadRequest, ChatCompletionRequest,
+    DetokenizationRequest, EmbeddingInput, EmbeddingRequest, Message, ModelObjects, StopTokens,
+    TokenizationRequest,
+};
+mod adapters;
 mod chat_completion;
 mod completions;
-use crate::{chat_completion::__path_chatcompletions, completions::completions};
+mod embeddings;
+use crate::{
+    adapters::{
+        __path_list_adapters, __path_load_adapter, __path_unload_adapter, list_adapters,
+        load_adapter, unload_adapter,
+    },
+    chat_completion::__path_chatcompletions,
+    completions::completions,
+    embeddings::{__path_embeddings, embeddings},
+    tokenization::{__path_detokenize, __path_tokenize, detokenize, tokenize},
+};
 
 use crate::{chat_completion::chatcompletions, openai::ModelObject};
 mod interactive_mode;
 mod model_selected;
 mod openai;
 mod prompt_mode;
+mod registry;
+mod tokenization;
 
 use interactive_mode::interactive_mode;
 use prompt_mode::prompt_mode;
+use registry::ModelRegistry;
+use serde::Deserialize;
 use tower_http::cors::{AllowOrigin, CorsLayer};
 use tracing::{info, warn};
 use utoipa::OpenApi;
@@ -37,6 +57,10 @@ fn parse_token_source(s: &str) -> Result<TokenSource, String> {
     s.parse()
 }
 
+fn parse_queue_order(s: &str) -> Result<QueueOrder, String> {
+    s.parse()
+}
+
 #[derive(Parser)]
 #[command(version, about, long_about = None)]
 struct Args {
@@ -60,12 +84,50 @@ struct Args {
 
     /// Model
     #[clap(subcommand)]
-    model: ModelSelected,
+    model: Option<ModelSelected>,
+
+    /// Serve several models instead of the model of the subcommand. This is a JSON file with a list of models
+    /// like `{"name": "gemma", "args": ["gemma", "--model-id", "google/gemma-7b-it"]}`, where `args` are
+    /// the model subcommand and its arguments. Requests are routed by their `model`, and each model is
+    /// loaded when it is first requested.
+    #[arg(long)]
+    models_config: Option<String>,
+
+    /// Speculative decoding with a draft model, given as a model subcommand and its arguments like
+    /// "llama --model-id TinyLlama/TinyLlama-1.1B-Chat-v1.0". The draft model proposes tokens which the model
+    /// verifies, so it must have the same vocabulary. The models of `models_config` give theirs in `draft_args`.
+    #[arg(long)]
+    draft_model: Option<String>,
+
+    /// Number of tokens which the draft model proposes per step of speculative decoding.
+    #[arg(long, default_value_t = 4)]
+    speculative_gamma: usize,
+
+    /// Unload a model which no request has used for this many seconds. It is loaded again when it is requested.
+    #[arg(long)]
+    model_idle_timeout_secs: Option<u64>,
 
-    /// Maximum running sequences at any time. If the `tgt_non_granular_index` flag is set for X-LoRA models, this will be set to 1.
+    /// Maximum running sequences at any time. If the `tgt_non_granular_index` flag is set for X-LoRA models, this will be set to 1
+    /// and `max_kv_tokens` is ignored.
     #[arg(long, default_value_t = 16)]
     max_seqs: usize,
 
+    /// Schedule by KV cache size instead of `max_seqs`: keep at most this many tokens in the KV cache.
+    /// A request reserves its prompt length plus `max_tokens`, and running sequences are preempted and
+    /// recomputed later if the budget is exceeded.
+    #[arg(long)]
+    max_kv_tokens: Option<usize>,
+
+    /// Order in which waiting requests are admitted: "fcfs", "priority" (by the `priority` of the request),
+    /// "shortest-prompt", or "fair" for weighted fair queuing between the `tenant`s of the requests.
+    /// Tenant weights can be given as "fair:<tenant>=<weight>,...", and default to 1.
+    #[arg(long, default_value = "fcfs", value_parser = parse_queue_order)]
+    queue_order: QueueOrder,
+
+    /// Admit requests which have waited this many milliseconds before all others, so that no request starves.
+    #[arg(long)]
+    max_queue_wait_ms: Option<u64>,
+
     /// Use no KV cache.
     #[arg(long, default_value_t = false)]
     no_kv_cache: bool,
@@ -92,6 +154,27 @@ struct Args {
     /// Number of prefix caches to hold on the device. Other caches are evicted to the CPU based on a LRU strategy.
     #[arg(long, default_value_t = 16)]
     prefix_cache_n: usize,
+
+    /// Use a paged KV cache with this many blocks, preallocated on the device. Each step copies the blocks
+    /// of the scheduled sequences into a contiguous KV cache and writes the new keys and values back.
+    /// Prefix caching, speculative decoding and beam search are disabled when the paged KV cache is used.
+    /// It cannot be used with `--no-kv-cache` or with X-LoRA and LoRA models.
+    #[arg(long)]
+    paged_kv_blocks: Option<usize>,
+
+    /// Number of tokens per block of the paged KV cache.
+    #[arg(long, default_value_t = 16)]
+    paged_kv_block_size: usize,
+
+    /// Prefill long prompts in chunks of at most this many tokens, interleaved with the decoding steps
+    /// of the running sequences. By default, a prompt is processed in one step.
+    #[arg(long)]
+    prefill_chunk_size: Option<usize>,
+
+    /// Finish requests which are not done this many milliseconds after they are received, responding with
+    /// the output generated so far and the finish reason "timeout". Requests can set a shorter `timeout_ms`.
+    #[arg(long)]
+    max_request_time_ms: Option<u64>,
 }
 
 #[utoipa::path(
@@ -100,15 +183,18 @@ struct Args {
     path = "/v1/models",
     responses((status = 200, description = "Served model info", body = ModelObjects))
 )]
-async fn models(State(state): State<Arc<MistralRs>>) -> Json<ModelObjects> {
+async fn models(State(registry): State<Arc<ModelRegistry>>) -> Json<ModelObjects> {
     Json(ModelObjects {
         object: "list",
-        data: vec![ModelObject {
-            id: state.get_id(),
-            object: "model",
-            created: state.get_creation_time(),
-            owned_by: "local",
-        }],
+        data: registry
+            .models()
+            .map(|(id, created)| ModelObject {
+                id: id.to_string(),
+                object: "model",
+                created,
+                owned_by: "local",
+            })
+            .collect(),
     })
 }
 
@@ -122,12 +208,12 @@ async fn health() -> &'static str {
     "OK"
 }
 
-fn get_router(state: Arc<MistralRs>) -> Router {
+fn get_router(registry: Arc<ModelRegistry>) -> Router {
     #[derive(OpenApi)]
     #[openapi(
-        paths(models, health, chatcompletions),
+        paths(models, health, chatcompletions, embeddings, tokenize, detokenize, list_adapters, load_adapter, unload_adapter),
         components(
-            schemas(ModelObjects, ModelObject, ChatCompletionRequest, StopTokens, Message)),
+            schemas(ModelObjects, ModelObject, ChatCompletionRequest, StopTokens, Message, EmbeddingRequest, EmbeddingInput, TokenizationRequest, DetokenizationRequest, AdapterList, AdapterLoadRequest, AdapterUnloadRequest)),
         tags(
             (name = "Mistral.rs", description = "Mistral.rs API")
         ),
@@ -153,22 +239,20 @@ fn get_router(state: Arc<MistralRs>) -> Router {
         .layer(cors_layer)
         .route("/v1/chat/completions", post(chatcompletions))
         .route("/v1/completions", post(completions))
+        .route("/v1/embeddings", post(embeddings))
+        .route("/tokenize", post(tokenize))
+        .route("/detokenize", post(detokenize))
+        .route("/v1/adapters", get(list_adapters))
+        .route("/v1/adapters/load", post(load_adapter))
+        .route("/v1/adapters/unload", post(unload_adapter))
         .route("/v1/models", get(models))
         .route("/health", get(health))
         .route("/", get(health))
-        .with_state(state)
+        .with_state(registry)
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    let mut args = Args::parse();
-
-    #[cfg(not(feature = "flash-attn"))]
-    let use_flash_attn = false;
-    #[cfg(feature = "flash-attn")]
-    let use_flash_attn = true;
-
-    let tgt_non_granular_index = match args.model {
+fn get_tgt_non_granular_index(model: &ModelSelected) -> Option<usize> {
+    match model {
         ModelSelected::Gemma { .. }
         | ModelSelected::Llama { .. }
         | ModelSelected::LlamaGGML { .. }
@@ -218,13 +302,16 @@ async fn main() -> Result<()> {
             tgt_non_granular_index,
             ..
         } => tgt_non_granular_index,
-    };
-    if tgt_non_granular_index.is_some() {
-        args.max_seqs = 1;
     }
-    let args = args;
+}
 
-    let loader: Box<dyn Loader> = match args.model {
+fn get_loader(
+    model: ModelSelected,
+    args: &Args,
+    tgt_non_granular_index: Option<usize>,
+) -> Result<Box<dyn Loader>> {
+    let use_flash_attn = cfg!(feature = "flash-attn");
+    let loader: Box<dyn Loader> = match model {
         ModelSelected::Mistral {
             model_id,
             repeat_last_n,
@@ -241,7 +328,7 @@ async fn main() -> Result<()> {
             ModelKind::Normal,
             None,
             args.no_kv_cache,
-            args.chat_template,
+            args.chat_template.clone(),
             tokenizer_json,
             None,
         )),
@@ -263,7 +350,7 @@ async fn main() -> Result<()> {
             ModelKind::QuantizedGGUF,
             None,
             args.no_kv_cache,
-            args.chat_template,
+            args.chat_template.clone(),
             tokenizer_json,
             None,
         )),
@@ -289,7 +376,7 @@ async fn main() -> Result<()> {
                     .unwrap_or_else(|_| panic!("Could not load ordering file at {order}")),
             )?),
             args.no_kv_cache,
-            args.chat_template,
+            args.chat_template.clone(),
             tokenizer_json,
             tgt_non_granular_index,
         )),
@@ -306,7 +393,7 @@ async fn main() -> Result<()> {
             ModelKind::Normal,
             None,
             args.no_kv_cache,
-            args.chat_template,
+            args.chat_template.clone(),
             tokenizer_json,
             None,
         )),
@@ -329,7 +416,7 @@ async fn main() -> Result<()> {
                     .unwrap_or_else(|_| panic!("Could not load ordering file at {order}")),
             )?),
             args.no_kv_cache,
-            args.chat_template,
+            args.chat_template.clone(),
             tokenizer_json,
             tgt_non_granular_index,
         )),
@@ -350,7 +437,7 @@ async fn main() -> Result<()> {
             ModelKind::Normal,
             None,
             args.no_kv_cache,
-            args.chat_template,
+            args.chat_template.clone(),
             tokenizer_json,
             None,
         )),
@@ -373,7 +460,7 @@ async fn main() -> Result<()> {
             ModelKind::QuantizedGGUF,
             None,
             args.no_kv_cache,
-            args.chat_template,
+            args.chat_template.clone(),
             tokenizer_json,
             None,
         )),
@@ -397,7 +484,7 @@ async fn main() -> Result<()> {
             ModelKind::QuantizedGGML,
             None,
             args.no_kv_cache,
-            args.chat_template,
+            args.chat_template.clone(),
             tokenizer_json,
             None,
         )),
@@ -424,7 +511,7 @@ async fn main() -> Result<()> {
                     .unwrap_or_else(|_| panic!("Could not load ordering file at {order}")),
             )?),
             args.no_kv_cache,
-            args.chat_template,
+            args.chat_template.clone(),
             tokenizer_json,
             tgt_non_granular_index,
         )),
@@ -444,7 +531,7 @@ async fn main() -> Result<()> {
             ModelKind::Normal,
             None,
             args.no_kv_cache,
-            args.chat_template,
+            args.chat_template.clone(),
             tokenizer_json,
             None,
         )),
@@ -466,7 +553,7 @@ async fn main() -> Result<()> {
             ModelKind::QuantizedGGUF,
             None,
             args.no_kv_cache,
-            args.chat_template,
+            args.chat_template.clone(),
             tokenizer_json,
             None,
         )),
@@ -492,7 +579,7 @@ async fn main() -> Result<()> {
                     .unwrap_or_else(|_| panic!("Could not load ordering file at {order}")),
             )?),
             args.no_kv_cache,
-            args.chat_template,
+            args.chat_template.clone(),
             tokenizer_json,
             tgt_non_granular_index,
         )),
@@ -520,7 +607,7 @@ async fn main() -> Result<()> {
                     .unwrap_or_else(|_| panic!("Could not load ordering file at {order}")),
             )?),
             args.no_kv_cache,
-            args.chat_template,
+            args.chat_template.clone(),
             tokenizer_json,
             tgt_non_granular_index,
         )),
@@ -548,7 +635,7 @@ async fn main() -> Result<()> {
                     .unwrap_or_else(|_| panic!("Could not load ordering file at {order}")),
             )?),
             args.no_kv_cache,
-            args.chat_template,
+            args.chat_template.clone(),
             tokenizer_json,
             tgt_non_granular_index,
         )),
@@ -577,7 +664,7 @@ async fn main() -> Result<()> {
                     .unwrap_or_else(|_| panic!("Could not load ordering file at {order}")),
             )?),
             args.no_kv_cache,
-            args.chat_template,
+            args.chat_template.clone(),
             tokenizer_json,
             tgt_non_granular_index,
         )),
@@ -607,7 +694,7 @@ async fn main() -> Result<()> {
                     .unwrap_or_else(|_| panic!("Could not load ordering file at {order}")),
             )?),
             args.no_kv_cache,
-            args.chat_template,
+            args.chat_template.clone(),
             tokenizer_json,
             tgt_non_granular_index,
         )),
@@ -627,7 +714,7 @@ async fn main() -> Result<()> {
             ModelKind::Normal,
             None,
             args.no_kv_cache,
-            args.chat_template,
+            args.chat_template.clone(),
             tokenizer_json,
             None,
         )),
@@ -653,7 +740,7 @@ async fn main() -> Result<()> {
                     .unwrap_or_else(|_| panic!("Could not load ordering file at {order}")),
             )?),
             args.no_kv_cache,
-            args.chat_template,
+            args.chat_template.clone(),
             tokenizer_json,
             tgt_non_granular_index,
         )),
@@ -680,7 +767,7 @@ async fn main() -> Result<()> {
                     .unwrap_or_else(|_| panic!("Could not load ordering file at {order}")),
             )?),
             args.no_kv_cache,
-            args.chat_template,
+            args.chat_template.clone(),
             tokenizer_json,
             tgt_non_granular_index,
         )),
@@ -705,7 +792,7 @@ async fn main() -> Result<()> {
                     .unwrap_or_else(|_| panic!("Could not load ordering file at {order}")),
             )?),
             args.no_kv_cache,
-            args.chat_template,
+            args.chat_template.clone(),
             tokenizer_json,
             tgt_non_granular_index,
         )),
@@ -730,7 +817,7 @@ async fn main() -> Result<()> {
                     .unwrap_or_else(|_| panic!("Could not load ordering file at {order}")),
             )?),
             args.no_kv_cache,
-            args.chat_template,
+            args.chat_template.clone(),
             tokenizer_json,
             tgt_non_granular_index,
         )),
@@ -757,7 +844,7 @@ async fn main() -> Result<()> {
                     .unwrap_or_else(|_| panic!("Could not load ordering file at {order}")),
             )?),
             args.no_kv_cache,
-            args.chat_template,
+            args.chat_template.clone(),
             tokenizer_json,
             tgt_non_granular_index,
         )),
@@ -782,7 +869,7 @@ async fn main() -> Result<()> {
                     .unwrap_or_else(|_| panic!("Could not load ordering file at {order}")),
             )?),
             args.no_kv_cache,
-            args.chat_template,
+            args.chat_template.clone(),
             tokenizer_json,
             tgt_non_granular_index,
         )),
@@ -810,7 +897,7 @@ async fn main() -> Result<()> {
                     .unwrap_or_else(|_| panic!("Could not load ordering file at {order}")),
             )?),
             args.no_kv_cache,
-            args.chat_template,
+            args.chat_template.clone(),
             tokenizer_json,
             tgt_non_granular_index,
         )),
@@ -839,26 +926,77 @@ async fn main() -> Result<()> {
                     .unwrap_or_else(|_| panic!("Could not load ordering file at {order}")),
             )?),
             args.no_kv_cache,
-            args.chat_template,
+            args.chat_template.clone(),
             tokenizer_json,
             tgt_non_granular_index,
         )),
     };
+    Ok(loader)
+}
 
-    #[cfg(feature = "metal")]
-    let device = Device::new_metal(0)?;
-    #[cfg(not(feature = "metal"))]
-    let device = Device::cuda_if_available(0)?;
+#[derive(Deserialize)]
+struct ModelConfig {
+    name: String,
+    args: Vec<String>,
+    draft_args: Option<Vec<String>>,
+}
 
-    tracing_subscriber::fmt().init();
-    info!(
-        "avx: {}, neon: {}, simd128: {}, f16c: {}",
-        candle_core::utils::with_avx(),
-        candle_core::utils::with_neon(),
-        candle_core::utils::with_simd128(),
-        candle_core::utils::with_f16c()
-    );
-    info!("Sampling method: penalties -> temperature -> topk -> topp -> multinomial");
+/// The model subcommand of a model in a models config or of a draft model, which is parsed like the command line.
+#[derive(Parser)]
+struct ModelArgs {
+    #[clap(subcommand)]
+    model: ModelSelected,
+}
+
+fn parse_model_args(args: impl IntoIterator<Item = String>) -> Result<ModelSelected, clap::Error> {
+    ModelArgs::try_parse_from(std::iter::once("model".to_string()).chain(args))
+        .map(|args| args.model)
+}
+
+/// A model to serve, with its name and its draft model for speculative decoding.
+type ConfiguredModel = (String, ModelSelected, Option<ModelSelected>);
+
+fn parse_models_config(path: &str) -> Result<Vec<ConfiguredModel>> {
+    let file = File::open(path)
+        .with_context(|| format!("Could not open the models config at `{path}`."))?;
+    let configs: Vec<ModelConfig> = serde_json::from_reader(file)
+        .with_context(|| format!("Could not parse the models config at `{path}`."))?;
+    let mut models: Vec<ConfiguredModel> = Vec::new();
+    for config in configs {
+        if models.iter().any(|(name, _, _)| *name == config.name) {
+            anyhow::bail!("The model `{}` is configured twice.", config.name);
+        }
+        let model = parse_model_args(config.args).map_err(|e| {
+            anyhow::anyhow!("Invalid arguments for the model `{}`: {e}", config.name)
+        })?;
+        let draft = config
+            .draft_args
+            .map(parse_model_args)
+            .transpose()
+            .map_err(|e| {
+                anyhow::anyhow!(
+                    "Invalid draft arguments for the model `{}`: {e}",
+                    config.name
+                )
+            })?;
+        models.push((config.name, model, draft));
+    }
+    if models.is_empty() {
+        anyhow::bail!("The models config has no models.");
+    }
+    Ok(models)
+}
+
+/// Load a model, and its draft model if it has one, and start its engine.
+fn load_model(
+    model: ModelSelected,
+    draft: Option<ModelSelected>,
+    args: &Args,
+    device: &Device,
+) -> Result<Arc<MistralRs>> {
+    let use_flash_attn = cfg!(feature = "flash-attn");
+    let tgt_non_granular_index = get_tgt_non_granular_index(&model);
+    let loader = get_loader(model, args, tgt_non_granular_index)?;
     info!("Loading model `{}` on {device:?}...", loader.get_id());
     if use_flash_attn {
         info!("Using flash attention.");
@@ -875,30 +1013,132 @@ async fn main() -> Result<()> {
         warn!("Using flash attention with a quantized model has no effect!")
     }
     info!("Model kind is: {}", loader.get_kind().as_ref());
-    let pipeline = loader.load_model(None, args.token_source, None, &device)?;
+    if args.paged_kv_blocks.is_some() {
+        if args.no_kv_cache {
+            anyhow::bail!("The paged KV cache cannot be used with `--no-kv-cache`.");
+        }
+        if !matches!(
+            loader.get_kind(),
+            ModelKind::Normal | ModelKind::QuantizedGGML | ModelKind::QuantizedGGUF
+        ) {
+            anyhow::bail!("The paged KV cache is not supported for X-LoRA and LoRA models.");
+        }
+    }
+    let pipeline = loader.load_model(None, args.token_source.clone(), None, device)?;
     info!("Model loaded.");
+    let speculative = match draft {
+        Some(draft) => {
+            let loader = get_loader(draft, args, None)?;
+            info!("Loading draft model `{}`...", loader.get_id());
+            let draft = loader.load_model(None, args.token_source.clone(), None, device)?;
+            info!("Draft model loaded.");
+            Some(SpeculativeConfig {
+                draft,
+                gamma: args.speculative_gamma,
+            })
+        }
+        None => None,
+    };
 
-    let mistralrs = MistralRs::new(
+    // X-LoRA models with a `tgt_non_granular_index` run one sequence at a time.
+    let (max_seqs, max_kv_tokens) = if tgt_non_granular_index.is_some() {
+        (1, None)
+    } else {
+        (args.max_seqs, args.max_kv_tokens)
+    };
+    Ok(MistralRs::new(
         pipeline,
-        SchedulerMethod::Fixed(args.max_seqs.try_into().unwrap()),
-        args.log,
+        match max_kv_tokens {
+            Some(max_kv_tokens) => SchedulerMethod::TokenBudget(max_kv_tokens.try_into().unwrap()),
+            None => SchedulerMethod::Fixed(max_seqs.try_into().unwrap()),
+        },
+        QueuePolicy {
+            order: args.queue_order.clone(),
+            max_wait: args.max_queue_wait_ms.map(Duration::from_millis),
+        },
+        args.log.clone(),
         args.truncate_sequence,
         args.no_kv_cache,
         args.prefix_cache_n,
-    );
+        args.paged_kv_blocks.map(|num_blocks| PagedCacheConfig {
+            block_size: args.paged_kv_block_size,
+            num_blocks,
+        }),
+        args.prefill_chunk_size,
+        args.max_request_time_ms.map(Duration::from_millis),
+        speculative,
+    ))
+}
 
-    if let Some(prompt) = args.prompt {
-        prompt_mode(mistralrs, prompt);
-        return Ok(());
-    }
-    if args.interactive_mode {
-        interactive_mode(mistralrs);
-        return Ok(());
+#[tokio::main]
+async fn main() -> Result<()> {
+    let mut args = Args::parse();
+    let model = args.model.take();
+    let args = Arc::new(args);
+
+    #[cfg(feature = "metal")]
+    let device = Device::new_metal(0)?;
+    #[cfg(not(feature = "metal"))]
+    let device = Device::cuda_if_available(0)?;
+
+    tracing_subscriber::fmt().init();
+    info!(
+        "avx: {}, neon: {}, simd128: {}, f16c: {}",
+        candle_core::utils::with_avx(),
+        candle_core::utils::with_neon(),
+        candle_core::utils::with_simd128(),
+        candle_core::utils::with_f16c()
+    );
+    info!("Sampling method: penalties -> temperature -> topk -> topp -> multinomial");
+    let mut registry = ModelRegistry::new(args.model_idle_timeout_secs.map(Duration::from_secs));
+    match (model, &args.models_config) {
+        (Some(model), None) => {
+            let draft = args
+                .draft_model
+                .as_ref()
+                .map(|draft| parse_model_args(draft.split_whitespace().map(String::from)))
+                .transpose()
+                .map_err(|e| anyhow::anyhow!("Invalid draft model: {e}"))?;
+            let mistralrs = load_model(model.clone(), draft.clone(), &args, &device)?;
+            if let Some(prompt) = args.prompt.clone() {
+                prompt_mode(mistralrs, prompt);
+                return Ok(());
+            }
+            if args.interactive_mode {
+                interactive_mode(mistralrs);
+                return Ok(());
+            }
+            let (args, device) = (args.clone(), device.clone());
+            registry.add(
+                mistralrs.get_id(),
+                Some(mistralrs),
+                Arc::new(move || load_model(model.clone(), draft.clone(), &args, &device)),
+            );
+        }
+        (None, Some(models_config)) => {
+            if args.prompt.is_some() || args.interactive_mode {
+                anyhow::bail!("The prompt and interactive modes need a model subcommand.");
+            }
+            if args.draft_model.is_some() {
+                anyhow::bail!(
+                    "The models of `--models-config` give their draft models in `draft_args`."
+                );
+            }
+            for (name, model, draft) in parse_models_config(models_config)? {
+                let (args, device) = (args.clone(), device.clone());
+                registry.add(
+                    name,
+                    None,
+                    Arc::new(move || load_model(model.clone(), draft.clone(), &args, &device)),
+                );
+            }
+        }
+        _ => anyhow::bail!("Give either a model subcommand or `--models-config`."),
     }
 
-    let port = args.port.expect("Expected port to be specified.");
+    let port = args.port.clone().expect("Expected port to be specified.");
 
-    let app = get_router(mistralrs);
+    let app = get_router(registry.start());
 
     let ip = if let Some(ref ip) = args.serve_ip {
         ip.to_string()
@@ -1,6 +1,6 @@
 use clap::Subcommand;
 
-#[derive(Debug, Subcommand)]
+#[derive(Debug, Clone, Subcommand)]
 pub enum ModelSelected {
     /// Select the mistral model.
     Mistral {
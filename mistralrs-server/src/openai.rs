@@ -5,9 +5,75 @@ use utoipa::ToSchema;
 
 #[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
 pub struct Message {
-    pub content: String,
+    pub content: Option<String>,
     pub role: String,
     pub name: Option<String>,
+    /// The tool calls of an assistant message.
+    pub tool_calls: Option<Vec<MessageToolCall>>,
+    /// The tool call which a tool message answers.
+    pub tool_call_id: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+pub enum ToolType {
+    #[serde(rename = "function")]
+    Function,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+pub struct CalledFunction {
+    pub name: String,
+    pub arguments: String,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+pub struct MessageToolCall {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub tp: ToolType,
+    pub function: CalledFunction,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+pub struct Function {
+    pub name: String,
+    pub description: Option<String>,
+    #[schema(value_type = Option<Object>)]
+    pub parameters: Option<serde_json::Value>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+pub struct Tool {
+    #[serde(rename = "type")]
+    pub tp: ToolType,
+    pub function: Function,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum ToolChoiceMode {
+    None,
+    Auto,
+    Required,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+pub struct FunctionName {
+    pub name: String,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+pub struct NamedToolChoice {
+    #[serde(rename = "type")]
+    pub tp: ToolType,
+    pub function: FunctionName,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+#[serde(untagged)]
+pub enum ToolChoice {
+    Mode(ToolChoiceMode),
+    Function(NamedToolChoice),
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
@@ -32,11 +98,38 @@ pub enum Grammar {
     Regex(String),
     #[serde(rename = "yacc")]
     Yacc(String),
+    #[serde(rename = "json_schema")]
+    JsonSchema(#[schema(value_type = Object)] serde_json::Value),
+    #[serde(rename = "gbnf")]
+    Gbnf(String),
+    #[serde(rename = "lark")]
+    Lark(String),
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+pub struct JsonSchemaFormat {
+    pub name: String,
+    pub description: Option<String>,
+    #[schema(value_type = Option<Object>)]
+    pub schema: Option<serde_json::Value>,
+    /// The output always follows the schema, so this is accepted but has no effect.
+    pub strict: Option<bool>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+#[serde(tag = "type")]
+pub enum ResponseFormat {
+    #[serde(rename = "text")]
+    Text,
+    #[serde(rename = "json_object")]
+    JsonObject,
+    #[serde(rename = "json_schema")]
+    JsonSchema { json_schema: JsonSchemaFormat },
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
 pub struct ChatCompletionRequest {
-    #[schema(example = json!(vec![Message{content:"Why did the crab cross the road?".to_string(), role:"user".to_string(), name: None}]))]
+    #[schema(example = json!(vec![Message{content:Some("Why did the crab cross the road?".to_string()), role:"user".to_string(), name: None, tool_calls: None, tool_call_id: None}]))]
     #[serde(with = "either::serde_untagged")]
     pub messages: Either<Vec<Message>, String>,
     #[schema(example = "mistral")]
@@ -65,15 +158,94 @@ pub struct ChatCompletionRequest {
     pub temperature: Option<f64>,
     #[schema(example = json!(Option::None::<f64>))]
     pub top_p: Option<f64>,
+    #[schema(example = json!(Option::None::<u64>))]
+    pub seed: Option<u64>,
     #[schema(example = true)]
     pub stream: Option<bool>,
+    #[schema(example = json!(Option::None::<Vec<Tool>>))]
+    pub tools: Option<Vec<Tool>>,
+    #[schema(example = json!(Option::None::<ToolChoice>))]
+    pub tool_choice: Option<ToolChoice>,
+    #[schema(example = json!(Option::None::<ResponseFormat>))]
+    pub response_format: Option<ResponseFormat>,
 
     // mistral.rs additional
     #[schema(example = json!(Option::None::<usize>))]
     pub top_k: Option<usize>,
 
+    #[schema(example = json!(Option::None::<f64>))]
+    pub min_p: Option<f64>,
+
+    #[schema(example = json!(Option::None::<f64>))]
+    pub typical_p: Option<f64>,
+
+    #[schema(example = json!(Option::None::<f64>))]
+    pub tfs_z: Option<f64>,
+
+    /// Mirostat sampling as in llama.cpp: 0 disables it, and 1 and 2 are the versions.
+    #[schema(example = json!(Option::None::<u8>))]
+    pub mirostat: Option<u8>,
+
+    /// The target surprise of Mirostat, in bits. Defaults to 5.
+    #[schema(example = json!(Option::None::<f32>))]
+    pub mirostat_tau: Option<f32>,
+
+    /// The learning rate of Mirostat. Defaults to 0.1.
+    #[schema(example = json!(Option::None::<f32>))]
+    pub mirostat_eta: Option<f32>,
+
+    /// The multiplicative repetition penalty of CTRL and llama.cpp.
+    #[schema(example = json!(Option::None::<f32>))]
+    pub repetition_penalty: Option<f32>,
+
+    #[schema(example = json!(Option::None::<usize>))]
+    pub no_repeat_ngram_size: Option<usize>,
+
+    /// Enables the DRY penalty on repeated sequences.
+    #[schema(example = json!(Option::None::<f32>))]
+    pub dry_multiplier: Option<f32>,
+
+    /// Defaults to 1.75.
+    #[schema(example = json!(Option::None::<f32>))]
+    pub dry_base: Option<f32>,
+
+    /// Defaults to 2.
+    #[schema(example = json!(Option::None::<usize>))]
+    pub dry_allowed_length: Option<usize>,
+
+    /// Defaults to `["\n", ":", "\"", "*"]`.
+    #[schema(example = json!(Option::None::<Vec<String>>))]
+    pub dry_sequence_breakers: Option<Vec<String>>,
+
     #[schema(example = json!(Option::None::<Grammar>))]
     pub grammar: Option<Grammar>,
+
+    #[schema(example = json!(Option::None::<i32>))]
+    pub priority: Option<i32>,
+
+    #[schema(example = json!(Option::None::<String>))]
+    pub tenant: Option<String>,
+
+    #[schema(example = json!(Option::None::<u64>))]
+    pub timeout_ms: Option<u64>,
+
+    /// Decode with a beam search of this width instead of sampling. `n` is then the number of best beams to
+    /// return.
+    #[schema(example = json!(Option::None::<usize>))]
+    pub beam_width: Option<usize>,
+
+    /// The exponent of the length by which the logprob of a beam is divided to score it.
+    #[schema(example = json!(Option::None::<f32>))]
+    pub length_penalty: Option<f32>,
+
+    /// Stop the beam search once `beam_width` beams are finished.
+    #[schema(example = json!(Option::None::<bool>))]
+    pub early_stopping: Option<bool>,
+
+    /// The LoRA adapters to apply, for LoRA models. Defaults to all loaded adapters, and an empty list applies
+    /// the base model.
+    #[schema(example = json!(Option::None::<Vec<String>>))]
+    pub adapters: Option<Vec<String>>,
 }
 
 #[derive(Debug, Serialize, ToSchema)]
@@ -120,12 +292,14 @@ pub struct CompletionRequest {
     #[serde(rename = "stop")]
     #[schema(example = json!(Option::None::<StopTokens>))]
     pub stop_seqs: Option<StopTokens>,
-    #[serde(rename = "stream")]
-    pub _stream: Option<bool>,
+    #[schema(example = false)]
+    pub stream: Option<bool>,
     #[schema(example = 0.7)]
     pub temperature: Option<f64>,
     #[schema(example = json!(Option::None::<f64>))]
     pub top_p: Option<f64>,
+    #[schema(example = json!(Option::None::<u64>))]
+    pub seed: Option<u64>,
     #[schema(example = json!(Option::None::<String>))]
     pub suffix: Option<String>,
     #[serde(rename = "user")]
@@ -135,6 +309,198 @@ pub struct CompletionRequest {
     #[schema(example = json!(Option::None::<usize>))]
     pub top_k: Option<usize>,
 
+    #[schema(example = json!(Option::None::<f64>))]
+    pub min_p: Option<f64>,
+
+    #[schema(example = json!(Option::None::<f64>))]
+    pub typical_p: Option<f64>,
+
+    #[schema(example = json!(Option::None::<f64>))]
+    pub tfs_z: Option<f64>,
+
+    /// Mirostat sampling as in llama.cpp: 0 disables it, and 1 and 2 are the versions.
+    #[schema(example = json!(Option::None::<u8>))]
+    pub mirostat: Option<u8>,
+
+    /// The target surprise of Mirostat, in bits. Defaults to 5.
+    #[schema(example = json!(Option::None::<f32>))]
+    pub mirostat_tau: Option<f32>,
+
+    /// The learning rate of Mirostat. Defaults to 0.1.
+    #[schema(example = json!(Option::None::<f32>))]
+    pub mirostat_eta: Option<f32>,
+
+    /// The multiplicative repetition penalty of CTRL and llama.cpp.
+    #[schema(example = json!(Option::None::<f32>))]
+    pub repetition_penalty: Option<f32>,
+
+    #[schema(example = json!(Option::None::<usize>))]
+    pub no_repeat_ngram_size: Option<usize>,
+
+    /// Enables the DRY penalty on repeated sequences.
+    #[schema(example = json!(Option::None::<f32>))]
+    pub dry_multiplier: Option<f32>,
+
+    /// Defaults to 1.75.
+    #[schema(example = json!(Option::None::<f32>))]
+    pub dry_base: Option<f32>,
+
+    /// Defaults to 2.
+    #[schema(example = json!(Option::None::<usize>))]
+    pub dry_allowed_length: Option<usize>,
+
+    /// Defaults to `["\n", ":", "\"", "*"]`.
+    #[schema(example = json!(Option::None::<Vec<String>>))]
+    pub dry_sequence_breakers: Option<Vec<String>>,
+
     #[schema(example = json!(Option::None::<Grammar>))]
     pub grammar: Option<Grammar>,
+
+    #[schema(example = json!(Option::None::<i32>))]
+    pub priority: Option<i32>,
+
+    #[schema(example = json!(Option::None::<String>))]
+    pub tenant: Option<String>,
+
+    #[schema(example = json!(Option::None::<u64>))]
+    pub timeout_ms: Option<u64>,
+
+    /// Decode with a beam search of this width instead of sampling. `n` is then the number of best beams to
+    /// return.
+    #[schema(example = json!(Option::None::<usize>))]
+    pub beam_width: Option<usize>,
+
+    /// The exponent of the length by which the logprob of a beam is divided to score it.
+    #[schema(example = json!(Option::None::<f32>))]
+    pub length_penalty: Option<f32>,
+
+    /// Stop the beam search once `beam_width` beams are finished.
+    #[schema(example = json!(Option::None::<bool>))]
+    pub early_stopping: Option<bool>,
+
+    /// The LoRA adapters to apply, for LoRA models. Defaults to all loaded adapters, and an empty list applies
+    /// the base model.
+    #[schema(example = json!(Option::None::<Vec<String>>))]
+    pub adapters: Option<Vec<String>>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+#[serde(untagged)]
+pub enum EmbeddingInput {
+    Single(String),
+    Multi(Vec<String>),
+    Tokens(Vec<u32>),
+    MultiTokens(Vec<Vec<u32>>),
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize, ToSchema)]
+pub enum EncodingFormat {
+    #[default]
+    #[serde(rename = "float")]
+    Float,
+    /// The little-endian `f32`s of each embedding, in base64.
+    #[serde(rename = "base64")]
+    Base64,
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize, ToSchema)]
+pub enum EmbeddingPooling {
+    /// The mean of the hidden states of all of the tokens.
+    #[default]
+    #[serde(rename = "mean")]
+    Mean,
+    /// The hidden state of the last token.
+    #[serde(rename = "last_token")]
+    LastToken,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+pub struct EmbeddingRequest {
+    #[schema(example = "mistral")]
+    pub model: String,
+    #[schema(example = json!("The food was delicious."))]
+    pub input: EmbeddingInput,
+    #[serde(default)]
+    #[schema(example = json!(EncodingFormat::Float))]
+    pub encoding_format: EncodingFormat,
+    #[schema(example = json!(Option::None::<usize>))]
+    pub dimensions: Option<usize>,
+    #[serde(rename = "user")]
+    pub _user: Option<String>,
+
+    // mistral.rs additional
+    #[serde(default)]
+    #[schema(example = json!(EmbeddingPooling::Mean))]
+    pub pooling: EmbeddingPooling,
+
+    #[serde(default = "default_true")]
+    #[schema(example = true)]
+    pub normalize: bool,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+pub struct TokenizationRequest {
+    /// Only needed if the server serves several models.
+    #[schema(example = json!(Option::None::<String>))]
+    pub model: Option<String>,
+    /// A raw prompt, which is tokenized as is. Exactly one of `prompt` and `messages` must be given.
+    #[schema(example = "Why did the crab cross the road?")]
+    pub prompt: Option<String>,
+    /// Messages, which are rendered with the chat template of the model first.
+    #[schema(example = json!(Option::None::<Vec<Message>>))]
+    pub messages: Option<Vec<Message>>,
+    #[serde(default = "default_true")]
+    #[schema(example = true)]
+    pub add_generation_prompt: bool,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+pub struct DetokenizationRequest {
+    /// Only needed if the server serves several models.
+    #[schema(example = json!(Option::None::<String>))]
+    pub model: Option<String>,
+    #[schema(example = json!(vec![1, 22557]))]
+    pub tokens: Vec<u32>,
+    #[serde(default = "default_false")]
+    #[schema(example = false)]
+    pub skip_special_tokens: bool,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+pub struct AdapterListRequest {
+    /// Only needed if the server serves several models.
+    #[schema(example = json!(Option::None::<String>))]
+    pub model: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+pub struct AdapterLoadRequest {
+    /// Only needed if the server serves several models.
+    #[schema(example = json!(Option::None::<String>))]
+    pub model: Option<String>,
+    /// The name which requests select the adapter by.
+    #[schema(example = "math")]
+    pub name: String,
+    /// A local directory with the `adapter_config.json` and `adapter_model.safetensors` of a PEFT adapter.
+    #[schema(example = "/adapters/math")]
+    pub path: String,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+pub struct AdapterUnloadRequest {
+    /// Only needed if the server serves several models.
+    #[schema(example = json!(Option::None::<String>))]
+    pub model: Option<String>,
+    #[schema(example = "math")]
+    pub name: String,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct AdapterList {
+    /// The names of the loaded adapters.
+    pub adapters: Vec<String>,
 }
@@ -2,7 +2,9 @@ use std::sync::{mpsc::channel, Arc};
 
 use either::Either;
 use indexmap::IndexMap;
-use mistralrs_core::{Constraint, MistralRs, Request, RequestType, Response, SamplingParams};
+use mistralrs_core::{
+    Constraint, MistralRs, NormalRequest, Request, RequestType, Response, SamplingParams,
+};
 use tracing::{error, info};
 
 pub fn prompt_mode(mistralrs: Arc<MistralRs>, prompt: String) {
@@ -13,13 +15,22 @@ pub fn prompt_mode(mistralrs: Arc<MistralRs>, prompt: String) {
         temperature: Some(0.1),
         top_k: Some(32),
         top_p: Some(0.1),
+        min_p: None,
+        typical_p: None,
+        tfs_z: None,
+        mirostat: None,
         top_n_logprobs: 0,
         frequency_penalty: Some(0.1),
         presence_penalty: Some(0.1),
+        repetition_penalty: None,
+        no_repeat_ngram_size: None,
+        dry_params: None,
         max_len: Some(4096),
         stop_toks: None,
         logits_bias: None,
         n_choices: 1,
+        beam_search: None,
+        seed: None,
     };
     info!("Running the prompt `{prompt}` with sampling params: {sampling_params:?}");
 
@@ -29,7 +40,7 @@ pub fn prompt_mode(mistralrs: Arc<MistralRs>, prompt: String) {
     messages.push(user_message);
 
     let (tx, rx) = channel();
-    let req = Request {
+    let req = NormalRequest {
         id: mistralrs.next_request_id(),
         messages: Either::Left(messages.clone()),
         sampling_params: sampling_params.clone(),
@@ -40,8 +51,14 @@ pub fn prompt_mode(mistralrs: Arc<MistralRs>, prompt: String) {
         request_type: RequestType::Chat,
         suffix: None,
         best_of: None,
+        priority: 0,
+        tenant: None,
+        timeout: None,
+        tools: None,
+        tool_choice: None,
+        adapters: None,
     };
-    sender.send(req).unwrap();
+    sender.send(Request::Normal(req)).unwrap();
 
     let resp = rx.recv();
     if let Ok(resp) = resp {
@@ -65,6 +82,8 @@ pub fn prompt_mode(mistralrs: Arc<MistralRs>, prompt: String) {
             Response::Chunk(_) => unreachable!(),
             Response::CompletionDone(_) => unreachable!(),
             Response::CompletionModelError(_, _) => unreachable!(),
+            Response::CompletionChunk(_) => unreachable!(),
+            Response::Embeddings(_) => unreachable!(),
         }
     }
 }
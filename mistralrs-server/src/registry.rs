@@ -0,0 +1,174 @@
+use std::{
+    fmt,
+    sync::Arc,
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use mistralrs_core::MistralRs;
+use tokio::sync::Mutex;
+use tracing::info;
+
+/// How often the registry checks for idle models.
+const UNLOAD_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Loads a model and starts its engine. It blocks, so it runs on a blocking thread.
+pub type LoadModel = Arc<dyn Fn() -> anyhow::Result<Arc<MistralRs>> + Send + Sync>;
+
+#[derive(Debug)]
+pub enum RegistryError {
+    /// The request names no model, or one which is not served.
+    UnknownModel(String),
+    /// The model could not be loaded.
+    Load(anyhow::Error),
+}
+
+impl fmt::Display for RegistryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnknownModel(msg) => write!(f, "{msg}"),
+            Self::Load(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl std::error::Error for RegistryError {}
+
+struct ModelState {
+    loaded: Option<Arc<MistralRs>>,
+    last_used: Instant,
+}
+
+struct RegisteredModel {
+    name: String,
+    created: u64,
+    load: LoadModel,
+    state: Mutex<ModelState>,
+}
+
+/// The models served by the server, which requests are routed to by their `model`. A model is loaded when it
+/// is first requested, and with an idle timeout it is unloaded again once no request has used it for that long.
+pub struct ModelRegistry {
+    models: Vec<RegisteredModel>,
+    idle_timeout: Option<Duration>,
+}
+
+impl ModelRegistry {
+    pub fn new(idle_timeout: Option<Duration>) -> Self {
+        Self {
+            models: Vec::new(),
+            idle_timeout,
+        }
+    }
+
+    /// Register a model, which may already be loaded.
+    pub fn add(&mut self, name: String, loaded: Option<Arc<MistralRs>>, load: LoadModel) {
+        let created = match &loaded {
+            Some(loaded) => loaded.get_creation_time(),
+            None => SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .expect("Time travel has occurred!")
+                .as_secs(),
+        };
+        self.models.push(RegisteredModel {
+            name,
+            created,
+            load,
+            state: Mutex::new(ModelState {
+                loaded,
+                last_used: Instant::now(),
+            }),
+        });
+    }
+
+    /// Share the registry, and unload idle models in the background for as long as it is used.
+    pub fn start(self) -> Arc<Self> {
+        let this = Arc::new(self);
+        if let Some(idle_timeout) = this.idle_timeout {
+            let registry = Arc::downgrade(&this);
+            thread::spawn(move || loop {
+                thread::sleep(UNLOAD_INTERVAL.min(idle_timeout));
+                let Some(registry) = registry.upgrade() else {
+                    return;
+                };
+                registry.unload_idle(idle_timeout);
+            });
+        }
+        this
+    }
+
+    /// The name and creation time of each model, loaded or not.
+    pub fn models(&self) -> impl Iterator<Item = (&str, u64)> {
+        self.models
+            .iter()
+            .map(|model| (model.name.as_str(), model.created))
+    }
+
+    /// Get the model of a request, loading it if needed. With only one model, every request is routed to it,
+    /// whatever its `model`.
+    pub async fn get(&self, name: Option<&str>) -> Result<Arc<MistralRs>, RegistryError> {
+        let model = match (name, self.models.as_slice()) {
+            (_, [model]) => model,
+            (Some(name), models) => {
+                models
+                    .iter()
+                    .find(|model| model.name == name)
+                    .ok_or_else(|| {
+                        RegistryError::UnknownModel(format!(
+                            "The model `{name}` is not served. The served models are {}.",
+                            self.names()
+                        ))
+                    })?
+            }
+            (None, _) => {
+                return Err(RegistryError::UnknownModel(format!(
+                    "A `model` must be given. The served models are {}.",
+                    self.names()
+                )))
+            }
+        };
+        // Requests for a model which is being loaded wait here until it is loaded.
+        let mut state = model.state.lock().await;
+        state.last_used = Instant::now();
+        if let Some(loaded) = &state.loaded {
+            return Ok(loaded.clone());
+        }
+        info!("Loading the model `{}`.", model.name);
+        let load = model.load.clone();
+        let loaded = tokio::task::spawn_blocking(move || load())
+            .await
+            .map_err(|e| RegistryError::Load(anyhow::anyhow!("Loading the model failed: {e}")))?
+            .map_err(RegistryError::Load)?;
+        state.loaded = Some(loaded.clone());
+        Ok(loaded)
+    }
+
+    fn names(&self) -> String {
+        self.models
+            .iter()
+            .map(|model| format!("`{}`", model.name))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    /// Unload the models which no request has used for `idle_timeout`. The engine of a model stops once the
+    /// registry drops it, which frees the model.
+    fn unload_idle(&self, idle_timeout: Duration) {
+        for model in &self.models {
+            // A model which is being loaded is skipped.
+            let Ok(mut state) = model.state.try_lock() else {
+                continue;
+            };
+            let Some(loaded) = &state.loaded else {
+                continue;
+            };
+            // Requests which are running, like streams, hold the model, so it is still in use.
+            if Arc::strong_count(loaded) > 1 {
+                state.last_used = Instant::now();
+            } else if state.last_used.elapsed() >= idle_timeout {
+                info!("Unloading the idle model `{}`.", model.name);
+                state.loaded = None;
+            }
+        }
+    }
+}
@@ -0,0 +1,135 @@
+use std::{error::Error, sync::mpsc::channel, sync::Arc};
+
+use crate::{
+    chat_completion::messages_to_maps,
+    openai::{DetokenizationRequest, TokenizationRequest},
+    registry::{ModelRegistry, RegistryError},
+};
+use axum::{
+    extract::{Json, State},
+    http::{self, StatusCode},
+    response::IntoResponse,
+};
+use either::Either;
+use mistralrs_core::{
+    DetokenizationRequest as InternalDetokenizationRequest, DetokenizationResponse, Request,
+    TokenizationRequest as InternalTokenizationRequest, TokenizationResponse,
+};
+use serde::Serialize;
+
+pub enum TokenizationResponder<T> {
+    Json(T),
+    InternalError(Box<dyn Error>),
+    ValidationError(Box<dyn Error>),
+}
+
+trait ErrorToResponse: Serialize {
+    fn to_response(&self, code: StatusCode) -> axum::response::Response {
+        let mut r = Json(self).into_response();
+        *r.status_mut() = code;
+        r
+    }
+}
+
+#[derive(Serialize)]
+struct JsonError {
+    message: String,
+}
+
+impl JsonError {
+    fn new(message: String) -> Self {
+        Self { message }
+    }
+}
+impl ErrorToResponse for JsonError {}
+
+impl<T: Serialize> IntoResponse for TokenizationResponder<T> {
+    fn into_response(self) -> axum::response::Response {
+        match self {
+            TokenizationResponder::Json(s) => Json(s).into_response(),
+            TokenizationResponder::InternalError(e) => {
+                JsonError::new(e.to_string()).to_response(http::StatusCode::INTERNAL_SERVER_ERROR)
+            }
+            TokenizationResponder::ValidationError(e) => {
+                JsonError::new(e.to_string()).to_response(http::StatusCode::UNPROCESSABLE_ENTITY)
+            }
+        }
+    }
+}
+
+#[utoipa::path(
+    post,
+    tag = "Mistral.rs",
+    path = "/tokenize",
+    request_body = TokenizationRequest,
+    responses((status = 200, description = "The tokens of the prompt"))
+)]
+pub async fn tokenize(
+    State(registry): State<Arc<ModelRegistry>>,
+    Json(request): Json<TokenizationRequest>,
+) -> TokenizationResponder<TokenizationResponse> {
+    let state = match registry.get(request.model.as_deref()).await {
+        Ok(state) => state,
+        Err(RegistryError::UnknownModel(e)) => {
+            return TokenizationResponder::ValidationError(e.into())
+        }
+        Err(e @ RegistryError::Load(_)) => return TokenizationResponder::InternalError(e.into()),
+    };
+    let text = match (request.prompt, request.messages) {
+        (Some(prompt), None) => Either::Right(prompt),
+        (None, Some(messages)) => Either::Left(messages_to_maps(messages)),
+        _ => {
+            return TokenizationResponder::ValidationError(
+                "Exactly one of `prompt` and `messages` must be given.".into(),
+            )
+        }
+    };
+    let (tx, rx) = channel();
+    state
+        .get_sender()
+        .send(Request::Tokenize(InternalTokenizationRequest {
+            text,
+            add_generation_prompt: request.add_generation_prompt,
+            response: tx,
+        }))
+        .unwrap();
+
+    match rx.recv().unwrap() {
+        Ok(response) => TokenizationResponder::Json(response),
+        Err(e) => TokenizationResponder::ValidationError(e.into()),
+    }
+}
+
+#[utoipa::path(
+    post,
+    tag = "Mistral.rs",
+    path = "/detokenize",
+    request_body = DetokenizationRequest,
+    responses((status = 200, description = "The text of the tokens"))
+)]
+pub async fn detokenize(
+    State(registry): State<Arc<ModelRegistry>>,
+    Json(request): Json<DetokenizationRequest>,
+) -> TokenizationResponder<DetokenizationResponse> {
+    let state = match registry.get(request.model.as_deref()).await {
+        Ok(state) => state,
+        Err(RegistryError::UnknownModel(e)) => {
+            return TokenizationResponder::ValidationError(e.into())
+        }
+        Err(e @ RegistryError::Load(_)) => return TokenizationResponder::InternalError(e.into()),
+    };
+    let (tx, rx) = channel();
+    state
+        .get_sender()
+        .send(Request::Detokenize(InternalDetokenizationRequest {
+            tokens: request.tokens,
+            skip_special_tokens: request.skip_special_tokens,
+            response: tx,
+        }))
+        .unwrap();
+
+    match rx.recv().unwrap() {
+        Ok(response) => TokenizationResponder::Json(response),
+        Err(e) => TokenizationResponder::ValidationError(e.into()),
+    }
+}
@@ -1,6 +1,8 @@
 pub use mistralrs_core::{
-    Constraint, GemmaLoader, GemmaSpecificConfig, LlamaLoader, LlamaSpecificConfig, Loader,
+    AdapterAction, AdapterRequest, BeamSearchParams, Constraint, DryParams, GemmaLoader,
+    GemmaSpecificConfig, LlamaLoader, LlamaSpecificConfig, Loader, Mirostat, MirostatVersion,
     MistralLoader, MistralRs, MistralSpecificConfig, MixtralLoader, MixtralSpecificConfig,
-    ModelKind, Ordering, Phi2Loader, Phi2SpecificConfig, Pipeline, Request, RequestType, Response,
-    SamplingParams, SchedulerMethod, StopTokens, TokenSource,
+    ModelKind, NormalRequest, Ordering, PagedCacheConfig, Phi2Loader, Phi2SpecificConfig, Pipeline,
+    QueueOrder, QueuePolicy, Request, RequestType, Response, SamplingParams, SchedulerMethod,
+    SpeculativeConfig, StopTokens, TokenSource,
 };